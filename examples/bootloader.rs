@@ -0,0 +1,120 @@
+//! A first stage bootloader's use of the driver: check the part, read an image out of flash, and
+//! check its CRC. Only identification, page reads, and `crc` are reached, which is the subset to
+//! measure when sizing a bootloader build. The panic handler never formats anything, and nothing
+//! here uses `FlashError`'s `Display`, so no formatting machinery is linked in.
+//!
+//! The image is expected at block 1, with its length and CRC-32 as two little endian u32s at the
+//! start of the block's first page and the image itself starting on the page after.
+
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+extern crate cortex_m;
+extern crate cortex_m_rt as rt;
+extern crate stm32l4xx_hal as hal;
+
+use hal::qspi::{AddressSize, Qspi, QspiConfig};
+
+use crate::hal::prelude::*;
+use crate::rt::entry;
+use w25n01gv_rs::{
+    crc::crc32_update, new_w25_n01_gv, DeviceVariant, Geometry, ReadMethod, PAGE_SIZE_BYTES,
+    PAGE_SIZE_WITH_ECC_BYTES,
+};
+
+use core::panic::PanicInfo;
+
+const IMAGE_BLOCK: u16 = 1;
+
+#[entry]
+fn main() -> ! {
+    let dp = hal::stm32::Peripherals::take().unwrap();
+
+    let mut rcc = dp.RCC.constrain();
+    let mut gpioa = dp.GPIOA.split(&mut rcc.ahb2);
+    let mut gpiob = dp.GPIOB.split(&mut rcc.ahb2);
+
+    let quadspi_clk = gpioa.pa3.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+    let quadspi_ncs = gpioa.pa2.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+    let quadspi_io0 = gpiob.pb1.into_af10(&mut gpiob.moder, &mut gpiob.afrl);
+    let quadspi_io1 = gpiob.pb0.into_af10(&mut gpiob.moder, &mut gpiob.afrl);
+    let quadspi_io2 = gpioa.pa7.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+    let quadspi_io3 = gpioa.pa6.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+
+    let quadspi = Qspi::new(
+        dp.QUADSPI,
+        (
+            quadspi_clk,
+            quadspi_ncs,
+            quadspi_io0,
+            quadspi_io1,
+            quadspi_io2,
+            quadspi_io3,
+        ),
+        &mut rcc.ahb3,
+        QspiConfig::default()
+            .flash_size(29)
+            .address_size(AddressSize::Addr16Bit)
+            .clock_prescaler(3),
+    );
+
+    let mut flash_chip = new_w25_n01_gv(quadspi);
+
+    match flash_chip.device_info() {
+        Ok(info) if info.variant == DeviceVariant::W25N01GV => {}
+        _ => fail(),
+    }
+
+    let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+    let first_page = Geometry::W25N01GV.block_first_page(IMAGE_BLOCK);
+
+    let read_page = |page_address: u16, buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES]| {
+        flash_chip.read_memory_to_data_buffer(page_address)?;
+        flash_chip.wait_while_busy().unwrap();
+        flash_chip.read_data_buffer(buffer, ReadMethod::FastReadQuadIO)
+    };
+
+    if read_page(first_page, &mut buffer).is_err() {
+        fail();
+    }
+
+    let image_len = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    let image_crc = u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]);
+
+    let mut crc = 0xFFFF_FFFF;
+    let mut remaining = image_len;
+    let mut page_address = first_page + 1;
+
+    while remaining > 0 {
+        if read_page(page_address, &mut buffer).is_err() {
+            fail();
+        }
+
+        let len = remaining.min(PAGE_SIZE_BYTES);
+        crc = crc32_update(crc, &buffer[..len]);
+
+        remaining -= len;
+        page_address += 1;
+    }
+
+    if !crc != image_crc {
+        fail();
+    }
+
+    // The image checks out, copy it to RAM and jump to it here
+    loop {
+        continue;
+    }
+}
+
+fn fail() -> ! {
+    loop {
+        continue;
+    }
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    fail()
+}
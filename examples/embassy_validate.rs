@@ -0,0 +1,104 @@
+//! The `validate` example over embassy-stm32's QUADSPI driver instead of stm32l4xx-hal's, writing
+//! and reading back pages with quad loads and quad I/O reads.
+//!
+//! Build with the embassy-stm32 features for the target, e.g.
+//! `--features embassy,embassy-stm32/stm32l432kc,embassy-stm32/memory-x`.
+
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+extern crate cortex_m;
+#[macro_use]
+extern crate cortex_m_rt as rt;
+
+use cortex_m::delay::Delay;
+use cortex_m_semihosting::hprintln;
+use embassy_stm32::qspi::{enums::AddressSize, Config, Qspi};
+
+use crate::rt::entry;
+use crate::rt::ExceptionFrame;
+use w25n01gv_rs::{
+    new_w25_n01_gv, EmbassyQspiBus, LoadMode, ReadMethod, WriteMethod, PAGES_PER_BLOCK,
+    PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES,
+};
+
+use core::panic::PanicInfo;
+
+/// embassy-stm32 leaves the STM32L4 on its 4MHz MSI clock by default
+const SYSCLK_HZ: u32 = 4_000_000;
+
+#[entry]
+fn main() -> ! {
+    let cp = cortex_m::Peripherals::take().unwrap();
+    let p = embassy_stm32::init(Default::default());
+
+    let mut config = Config::default();
+    config.address_size = AddressSize::_16Bit;
+    config.prescaler = 1;
+
+    let quadspi =
+        Qspi::new_blocking_bank1(p.QUADSPI, p.PB1, p.PB0, p.PA7, p.PA6, p.PA3, p.PA2, config);
+
+    let mut delay = Delay::new(cp.SYST, SYSCLK_HZ);
+
+    let mut flash_chip = new_w25_n01_gv(EmbassyQspiBus::new(quadspi));
+    flash_chip
+        .set_write_protection(false, false, false, false, false)
+        .unwrap();
+    flash_chip.set_continuous_read_mode(false).unwrap();
+
+    let mut buffer = [0_u8; PAGE_SIZE_BYTES];
+    for (i, elem) in buffer.iter_mut().enumerate() {
+        *elem = (i & 0xFF) as u8;
+    }
+
+    loop {
+        hprintln!("Start new block test").unwrap();
+
+        let write_flash_chip = flash_chip.into_write_mode().unwrap();
+        flash_chip = write_flash_chip.erase_128kb_block(0).unwrap();
+        flash_chip.wait_while_busy().unwrap();
+
+        for page_index in 0..PAGES_PER_BLOCK as u16 {
+            let write_flash_chip = flash_chip.into_write_mode().unwrap();
+            write_flash_chip
+                .load_to_data_buffer(&buffer, 0, WriteMethod::QuadLoad, LoadMode::ResetThenLoad)
+                .unwrap();
+            flash_chip = write_flash_chip.commit(page_index, &mut delay).unwrap();
+
+            let mut read_buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+            flash_chip
+                .read_page(page_index, &mut read_buffer, ReadMethod::FastReadQuadIO)
+                .unwrap();
+
+            for (index, (truth, read)) in buffer.iter().zip(read_buffer.iter()).enumerate() {
+                if truth != read {
+                    hprintln!(
+                        "Read back a byte incorrectly! Got {} wanted {} ({}, {})",
+                        read,
+                        truth,
+                        page_index,
+                        index
+                    )
+                    .unwrap();
+                }
+            }
+
+            for elem in buffer.iter_mut() {
+                (*elem) = elem.wrapping_add(1);
+            }
+        }
+    }
+}
+
+#[exception]
+fn HardFault(ef: &ExceptionFrame) -> ! {
+    panic!("{:#?}", ef);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hprintln!("{:?}", info).unwrap();
+    loop {}
+}
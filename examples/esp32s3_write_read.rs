@@ -0,0 +1,80 @@
+//! Reads the JEDEC ID of a W25N01GV wired to SPI2 of an ESP32-S3, then erases block 0 and writes
+//! and reads back its first page with quad loads and quad I/O reads, over `EspSpiBus`.
+//!
+//! Build for the `xtensa-esp32s3-none-elf` target with `--features esp32s3`. Flashing with espflash
+//! also needs the app descriptor from esp-bootloader-esp-idf linked in.
+
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+use esp_hal::{
+    main,
+    spi::{
+        master::{Config, Spi},
+        Mode,
+    },
+    time::Rate,
+};
+use w25n01gv_rs::{
+    new_w25_n01_gv, EspSpiBus, ReadMethod, WriteMethod, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES,
+};
+
+use core::panic::PanicInfo;
+
+/// Manufacturer (Winbond) and device ID bytes of the W25N01GV
+const JEDEC_ID: [u8; 3] = [0xEF, 0xAA, 0x21];
+
+#[main]
+fn main() -> ! {
+    let peripherals = esp_hal::init(esp_hal::Config::default());
+
+    let spi = Spi::new(
+        peripherals.SPI2,
+        Config::default()
+            .with_frequency(Rate::from_mhz(20))
+            .with_mode(Mode::_0),
+    )
+    .unwrap()
+    .with_sck(peripherals.GPIO12)
+    .with_cs(peripherals.GPIO10)
+    .with_sio0(peripherals.GPIO11)
+    .with_sio1(peripherals.GPIO13)
+    .with_sio2(peripherals.GPIO14)
+    .with_sio3(peripherals.GPIO9);
+
+    let mut flash_chip = new_w25_n01_gv(EspSpiBus::new(spi));
+    assert_eq!(flash_chip.get_jedec_id().unwrap(), JEDEC_ID);
+
+    flash_chip
+        .set_write_protection(false, false, false, false, false)
+        .unwrap();
+    flash_chip.set_continuous_read_mode(false).unwrap();
+
+    let mut buffer = [0_u8; PAGE_SIZE_BYTES];
+    for (i, elem) in buffer.iter_mut().enumerate() {
+        *elem = (i & 0xFF) as u8;
+    }
+
+    let write_flash_chip = flash_chip.into_write_mode().unwrap();
+    flash_chip = write_flash_chip.erase_128kb_block(0).unwrap();
+    flash_chip.wait_while_busy().unwrap();
+
+    let (flash_chip, write_failure) = flash_chip
+        .program_page(0, &buffer, 0, WriteMethod::QuadLoad)
+        .unwrap();
+    assert!(!write_failure);
+
+    let mut read_buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+    flash_chip
+        .read_page(0, &mut read_buffer, ReadMethod::FastReadQuadIO)
+        .unwrap();
+    assert_eq!(buffer[..], read_buffer[..PAGE_SIZE_BYTES]);
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,126 @@
+//! The `validate` example on an STM32H743 over the stm32h7xx-hal QUADSPI driver, wired as on a
+//! Nucleo-H743ZI. The HAL's `Qspi` sends every phase of a command on the same number of lines, so
+//! it writes and reads back pages with single line loads and fast reads only. The quad methods
+//! return `UnsupportedOnThisBus` on this bus.
+//!
+//! Build with the stm32h7xx-hal features for the target, e.g.
+//! `--features stm32h7,stm32h7xx-hal/stm32h743v,stm32h7xx-hal/rt`.
+
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+extern crate cortex_m;
+#[macro_use]
+extern crate cortex_m_rt as rt;
+extern crate stm32h7xx_hal as hal;
+
+use cortex_m_semihosting::hprintln;
+use hal::xspi::QspiExt;
+
+use crate::hal::prelude::*;
+use crate::rt::entry;
+use crate::rt::ExceptionFrame;
+use w25n01gv_rs::{
+    new_w25_n01_gv, LoadMode, ReadMethod, Stm32h7QspiBus, WriteMethod, PAGES_PER_BLOCK,
+    PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES,
+};
+
+use core::panic::PanicInfo;
+
+#[entry]
+fn main() -> ! {
+    let cp = cortex_m::Peripherals::take().unwrap();
+    let dp = hal::stm32::Peripherals::take().unwrap();
+
+    let pwr = dp.PWR.constrain();
+    let pwrcfg = pwr.freeze();
+    let rcc = dp.RCC.constrain();
+    let ccdr = rcc.sys_ck(96.MHz()).freeze(pwrcfg, &dp.SYSCFG);
+
+    let gpiob = dp.GPIOB.split(ccdr.peripheral.GPIOB);
+    let gpiod = dp.GPIOD.split(ccdr.peripheral.GPIOD);
+    let gpioe = dp.GPIOE.split(ccdr.peripheral.GPIOE);
+
+    let quadspi_clk = gpiob.pb2.into_alternate::<9>();
+    let _quadspi_ncs = gpiob.pb6.into_alternate::<10>();
+    let quadspi_io0 = gpiod.pd11.into_alternate::<9>();
+    let quadspi_io1 = gpiod.pd12.into_alternate::<9>();
+    let quadspi_io2 = gpioe.pe2.into_alternate::<9>();
+    let quadspi_io3 = gpiod.pd13.into_alternate::<9>();
+
+    let quadspi = dp.QUADSPI.bank1(
+        (
+            quadspi_clk,
+            quadspi_io0,
+            quadspi_io1,
+            quadspi_io2,
+            quadspi_io3,
+        ),
+        24.MHz(),
+        &ccdr.clocks,
+        ccdr.peripheral.QSPI,
+    );
+
+    let mut delay = cp.SYST.delay(ccdr.clocks);
+
+    let mut flash_chip = new_w25_n01_gv(Stm32h7QspiBus::new(quadspi));
+    flash_chip
+        .set_write_protection(false, false, false, false, false)
+        .unwrap();
+    flash_chip.set_continuous_read_mode(false).unwrap();
+
+    let mut buffer = [0_u8; PAGE_SIZE_BYTES];
+    for (i, elem) in buffer.iter_mut().enumerate() {
+        *elem = (i & 0xFF) as u8;
+    }
+
+    loop {
+        hprintln!("Start new block test").unwrap();
+
+        let write_flash_chip = flash_chip.into_write_mode().unwrap();
+        flash_chip = write_flash_chip.erase_128kb_block(0).unwrap();
+        flash_chip.wait_while_busy().unwrap();
+
+        for page_index in 0..PAGES_PER_BLOCK as u16 {
+            let write_flash_chip = flash_chip.into_write_mode().unwrap();
+            write_flash_chip
+                .load_to_data_buffer(&buffer, 0, WriteMethod::SingleLoad, LoadMode::ResetThenLoad)
+                .unwrap();
+            flash_chip = write_flash_chip.commit(page_index, &mut delay).unwrap();
+
+            let mut read_buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+            flash_chip
+                .read_page(page_index, &mut read_buffer, ReadMethod::FastRead)
+                .unwrap();
+
+            for (index, (truth, read)) in buffer.iter().zip(read_buffer.iter()).enumerate() {
+                if truth != read {
+                    hprintln!(
+                        "Read back a byte incorrectly! Got {} wanted {} ({}, {})",
+                        read,
+                        truth,
+                        page_index,
+                        index
+                    )
+                    .unwrap();
+                }
+            }
+
+            for elem in buffer.iter_mut() {
+                (*elem) = elem.wrapping_add(1);
+            }
+        }
+    }
+}
+
+#[exception]
+fn HardFault(ef: &ExceptionFrame) -> ! {
+    panic!("{:#?}", ef);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hprintln!("{:?}", info).unwrap();
+    loop {}
+}
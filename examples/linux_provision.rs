@@ -0,0 +1,61 @@
+//! Programs and reads back the first page of a W25N01GV from a Linux host over spidev, e.g. a
+//! Raspberry Pi with the chip on SPI0 CE0, as a starting point for provisioning before the MCU is
+//! fitted. Everything goes out on one data line, so it uses the single line load and fast read.
+//!
+//! Build with `--no-default-features --features linux`.
+
+use linux_embedded_hal::{
+    spidev::{SpiModeFlags, SpidevOptions},
+    SpidevDevice,
+};
+use w25n01gv_rs::{
+    new_w25_n01_gv, LinuxSpiBus, ReadMethod, WriteMethod, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES,
+};
+
+const SPIDEV_PATH: &str = "/dev/spidev0.0";
+
+fn main() {
+    let mut spi = SpidevDevice::open(SPIDEV_PATH).expect("couldn't open spidev");
+    spi.0
+        .configure(
+            &SpidevOptions::new()
+                .bits_per_word(8)
+                .max_speed_hz(10_000_000)
+                .mode(SpiModeFlags::SPI_MODE_0)
+                .build(),
+        )
+        .expect("couldn't configure spidev");
+
+    let mut flash_chip = new_w25_n01_gv(LinuxSpiBus::new(spi));
+    println!("JEDEC ID {:02X?}", flash_chip.get_jedec_id().unwrap());
+
+    flash_chip
+        .set_write_protection(false, false, false, false, false)
+        .unwrap();
+    flash_chip.set_continuous_read_mode(false).unwrap();
+
+    let mut buffer = [0_u8; PAGE_SIZE_BYTES];
+    for (i, elem) in buffer.iter_mut().enumerate() {
+        *elem = (i & 0xFF) as u8;
+    }
+
+    let write_flash_chip = flash_chip.into_write_mode().unwrap();
+    flash_chip = write_flash_chip.erase_128kb_block(0).unwrap();
+    flash_chip.wait_while_busy().unwrap();
+
+    let (flash_chip, write_failure) = flash_chip
+        .program_page(0, &buffer, 0, WriteMethod::SingleLoad)
+        .unwrap();
+    assert!(!write_failure, "program failed");
+
+    let mut read_buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+    flash_chip
+        .read_page(0, &mut read_buffer, ReadMethod::FastRead)
+        .unwrap();
+
+    if buffer[..] == read_buffer[..PAGE_SIZE_BYTES] {
+        println!("Page 0 read back correctly");
+    } else {
+        println!("Page 0 read back incorrectly");
+    }
+}
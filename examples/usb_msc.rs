@@ -0,0 +1,252 @@
+//! Shows the device to a host as a read-only USB drive, e.g. for pulling logs off it. The SCSI
+//! commands come from usbd-storage's bulk-only transport over the STM32L4's USB peripheral, and
+//! READ(10) is served by `UsbMscBackend`, which keeps the last 4 pages read in RAM.
+//!
+//! The drive reports itself write protected, and writes fail with DATA PROTECT, so the host can
+//! mount it but can't change it. Whatever filesystem the host should see has to be put on the
+//! device some other way first, e.g. with the `linux_provision` example.
+//!
+//! Build with `--features usb-msc`.
+
+#![deny(unsafe_code)]
+#![no_std]
+#![no_main]
+
+extern crate cortex_m;
+#[macro_use]
+extern crate cortex_m_rt as rt;
+extern crate stm32l4xx_hal as hal;
+
+use cortex_m_semihosting::hprintln;
+use hal::{
+    qspi::{AddressSize, Qspi, QspiConfig},
+    rcc::{PllConfig, PllDivider, PllSource},
+    usb::{Peripheral, UsbBus},
+};
+use usb_device::prelude::*;
+use usbd_storage::{
+    subclass::{
+        scsi::{Scsi, ScsiCommand},
+        Command,
+    },
+    transport::{
+        bbb::{BulkOnly, BulkOnlyError},
+        TransportError,
+    },
+};
+
+use crate::hal::prelude::*;
+use crate::rt::entry;
+use crate::rt::ExceptionFrame;
+use w25n01gv_rs::{new_w25_n01_gv, ReadMethod, UsbMscBackend, MSC_BLOCK_SIZE};
+
+use core::panic::PanicInfo;
+
+type Drive<'a> = Scsi<BulkOnly<'a, UsbBus<Peripheral>, &'a mut [u8]>>;
+
+/// Vendor, product and revision as INQUIRY reports them, padded to 8, 16 and 4 bytes
+const INQUIRY: [u8; 36] = *b"\x00\x80\x04\x02\x1F\x00\x00\x00W25N01GVFlash log drive 0.1 ";
+
+/// Sense key, additional sense code and qualifier of the last failed command
+#[derive(Clone, Copy)]
+struct Sense(u8, u8, u8);
+
+const NO_SENSE: Sense = Sense(0x00, 0x00, 0x00);
+const UNRECOVERED_READ_ERROR: Sense = Sense(0x03, 0x11, 0x00);
+const WRITE_PROTECTED: Sense = Sense(0x07, 0x27, 0x00);
+const INVALID_COMMAND: Sense = Sense(0x05, 0x20, 0x00);
+
+/// What has to carry over between polls while a READ(10) is sent out a packet at a time
+struct State {
+    sense: Sense,
+    /// Bytes of the current READ(10) already handed to the transport
+    sent: usize,
+    block: [u8; MSC_BLOCK_SIZE],
+}
+
+#[entry]
+fn main() -> ! {
+    let dp = hal::stm32::Peripherals::take().unwrap();
+
+    let mut flash = dp.FLASH.constrain();
+    let mut rcc = dp.RCC.constrain();
+    let mut pwr = dp.PWR.constrain(&mut rcc.apb1r1);
+
+    let _clocks = rcc
+        .cfgr
+        .hsi48(true)
+        .pll_source(PllSource::HSI16)
+        .sysclk_with_pll(80.mhz(), PllConfig::new(2, 20, PllDivider::Div2))
+        .pclk1(80.mhz())
+        .pclk2(80.mhz())
+        .freeze(&mut flash.acr, &mut pwr);
+
+    enable_usb_supply();
+
+    let mut gpioa = dp.GPIOA.split(&mut rcc.ahb2);
+    let mut gpiob = dp.GPIOB.split(&mut rcc.ahb2);
+
+    let quadspi_clk = gpioa.pa3.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+    let quadspi_ncs = gpioa.pa2.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+    let quadspi_io0 = gpiob.pb1.into_af10(&mut gpiob.moder, &mut gpiob.afrl);
+    let quadspi_io1 = gpiob.pb0.into_af10(&mut gpiob.moder, &mut gpiob.afrl);
+    let quadspi_io2 = gpioa.pa7.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+    let quadspi_io3 = gpioa.pa6.into_af10(&mut gpioa.moder, &mut gpioa.afrl);
+
+    let quadspi = Qspi::new(
+        dp.QUADSPI,
+        (
+            quadspi_clk,
+            quadspi_ncs,
+            quadspi_io0,
+            quadspi_io1,
+            quadspi_io2,
+            quadspi_io3,
+        ),
+        &mut rcc.ahb3,
+        QspiConfig::default()
+            .flash_size(29)
+            .address_size(AddressSize::Addr16Bit)
+            .clock_prescaler(1),
+    );
+
+    let flash_chip = new_w25_n01_gv(quadspi);
+    flash_chip.set_continuous_read_mode(false).unwrap();
+    let mut backend = UsbMscBackend::<_, 4>::new(flash_chip, ReadMethod::FastReadQuadIO);
+
+    let usb = Peripheral {
+        usb: dp.USB,
+        pin_dm: gpioa.pa11.into_af10(&mut gpioa.moder, &mut gpioa.afrh),
+        pin_dp: gpioa.pa12.into_af10(&mut gpioa.moder, &mut gpioa.afrh),
+    };
+    let usb_bus = UsbBus::new(usb);
+
+    let mut transport_buffer = [0_u8; 512];
+    let mut scsi = Scsi::new(&usb_bus, 64, 0, &mut transport_buffer[..]).unwrap();
+
+    let mut usb_device = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x16C0, 0x27DD))
+        .strings(&[StringDescriptors::default()
+            .manufacturer("w25n01gv-rs")
+            .product("Flash log drive")
+            .serial_number("0001")])
+        .unwrap()
+        .self_powered(false)
+        .build();
+
+    let mut state = State {
+        sense: NO_SENSE,
+        sent: 0,
+        block: [0; MSC_BLOCK_SIZE],
+    };
+
+    hprintln!("Serving {} blocks", backend.block_count()).unwrap();
+
+    loop {
+        if !usb_device.poll(&mut [&mut scsi]) {
+            continue;
+        }
+
+        let _ = scsi.poll(|command| {
+            if let Err(err) = process_command(command, &mut backend, &mut state) {
+                hprintln!("USB transport error {:?}", err).unwrap();
+            }
+        });
+    }
+}
+
+fn process_command<BUS: w25n01gv_rs::QspiBus>(
+    mut command: Command<ScsiCommand, Drive<'_>>,
+    backend: &mut UsbMscBackend<BUS, 4>,
+    state: &mut State,
+) -> Result<(), TransportError<BulkOnlyError>> {
+    match command.kind {
+        ScsiCommand::TestUnitReady | ScsiCommand::PreventAllowMediumRemoval { .. } => {
+            command.pass();
+        }
+        ScsiCommand::Inquiry { .. } => {
+            command.try_write_data_all(&INQUIRY)?;
+            command.pass();
+        }
+        ScsiCommand::RequestSense { .. } => {
+            let Sense(key, code, qualifier) = state.sense;
+            command.try_write_data_all(&[
+                0x70, 0, key, 0, 0, 0, 0, 10, 0, 0, 0, 0, code, qualifier, 0, 0, 0, 0,
+            ])?;
+            state.sense = NO_SENSE;
+            command.pass();
+        }
+        ScsiCommand::ReadCapacity10 { .. } => {
+            let mut data = [0_u8; 8];
+            data[..4].copy_from_slice(&(backend.block_count() - 1).to_be_bytes());
+            data[4..].copy_from_slice(&(MSC_BLOCK_SIZE as u32).to_be_bytes());
+            command.try_write_data_all(&data)?;
+            command.pass();
+        }
+        ScsiCommand::ReadFormatCapacities { .. } => {
+            let mut data = [0_u8; 12];
+            data[3] = 8;
+            data[4..8].copy_from_slice(&backend.block_count().to_be_bytes());
+            data[8..].copy_from_slice(&(MSC_BLOCK_SIZE as u32).to_be_bytes());
+            // Formatted media
+            data[8] = 0x02;
+            command.try_write_data_all(&data)?;
+            command.pass();
+        }
+        ScsiCommand::ModeSense6 { .. } => {
+            // The WP bit of the device specific parameter, so the host mounts the drive read-only
+            command.try_write_data_all(&[0x03, 0x00, 0x80, 0x00])?;
+            command.pass();
+        }
+        ScsiCommand::Read { lba, len } => {
+            let total = len as usize * MSC_BLOCK_SIZE;
+            if state.sent == total {
+                state.sent = 0;
+                command.pass();
+                return Ok(());
+            }
+
+            // A logical block is read once per packet the transport takes, which the backend's page
+            // cache makes cheap
+            let offset = state.sent % MSC_BLOCK_SIZE;
+            let block_lba = lba as u32 + (state.sent / MSC_BLOCK_SIZE) as u32;
+            if let Err(err) = backend.read_blocks(block_lba, &mut state.block) {
+                hprintln!("Couldn't read block {}: {:?}", block_lba, err).unwrap();
+                state.sent = 0;
+                state.sense = UNRECOVERED_READ_ERROR;
+                command.fail();
+                return Ok(());
+            }
+
+            state.sent += command.write_data(&state.block[offset..])?;
+        }
+        ScsiCommand::Write { .. } => {
+            state.sense = WRITE_PROTECTED;
+            command.fail();
+        }
+        _ => {
+            state.sense = INVALID_COMMAND;
+            command.fail();
+        }
+    }
+
+    Ok(())
+}
+
+/// The STM32L4 powers its USB transceiver from VDDUSB, which stays isolated until `USV` is set, and
+/// the HAL has no safe way to set it
+#[allow(unsafe_code)]
+fn enable_usb_supply() {
+    let pwr = unsafe { &*hal::stm32::PWR::ptr() };
+    pwr.cr2.modify(|_, w| w.usv().set_bit());
+}
+
+#[exception]
+fn HardFault(ef: &ExceptionFrame) -> ! {
+    panic!("{:#?}", ef);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    hprintln!("{:?}", info).unwrap();
+    loop {}
+}
@@ -11,6 +11,7 @@ extern crate stm32l4xx_hal as hal;
 
 use cortex_m_semihosting::hprintln;
 use hal::{
+    delay::Delay,
     qspi::{AddressSize, Qspi, QspiConfig},
     rcc::{PllConfig, PllDivider, PllSource},
 };
@@ -19,7 +20,7 @@ use crate::hal::prelude::*;
 use crate::rt::entry;
 use crate::rt::ExceptionFrame;
 use w25n01gv_rs::{
-    new_w25_n01_gv, ReadMethod, WriteMethod, PAGES_PER_BLOCK, PAGE_SIZE_BYTES,
+    new_w25_n01_gv, LoadMode, ReadMethod, WriteMethod, PAGES_PER_BLOCK, PAGE_SIZE_BYTES,
     PAGE_SIZE_WITH_ECC_BYTES,
 };
 
@@ -69,6 +70,8 @@ fn main() -> ! {
             .clock_prescaler(1),
     );
 
+    let mut delay = Delay::new(cp.SYST, clocks);
+
     let mut flash_chip = new_w25_n01_gv(quadspi);
     flash_chip
         .set_write_protection(false, false, false, false, false)
@@ -85,23 +88,18 @@ fn main() -> ! {
 
         let write_flash_chip = flash_chip.into_write_mode().unwrap();
         flash_chip = write_flash_chip.erase_128kb_block(0).unwrap();
-        flash_chip.wait_while_busy();
+        flash_chip.wait_while_busy().unwrap();
 
         for page_index in 0..PAGES_PER_BLOCK as u16 {
             let write_flash_chip = flash_chip.into_write_mode().unwrap();
             write_flash_chip
-                .load_to_data_buffer(&buffer, 0, WriteMethod::QuadLoad)
-                .unwrap();
-            flash_chip = write_flash_chip
-                .write_data_buffer_to_memory(page_index)
+                .load_to_data_buffer(&buffer, 0, WriteMethod::QuadLoad, LoadMode::ResetThenLoad)
                 .unwrap();
-            flash_chip.wait_while_busy();
+            flash_chip = write_flash_chip.commit(page_index, &mut delay).unwrap();
 
-            flash_chip.read_memory_to_data_buffer(page_index).unwrap();
-            flash_chip.wait_while_busy();
             let mut read_buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
             flash_chip
-                .read_data_buffer(&mut read_buffer, ReadMethod::FastReadQuadIO)
+                .read_page(page_index, &mut read_buffer, ReadMethod::FastReadQuadIO)
                 .unwrap();
 
             for (index, (truth, read)) in buffer.iter().zip(read_buffer.iter()).enumerate() {
@@ -11,6 +11,7 @@ extern crate stm32l4xx_hal as hal;
 
 use cortex_m_semihosting::hprintln;
 use hal::{
+    delay::Delay,
     qspi::{AddressSize, Qspi, QspiConfig},
     rcc::{PllConfig, PllDivider, PllSource},
 };
@@ -18,7 +19,7 @@ use hal::{
 use crate::hal::prelude::*;
 use crate::rt::entry;
 use crate::rt::ExceptionFrame;
-use w25n01gv_rs::{new_w25_n01_gv, ReadMethod, WriteMethod, PAGE_SIZE_WITH_ECC_BYTES};
+use w25n01gv_rs::{new_w25_n01_gv, LoadMode, ReadMethod, WriteMethod, PAGE_SIZE_WITH_ECC_BYTES};
 
 use core::panic::PanicInfo;
 
@@ -66,6 +67,8 @@ fn main() -> ! {
             .clock_prescaler(3),
     );
 
+    let mut delay = Delay::new(cp.SYST, clocks);
+
     let mut flash_chip = new_w25_n01_gv(quadspi);
 
     let id = flash_chip.get_jedec_id().unwrap();
@@ -78,7 +81,7 @@ fn main() -> ! {
 
     let flash_chip = flash_chip.into_write_mode().unwrap();
     let flash_chip = flash_chip.erase_128kb_block(0).unwrap();
-    flash_chip.wait_while_busy();
+    flash_chip.wait_while_busy().unwrap();
 
     let buffer = [0, 1, 2, 3, 42];
     hprintln!(
@@ -93,18 +96,14 @@ fn main() -> ! {
 
     let flash_chip = flash_chip.into_write_mode().unwrap();
     flash_chip
-        .load_to_data_buffer(&buffer, 0, WriteMethod::SingleLoad)
+        .load_to_data_buffer(&buffer, 0, WriteMethod::SingleLoad, LoadMode::ResetThenLoad)
         .unwrap();
 
-    let flash_chip = flash_chip.write_data_buffer_to_memory(0).unwrap();
-    flash_chip.wait_while_busy();
+    let flash_chip = flash_chip.commit(0, &mut delay).unwrap();
 
-    flash_chip.read_memory_to_data_buffer(0).unwrap();
     let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
-
-    flash_chip.wait_while_busy();
     flash_chip
-        .read_data_buffer(&mut buffer, ReadMethod::FastRead)
+        .read_page(0, &mut buffer, ReadMethod::FastRead)
         .unwrap();
 
     hprintln!(
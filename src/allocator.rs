@@ -0,0 +1,507 @@
+use core::{convert::TryInto, ops::Range};
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    block_header::{BlockHeader, StructureKind},
+    digest::Crc32,
+    FlashError, Geometry, QspiBus, ReadMethod, ReadMode, StorageError, WriteMethod, BLOCK_COUNT,
+    PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+const BITMAP_WORDS: usize = BLOCK_COUNT / 32;
+const BITMAP_BYTES: usize = BLOCK_COUNT / 8;
+
+const ALLOCATOR_MAGIC: u32 = 0x424C_4B41;
+
+// Layout of the persisted allocator within the main area of a slot's first page
+const MAGIC_OFFSET: usize = 0;
+const GENERATION_OFFSET: usize = 4;
+const NEXT_BLOCK_OFFSET: usize = 8;
+const USED_OFFSET: usize = 10;
+const RESERVED_OFFSET: usize = USED_OFFSET + BITMAP_BYTES;
+const CRC_OFFSET: usize = RESERVED_OFFSET + BITMAP_BYTES;
+
+/// One bit per block of the device
+#[derive(Clone)]
+pub(crate) struct Bitmap([u32; BITMAP_WORDS]);
+
+impl Bitmap {
+    pub(crate) fn new() -> Bitmap {
+        Bitmap([0; BITMAP_WORDS])
+    }
+
+    pub(crate) fn get(&self, block: u16) -> bool {
+        self.0[block as usize / 32] & (1 << (block % 32)) != 0
+    }
+
+    pub(crate) fn set(&mut self, block: u16, value: bool) {
+        if value {
+            self.0[block as usize / 32] |= 1 << (block % 32);
+        } else {
+            self.0[block as usize / 32] &= !(1 << (block % 32));
+        }
+    }
+
+    fn write_to(&self, bytes: &mut [u8]) {
+        for (word, chunk) in self.0.iter().zip(bytes.chunks_mut(4)) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+    }
+
+    fn read_from(bytes: &[u8]) -> Bitmap {
+        let mut bitmap = Bitmap::new();
+        for (word, chunk) in bitmap.0.iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+
+        bitmap
+    }
+}
+
+/// Tracks which blocks are free for applications that manage their own block usage. Blocks are
+/// handed out round-robin starting after the most recent allocation to spread wear.
+///
+/// The allocator persists itself into one of two slot blocks, alternating between them so a
+/// power loss part way through saving always leaves the previous copy intact. The slot blocks are
+/// reserved and never allocated.
+#[derive(Clone)]
+pub struct BlockAllocator {
+    used: Bitmap,
+    reserved: Bitmap,
+    next_block: u16,
+    generation: u32,
+    slots: [u16; 2],
+}
+
+impl BlockAllocator {
+    /// Creates an allocator with every block free except for the two slot blocks
+    pub fn new(slots: [u16; 2]) -> BlockAllocator {
+        let mut allocator = BlockAllocator {
+            used: Bitmap::new(),
+            reserved: Bitmap::new(),
+            next_block: 0,
+            generation: 0,
+            slots,
+        };
+
+        allocator.mark_bad(slots[0]);
+        allocator.mark_bad(slots[1]);
+
+        allocator
+    }
+
+    /// Allocates the next free block after the most recently allocated one, or returns None if
+    /// every block is used or reserved
+    pub fn allocate(&mut self) -> Option<u16> {
+        for offset in 0..BLOCK_COUNT as u16 {
+            let block = (self.next_block + offset) % BLOCK_COUNT as u16;
+
+            if self.is_free(block) {
+                self.used.set(block, true);
+                self.next_block = (block + 1) % BLOCK_COUNT as u16;
+
+                return Some(block);
+            }
+        }
+
+        None
+    }
+
+    pub fn free(&mut self, block: u16) {
+        if (block as usize) < BLOCK_COUNT {
+            self.used.set(block, false);
+        }
+    }
+
+    /// Marks a block as in use without allocating it, e.g. when a structure claims a known block
+    pub fn mark_used(&mut self, block: u16) {
+        if (block as usize) < BLOCK_COUNT {
+            self.used.set(block, true);
+        }
+    }
+
+    /// Reserves a range of blocks so they're never allocated
+    pub fn mark_reserved(&mut self, blocks: Range<u16>) {
+        for block in blocks {
+            self.mark_bad(block);
+        }
+    }
+
+    /// Reserves a bad block so it's never allocated
+    pub fn mark_bad(&mut self, block: u16) {
+        if (block as usize) < BLOCK_COUNT {
+            self.reserved.set(block, true);
+        }
+    }
+
+    /// Makes a reserved block available for allocation again
+    pub fn unreserve(&mut self, block: u16) {
+        if (block as usize) < BLOCK_COUNT {
+            self.reserved.set(block, false);
+        }
+    }
+
+    /// Is true if the block is reserved or marked bad
+    pub fn is_reserved(&self, block: u16) -> bool {
+        (block as usize) < BLOCK_COUNT && self.reserved.get(block)
+    }
+
+    pub fn is_free(&self, block: u16) -> bool {
+        (block as usize) < BLOCK_COUNT && !self.used.get(block) && !self.reserved.get(block)
+    }
+
+    pub fn free_count(&self) -> usize {
+        (0..BLOCK_COUNT as u16)
+            .filter(|block| self.is_free(*block))
+            .count()
+    }
+
+    /// Incremented every time the allocator is saved
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Saves the allocator into the older of the two slot blocks. Returns
+    /// `StorageError::InsufficientGoodBlocks` without erasing anything if that slot block is
+    /// bad, since erasing it would destroy its bad block marker.
+    pub fn save<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        read_method: ReadMethod,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        for slot in self.slots.iter() {
+            flash.check_block0(*slot, 1)?;
+        }
+
+        let generation = self.generation.wrapping_add(1);
+        let slot = self.slots[generation as usize % 2];
+        if flash.is_bad_block(slot, read_method)? {
+            return Err(StorageError::InsufficientGoodBlocks {
+                first_block: slot,
+                good_blocks: 0,
+            });
+        }
+        self.generation = generation;
+
+        let mut page = [0xFF_u8; PAGE_SIZE_BYTES];
+        self.serialize(&mut page, flash.crc32_digest());
+        BlockHeader {
+            kind: StructureKind::AllocatorSlot,
+            region_id: 0,
+            sequence: self.generation,
+        }
+        .write_into(&mut page, flash.crc32_digest())?;
+
+        let flash = flash.erase_block(slot, delay)?;
+        let flash = flash.into_write_mode()?.write_page_split(
+            Geometry::W25N01GV.block_first_page(slot),
+            &page,
+            &[],
+            write_method,
+            delay,
+        )?;
+
+        Ok(flash)
+    }
+
+    /// Loads the most recently saved allocator from the slot blocks, or returns None if neither
+    /// slot holds a valid copy. Block 0 is reserved in the loaded allocator while the block 0
+    /// policy reserves it.
+    pub fn load<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        slots: [u16; 2],
+        method: ReadMethod,
+    ) -> Result<Option<BlockAllocator>, StorageError> {
+        if slots.iter().any(|slot| *slot as usize >= BLOCK_COUNT) {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        let mut newest: Option<BlockAllocator> = None;
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+        for slot in slots.iter() {
+            flash.read_memory_to_data_buffer(Geometry::W25N01GV.block_first_page(*slot))?;
+            flash.wait_while_busy()?;
+            flash.read_data_buffer(&mut buffer, method)?;
+
+            if let Some(allocator) =
+                BlockAllocator::deserialize(&buffer[..PAGE_SIZE_BYTES], slots, flash.crc32_digest())
+            {
+                let is_newer = match &newest {
+                    Some(newest) => allocator.generation.wrapping_sub(newest.generation) as i32 > 0,
+                    None => true,
+                };
+
+                if is_newer {
+                    newest = Some(allocator);
+                }
+            }
+        }
+
+        if flash.block0_reserved() {
+            if let Some(allocator) = newest.as_mut() {
+                allocator.mark_reserved(0..1);
+            }
+        }
+
+        Ok(newest)
+    }
+
+    /// Rebuilds the allocator from the device itself when no saved copy is available. Blocks in
+    /// `blocks` with a bad block marker are reserved, and any whose first page holds a block
+    /// header, even a corrupt one, is treated as used. Block 0 is reserved while the block 0 policy
+    /// reserves it.
+    pub fn reconstruct<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        slots: [u16; 2],
+        blocks: Range<u16>,
+        method: ReadMethod,
+    ) -> Result<BlockAllocator, StorageError> {
+        let mut allocator = BlockAllocator::new(slots);
+        if flash.block0_reserved() {
+            allocator.mark_reserved(0..1);
+        }
+
+        for block in blocks {
+            if !allocator.is_free(block) {
+                continue;
+            }
+
+            if flash.is_bad_block(block, method)? {
+                allocator.mark_bad(block);
+                continue;
+            }
+
+            match flash.read_block_header(block, method) {
+                Ok(None) => {}
+                Ok(Some(_)) | Err(StorageError::CorruptBlockHeader) => allocator.mark_used(block),
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(allocator)
+    }
+
+    fn serialize(&self, page: &mut [u8; PAGE_SIZE_BYTES], digest: Crc32) {
+        page[MAGIC_OFFSET..MAGIC_OFFSET + 4].copy_from_slice(&ALLOCATOR_MAGIC.to_le_bytes());
+        page[GENERATION_OFFSET..GENERATION_OFFSET + 4]
+            .copy_from_slice(&self.generation.to_le_bytes());
+        page[NEXT_BLOCK_OFFSET..NEXT_BLOCK_OFFSET + 2]
+            .copy_from_slice(&self.next_block.to_le_bytes());
+        self.used
+            .write_to(&mut page[USED_OFFSET..USED_OFFSET + BITMAP_BYTES]);
+        self.reserved
+            .write_to(&mut page[RESERVED_OFFSET..RESERVED_OFFSET + BITMAP_BYTES]);
+
+        let crc = digest.checksum(&page[..CRC_OFFSET]);
+        page[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn deserialize(page: &[u8], slots: [u16; 2], digest: Crc32) -> Option<BlockAllocator> {
+        let read_u32 = |offset: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(
+                page[offset..offset + 4].try_into().ok()?,
+            ))
+        };
+
+        if read_u32(MAGIC_OFFSET)? != ALLOCATOR_MAGIC
+            || read_u32(CRC_OFFSET)? != digest.checksum(&page[..CRC_OFFSET])
+        {
+            return None;
+        }
+
+        Some(BlockAllocator {
+            used: Bitmap::read_from(&page[USED_OFFSET..USED_OFFSET + BITMAP_BYTES]),
+            reserved: Bitmap::read_from(&page[RESERVED_OFFSET..RESERVED_OFFSET + BITMAP_BYTES]),
+            next_block: u16::from_le_bytes([page[NEXT_BLOCK_OFFSET], page[NEXT_BLOCK_OFFSET + 1]])
+                % BLOCK_COUNT as u16,
+            generation: read_u32(GENERATION_OFFSET)?,
+            slots,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use std::vec::Vec;
+
+    const SLOTS: [u16; 2] = [10, 11];
+
+    fn save(allocator: &mut BlockAllocator, sim: &SimFlash) {
+        allocator
+            .save(
+                sim.driver(),
+                ReadMethod::FastRead,
+                WriteMethod::SingleLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+    }
+
+    fn free_blocks(allocator: &BlockAllocator, blocks: Range<u16>) -> Vec<u16> {
+        blocks.filter(|block| allocator.is_free(*block)).collect()
+    }
+
+    #[test]
+    fn blocks_are_allocated_round_robin() {
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_reserved(0..2);
+        allocator.mark_bad(5);
+
+        assert_eq!(allocator.allocate(), Some(2));
+        assert_eq!(allocator.allocate(), Some(3));
+        allocator.free(2);
+        assert_eq!(allocator.allocate(), Some(4));
+        assert_eq!(allocator.allocate(), Some(6));
+
+        // Once every other block is handed out it comes back around to the freed one
+        let free = allocator.free_count();
+        assert_eq!(free, BLOCK_COUNT - 2 - 2 - 1 - 3);
+        let allocated: Vec<u16> = (0..free).map(|_| allocator.allocate().unwrap()).collect();
+        assert_eq!(allocated[0], 7);
+        assert_eq!(allocated[free - 1], 2);
+        assert_eq!(allocator.allocate(), None);
+    }
+
+    #[test]
+    fn reserved_and_bad_blocks_are_never_allocated() {
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_reserved(0..4);
+        allocator.mark_bad(500);
+
+        let allocated: Vec<u16> = core::iter::from_fn(|| allocator.allocate()).collect();
+        assert_eq!(allocated.len(), BLOCK_COUNT - 4 - 1 - 2);
+        for block in [0, 3, 10, 11, 500].iter() {
+            assert!(!allocated.contains(block), "block {}", block);
+            assert!(allocator.is_reserved(*block));
+        }
+
+        allocator.unreserve(500);
+        assert_eq!(allocator.allocate(), Some(500));
+    }
+
+    #[test]
+    fn saved_allocators_load_back() {
+        let sim = SimFlash::new();
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_reserved(1..3);
+        allocator.allocate();
+        save(&mut allocator, &sim);
+        let previous = allocator.clone();
+
+        allocator.allocate();
+        allocator.mark_bad(700);
+        save(&mut allocator, &sim);
+
+        let loaded = BlockAllocator::load(&sim.driver(), SLOTS, ReadMethod::FastRead)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.generation(), 2);
+        assert_eq!(
+            free_blocks(&loaded, 0..BLOCK_COUNT as u16),
+            free_blocks(&allocator, 0..BLOCK_COUNT as u16)
+        );
+        assert!(loaded.is_reserved(700));
+        assert_eq!(loaded.clone().allocate(), allocator.clone().allocate());
+
+        // Losing the newest slot falls back to the copy before it
+        sim.set_page(Geometry::W25N01GV.block_first_page(SLOTS[0]), &[0; 64]);
+        let loaded = BlockAllocator::load(&sim.driver(), SLOTS, ReadMethod::FastRead)
+            .unwrap()
+            .unwrap();
+        assert_eq!(loaded.generation(), 1);
+        assert_eq!(
+            free_blocks(&loaded, 0..BLOCK_COUNT as u16),
+            free_blocks(&previous, 0..BLOCK_COUNT as u16)
+        );
+
+        sim.set_page(Geometry::W25N01GV.block_first_page(SLOTS[1]), &[0; 64]);
+        assert!(
+            BlockAllocator::load(&sim.driver(), SLOTS, ReadMethod::FastRead)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn slots_past_the_device_are_refused() {
+        let sim = SimFlash::new();
+
+        assert!(matches!(
+            BlockAllocator::load(&sim.driver(), [10, 1024], ReadMethod::FastRead),
+            Err(StorageError::Flash {
+                source: FlashError::OutOfBounds
+            })
+        ));
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn reconstruction_finds_headers_and_bad_blocks() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        for block in [3, 7].iter() {
+            flash = flash
+                .into_write_mode()
+                .unwrap()
+                .write_block_header(
+                    *block,
+                    &BlockHeader {
+                        kind: StructureKind::Log,
+                        region_id: 0,
+                        sequence: 1,
+                    },
+                    WriteMethod::SingleLoad,
+                    &mut NoDelay,
+                )
+                .unwrap();
+        }
+        sim.mark_bad(5);
+        // Data without a header doesn't claim the block, a torn header does
+        sim.set_page(Geometry::W25N01GV.block_first_page(8), &[0; 64]);
+        sim.set_page(
+            Geometry::W25N01GV.block_first_page(9),
+            &[0; PAGE_SIZE_BYTES],
+        );
+
+        let allocator =
+            BlockAllocator::reconstruct(&flash, SLOTS, 1..13, ReadMethod::FastRead).unwrap();
+
+        assert_eq!(free_blocks(&allocator, 0..14), [0, 1, 2, 4, 6, 8, 12, 13]);
+        assert!(allocator.is_reserved(5));
+        assert!(!allocator.is_reserved(3));
+    }
+
+    #[test]
+    fn save_refuses_a_bad_slot() {
+        let sim = SimFlash::new();
+        sim.mark_bad(11);
+        let mut allocator = BlockAllocator::new([10, 11]);
+
+        // The first save goes to the second slot
+        let result = allocator.save(
+            sim.driver(),
+            ReadMethod::FastRead,
+            WriteMethod::SingleLoad,
+            &mut NoDelay,
+        );
+
+        assert!(matches!(
+            result,
+            Err(StorageError::InsufficientGoodBlocks {
+                first_block: 11,
+                good_blocks: 0
+            })
+        ));
+        assert_eq!(sim.count(0xD8), 0);
+        assert_eq!(allocator.generation(), 0);
+    }
+}
@@ -0,0 +1,802 @@
+//! An async driver for async executors like embassy, implementing the `embedded-storage-async`
+//! NOR flash traits. Enabled with the `async` feature.
+//!
+//! `AsyncW25N01GV` sends the same commands as the blocking driver, built by `commands`, over an
+//! `AsyncQspiBus`. Wherever the blocking driver spins on the BUSY bit, it awaits a delay between
+//! polls instead, so the executor can run other tasks through a multi-millisecond erase or
+//! program. The blocking driver is unchanged and the two don't share state, so use one or the
+//! other for a device.
+//!
+//! The NOR flash mapping is the one `NorFlashAdapter` uses: offsets run linearly over the main
+//! areas of the pages, writes are whole pages, and erases are whole 128KB blocks. Reads address
+//! columns within the data buffer, so the device has to be in buffered read mode, e.g. set up
+//! beforehand with the blocking driver's `set_continuous_read_mode(false)`. The async driver
+//! keeps none of the blocking driver's policies (dry runs, stats, block 0, verification levels).
+//!
+//! # Cancellation
+//!
+//! A future can be dropped at any await point, e.g. by a `select` or a timeout, and the device
+//! carries on with whatever it was last sent. The driver records an operation as in flight from
+//! its first command on, so the next `read_page`, `program_page` or `erase_block` finds it, waits
+//! for the device to finish, clears a write enable latch it left set, and returns
+//! `FlashError::InterruptedOperation` with what became of it instead of running. The call can
+//! then be retried. Per operation:
+//!
+//! - The busy check each starts with is cancellation safe, nothing has been sent yet.
+//! - `program_page` and `erase_block`'s write enable and data load don't change the array, the
+//!   outcome of dropping them is `NotStarted`.
+//! - Sending the page data read, program execute or block erase commits the operation. From the
+//!   moment it's awaited, dropping the future leaves it to the device: a program or erase is
+//!   `Completed` or `Failed` by the device's failure bits, or `NotStarted` if the device never
+//!   got the command and still has its write enable latch set. A page read only changes the data
+//!   buffer and is always reported `Completed`.
+//! - Dropping the wait for the device to finish is the same as dropping the command before it,
+//!   the next call picks the operation up.
+//! - `read_page`'s transfer out of the data buffer comes after the operation is finished and is
+//!   cancellation safe, it can simply be repeated.
+//!
+//! An operation that fails part way with a bus error is left in flight the same way, since the
+//! driver can't tell how much of it the device saw.
+
+use embedded_hal_async::delay::DelayNs;
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+
+use crate::{
+    bus::{QspiError, QspiReadCommand, QspiWriteCommand},
+    commands,
+    nor_flash::{BLOCK_SIZE_BYTES, DEFAULT_BUSY_TIMEOUT_POLLS},
+    status::{ECCStatus, StatusRegister},
+    FlashError, Geometry, InterruptedOp, InterruptedOutcome, ReadMethod, WriteMethod, BLOCK_COUNT,
+    BUSY_POLL_INTERVAL_US, PAGE_SIZE_BYTES,
+};
+
+/// A QSPI peripheral whose transfers can be awaited, the async counterpart of `QspiBus`. The
+/// peripheral is expected to be set up with 16 bit addresses.
+#[allow(async_fn_in_trait)]
+pub trait AsyncQspiBus {
+    async fn write_command(&mut self, command: QspiWriteCommand<'_>) -> Result<(), QspiError>;
+
+    /// Sends `command` and fills `buffer` with the `command.receive_length` bytes received
+    async fn read_command(
+        &mut self,
+        command: QspiReadCommand<'_>,
+        buffer: &mut [u8],
+    ) -> Result<(), QspiError>;
+}
+
+/// How far an operation got before its future was dropped
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    /// Write enable or data load sent, the array is untouched
+    Prepared,
+    /// The command that commits the operation was sent
+    Submitted,
+}
+
+/// The operation the driver is in the middle of, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct InFlight {
+    op: InterruptedOp,
+    address: u16,
+    stage: Stage,
+}
+
+/// The async driver, see the module docs
+pub struct AsyncW25N01GV<BUS, D> {
+    qspi: BUS,
+    delay: D,
+    method: ReadMethod,
+    write_method: WriteMethod,
+    busy_timeout_polls: u32,
+    in_flight: Option<InFlight>,
+}
+
+impl<BUS: AsyncQspiBus, D: DelayNs> AsyncW25N01GV<BUS, D> {
+    /// Sleeps with `delay` between busy polls and moves data with `method` and `write_method`
+    pub fn new(
+        qspi: BUS,
+        delay: D,
+        method: ReadMethod,
+        write_method: WriteMethod,
+    ) -> AsyncW25N01GV<BUS, D> {
+        AsyncW25N01GV {
+            qspi,
+            delay,
+            method,
+            write_method,
+            busy_timeout_polls: DEFAULT_BUSY_TIMEOUT_POLLS,
+            in_flight: None,
+        }
+    }
+
+    /// Sets how many times the driver polls a busy device, 10us apart, before giving up with
+    /// `FlashError::Timeout`
+    pub fn set_busy_timeout(&mut self, max_polls: u32) {
+        self.busy_timeout_polls = max_polls;
+    }
+
+    pub fn into_inner(self) -> (BUS, D) {
+        (self.qspi, self.delay)
+    }
+
+    async fn qspi_write(&mut self, command: QspiWriteCommand<'_>) -> Result<(), FlashError> {
+        let address = command.address.map(|(address, _)| address);
+        let len = command.data.map(|(data, _)| data.len() as u32).unwrap_or(0);
+
+        self.qspi
+            .write_command(command)
+            .await
+            .map_err(|err| FlashError::from_qspi_error(err, address, len))
+    }
+
+    async fn qspi_transfer(
+        &mut self,
+        command: QspiReadCommand<'_>,
+        buffer: &mut [u8],
+    ) -> Result<(), FlashError> {
+        let address = command.address.map(|(address, _)| address);
+        let len = command.receive_length;
+
+        self.qspi
+            .read_command(command, buffer)
+            .await
+            .map_err(|err| FlashError::from_qspi_error(err, address, len))
+    }
+
+    pub async fn read_status_register(&mut self) -> Result<StatusRegister, FlashError> {
+        let sar_address = [StatusRegister::SAR_ADDRESS];
+        let mut reg_value = [0_u8; 1];
+
+        self.qspi_transfer(commands::read_status_register(&sar_address), &mut reg_value)
+            .await?;
+
+        Ok(StatusRegister::from_u8(reg_value[0]))
+    }
+
+    /// Awaits the end of whatever the device is busy with, returning the status register as it
+    /// was once it finished, or `FlashError::Timeout` if it's still busy after the polls set with
+    /// `set_busy_timeout`
+    pub async fn wait_while_busy(&mut self) -> Result<StatusRegister, FlashError> {
+        for _ in 0..self.busy_timeout_polls {
+            let status_register = self.read_status_register().await?;
+            if !status_register.device_busy {
+                return Ok(status_register);
+            }
+
+            self.delay.delay_us(BUSY_POLL_INTERVAL_US).await;
+        }
+
+        Err(FlashError::Timeout)
+    }
+
+    /// Resolves an operation whose future was dropped: waits for the device to finish it, clears
+    /// the write enable latch if it was left set, and returns `FlashError::InterruptedOperation`
+    /// with the outcome. Dropping this too leaves the operation in flight for the next call.
+    async fn resolve_interrupted(&mut self) -> Result<(), FlashError> {
+        let in_flight = match self.in_flight {
+            Some(in_flight) => in_flight,
+            None => return Ok(()),
+        };
+
+        let status = self.wait_while_busy().await?;
+        let outcome = match (in_flight.stage, in_flight.op) {
+            (Stage::Prepared, _) => InterruptedOutcome::NotStarted,
+            (Stage::Submitted, InterruptedOp::PageRead) => InterruptedOutcome::Completed,
+            // The device clears the latch once it finishes a program or erase
+            (Stage::Submitted, _) if status.write_enable_latch => InterruptedOutcome::NotStarted,
+            (Stage::Submitted, InterruptedOp::Program) if status.write_failure => {
+                InterruptedOutcome::Failed
+            }
+            (Stage::Submitted, InterruptedOp::Erase) if status.erase_failure => {
+                InterruptedOutcome::Failed
+            }
+            (Stage::Submitted, _) => InterruptedOutcome::Completed,
+        };
+
+        if status.write_enable_latch {
+            self.qspi_write(commands::write_disable()).await?;
+        }
+
+        self.in_flight = None;
+        Err(FlashError::InterruptedOperation {
+            op: in_flight.op,
+            address: in_flight.address,
+            outcome,
+        })
+    }
+
+    /// Records how far the current operation has got, for `resolve_interrupted`
+    fn mark(&mut self, op: InterruptedOp, address: u16, stage: Stage) {
+        self.in_flight = Some(InFlight { op, address, stage });
+    }
+
+    /// Returns `FlashError::DeviceBusy` if the device would silently reject a command
+    async fn check_busy(&mut self) -> Result<(), FlashError> {
+        if self.read_status_register().await?.device_busy {
+            return Err(FlashError::DeviceBusy);
+        }
+
+        Ok(())
+    }
+
+    /// Reads `buffer.len()` bytes of a page's main area from `column` on, returning
+    /// `FlashError::ECC` if the page had more bit errors than ECC could correct
+    pub async fn read_page(
+        &mut self,
+        page_address: u16,
+        column: u16,
+        buffer: &mut [u8],
+    ) -> Result<(), FlashError> {
+        if column as usize + buffer.len() > PAGE_SIZE_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.resolve_interrupted().await?;
+        self.check_busy().await?;
+
+        let page_address_bytes = page_address.to_be_bytes();
+        self.mark(InterruptedOp::PageRead, page_address, Stage::Submitted);
+        self.qspi_write(commands::page_data_read(&page_address_bytes))
+            .await?;
+
+        let status = self.wait_while_busy().await?.ecc_status;
+        self.in_flight = None;
+        if let ECCStatus::SinglePageError | ECCStatus::MultiPageError = status {
+            return Err(FlashError::ECC {
+                status,
+                page_address,
+            });
+        }
+
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        let command = commands::fast_read(self.method, column, buffer.len() as u32);
+        self.qspi_transfer(command, buffer).await
+    }
+
+    /// Loads `bytes` into the data buffer from column 0, resetting the rest of it, and programs it
+    /// into the page, returning `FlashError::ProgramFailed` if the device reports a failure
+    pub async fn program_page(
+        &mut self,
+        page_address: u16,
+        bytes: &[u8],
+    ) -> Result<(), FlashError> {
+        if bytes.len() > PAGE_SIZE_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.resolve_interrupted().await?;
+        self.check_busy().await?;
+
+        self.mark(InterruptedOp::Program, page_address, Stage::Prepared);
+        self.qspi_write(commands::write_enable()).await?;
+
+        if !bytes.is_empty() {
+            let command = commands::program_data_load(self.write_method.resetting(), 0, bytes);
+            self.qspi_write(command).await?;
+        }
+
+        let page_address_bytes = page_address.to_be_bytes();
+        self.mark(InterruptedOp::Program, page_address, Stage::Submitted);
+        self.qspi_write(commands::program_execute(&page_address_bytes))
+            .await?;
+
+        let status = self.wait_while_busy().await?;
+        self.in_flight = None;
+        if status.write_failure {
+            return Err(FlashError::ProgramFailed { page_address });
+        }
+
+        Ok(())
+    }
+
+    /// Erases a block (block index, not page address), returning
+    /// `FlashError::EraseFailed` if the device reports a failure
+    pub async fn erase_block(&mut self, block: u16) -> Result<(), FlashError> {
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.resolve_interrupted().await?;
+        self.check_busy().await?;
+
+        let page_address = Geometry::W25N01GV.block_first_page(block);
+        self.mark(InterruptedOp::Erase, page_address, Stage::Prepared);
+        self.qspi_write(commands::write_enable()).await?;
+
+        let page_address_bytes = page_address.to_be_bytes();
+        self.mark(InterruptedOp::Erase, page_address, Stage::Submitted);
+        self.qspi_write(commands::block_erase(&page_address_bytes))
+            .await?;
+
+        let status = self.wait_while_busy().await?;
+        self.in_flight = None;
+        if status.erase_failure {
+            return Err(FlashError::EraseFailed { page_address });
+        }
+
+        Ok(())
+    }
+}
+
+impl<BUS, D> ErrorType for AsyncW25N01GV<BUS, D> {
+    type Error = FlashError;
+}
+
+impl<BUS: AsyncQspiBus, D: DelayNs> AsyncW25N01GV<BUS, D> {
+    /// The bounds and alignment checks `embedded-storage` does for the blocking traits
+    fn check_slice(&self, align: usize, offset: u32, len: usize) -> Result<(), FlashError> {
+        let capacity = ReadNorFlash::capacity(self);
+        let offset = offset as usize;
+
+        if len > capacity || offset > capacity - len {
+            return Err(FlashError::OutOfBounds);
+        }
+        if !offset.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(FlashError::NotAligned);
+        }
+
+        Ok(())
+    }
+}
+
+impl<BUS: AsyncQspiBus, D: DelayNs> ReadNorFlash for AsyncW25N01GV<BUS, D> {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        self.check_slice(Self::READ_SIZE, offset, bytes.len())?;
+
+        let mut done = 0;
+        while done < bytes.len() {
+            let position = offset as usize + done;
+            let page_address = (position / PAGE_SIZE_BYTES) as u16;
+            let column = position % PAGE_SIZE_BYTES;
+            let len = (PAGE_SIZE_BYTES - column).min(bytes.len() - done);
+
+            self.read_page(page_address, column as u16, &mut bytes[done..done + len])
+                .await?;
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        BLOCK_COUNT * BLOCK_SIZE_BYTES
+    }
+}
+
+impl<BUS: AsyncQspiBus, D: DelayNs> NorFlash for AsyncW25N01GV<BUS, D> {
+    const WRITE_SIZE: usize = PAGE_SIZE_BYTES;
+    const ERASE_SIZE: usize = BLOCK_SIZE_BYTES;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        if from > to {
+            return Err(FlashError::OutOfBounds);
+        }
+        self.check_slice(Self::ERASE_SIZE, from, (to - from) as usize)?;
+
+        let first_block = (from as usize / BLOCK_SIZE_BYTES) as u16;
+        let end_block = (to as usize / BLOCK_SIZE_BYTES) as u16;
+
+        for block in first_block..end_block {
+            self.erase_block(block).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Each page has to be erased before it's written, since programming can only clear bits
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        self.check_slice(Self::WRITE_SIZE, offset, bytes.len())?;
+
+        let first_page = (offset as usize / PAGE_SIZE_BYTES) as u16;
+
+        for (index, page) in bytes.chunks_exact(PAGE_SIZE_BYTES).enumerate() {
+            self.program_page(first_page + index as u16, page).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::{
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll, Waker},
+    };
+    use std::{boxed::Box, sync::Arc, task::Wake, vec, vec::Vec};
+
+    use super::*;
+    use crate::{bus::QspiBus, sim::SimFlash, PAGES_PER_BLOCK};
+
+    /// Returns `Pending` once, so every await on the mock is a point a future can be dropped at
+    struct YieldOnce(bool);
+
+    impl Future for YieldOnce {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    /// The simulated device behind an async bus. Each command yields once, after the device got
+    /// it or, with `yield_first`, before.
+    struct AsyncSim {
+        sim: SimFlash,
+        yield_first: bool,
+    }
+
+    impl AsyncQspiBus for AsyncSim {
+        async fn write_command(&mut self, command: QspiWriteCommand<'_>) -> Result<(), QspiError> {
+            if self.yield_first {
+                YieldOnce(false).await;
+                return self.sim.write_command(command);
+            }
+
+            let result = self.sim.write_command(command);
+            YieldOnce(false).await;
+            result
+        }
+
+        async fn read_command(
+            &mut self,
+            command: QspiReadCommand<'_>,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            if self.yield_first {
+                YieldOnce(false).await;
+                return self.sim.read_command(command, buffer);
+            }
+
+            let result = self.sim.read_command(command, buffer);
+            YieldOnce(false).await;
+            result
+        }
+    }
+
+    struct YieldDelay;
+
+    impl DelayNs for YieldDelay {
+        async fn delay_ns(&mut self, _ns: u32) {
+            YieldOnce(false).await
+        }
+    }
+
+    type Driver = AsyncW25N01GV<AsyncSim, YieldDelay>;
+
+    fn driver(sim: &SimFlash, yield_first: bool) -> Driver {
+        let bus = AsyncSim {
+            sim: sim.clone(),
+            yield_first,
+        };
+        AsyncW25N01GV::new(
+            bus,
+            YieldDelay,
+            ReadMethod::FastRead,
+            WriteMethod::SingleLoad,
+        )
+    }
+
+    struct NoWake;
+
+    impl Wake for NoWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Polls `future` at most `polls` times, dropping it if it hasn't finished by then
+    fn poll_then_drop<F: Future>(future: F, polls: usize) -> Option<F::Output> {
+        let waker = Waker::from(Arc::new(NoWake));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+
+        for _ in 0..polls {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return Some(output);
+            }
+        }
+
+        None
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        poll_then_drop(future, usize::MAX).unwrap()
+    }
+
+    fn erased(sim: &SimFlash, page_address: u16) -> bool {
+        sim.page(page_address)[..PAGE_SIZE_BYTES]
+            .iter()
+            .all(|byte| *byte == 0xFF)
+    }
+
+    fn write_enable_latch(flash: &mut Driver) -> bool {
+        block_on(flash.read_status_register())
+            .unwrap()
+            .write_enable_latch
+    }
+
+    /// Drops the future `op` makes after every number of polls until it finishes on its own, and
+    /// hands `check` the device, the driver and what the next call returned
+    fn drop_at_every_await_point<F>(
+        setup: impl Fn(&SimFlash),
+        op: impl Fn(&mut Driver, usize) -> Option<Result<(), FlashError>>,
+        mut check: F,
+    ) where
+        F: FnMut(&SimFlash, &mut Driver, Result<(), FlashError>),
+    {
+        for yield_first in [false, true] {
+            for polls in 0.. {
+                let sim = SimFlash::new();
+                sim.set_busy_polls(2);
+                setup(&sim);
+                let mut flash = driver(&sim, yield_first);
+
+                if op(&mut flash, polls).is_some() {
+                    break;
+                }
+
+                let next = block_on(flash.read_page(0, 0, &mut [0; 4]));
+                check(&sim, &mut flash, next);
+
+                // Whatever was interrupted has been dealt with
+                assert_eq!(block_on(flash.read_page(0, 0, &mut [0; 4])), Ok(()));
+                assert!(!write_enable_latch(&mut flash));
+            }
+        }
+    }
+
+    #[test]
+    fn operations_send_the_commands_the_datasheet_gives() {
+        let sim = SimFlash::new();
+        sim.set_page(7, &[1, 2, 3, 4]);
+        let mut flash = driver(&sim, false);
+        // Everything but the status register polls
+        let opcodes = |sim: &SimFlash| -> Vec<u8> {
+            let opcodes = sim
+                .commands()
+                .iter()
+                .map(|command| command.opcode)
+                .filter(|opcode| *opcode != 0x05)
+                .collect();
+            sim.clear_log();
+            opcodes
+        };
+
+        let mut buffer = [0; 4];
+        block_on(flash.read_page(7, 0, &mut buffer)).unwrap();
+        assert_eq!(buffer, [1, 2, 3, 4]);
+        assert_eq!(opcodes(&sim), [0x13, 0x0B]);
+        assert_eq!(sim.commands().len(), 0);
+
+        block_on(flash.program_page(9, &[0xA5; 4])).unwrap();
+        let execute = sim
+            .commands()
+            .into_iter()
+            .find(|command| command.opcode == 0x10);
+        assert_eq!(opcodes(&sim), [0x06, 0x02, 0x10]);
+        assert_eq!(execute.unwrap().page_address(), Some(9));
+        assert_eq!(&sim.page(9)[..4], &[0xA5; 4]);
+
+        block_on(flash.erase_block(3)).unwrap();
+        let erase = sim
+            .commands()
+            .into_iter()
+            .find(|command| command.opcode == 0xD8);
+        assert_eq!(opcodes(&sim), [0x06, 0xD8]);
+        assert_eq!(
+            erase.unwrap().page_address(),
+            Some(3 * PAGES_PER_BLOCK as u16)
+        );
+    }
+
+    #[test]
+    fn a_device_busy_past_the_poll_bound_times_out() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(5);
+        let mut flash = driver(&sim, false);
+        flash.set_busy_timeout(3);
+
+        assert_eq!(block_on(flash.erase_block(3)), Err(FlashError::Timeout));
+        // Status polls: the busy check, then the three the bound allows
+        assert_eq!(sim.count(0x05), 4);
+
+        flash.set_busy_timeout(0);
+        sim.clear_log();
+        assert!(matches!(
+            block_on(flash.wait_while_busy()),
+            Err(FlashError::Timeout)
+        ));
+        assert_eq!(sim.commands().len(), 0);
+
+        // The erase is still in flight, a generous bound sees it out
+        flash.set_busy_timeout(DEFAULT_BUSY_TIMEOUT_POLLS);
+        assert!(matches!(
+            block_on(flash.read_page(0, 0, &mut [0; 4])),
+            Err(FlashError::InterruptedOperation {
+                op: InterruptedOp::Erase,
+                outcome: InterruptedOutcome::Completed,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn nor_flash_calls_check_bounds_and_alignment_before_sending_anything() {
+        let sim = SimFlash::new();
+        let mut flash = driver(&sim, false);
+        let capacity = ReadNorFlash::capacity(&flash) as u32;
+        let page = PAGE_SIZE_BYTES as u32;
+        let block = BLOCK_SIZE_BYTES as u32;
+
+        assert_eq!(
+            block_on(ReadNorFlash::read(&mut flash, capacity - 2, &mut [0; 4])),
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(
+            block_on(NorFlash::write(&mut flash, 1, &[0; PAGE_SIZE_BYTES])),
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            block_on(NorFlash::write(&mut flash, page, &[0; 4])),
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            block_on(NorFlash::write(&mut flash, capacity, &[0; PAGE_SIZE_BYTES])),
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(
+            block_on(NorFlash::erase(&mut flash, page, block)),
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            block_on(NorFlash::erase(&mut flash, block, capacity + block)),
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(
+            block_on(NorFlash::erase(&mut flash, 2 * block, block)),
+            Err(FlashError::OutOfBounds)
+        );
+        assert_eq!(sim.commands().len(), 0);
+
+        // Unaligned reads are fine, and may span pages
+        sim.set_page(1, &[7; 2]);
+        let mut buffer = [0; 4];
+        block_on(ReadNorFlash::read(&mut flash, page - 2, &mut buffer)).unwrap();
+        assert_eq!(buffer, [0xFF, 0xFF, 7, 7]);
+    }
+
+    #[test]
+    fn a_dropped_program_is_reported_by_the_next_call_as_it_ended_up() {
+        let data = vec![0x5A; PAGE_SIZE_BYTES];
+        let mut outcomes = Vec::new();
+
+        drop_at_every_await_point(
+            |_| {},
+            |flash, polls| poll_then_drop(flash.program_page(5, &data), polls),
+            |sim, flash, next| {
+                let outcome = match next {
+                    Ok(()) => None,
+                    Err(FlashError::InterruptedOperation {
+                        op: InterruptedOp::Program,
+                        address: 5,
+                        outcome,
+                    }) => Some(outcome),
+                    other => panic!("{:?}", other),
+                };
+
+                match outcome {
+                    Some(InterruptedOutcome::Completed) => {
+                        assert_eq!(&sim.page(5)[..PAGE_SIZE_BYTES], &data[..])
+                    }
+                    Some(InterruptedOutcome::Failed) => panic!("nothing failed"),
+                    _ => {
+                        assert!(erased(sim, 5));
+                        // The driver is usable again, the program can just be retried
+                        assert!(!write_enable_latch(flash));
+                        assert_eq!(block_on(flash.program_page(5, &data)), Ok(()));
+                        assert_eq!(&sim.page(5)[..PAGE_SIZE_BYTES], &data[..]);
+                    }
+                }
+
+                outcomes.push(outcome);
+            },
+        );
+
+        assert!(outcomes.contains(&None));
+        assert!(outcomes.contains(&Some(InterruptedOutcome::NotStarted)));
+        assert!(outcomes.contains(&Some(InterruptedOutcome::Completed)));
+    }
+
+    #[test]
+    fn a_dropped_erase_is_reported_by_the_next_call_as_it_ended_up() {
+        let first_page = 3 * PAGES_PER_BLOCK as u16;
+        let mut outcomes = Vec::new();
+
+        drop_at_every_await_point(
+            |sim| sim.set_page(first_page, &[0; 16]),
+            |flash, polls| poll_then_drop(flash.erase_block(3), polls),
+            |sim, _, next| {
+                match next {
+                    Ok(()) => assert!(!erased(sim, first_page)),
+                    Err(FlashError::InterruptedOperation {
+                        op: InterruptedOp::Erase,
+                        address,
+                        outcome,
+                    }) => {
+                        assert_eq!(address, first_page);
+                        assert_eq!(
+                            erased(sim, first_page),
+                            outcome == InterruptedOutcome::Completed
+                        );
+                    }
+                    other => panic!("{:?}", other),
+                }
+
+                outcomes.push(next);
+            },
+        );
+
+        assert!(outcomes.contains(&Ok(())));
+        assert!(outcomes.iter().any(|next| matches!(
+            next,
+            Err(FlashError::InterruptedOperation {
+                outcome: InterruptedOutcome::Completed,
+                ..
+            })
+        )));
+    }
+
+    #[test]
+    fn a_dropped_program_the_device_failed_is_reported_failed() {
+        let mut interrupted = 0;
+
+        drop_at_every_await_point(
+            |sim| sim.fail_program(5),
+            |flash, polls| poll_then_drop(flash.program_page(5, &[0; 4]), polls),
+            |_, _, next| {
+                if let Err(FlashError::InterruptedOperation {
+                    outcome: InterruptedOutcome::Failed,
+                    ..
+                }) = next
+                {
+                    interrupted += 1;
+                }
+            },
+        );
+
+        assert!(interrupted > 0);
+    }
+
+    #[test]
+    fn a_dropped_page_read_is_waited_out_and_the_read_can_be_repeated() {
+        drop_at_every_await_point(
+            |sim| sim.set_page(7, &[1, 2, 3, 4]),
+            |flash, polls| poll_then_drop(flash.read_page(7, 0, &mut [0; 4]), polls),
+            |_, flash, next| {
+                match next {
+                    Ok(()) => {}
+                    Err(FlashError::InterruptedOperation {
+                        op: InterruptedOp::PageRead,
+                        address: 7,
+                        outcome: InterruptedOutcome::Completed,
+                    }) => {}
+                    other => panic!("{:?}", other),
+                }
+                let mut buffer = [0; 4];
+                assert_eq!(block_on(flash.read_page(7, 0, &mut buffer)), Ok(()));
+                assert_eq!(buffer, [1, 2, 3, 4]);
+            },
+        );
+    }
+}
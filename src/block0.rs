@@ -0,0 +1,100 @@
+use crate::{FlashError, W25N01GV};
+
+/// Decides whether the managed layers of the driver may use block 0. Winbond guarantees block 0
+/// is good for a minimum number of program/erase cycles, so bootloaders often keep it to
+/// themselves.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Block0Policy {
+    /// Block 0 is treated like every other block
+    Normal,
+    /// Block 0 is never allocated, used as a bad block replacement, erased by range erases, or
+    /// claimed by a layout or log. Managed APIs asked to use it return
+    /// `FlashError::Block0Reserved`. Raw page and block APIs like `erase_block` still
+    /// reach it, which is the way to erase it deliberately.
+    Reserved,
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Sets how the managed layers treat block 0. `Block0Policy::Normal` by default.
+    pub fn set_block0_policy(&mut self, policy: Block0Policy) {
+        self.block0_policy = policy;
+    }
+
+    pub fn block0_policy(&self) -> Block0Policy {
+        self.block0_policy
+    }
+
+    /// Is true if block 0 should be left alone by the managed layers
+    pub(crate) fn block0_reserved(&self) -> bool {
+        self.block0_policy == Block0Policy::Reserved
+    }
+
+    /// Returns `FlashError::Block0Reserved` if the blocks include a reserved block 0
+    pub(crate) fn check_block0(
+        &self,
+        first_block: u16,
+        block_count: u16,
+    ) -> Result<(), FlashError> {
+        if self.block0_reserved() && first_block == 0 && block_count > 0 {
+            return Err(FlashError::Block0Reserved);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{NoDelay, SimFlash},
+        BlockAllocator, FlashLogSink, ReadMethod, StorageError,
+    };
+
+    #[test]
+    fn only_a_reserved_block_0_is_refused() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        assert_eq!(flash.block0_policy(), Block0Policy::Normal);
+        assert_eq!(flash.check_block0(0, 4), Ok(()));
+
+        flash.set_block0_policy(Block0Policy::Reserved);
+        assert_eq!(flash.check_block0(0, 4), Err(FlashError::Block0Reserved));
+        assert_eq!(flash.check_block0(0, 1), Err(FlashError::Block0Reserved));
+        assert_eq!(flash.check_block0(0, 0), Ok(()));
+        assert_eq!(flash.check_block0(1, 4), Ok(()));
+    }
+
+    #[test]
+    fn managed_layers_keep_off_a_reserved_block_0() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_block0_policy(Block0Policy::Reserved);
+
+        assert!(matches!(
+            FlashLogSink::mount(&flash, 0, 2, ReadMethod::FastRead),
+            Err(StorageError::Flash {
+                source: FlashError::Block0Reserved
+            })
+        ));
+
+        let mut allocator =
+            BlockAllocator::reconstruct(&flash, [2, 3], 0..6, ReadMethod::FastRead).unwrap();
+        assert!(allocator.is_reserved(0));
+        while let Some(block) = allocator.allocate() {
+            assert_ne!(block, 0);
+        }
+    }
+
+    #[test]
+    fn raw_erases_still_reach_a_reserved_block_0() {
+        let sim = SimFlash::new();
+        sim.set_page(0, &[0; 4]);
+        let mut flash = sim.driver();
+        flash.set_block0_policy(Block0Policy::Reserved);
+
+        flash.erase_block(0, &mut NoDelay).unwrap();
+        assert_eq!(sim.page(0)[0], 0xFF);
+    }
+}
@@ -0,0 +1,328 @@
+//! A common header for recognizing what lives in a block from its first page.
+//!
+//! The header is 32 bytes at the end of the main area of the block's first page
+//! (`BLOCK_HEADER_COLUMN`), which is readable with ECC on or off and keeps clear of the data
+//! structures that start at column 0. The built-in structures (the allocator slots, the layout
+//! descriptor, and the first page of each log block) write it alongside their own data.
+//!
+//! Serialized little endian as:
+//!
+//! | Bytes  | Field                            |
+//! |--------|----------------------------------|
+//! | 0..4   | Magic, 0x52444842 ("BHDR")       |
+//! | 4      | Format version, currently 1      |
+//! | 5      | Structure kind                   |
+//! | 6      | Region ID                        |
+//! | 7      | Reserved, 0                      |
+//! | 8..12  | Sequence number                  |
+//! | 12..28 | Reserved, 0                      |
+//! | 28..32 | CRC-32 of bytes 0..28            |
+
+use core::convert::TryInto;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    digest::Crc32, FlashError, Geometry, LoadMode, QspiBus, ReadMethod, ReadMode, StorageError,
+    WriteMethod, WriteMode, BLOCK_COUNT, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+pub const BLOCK_HEADER_BYTES: usize = 32;
+/// Where the header sits in the block's first page
+pub const BLOCK_HEADER_COLUMN: u16 = (PAGE_SIZE_BYTES - BLOCK_HEADER_BYTES) as u16;
+
+const BLOCK_HEADER_MAGIC: u32 = 0x5244_4842;
+const BLOCK_HEADER_VERSION: u8 = 1;
+const CRC_OFFSET: usize = BLOCK_HEADER_BYTES - 4;
+const APPLICATION_KINDS_START: u8 = 0x80;
+
+/// What a block holds
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StructureKind {
+    /// A `BlockAllocator` slot
+    AllocatorSlot,
+    /// A `Layout` descriptor block
+    LayoutDescriptor,
+    /// A block of a `FlashLogSink`
+    Log,
+    /// A block of a `SpanningRecordWriter`
+    SpanningRecords,
+    /// The commit record block of a `TwoPhase` coordinator
+    CommitRecords,
+    /// Anything defined by the application. Values below 0x80 are kept for the driver, so headers
+    /// with them are refused with `StorageError::ReservedStructureKind`.
+    Application(u8),
+}
+
+impl StructureKind {
+    fn to_u8(self) -> Result<u8, StorageError> {
+        match self {
+            StructureKind::AllocatorSlot => Ok(1),
+            StructureKind::LayoutDescriptor => Ok(2),
+            StructureKind::Log => Ok(3),
+            StructureKind::SpanningRecords => Ok(4),
+            StructureKind::CommitRecords => Ok(5),
+            StructureKind::Application(value) if value >= APPLICATION_KINDS_START => Ok(value),
+            StructureKind::Application(value) => Err(StorageError::ReservedStructureKind { value }),
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<StructureKind> {
+        match value {
+            1 => Some(StructureKind::AllocatorSlot),
+            2 => Some(StructureKind::LayoutDescriptor),
+            3 => Some(StructureKind::Log),
+            4 => Some(StructureKind::SpanningRecords),
+            5 => Some(StructureKind::CommitRecords),
+            value if value >= APPLICATION_KINDS_START => Some(StructureKind::Application(value)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockHeader {
+    pub kind: StructureKind,
+    /// Which region of its kind the block belongs to, 0 for the built-in structures
+    pub region_id: u8,
+    /// Increases each time the structure rewrites the block, e.g. the allocator's generation
+    pub sequence: u32,
+}
+
+impl BlockHeader {
+    /// Serializes the header with its CRC-32 computed in software. Returns
+    /// `StorageError::ReservedStructureKind` for an application kind below 0x80.
+    pub fn to_bytes(&self) -> Result<[u8; BLOCK_HEADER_BYTES], StorageError> {
+        self.to_bytes_with(Crc32::new())
+    }
+
+    /// Serializes the header like `to_bytes`, with its CRC-32 computed by `digest`
+    pub fn to_bytes_with(&self, digest: Crc32) -> Result<[u8; BLOCK_HEADER_BYTES], StorageError> {
+        let mut bytes = [0_u8; BLOCK_HEADER_BYTES];
+        bytes[0..4].copy_from_slice(&BLOCK_HEADER_MAGIC.to_le_bytes());
+        bytes[4] = BLOCK_HEADER_VERSION;
+        bytes[5] = self.kind.to_u8()?;
+        bytes[6] = self.region_id;
+        bytes[8..12].copy_from_slice(&self.sequence.to_le_bytes());
+
+        let crc = digest.checksum(&bytes[..CRC_OFFSET]);
+        bytes[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(bytes)
+    }
+
+    /// Parses a header, returning None if the bytes are erased and
+    /// `StorageError::CorruptBlockHeader` if they're neither erased nor a valid header. A kind
+    /// below 0x80 that the driver doesn't know makes the header invalid. The CRC-32 is checked in
+    /// software.
+    pub fn from_bytes(
+        bytes: &[u8; BLOCK_HEADER_BYTES],
+    ) -> Result<Option<BlockHeader>, StorageError> {
+        BlockHeader::from_bytes_with(bytes, Crc32::new())
+    }
+
+    /// Parses a header like `from_bytes`, checking its CRC-32 with `digest`
+    pub fn from_bytes_with(
+        bytes: &[u8; BLOCK_HEADER_BYTES],
+        digest: Crc32,
+    ) -> Result<Option<BlockHeader>, StorageError> {
+        if bytes.iter().all(|byte| *byte == 0xFF) {
+            return Ok(None);
+        }
+
+        let read_u32 = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap_or([0; 4]))
+        };
+
+        if read_u32(0) != BLOCK_HEADER_MAGIC
+            || bytes[4] != BLOCK_HEADER_VERSION
+            || read_u32(CRC_OFFSET) != digest.checksum(&bytes[..CRC_OFFSET])
+        {
+            return Err(StorageError::CorruptBlockHeader);
+        }
+
+        let kind = StructureKind::from_u8(bytes[5]).ok_or(StorageError::CorruptBlockHeader)?;
+
+        Ok(Some(BlockHeader {
+            kind,
+            region_id: bytes[6],
+            sequence: read_u32(8),
+        }))
+    }
+
+    /// Places the header where it belongs in a block's first page, for structures that program
+    /// the page in one go
+    pub(crate) fn write_into(
+        &self,
+        page: &mut [u8; PAGE_SIZE_BYTES],
+        digest: Crc32,
+    ) -> Result<(), StorageError> {
+        page[BLOCK_HEADER_COLUMN as usize..].copy_from_slice(&self.to_bytes_with(digest)?);
+        Ok(())
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads the header from the block's first page. Returns None for an erased header and
+    /// `StorageError::CorruptBlockHeader` for anything else that isn't a valid header. Leaves
+    /// that page in the data buffer.
+    pub fn read_block_header(
+        &self,
+        block: u16,
+        method: ReadMethod,
+    ) -> Result<Option<BlockHeader>, StorageError> {
+        let _guard = self.begin_operation()?;
+
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        self.read_memory_to_data_buffer_unguarded(Geometry::W25N01GV.block_first_page(block))?;
+        self.wait_while_busy_unguarded()?;
+
+        let mut bytes = [0_u8; BLOCK_HEADER_BYTES];
+        self.read_physical_columns(BLOCK_HEADER_COLUMN, &mut bytes, method)?;
+
+        BlockHeader::from_bytes_with(&bytes, self.crc32_digest())
+    }
+}
+
+impl<BUS: QspiBus> W25N01GV<BUS, WriteMode> {
+    /// Programs just the header into the block's first page, using one of its partial programs.
+    /// The header sits in the page's last 512 byte ECC sector, so with ECC enabled the rest of
+    /// that sector shouldn't be programmed separately.
+    pub fn write_block_header<D: DelayUs<u32>>(
+        self,
+        block: u16,
+        header: &BlockHeader,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError> {
+        let guard = self.begin_operation()?;
+
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        self.load_to_data_buffer_unguarded(
+            &header.to_bytes_with(self.crc32_digest())?,
+            BLOCK_HEADER_COLUMN,
+            write_method,
+            LoadMode::ResetThenLoad,
+        )?;
+
+        let page_address = Geometry::W25N01GV.block_first_page(block);
+        self.commit_with_unguarded(page_address, self.verification_level, delay)?;
+
+        drop(guard);
+        Ok(self.into_mode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOG_HEADER: [u8; BLOCK_HEADER_BYTES] = [
+        0x42, 0x48, 0x44, 0x52, 0x01, 0x03, 0x02, 0x00, 0x04, 0x03, 0x02, 0x01, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x91, 0x84,
+        0x0E, 0xA5,
+    ];
+
+    const APPLICATION_HEADER: [u8; BLOCK_HEADER_BYTES] = [
+        0x42, 0x48, 0x44, 0x52, 0x01, 0x90, 0x07, 0x00, 0xFE, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x44, 0x6C,
+        0x00, 0x68,
+    ];
+
+    #[test]
+    fn headers_serialize_to_golden_bytes() {
+        let log = BlockHeader {
+            kind: StructureKind::Log,
+            region_id: 2,
+            sequence: 0x0102_0304,
+        };
+        assert_eq!(log.to_bytes(), Ok(LOG_HEADER));
+        assert_eq!(BlockHeader::from_bytes(&LOG_HEADER), Ok(Some(log)));
+
+        let application = BlockHeader {
+            kind: StructureKind::Application(0x90),
+            region_id: 7,
+            sequence: 0xFFFF_FFFE,
+        };
+        assert_eq!(application.to_bytes(), Ok(APPLICATION_HEADER));
+        assert_eq!(
+            BlockHeader::from_bytes(&APPLICATION_HEADER),
+            Ok(Some(application))
+        );
+    }
+
+    #[test]
+    fn every_writable_kind_round_trips() {
+        let kinds = [
+            StructureKind::AllocatorSlot,
+            StructureKind::LayoutDescriptor,
+            StructureKind::Log,
+            StructureKind::SpanningRecords,
+            StructureKind::CommitRecords,
+            StructureKind::Application(0x80),
+            StructureKind::Application(0xFF),
+        ];
+
+        for kind in kinds.iter() {
+            let header = BlockHeader {
+                kind: *kind,
+                region_id: 1,
+                sequence: 42,
+            };
+            let bytes = header.to_bytes().unwrap();
+            assert_eq!(BlockHeader::from_bytes(&bytes), Ok(Some(header)));
+        }
+    }
+
+    #[test]
+    fn reserved_application_kinds_are_refused() {
+        for value in [0x00, 0x03, 0x7F].iter() {
+            let header = BlockHeader {
+                kind: StructureKind::Application(*value),
+                region_id: 0,
+                sequence: 0,
+            };
+            assert_eq!(
+                header.to_bytes(),
+                Err(StorageError::ReservedStructureKind { value: *value })
+            );
+        }
+
+        // A kind the driver keeps but doesn't know, with a valid CRC
+        let mut bytes = LOG_HEADER;
+        bytes[5] = 0x06;
+        let crc = Crc32::new().checksum(&bytes[..CRC_OFFSET]);
+        bytes[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(
+            BlockHeader::from_bytes(&bytes),
+            Err(StorageError::CorruptBlockHeader)
+        );
+    }
+
+    #[test]
+    fn erased_and_damaged_headers_are_told_apart() {
+        assert_eq!(
+            BlockHeader::from_bytes(&[0xFF; BLOCK_HEADER_BYTES]),
+            Ok(None)
+        );
+
+        for index in [0, 4, 5, 8, CRC_OFFSET].iter() {
+            let mut bytes = LOG_HEADER;
+            bytes[*index] ^= 0x01;
+            assert_eq!(
+                BlockHeader::from_bytes(&bytes),
+                Err(StorageError::CorruptBlockHeader),
+                "byte {}",
+                index
+            );
+        }
+    }
+}
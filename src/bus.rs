@@ -0,0 +1,1242 @@
+//! The QSPI bus the driver talks to the device over.
+//!
+//! The driver only needs a peripheral that can send a command made of an instruction, address,
+//! alternate bytes, dummy cycles, and data, each phase on its own number of lines. `QspiBus` is
+//! that much, so any QSPI peripheral can drive the device by implementing it. The command types
+//! mirror the stm32l4xx-hal ones field for field, and with the `stm32l4` feature (on by default)
+//! its `Qspi` implements `QspiBus` directly. With the `stm32h7` feature, `Stm32h7QspiBus`
+//! implements it over the stm32h7xx-hal `Qspi`, limited to commands with every phase on the same
+//! number of lines. With the `spi` feature, `SpiBus` implements it over a plain single line SPI
+//! bus, and with the `embassy` feature, `EmbassyQspiBus` implements it over embassy-stm32's `Qspi`.
+//! With the `esp32s3` feature, `EspSpiBus` implements it over an esp-hal SPI master in half duplex
+//! mode on the ESP32-S3. With the `linux` feature, `LinuxSpiBus` is `SpiBus` over a Linux spidev
+//! device. On the STM32L4+ parts, which have OCTOSPI instead of QUADSPI, `OctospiBus` implements
+//! it, see `octospi`.
+
+/// How many data lines a phase of a command uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QspiMode {
+    SingleChannel,
+    DualChannel,
+    QuadChannel,
+}
+
+/// Why the bus couldn't send a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum QspiError {
+    /// The peripheral was still busy with something else
+    Busy,
+    /// The peripheral rejected the command's address
+    Address,
+    /// The peripheral can't send the command as given, e.g. with more data lines than it has
+    Unsupported,
+    Unknown,
+}
+
+/// A command that sends its data, if any, to the device.
+///
+/// The phases go out in field order, each on its own number of lines, and a phase that's None
+/// isn't sent at all. The driver builds every command it sends in `commands`, which is the
+/// reference for what a transport has to handle.
+#[derive(Debug, Clone, Copy)]
+pub struct QspiWriteCommand<'a> {
+    /// The 8 bit instruction
+    pub instruction: Option<(u8, QspiMode)>,
+    /// A column address, always 16 bits sent most significant byte first, whatever the `u32`
+    /// could hold
+    pub address: Option<(u32, QspiMode)>,
+    /// Bytes sent as is, in order, between the address and dummy cycles. The driver only uses
+    /// them for the one byte register address of the status register reads.
+    pub alternative_bytes: Option<(&'a [u8], QspiMode)>,
+    /// Clock cycles with nothing driven, counted in clocks rather than bytes, so 8 is a byte on
+    /// one line and 4 a byte on two
+    pub dummy_cycles: u8,
+    pub data: Option<(&'a [u8], QspiMode)>,
+    /// Never set by the driver, since the device only works at single data rate
+    pub double_data_rate: bool,
+}
+
+/// A command that receives `receive_length` bytes from the device. The phases ahead of the data
+/// mean the same as in `QspiWriteCommand`.
+#[derive(Debug, Clone, Copy)]
+pub struct QspiReadCommand<'a> {
+    pub instruction: Option<(u8, QspiMode)>,
+    pub address: Option<(u32, QspiMode)>,
+    pub alternative_bytes: Option<(&'a [u8], QspiMode)>,
+    pub dummy_cycles: u8,
+    pub data_mode: QspiMode,
+    /// How many bytes to clock in, which may be fewer than the buffer passed along holds
+    pub receive_length: u32,
+    pub double_data_rate: bool,
+}
+
+/// A QSPI peripheral the driver can send commands over. The peripheral is expected to be set up
+/// with 16 bit addresses.
+pub trait QspiBus {
+    fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError>;
+
+    /// Sends `command` and fills `buffer` with the `command.receive_length` bytes received
+    fn read_command(&self, command: QspiReadCommand, buffer: &mut [u8]) -> Result<(), QspiError>;
+}
+
+#[cfg(feature = "stm32l4")]
+mod stm32l4 {
+    use stm32l4xx_hal::qspi as hal_qspi;
+
+    use super::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+
+    fn hal_mode(mode: QspiMode) -> hal_qspi::QspiMode {
+        match mode {
+            QspiMode::SingleChannel => hal_qspi::QspiMode::SingleChannel,
+            QspiMode::DualChannel => hal_qspi::QspiMode::DualChannel,
+            QspiMode::QuadChannel => hal_qspi::QspiMode::QuadChannel,
+        }
+    }
+
+    fn from_hal_error(err: hal_qspi::QspiError) -> QspiError {
+        match err {
+            hal_qspi::QspiError::Busy => QspiError::Busy,
+            hal_qspi::QspiError::Address => QspiError::Address,
+            hal_qspi::QspiError::Unknown => QspiError::Unknown,
+        }
+    }
+
+    impl<CLK, NCS, IO0, IO1, IO2, IO3> QspiBus for hal_qspi::Qspi<(CLK, NCS, IO0, IO1, IO2, IO3)> {
+        fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+            self.write(hal_qspi::QspiWriteCommand {
+                instruction: command
+                    .instruction
+                    .map(|(instruction, mode)| (instruction, hal_mode(mode))),
+                address: command
+                    .address
+                    .map(|(address, mode)| (address, hal_mode(mode))),
+                alternative_bytes: command
+                    .alternative_bytes
+                    .map(|(bytes, mode)| (bytes, hal_mode(mode))),
+                dummy_cycles: command.dummy_cycles,
+                data: command.data.map(|(data, mode)| (data, hal_mode(mode))),
+                double_data_rate: command.double_data_rate,
+            })
+            .map_err(from_hal_error)
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            self.transfer(
+                hal_qspi::QspiReadCommand {
+                    instruction: command
+                        .instruction
+                        .map(|(instruction, mode)| (instruction, hal_mode(mode))),
+                    address: command
+                        .address
+                        .map(|(address, mode)| (address, hal_mode(mode))),
+                    alternative_bytes: command
+                        .alternative_bytes
+                        .map(|(bytes, mode)| (bytes, hal_mode(mode))),
+                    dummy_cycles: command.dummy_cycles,
+                    data_mode: hal_mode(command.data_mode),
+                    receive_length: command.receive_length,
+                    double_data_rate: command.double_data_rate,
+                },
+                buffer,
+            )
+            .map_err(from_hal_error)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn modes_keep_their_line_counts() {
+            assert!(matches!(
+                hal_mode(QspiMode::SingleChannel),
+                hal_qspi::QspiMode::SingleChannel
+            ));
+            assert!(matches!(
+                hal_mode(QspiMode::DualChannel),
+                hal_qspi::QspiMode::DualChannel
+            ));
+            assert!(matches!(
+                hal_mode(QspiMode::QuadChannel),
+                hal_qspi::QspiMode::QuadChannel
+            ));
+        }
+
+        #[test]
+        fn hal_errors_keep_their_meaning() {
+            assert_eq!(from_hal_error(hal_qspi::QspiError::Busy), QspiError::Busy);
+            assert_eq!(
+                from_hal_error(hal_qspi::QspiError::Address),
+                QspiError::Address
+            );
+            assert_eq!(
+                from_hal_error(hal_qspi::QspiError::Unknown),
+                QspiError::Unknown
+            );
+        }
+    }
+}
+
+#[cfg(feature = "stm32h7")]
+pub use stm32h7::Stm32h7QspiBus;
+
+#[cfg(feature = "stm32h7")]
+mod stm32h7 {
+    use core::cell::{RefCell, RefMut};
+
+    use stm32h7xx_hal::{
+        stm32::QUADSPI,
+        xspi::{Qspi, QspiError as HalQspiError, QspiMode as HalQspiMode, QspiWord},
+    };
+
+    use super::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+
+    /// Drives the device through the stm32h7xx-hal `Qspi`, for STM32H7 parts.
+    ///
+    /// That `Qspi` sends every phase of a command on the same number of lines, the one set with
+    /// its `configure_mode`, so only commands whose phases all match can go out. That covers
+    /// `ReadMethod::FastRead` and `WriteMethod::SingleLoad` and every command of the driver's own,
+    /// while the dual and quad methods, which send the instruction on one line and the data on
+    /// more, return `FlashError::UnsupportedOnThisBus`. The HAL has no dummy cycles on
+    /// writes either, so those go out as zero alternate bytes, which is the same on the wire.
+    ///
+    /// So this is a single line backend only: `ReadMethod::FastReadQuadIO` and
+    /// `WriteMethod::QuadLoad` don't work on an H7 through it, and won't until the HAL can set the
+    /// lines per phase. Quad transfers on an H7 need a backend over the QUADSPI registers, the way
+    /// `OctospiBus` is done for OCTOSPI.
+    pub struct Stm32h7QspiBus {
+        qspi: RefCell<Qspi<QUADSPI>>,
+    }
+
+    impl Stm32h7QspiBus {
+        pub fn new(qspi: Qspi<QUADSPI>) -> Stm32h7QspiBus {
+            Stm32h7QspiBus {
+                qspi: RefCell::new(qspi),
+            }
+        }
+
+        pub fn free(self) -> Qspi<QUADSPI> {
+            self.qspi.into_inner()
+        }
+
+        /// Sets the HAL's mode for a command whose phases are on `modes` lines
+        fn configure(
+            &self,
+            modes: &[Option<QspiMode>],
+        ) -> Result<RefMut<'_, Qspi<QUADSPI>>, QspiError> {
+            let mode = common_mode(modes)?;
+
+            let mut qspi = self.qspi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+            qspi.configure_mode(hal_mode(mode))
+                .map_err(from_hal_error)?;
+
+            Ok(qspi)
+        }
+    }
+
+    /// The number of lines every phase present is on, or `QspiError::Unsupported` if they differ
+    fn common_mode(modes: &[Option<QspiMode>]) -> Result<QspiMode, QspiError> {
+        let mut present = modes.iter().flatten();
+        let mode = present.next().copied().unwrap_or(QspiMode::SingleChannel);
+        if present.any(|other| *other != mode) {
+            return Err(QspiError::Unsupported);
+        }
+
+        Ok(mode)
+    }
+
+    fn hal_mode(mode: QspiMode) -> HalQspiMode {
+        match mode {
+            QspiMode::SingleChannel => HalQspiMode::OneBit,
+            QspiMode::DualChannel => HalQspiMode::TwoBit,
+            QspiMode::QuadChannel => HalQspiMode::FourBit,
+        }
+    }
+
+    fn from_hal_error(err: HalQspiError) -> QspiError {
+        match err {
+            HalQspiError::Busy => QspiError::Busy,
+            _ => QspiError::Unknown,
+        }
+    }
+
+    fn instruction_word(instruction: Option<(u8, QspiMode)>) -> QspiWord {
+        instruction.map_or(QspiWord::None, |(instruction, _)| QspiWord::U8(instruction))
+    }
+
+    /// Addresses go out as 16 bits, the column or page address the device takes
+    fn address_word(address: Option<(u32, QspiMode)>) -> QspiWord {
+        address.map_or(QspiWord::None, |(address, _)| QspiWord::U16(address as u16))
+    }
+
+    fn bytes_word(bytes: &[u8]) -> Result<QspiWord, QspiError> {
+        match *bytes {
+            [] => Ok(QspiWord::None),
+            [a] => Ok(QspiWord::U8(a)),
+            [a, b] => Ok(QspiWord::U16(u16::from_be_bytes([a, b]))),
+            [a, b, c] => Ok(QspiWord::U24(u32::from_be_bytes([0, a, b, c]))),
+            [a, b, c, d] => Ok(QspiWord::U32(u32::from_be_bytes([a, b, c, d]))),
+            _ => Err(QspiError::Unsupported),
+        }
+    }
+
+    impl QspiBus for Stm32h7QspiBus {
+        fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+            if command.double_data_rate {
+                return Err(QspiError::Unsupported);
+            }
+
+            // Dummy cycles go out as zero alternate bytes, see the struct docs
+            let (alternate_bytes, alternate_mode) =
+                match (command.alternative_bytes, command.dummy_cycles) {
+                    (Some((bytes, mode)), 0) => (bytes_word(bytes)?, Some(mode)),
+                    (None, 0) => (QspiWord::None, None),
+                    (None, dummy_cycles) if dummy_cycles.is_multiple_of(8) => {
+                        let zeros = [0_u8; 4];
+                        let zeros = zeros
+                            .get(..(dummy_cycles / 8) as usize)
+                            .ok_or(QspiError::Unsupported)?;
+
+                        (bytes_word(zeros)?, Some(QspiMode::SingleChannel))
+                    }
+                    _ => return Err(QspiError::Unsupported),
+                };
+
+            let mut qspi = self.configure(&[
+                command.instruction.map(|(_, mode)| mode),
+                command.address.map(|(_, mode)| mode),
+                alternate_mode,
+                command.data.map(|(_, mode)| mode),
+            ])?;
+
+            qspi.write_extended(
+                instruction_word(command.instruction),
+                address_word(command.address),
+                alternate_bytes,
+                command.data.map_or(&[], |(data, _)| data),
+            )
+            .map_err(from_hal_error)
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            if command.double_data_rate {
+                return Err(QspiError::Unsupported);
+            }
+
+            let alternate_bytes = match command.alternative_bytes {
+                Some((bytes, _)) => bytes_word(bytes)?,
+                None => QspiWord::None,
+            };
+
+            let receive_length = command.receive_length as usize;
+            let buffer = buffer.get_mut(..receive_length).ok_or(QspiError::Unknown)?;
+
+            let mut qspi = self.configure(&[
+                command.instruction.map(|(_, mode)| mode),
+                command.address.map(|(_, mode)| mode),
+                command.alternative_bytes.map(|(_, mode)| mode),
+                Some(command.data_mode),
+            ])?;
+
+            qspi.read_extended(
+                instruction_word(command.instruction),
+                address_word(command.address),
+                alternate_bytes,
+                command.dummy_cycles,
+                buffer,
+            )
+            .map_err(from_hal_error)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{commands, ReadMethod, WriteMethod};
+
+        fn phase_modes(command: QspiReadCommand) -> [Option<QspiMode>; 4] {
+            [
+                command.instruction.map(|(_, mode)| mode),
+                command.address.map(|(_, mode)| mode),
+                command.alternative_bytes.map(|(_, mode)| mode),
+                Some(command.data_mode),
+            ]
+        }
+
+        #[test]
+        fn modes_keep_their_line_counts() {
+            assert!(matches!(
+                hal_mode(QspiMode::SingleChannel),
+                HalQspiMode::OneBit
+            ));
+            assert!(matches!(
+                hal_mode(QspiMode::DualChannel),
+                HalQspiMode::TwoBit
+            ));
+            assert!(matches!(
+                hal_mode(QspiMode::QuadChannel),
+                HalQspiMode::FourBit
+            ));
+        }
+
+        #[test]
+        fn only_commands_with_every_phase_on_the_same_lines_go_out() {
+            let status = commands::read_status_register(&[0xC0]);
+            assert_eq!(
+                common_mode(&phase_modes(status)),
+                Ok(QspiMode::SingleChannel)
+            );
+
+            let fast_read = commands::fast_read(ReadMethod::FastRead, 0, 4);
+            assert_eq!(
+                common_mode(&phase_modes(fast_read)),
+                Ok(QspiMode::SingleChannel)
+            );
+
+            // One line instruction, four line address and data
+            let quad_read = commands::fast_read(ReadMethod::FastReadQuadIO, 0, 4);
+            assert_eq!(
+                common_mode(&phase_modes(quad_read)),
+                Err(QspiError::Unsupported)
+            );
+
+            let quad_load = commands::program_data_load(WriteMethod::QuadLoad, 0, &[1]);
+            let modes = [
+                quad_load.instruction.map(|(_, mode)| mode),
+                quad_load.address.map(|(_, mode)| mode),
+                None,
+                quad_load.data.map(|(_, mode)| mode),
+            ];
+            assert_eq!(common_mode(&modes), Err(QspiError::Unsupported));
+
+            assert_eq!(common_mode(&[None, None]), Ok(QspiMode::SingleChannel));
+        }
+
+        #[test]
+        fn addresses_go_out_as_16_bits() {
+            assert!(matches!(
+                address_word(Some((0x0840, QspiMode::SingleChannel))),
+                QspiWord::U16(0x0840)
+            ));
+            // Only the column address the device takes
+            assert!(matches!(
+                address_word(Some((0x1_0840, QspiMode::SingleChannel))),
+                QspiWord::U16(0x0840)
+            ));
+            assert!(matches!(address_word(None), QspiWord::None));
+        }
+
+        #[test]
+        fn alternate_bytes_go_out_as_the_word_of_their_length() {
+            assert!(matches!(bytes_word(&[]), Ok(QspiWord::None)));
+            assert!(matches!(bytes_word(&[0xC0]), Ok(QspiWord::U8(0xC0))));
+            assert!(matches!(bytes_word(&[1, 2]), Ok(QspiWord::U16(0x0102))));
+            assert!(matches!(
+                bytes_word(&[1, 2, 3]),
+                Ok(QspiWord::U24(0x01_0203))
+            ));
+            assert!(matches!(
+                bytes_word(&[1, 2, 3, 4]),
+                Ok(QspiWord::U32(0x0102_0304))
+            ));
+            assert!(matches!(
+                bytes_word(&[1, 2, 3, 4, 5]),
+                Err(QspiError::Unsupported)
+            ));
+        }
+    }
+}
+
+#[cfg(feature = "esp32s3")]
+pub use esp32s3::EspSpiBus;
+
+#[cfg(feature = "esp32s3")]
+mod esp32s3 {
+    use core::cell::RefCell;
+
+    use esp_hal::{
+        spi::{
+            master::{Address, Command, Spi},
+            DataMode,
+        },
+        Blocking,
+    };
+
+    use super::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+
+    /// Drives the device through an esp-hal SPI master in half duplex mode, for the ESP32-S3,
+    /// whose SPI2 can send each phase of a transaction on one, two, or four lines. The SPI has to
+    /// be set up with the quad pins for the dual and quad methods to work.
+    ///
+    /// A half duplex transaction has no alternate bytes phase, so the one byte register address
+    /// of the status register reads goes out as an 8 bit address instead, which is the same on
+    /// the wire. Every other address is the 16 bit column address.
+    pub struct EspSpiBus<'d> {
+        spi: RefCell<Spi<'d, Blocking>>,
+    }
+
+    impl<'d> EspSpiBus<'d> {
+        pub fn new(spi: Spi<'d, Blocking>) -> EspSpiBus<'d> {
+            EspSpiBus {
+                spi: RefCell::new(spi),
+            }
+        }
+
+        pub fn free(self) -> Spi<'d, Blocking> {
+            self.spi.into_inner()
+        }
+    }
+
+    fn data_mode(mode: QspiMode) -> DataMode {
+        match mode {
+            // The usual SPI wiring, commands out on MOSI and data back on MISO
+            QspiMode::SingleChannel => DataMode::SingleTwoDataLines,
+            QspiMode::DualChannel => DataMode::Dual,
+            QspiMode::QuadChannel => DataMode::Quad,
+        }
+    }
+
+    fn command(instruction: Option<(u8, QspiMode)>) -> Command {
+        instruction.map_or(Command::None, |(instruction, mode)| {
+            Command::_8Bit(instruction as u16, data_mode(mode))
+        })
+    }
+
+    fn address(
+        address: Option<(u32, QspiMode)>,
+        alternative_bytes: Option<(&[u8], QspiMode)>,
+    ) -> Result<Address, QspiError> {
+        match (address, alternative_bytes) {
+            (None, None) => Ok(Address::None),
+            (Some((address, mode)), None) => Ok(Address::_16Bit(address, data_mode(mode))),
+            (None, Some((&[byte], mode))) => Ok(Address::_8Bit(byte as u32, data_mode(mode))),
+            _ => Err(QspiError::Unsupported),
+        }
+    }
+
+    impl<'d> QspiBus for EspSpiBus<'d> {
+        fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+            if command.double_data_rate {
+                return Err(QspiError::Unsupported);
+            }
+
+            let address = address(command.address, command.alternative_bytes)?;
+            let (data, mode) = command.data.unwrap_or((&[], QspiMode::SingleChannel));
+
+            let mut spi = self.spi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+            spi.half_duplex_write(
+                data_mode(mode),
+                self::command(command.instruction),
+                address,
+                command.dummy_cycles,
+                data,
+            )
+            .map_err(|_| QspiError::Unknown)
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            if command.double_data_rate {
+                return Err(QspiError::Unsupported);
+            }
+
+            let address = address(command.address, command.alternative_bytes)?;
+
+            let receive_length = command.receive_length as usize;
+            let buffer = buffer.get_mut(..receive_length).ok_or(QspiError::Unknown)?;
+
+            let mut spi = self.spi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+            spi.half_duplex_read(
+                data_mode(command.data_mode),
+                self::command(command.instruction),
+                address,
+                command.dummy_cycles,
+                buffer,
+            )
+            .map_err(|_| QspiError::Unknown)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn modes_keep_their_line_counts() {
+            assert!(matches!(
+                data_mode(QspiMode::SingleChannel),
+                DataMode::SingleTwoDataLines
+            ));
+            assert!(matches!(data_mode(QspiMode::DualChannel), DataMode::Dual));
+            assert!(matches!(data_mode(QspiMode::QuadChannel), DataMode::Quad));
+
+            assert!(matches!(
+                command(Some((0x6B, QspiMode::SingleChannel))),
+                Command::_8Bit(0x6B, DataMode::SingleTwoDataLines)
+            ));
+            assert!(matches!(command(None), Command::None));
+        }
+
+        #[test]
+        fn column_addresses_are_16_bits_and_register_addresses_8() {
+            assert!(matches!(
+                address(Some((0x0840, QspiMode::QuadChannel)), None),
+                Ok(Address::_16Bit(0x0840, DataMode::Quad))
+            ));
+            assert!(matches!(
+                address(None, Some((&[0xC0], QspiMode::SingleChannel))),
+                Ok(Address::_8Bit(0xC0, DataMode::SingleTwoDataLines))
+            ));
+            assert!(matches!(address(None, None), Ok(Address::None)));
+
+            // A half duplex transaction has room for one or the other
+            assert!(matches!(
+                address(
+                    Some((0, QspiMode::SingleChannel)),
+                    Some((&[0xC0], QspiMode::SingleChannel))
+                ),
+                Err(QspiError::Unsupported)
+            ));
+            assert!(matches!(
+                address(None, Some((&[0xC0, 0x00], QspiMode::SingleChannel))),
+                Err(QspiError::Unsupported)
+            ));
+        }
+    }
+}
+
+#[cfg(feature = "spi")]
+pub use spi::SpiBus;
+
+/// `SpiBus` over a Linux spidev device, for programming and testing the device from a Linux host
+/// such as a Raspberry Pi. Open it with `SpiBus::new(SpidevDevice::open("/dev/spidev0.0")?)`.
+#[cfg(feature = "linux")]
+pub type LinuxSpiBus = SpiBus<linux_embedded_hal::SpidevDevice>;
+
+#[cfg(feature = "spi")]
+mod spi {
+    use core::cell::RefCell;
+
+    use embedded_hal_1::spi::{Operation, SpiDevice};
+
+    use super::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+
+    /// Instruction, 16 bit address, alternate bytes, and dummy cycles
+    const MAX_HEADER_BYTES: usize = 8;
+
+    /// Drives the device over a plain SPI bus with one data line each way, for boards that don't
+    /// route the quad lines. Every phase of a command goes out on that one line, so only
+    /// `ReadMethod::FastRead` and the single line write methods work, and commands that use more
+    /// lines return `FlashError::UnsupportedOnThisBus`.
+    pub struct SpiBus<SPI> {
+        spi: RefCell<SPI>,
+    }
+
+    impl<SPI: SpiDevice> SpiBus<SPI> {
+        pub fn new(spi: SPI) -> SpiBus<SPI> {
+            SpiBus {
+                spi: RefCell::new(spi),
+            }
+        }
+
+        pub fn free(self) -> SPI {
+            self.spi.into_inner()
+        }
+
+        fn transaction(&self, operations: &mut [Operation<'_, u8>]) -> Result<(), QspiError> {
+            let mut spi = self.spi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+
+            spi.transaction(operations).map_err(|_| QspiError::Unknown)
+        }
+    }
+
+    fn check_single(mode: QspiMode) -> Result<(), QspiError> {
+        match mode {
+            QspiMode::SingleChannel => Ok(()),
+            QspiMode::DualChannel | QspiMode::QuadChannel => Err(QspiError::Unsupported),
+        }
+    }
+
+    /// Lays out everything ahead of the data phase as it goes out on the wire
+    fn encode_header(
+        instruction: Option<(u8, QspiMode)>,
+        address: Option<(u32, QspiMode)>,
+        alternative_bytes: Option<(&[u8], QspiMode)>,
+        dummy_cycles: u8,
+        double_data_rate: bool,
+        header: &mut [u8; MAX_HEADER_BYTES],
+    ) -> Result<usize, QspiError> {
+        // Dummy cycles are sent as whole bytes of clocks
+        if double_data_rate || !dummy_cycles.is_multiple_of(8) {
+            return Err(QspiError::Unsupported);
+        }
+
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            let end = len + bytes.len();
+            if end > MAX_HEADER_BYTES {
+                return Err(QspiError::Unsupported);
+            }
+
+            header[len..end].copy_from_slice(bytes);
+            len = end;
+            Ok(())
+        };
+
+        if let Some((instruction, mode)) = instruction {
+            check_single(mode)?;
+            push(&[instruction])?;
+        }
+        if let Some((address, mode)) = address {
+            check_single(mode)?;
+            let address = address as u16;
+            push(&address.to_be_bytes())?;
+        }
+        if let Some((bytes, mode)) = alternative_bytes {
+            check_single(mode)?;
+            push(bytes)?;
+        }
+        for _ in 0..dummy_cycles / 8 {
+            push(&[0x00])?;
+        }
+
+        Ok(len)
+    }
+
+    impl<SPI: SpiDevice> QspiBus for SpiBus<SPI> {
+        fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+            let mut header = [0_u8; MAX_HEADER_BYTES];
+            let header_len = encode_header(
+                command.instruction,
+                command.address,
+                command.alternative_bytes,
+                command.dummy_cycles,
+                command.double_data_rate,
+                &mut header,
+            )?;
+
+            match command.data {
+                Some((data, mode)) => {
+                    check_single(mode)?;
+                    self.transaction(&mut [
+                        Operation::Write(&header[..header_len]),
+                        Operation::Write(data),
+                    ])
+                }
+                None => self.transaction(&mut [Operation::Write(&header[..header_len])]),
+            }
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            check_single(command.data_mode)?;
+
+            let mut header = [0_u8; MAX_HEADER_BYTES];
+            let header_len = encode_header(
+                command.instruction,
+                command.address,
+                command.alternative_bytes,
+                command.dummy_cycles,
+                command.double_data_rate,
+                &mut header,
+            )?;
+
+            let receive_length = command.receive_length as usize;
+            let buffer = buffer.get_mut(..receive_length).ok_or(QspiError::Unknown)?;
+
+            self.transaction(&mut [
+                Operation::Write(&header[..header_len]),
+                Operation::Read(buffer),
+            ])
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use core::convert::Infallible;
+        use std::{rc::Rc, vec, vec::Vec};
+
+        use embedded_hal_1::spi::ErrorType;
+
+        use super::*;
+        use crate::{
+            commands, new_w25_n01_gv, status::ECCStatus, FlashError, ReadMethod, ReadMode,
+            WriteMethod, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+        };
+
+        /// Records what each write operation sent and answers reads with `reply`
+        struct Recorder {
+            written: Vec<Vec<u8>>,
+            reply: Vec<u8>,
+        }
+
+        impl ErrorType for Recorder {
+            type Error = Infallible;
+        }
+
+        impl SpiDevice for Recorder {
+            fn transaction(
+                &mut self,
+                operations: &mut [Operation<'_, u8>],
+            ) -> Result<(), Infallible> {
+                for operation in operations.iter_mut() {
+                    match operation {
+                        Operation::Write(bytes) => self.written.push(bytes.to_vec()),
+                        Operation::Read(buffer) => {
+                            let len = buffer.len();
+                            buffer.copy_from_slice(&self.reply[..len]);
+                        }
+                        _ => panic!("the bus only writes and reads"),
+                    }
+                }
+
+                Ok(())
+            }
+        }
+
+        fn bus(reply: &[u8]) -> SpiBus<Recorder> {
+            SpiBus::new(Recorder {
+                written: Vec::new(),
+                reply: reply.to_vec(),
+            })
+        }
+
+        #[test]
+        fn a_register_address_goes_out_after_the_instruction() {
+            let bus = bus(&[0x18]);
+            let mut value = [0_u8; 1];
+            bus.read_command(commands::read_status_register(&[0xC0]), &mut value)
+                .unwrap();
+
+            assert_eq!(value, [0x18]);
+            assert_eq!(bus.free().written, [vec![0x05, 0xC0]]);
+        }
+
+        #[test]
+        fn column_addresses_go_out_as_16_bits_msb_first_then_the_dummy_byte() {
+            let bus = bus(&[1, 2, 3, 4]);
+            let mut buffer = [0_u8; 8];
+            bus.read_command(
+                commands::fast_read(ReadMethod::FastRead, 0x0840, 4),
+                &mut buffer,
+            )
+            .unwrap();
+
+            // Only `receive_length` bytes are clocked in
+            assert_eq!(buffer, [1, 2, 3, 4, 0, 0, 0, 0]);
+            assert_eq!(bus.free().written, [vec![0x0B, 0x08, 0x40, 0x00]]);
+        }
+
+        #[test]
+        fn data_follows_the_header_in_the_same_transaction() {
+            let bus = bus(&[]);
+            bus.write_command(commands::program_data_load(
+                WriteMethod::SingleLoad,
+                2048,
+                &[0xA5, 0x5A],
+            ))
+            .unwrap();
+
+            assert_eq!(
+                bus.free().written,
+                [vec![0x02, 0x08, 0x00], vec![0xA5, 0x5A]]
+            );
+        }
+
+        #[test]
+        fn more_than_one_line_or_odd_dummy_cycles_are_unsupported() {
+            let bus = bus(&[0; 4]);
+            let mut buffer = [0_u8; 4];
+
+            assert_eq!(
+                bus.read_command(
+                    commands::fast_read(ReadMethod::FastReadQuadIO, 0, 4),
+                    &mut buffer
+                ),
+                Err(QspiError::Unsupported)
+            );
+            assert_eq!(
+                bus.write_command(commands::program_data_load(WriteMethod::QuadLoad, 0, &[1])),
+                Err(QspiError::Unsupported)
+            );
+
+            let mut header = [0_u8; MAX_HEADER_BYTES];
+            assert_eq!(
+                encode_header(
+                    Some((0x0B, QspiMode::SingleChannel)),
+                    None,
+                    None,
+                    4,
+                    false,
+                    &mut header
+                ),
+                Err(QspiError::Unsupported)
+            );
+            assert!(bus.free().written.is_empty());
+        }
+
+        type Transactions = Rc<RefCell<Vec<Vec<u8>>>>;
+
+        /// Enough of the device on the far end of the SPI bus to run the driver against. Every
+        /// transaction is logged as the bytes written in it, data buffer reads return the column
+        /// each byte came from, and page reads and programs keep the device busy for one poll.
+        struct Chip {
+            transactions: Transactions,
+            status: u8,
+            busy_polls: u32,
+        }
+
+        impl ErrorType for Chip {
+            type Error = Infallible;
+        }
+
+        impl SpiDevice for Chip {
+            fn transaction(
+                &mut self,
+                operations: &mut [Operation<'_, u8>],
+            ) -> Result<(), Infallible> {
+                let mut sent = Vec::new();
+                for operation in operations.iter_mut() {
+                    match operation {
+                        Operation::Write(bytes) => sent.extend_from_slice(bytes),
+                        Operation::Read(buffer) => match sent[0] {
+                            0x05 | 0x0F if sent[1] == 0xC0 => {
+                                let busy = if self.busy_polls > 0 { 0x01 } else { 0x00 };
+                                self.busy_polls = self.busy_polls.saturating_sub(1);
+                                buffer.fill(self.status | busy);
+                            }
+                            0x05 | 0x0F => buffer.fill(0x18),
+                            0x0B => {
+                                let column = u16::from_be_bytes([sent[1], sent[2]]) as usize;
+                                for (index, byte) in buffer.iter_mut().enumerate() {
+                                    *byte = (column + index) as u8;
+                                }
+                            }
+                            opcode => panic!("unexpected read {:#04x}", opcode),
+                        },
+                        _ => panic!("the bus only writes and reads"),
+                    }
+                }
+
+                match sent[0] {
+                    0x06 => self.status |= 0x02,
+                    0x04 => self.status &= !0x02,
+                    0x10 => {
+                        self.status &= !0x02;
+                        self.busy_polls = 1;
+                    }
+                    0x13 => self.busy_polls = 1,
+                    _ => {}
+                }
+
+                self.transactions.borrow_mut().push(sent);
+                Ok(())
+            }
+        }
+
+        fn driver() -> (W25N01GV<SpiBus<Chip>, ReadMode>, Transactions) {
+            let transactions = Transactions::default();
+            let chip = Chip {
+                transactions: transactions.clone(),
+                status: 0,
+                busy_polls: 0,
+            };
+
+            (new_w25_n01_gv(SpiBus::new(chip)), transactions)
+        }
+
+        /// The logged transactions other than status register polls
+        fn commands(transactions: &Transactions) -> Vec<Vec<u8>> {
+            transactions
+                .borrow()
+                .iter()
+                .filter(|sent| !(sent[0] == 0x05 && sent[1] == 0xC0))
+                .cloned()
+                .collect()
+        }
+
+        #[test]
+        fn the_driver_reads_a_page_over_plain_spi() {
+            let (flash, transactions) = driver();
+            let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+            // The page read keeps the chip busy for a poll, which has to be waited out
+            assert_eq!(
+                flash.read_page(4241, &mut buffer, ReadMethod::FastRead),
+                Ok(ECCStatus::Successful)
+            );
+
+            assert!(buffer
+                .iter()
+                .enumerate()
+                .all(|(column, byte)| *byte == column as u8));
+            assert_eq!(
+                commands(&transactions),
+                [vec![0x13, 0x00, 0x10, 0x91], vec![0x0B, 0x00, 0x00, 0x00]]
+            );
+        }
+
+        #[test]
+        fn the_driver_programs_a_page_over_plain_spi() {
+            let (flash, transactions) = driver();
+
+            let (_flash, failed) = flash
+                .program_page(64, &[0xA5, 0x5A], 2048, WriteMethod::SingleLoad)
+                .unwrap();
+
+            assert!(!failed);
+            assert_eq!(
+                commands(&transactions),
+                [
+                    vec![0x06],
+                    vec![0x02, 0x08, 0x00, 0xA5, 0x5A],
+                    vec![0x10, 0x00, 0x00, 0x40],
+                ]
+            );
+        }
+
+        #[test]
+        fn multi_line_methods_fail_before_reaching_the_spi_device() {
+            let (flash, transactions) = driver();
+            let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+            assert_eq!(
+                flash.read_data_buffer(&mut buffer, ReadMethod::QuadFastRead),
+                Err(FlashError::UnsupportedOnThisBus)
+            );
+            assert!(commands(&transactions).is_empty());
+        }
+    }
+}
+
+#[cfg(feature = "embassy")]
+pub use embassy::EmbassyQspiBus;
+
+#[cfg(feature = "embassy")]
+mod embassy {
+    use core::cell::RefCell;
+
+    use embassy_stm32::{
+        mode::Mode,
+        qspi::{
+            enums::{DummyCycles, QspiWidth},
+            Instance, Qspi, TransferConfig,
+        },
+    };
+
+    use super::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+
+    /// Drives the device through embassy-stm32's `Qspi`, for applications built on embassy rather
+    /// than stm32l4xx-hal. Commands go out with the peripheral's blocking transfers, which don't
+    /// fail, so only commands `Qspi` has no way of sending return an error.
+    ///
+    /// The peripheral has to be set up with `AddressSize::_16Bit`. `Qspi` has no alternate bytes
+    /// phase, so the one byte register address of the status register reads goes out as the high
+    /// byte of the 16 bit address instead. The device starts sending the register right after that
+    /// byte and keeps repeating it, so the low byte only costs a byte's worth of clocks.
+    pub struct EmbassyQspiBus<'d, T: Instance, M: Mode> {
+        qspi: RefCell<Qspi<'d, T, M>>,
+    }
+
+    impl<'d, T: Instance, M: Mode> EmbassyQspiBus<'d, T, M> {
+        pub fn new(qspi: Qspi<'d, T, M>) -> EmbassyQspiBus<'d, T, M> {
+            EmbassyQspiBus {
+                qspi: RefCell::new(qspi),
+            }
+        }
+
+        pub fn free(self) -> Qspi<'d, T, M> {
+            self.qspi.into_inner()
+        }
+    }
+
+    fn width(mode: QspiMode) -> QspiWidth {
+        match mode {
+            QspiMode::SingleChannel => QspiWidth::SING,
+            QspiMode::DualChannel => QspiWidth::DUAL,
+            QspiMode::QuadChannel => QspiWidth::QUAD,
+        }
+    }
+
+    /// Covers the dummy cycle counts the driver's commands use
+    fn dummy(dummy_cycles: u8) -> Result<DummyCycles, QspiError> {
+        match dummy_cycles {
+            0 => Ok(DummyCycles::_0),
+            4 => Ok(DummyCycles::_4),
+            8 => Ok(DummyCycles::_8),
+            _ => Err(QspiError::Unsupported),
+        }
+    }
+
+    /// Everything ahead of the data phase, as `Qspi` takes it
+    fn transfer_config(
+        instruction: Option<(u8, QspiMode)>,
+        address: Option<(u32, QspiMode)>,
+        alternative_bytes: Option<(&[u8], QspiMode)>,
+        dummy_cycles: u8,
+        data_mode: Option<QspiMode>,
+        double_data_rate: bool,
+    ) -> Result<TransferConfig, QspiError> {
+        if double_data_rate {
+            return Err(QspiError::Unsupported);
+        }
+
+        let address = match (address, alternative_bytes) {
+            (address, None) => address,
+            (None, Some((&[byte], mode))) => Some(((byte as u32) << 8, mode)),
+            (_, Some(_)) => return Err(QspiError::Unsupported),
+        };
+
+        Ok(TransferConfig {
+            iwidth: instruction.map_or(QspiWidth::NONE, |(_, mode)| width(mode)),
+            awidth: address.map_or(QspiWidth::NONE, |(_, mode)| width(mode)),
+            dwidth: data_mode.map_or(QspiWidth::NONE, width),
+            instruction: instruction.map_or(0, |(instruction, _)| instruction),
+            address: address.map(|(address, _)| address),
+            dummy: dummy(dummy_cycles)?,
+        })
+    }
+
+    impl<'d, T: Instance, M: Mode> QspiBus for EmbassyQspiBus<'d, T, M> {
+        fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+            let config = transfer_config(
+                command.instruction,
+                command.address,
+                command.alternative_bytes,
+                command.dummy_cycles,
+                command.data.map(|(_, mode)| mode),
+                command.double_data_rate,
+            )?;
+            let mut qspi = self.qspi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+
+            match command.data {
+                Some((data, _)) => qspi.blocking_write(data, config),
+                None => qspi.blocking_command(config),
+            }
+
+            Ok(())
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            let config = transfer_config(
+                command.instruction,
+                command.address,
+                command.alternative_bytes,
+                command.dummy_cycles,
+                Some(command.data_mode),
+                command.double_data_rate,
+            )?;
+
+            let receive_length = command.receive_length as usize;
+            let buffer = buffer.get_mut(..receive_length).ok_or(QspiError::Unknown)?;
+
+            let mut qspi = self.qspi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+            qspi.blocking_read(buffer, config);
+
+            Ok(())
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::{commands, ReadMethod};
+
+        #[test]
+        fn modes_and_dummy_cycles_map_to_the_peripherals() {
+            assert!(matches!(width(QspiMode::SingleChannel), QspiWidth::SING));
+            assert!(matches!(width(QspiMode::DualChannel), QspiWidth::DUAL));
+            assert!(matches!(width(QspiMode::QuadChannel), QspiWidth::QUAD));
+
+            assert!(matches!(dummy(0), Ok(DummyCycles::_0)));
+            assert!(matches!(dummy(4), Ok(DummyCycles::_4)));
+            assert!(matches!(dummy(8), Ok(DummyCycles::_8)));
+            assert!(matches!(dummy(6), Err(QspiError::Unsupported)));
+        }
+
+        #[test]
+        fn a_quad_read_keeps_its_column_address_and_line_counts() {
+            let command = commands::fast_read(ReadMethod::FastReadQuadIO, 0x0840, 4);
+            let config = transfer_config(
+                command.instruction,
+                command.address,
+                command.alternative_bytes,
+                command.dummy_cycles,
+                Some(command.data_mode),
+                command.double_data_rate,
+            )
+            .unwrap();
+
+            assert!(matches!(config.iwidth, QspiWidth::SING));
+            assert!(matches!(config.awidth, QspiWidth::QUAD));
+            assert!(matches!(config.dwidth, QspiWidth::QUAD));
+            assert_eq!(config.instruction, 0xEB);
+            assert_eq!(config.address, Some(0x0840));
+        }
+
+        #[test]
+        fn a_register_address_goes_out_as_the_high_byte_of_the_address() {
+            let command = commands::read_status_register(&[0xC0]);
+            let config = transfer_config(
+                command.instruction,
+                command.address,
+                command.alternative_bytes,
+                command.dummy_cycles,
+                Some(command.data_mode),
+                command.double_data_rate,
+            )
+            .unwrap();
+
+            assert!(matches!(config.awidth, QspiWidth::SING));
+            assert_eq!(config.address, Some(0xC000));
+        }
+
+        #[test]
+        fn commands_qspi_cant_send_are_unsupported() {
+            let single = QspiMode::SingleChannel;
+
+            // Both an address and alternate bytes
+            assert!(matches!(
+                transfer_config(
+                    Some((0x0B, single)),
+                    Some((0, single)),
+                    Some((&[0xC0], single)),
+                    0,
+                    None,
+                    false
+                ),
+                Err(QspiError::Unsupported)
+            ));
+            assert!(matches!(
+                transfer_config(Some((0x0B, single)), None, None, 0, None, true),
+                Err(QspiError::Unsupported)
+            ));
+
+            // No phases at all
+            let config = transfer_config(None, None, None, 0, None, false).unwrap();
+            assert!(matches!(config.iwidth, QspiWidth::NONE));
+            assert!(matches!(config.awidth, QspiWidth::NONE));
+            assert!(matches!(config.dwidth, QspiWidth::NONE));
+        }
+    }
+}
@@ -0,0 +1,370 @@
+//! How long a single QSPI transaction keeps the bus, for interrupt latency analysis on a shared
+//! bus. This is the time the transaction itself takes on the wire, not how long the device stays
+//! busy afterwards.
+//!
+//! The hold time is worked out from the command exactly as `commands` builds it: the instruction,
+//! a 16 bit address, alternate bytes, dummy cycles, and data, each at its own lane width. It needs
+//! the bus clock, declared with `set_bus_clock_hz`.
+//!
+//! With a limit set by `set_bus_hold_limit_us`, reads of the data buffer and loads into it are
+//! split into as many transactions as it takes for each to fit the limit. A split read relies on
+//! the column address, so it needs buffer read mode (BUF=1, the default). Commands without a
+//! data phase worth splitting, like register accesses and the page commands, are always sent
+//! whole.
+
+use crate::{
+    bus::{QspiMode, QspiReadCommand, QspiWriteCommand},
+    commands, FlashError, QspiBus, ReadMethod, WriteMethod, W25N01GV,
+};
+
+/// The driver expects the QSPI peripheral to be set up with 16 bit addresses
+const ADDRESS_BITS: u32 = 16;
+
+/// An operation whose bus hold time can be worked out
+#[derive(Debug, Clone, Copy)]
+pub enum BusOp {
+    /// Reading `len` bytes out of the data buffer
+    Read(ReadMethod),
+    /// Loading `len` bytes into the data buffer
+    Load(WriteMethod),
+    /// Page Data Read, Program Execute, or Block Erase
+    PageCommand,
+    /// Reading a status register
+    RegisterRead,
+    /// Writing a status register
+    RegisterWrite,
+}
+
+fn lanes(mode: QspiMode) -> u32 {
+    match mode {
+        QspiMode::SingleChannel => 1,
+        QspiMode::DualChannel => 2,
+        QspiMode::QuadChannel => 4,
+    }
+}
+
+fn phase_cycles(bits: u32, mode: QspiMode) -> u32 {
+    bits.div_ceil(lanes(mode))
+}
+
+/// Clock cycles a read command holds the bus for, data included
+pub fn read_command_cycles(command: &QspiReadCommand) -> u32 {
+    command
+        .instruction
+        .map_or(0, |(_, mode)| phase_cycles(8, mode))
+        + command
+            .address
+            .map_or(0, |(_, mode)| phase_cycles(ADDRESS_BITS, mode))
+        + command.alternative_bytes.map_or(0, |(bytes, mode)| {
+            phase_cycles(bytes.len() as u32 * 8, mode)
+        })
+        + command.dummy_cycles as u32
+        + phase_cycles(command.receive_length * 8, command.data_mode)
+}
+
+/// Clock cycles a write command holds the bus for, data included
+pub fn write_command_cycles(command: &QspiWriteCommand) -> u32 {
+    command
+        .instruction
+        .map_or(0, |(_, mode)| phase_cycles(8, mode))
+        + command
+            .address
+            .map_or(0, |(_, mode)| phase_cycles(ADDRESS_BITS, mode))
+        + command.alternative_bytes.map_or(0, |(bytes, mode)| {
+            phase_cycles(bytes.len() as u32 * 8, mode)
+        })
+        + command.dummy_cycles as u32
+        + command.data.map_or(0, |(bytes, mode)| {
+            phase_cycles(bytes.len() as u32 * 8, mode)
+        })
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Declares the QSPI clock, which the hold times are worked out from. 0, the default, means
+    /// unknown.
+    pub fn set_bus_clock_hz(&mut self, bus_clock_hz: u32) {
+        self.bus_clock_hz = bus_clock_hz;
+    }
+
+    /// Sets the longest a read or load may hold the bus for, see the module docs. Has no effect
+    /// until the bus clock is declared.
+    pub fn set_bus_hold_limit_us(&mut self, limit_us: Option<u32>) {
+        self.bus_hold_limit_us = limit_us;
+    }
+
+    pub fn bus_hold_limit_us(&self) -> Option<u32> {
+        self.bus_hold_limit_us
+    }
+
+    /// Clock cycles a single transaction of `op` moving `len` bytes of data holds the bus for.
+    /// `len` is ignored for ops with a fixed length.
+    pub fn bus_hold_cycles(&self, op: BusOp, len: u32) -> u32 {
+        let page_address = [0_u8; 2];
+        let register_write = [0_u8; 2];
+        let sar_address = [0_u8; 1];
+
+        match op {
+            BusOp::Read(method) => read_command_cycles(&commands::fast_read(method, 0, len)),
+            // Only the length of the data matters, not what's in it
+            BusOp::Load(method) => {
+                write_command_cycles(&commands::program_data_load(method, 0, &[]))
+                    + phase_cycles(len * 8, method.data_mode())
+            }
+            BusOp::PageCommand => write_command_cycles(&commands::page_data_read(&page_address)),
+            BusOp::RegisterRead => {
+                read_command_cycles(&commands::read_status_register(&sar_address))
+            }
+            BusOp::RegisterWrite => {
+                write_command_cycles(&commands::write_status_register(&register_write))
+            }
+        }
+    }
+
+    /// The longest a single transaction of `op` moving `len` bytes holds the bus for, in
+    /// microseconds rounded up, or None if the bus clock hasn't been declared. With a hold limit
+    /// set this is for the whole `len`, before any splitting.
+    pub fn max_bus_hold(&self, op: BusOp, len: u32) -> Option<u32> {
+        if self.bus_clock_hz == 0 {
+            return None;
+        }
+
+        let cycles = self.bus_hold_cycles(op, len) as u64 * 1_000_000;
+        Some(cycles.div_ceil(self.bus_clock_hz as u64) as u32)
+    }
+
+    /// The most data bytes a single transaction of `op` may move within the hold limit. Always
+    /// at least 1, since a transaction can't be split any further than that.
+    pub(crate) fn max_transfer_bytes(&self, op: BusOp) -> usize {
+        let limit_us = match self.bus_hold_limit_us {
+            Some(limit_us) if self.bus_clock_hz != 0 => limit_us,
+            _ => return usize::MAX,
+        };
+
+        let lanes = match op {
+            BusOp::Read(method) => lanes(method.data_mode()),
+            BusOp::Load(method) => lanes(method.data_mode()),
+            _ => return usize::MAX,
+        };
+
+        let limit_cycles = limit_us as u64 * self.bus_clock_hz as u64 / 1_000_000;
+        let overhead_cycles = self.bus_hold_cycles(op, 0) as u64;
+        let bytes = limit_cycles.saturating_sub(overhead_cycles) * lanes as u64 / 8;
+
+        bytes.clamp(1, usize::MAX as u64) as usize
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads `buffer.len()` bytes of the data buffer from `column` on, in as many transactions as
+    /// the hold limit needs
+    pub(crate) fn transfer_split(
+        &self,
+        column: u16,
+        buffer: &mut [u8],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        let chunk_bytes = self.max_transfer_bytes(BusOp::Read(method));
+
+        for (index, chunk) in buffer.chunks_mut(chunk_bytes).enumerate() {
+            let chunk_column = column + (index * chunk_bytes) as u16;
+            let command = commands::fast_read(method, chunk_column, chunk.len() as u32);
+            self.qspi_transfer(command, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads `bytes` into the data buffer from `column` on, in as many transactions as the hold
+    /// limit needs. Only the first load uses `write_method` as is, the rest keep what came before
+    /// them. The write enable latch must already be set.
+    pub(crate) fn load_split(
+        &self,
+        column: u16,
+        bytes: &[u8],
+        write_method: WriteMethod,
+    ) -> Result<(), FlashError> {
+        let chunk_bytes = self.max_transfer_bytes(BusOp::Load(write_method));
+
+        for (index, chunk) in bytes.chunks(chunk_bytes).enumerate() {
+            let chunk_method = if index == 0 {
+                write_method
+            } else {
+                write_method.random()
+            };
+
+            let chunk_column = column + (index * chunk_bytes) as u16;
+            let command = commands::program_data_load(chunk_method, chunk_column, chunk);
+            self.qspi_write(command)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sim::SimFlash, Column, LoadMode, PAGE_SIZE_WITH_ECC_BYTES};
+    use std::vec::Vec;
+
+    const PAGE: u32 = PAGE_SIZE_WITH_ECC_BYTES as u32;
+
+    #[test]
+    fn hold_cycles_follow_the_command_phases() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+
+        // Instruction, 16 bit address, 8 dummy cycles, then the data on one line
+        assert_eq!(
+            flash.bus_hold_cycles(BusOp::Read(ReadMethod::FastRead), PAGE),
+            8 + 16 + 8 + PAGE * 8
+        );
+        // Address and data on four lines, 4 dummy cycles
+        assert_eq!(
+            flash.bus_hold_cycles(BusOp::Read(ReadMethod::FastReadQuadIO), PAGE),
+            8 + 4 + 4 + PAGE * 2
+        );
+        assert_eq!(
+            flash.bus_hold_cycles(BusOp::Load(WriteMethod::QuadLoad), 2048),
+            8 + 16 + 2048 * 2
+        );
+        // A dummy byte then the page address, whatever the length
+        assert_eq!(flash.bus_hold_cycles(BusOp::PageCommand, PAGE), 8 + 8 + 16);
+        assert_eq!(flash.bus_hold_cycles(BusOp::RegisterRead, 0), 8 + 8 + 8);
+        assert_eq!(flash.bus_hold_cycles(BusOp::RegisterWrite, 0), 8 + 16);
+    }
+
+    #[test]
+    fn hold_times_need_the_bus_clock() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let op = BusOp::Read(ReadMethod::FastRead);
+
+        assert_eq!(flash.max_bus_hold(op, PAGE), None);
+
+        flash.set_bus_clock_hz(104_000_000);
+        // 16928 cycles at 104 MHz is 162.8 us, rounded up
+        assert_eq!(flash.max_bus_hold(op, PAGE), Some(163));
+    }
+
+    fn fast_reads(sim: &SimFlash) -> Vec<(u32, u32)> {
+        sim.commands()
+            .iter()
+            .filter(|command| command.opcode == ReadMethod::FastRead as u8)
+            .map(|command| (command.address.unwrap().0, command.receive_length.unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn reads_are_split_at_the_hold_limit() {
+        let sim = SimFlash::new();
+        let page: Vec<u8> = (0..PAGE_SIZE_WITH_ECC_BYTES).map(|i| i as u8).collect();
+        sim.set_page(0, &page);
+        let mut flash = sim.driver();
+        flash.set_bus_clock_hz(1_000_000);
+        flash.set_bus_hold_limit_us(Some(1000));
+        flash.read_memory_to_data_buffer(0).unwrap();
+        flash.wait_while_busy().unwrap();
+
+        sim.clear_log();
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        flash
+            .read_data_buffer(&mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(&buffer[..], &page[..]);
+
+        // 32 cycles of overhead leaves 968 cycles, 121 bytes, per transaction
+        let op = BusOp::Read(ReadMethod::FastRead);
+        assert_eq!(flash.max_bus_hold(op, 121), Some(1000));
+        assert_eq!(flash.max_bus_hold(op, 122), Some(1008));
+
+        let reads = fast_reads(&sim);
+        assert_eq!(reads.len(), PAGE_SIZE_WITH_ECC_BYTES.div_ceil(121));
+        let mut column = 0;
+        for (address, len) in reads {
+            assert_eq!(address, column);
+            assert!(len <= 121);
+            column += len;
+        }
+        assert_eq!(column, PAGE);
+    }
+
+    #[test]
+    fn reads_are_sent_whole_without_a_limit_or_a_clock() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+        sim.clear_log();
+        flash
+            .read_data_buffer(&mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(fast_reads(&sim), [(0, PAGE)]);
+
+        // A limit means nothing until the clock is known
+        flash.set_bus_hold_limit_us(Some(1));
+        sim.clear_log();
+        flash
+            .read_data_buffer(&mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(fast_reads(&sim), [(0, PAGE)]);
+    }
+
+    #[test]
+    fn loads_are_split_and_only_the_first_resets_the_buffer() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_bus_clock_hz(1_000_000);
+        flash.set_bus_hold_limit_us(Some(1000));
+        let flash = flash.into_write_mode().unwrap();
+
+        let bytes: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        sim.clear_log();
+        flash
+            .load_to_data_buffer(&bytes, 10, WriteMethod::SingleLoad, LoadMode::ResetThenLoad)
+            .unwrap();
+
+        // 24 cycles of overhead leaves 976 cycles, 122 bytes, per transaction
+        let loads: Vec<(u8, u32, usize)> = sim
+            .commands()
+            .iter()
+            .filter(|command| {
+                command.opcode == WriteMethod::SingleLoad as u8
+                    || command.opcode == WriteMethod::RandomSingleLoad as u8
+            })
+            .map(|command| {
+                (
+                    command.opcode,
+                    command.address.unwrap().0,
+                    command.data.len(),
+                )
+            })
+            .collect();
+        assert_eq!(loads, [(0x02, 10, 122), (0x84, 132, 122), (0x84, 254, 56)]);
+
+        let flash = flash.into_read_mode().unwrap();
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        flash
+            .read_data_buffer(&mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(&buffer[10..310], &bytes[..]);
+        assert!(buffer[..10].iter().all(|byte| *byte == 0xFF));
+    }
+
+    #[test]
+    fn a_limit_below_the_overhead_still_moves_a_byte_at_a_time() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_bus_clock_hz(1_000_000);
+        flash.set_bus_hold_limit_us(Some(1));
+        flash.read_memory_to_data_buffer(0).unwrap();
+        flash.wait_while_busy().unwrap();
+
+        sim.clear_log();
+        let mut buffer = [0_u8; 3];
+        flash
+            .read_columns(Column::Physical(0), &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(fast_reads(&sim), [(0, 1), (1, 1), (2, 1)]);
+    }
+}
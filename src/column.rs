@@ -0,0 +1,586 @@
+//! Column addressing within the data buffer. There are two coordinate systems:
+//!
+//! - **Physical** columns are what goes out on the bus. Columns 0..2048 are the main area and
+//!   2048..2112 are the spare area, split into four 16 byte sections. With ECC enabled the last 8
+//!   bytes of each section hold the ECC codes, so physical columns like 2056..2064 read back
+//!   device generated data and can't be written.
+//! - **Logical** columns only count the bytes the user owns. The main area is the same in both
+//!   systems, but the logical spare area packs the user bytes of each section back to back: 32
+//!   bytes with ECC enabled (logical 2048..2056 is physical 2048..2056, logical 2056..2064 is
+//!   physical 2064..2072, and so on), or all 64 bytes with ECC disabled, where logical and
+//!   physical columns are the same. That's the default OOB layout, other layouts pack their own
+//!   user bytes the same way, see `oob`.
+//!
+//! Logical accesses are split into one bus transfer per contiguous physical run, so they can span
+//! sections freely. Physical accesses are passed through as is, except that with ECC enabled a
+//! physical load that touches an ECC byte is refused rather than silently dropped by the device.
+//!
+//! No access runs past physical column 2111. Loads and buffered reads that would are refused with
+//! `FlashError::WouldWrapPageBuffer`, since some revisions of the device wrap back to
+//! column 0 there and others truncate. `load_wrapping_to_start` does the wrap explicitly for the
+//! rare caller that wants it. In continuous read mode the column is ignored and reads run on into
+//! the following pages, so column reads from anywhere but column 0 are refused there with
+//! `FlashError::ColumnIgnoredInContinuousRead`. The read mode is cached like the ECC state.
+
+use crate::{
+    soft_ecc::SOFT_ECC_BYTES, EccMode, FlashError, LoadMode, OobLayout, QspiBus, ReadMethod,
+    WriteMethod, WriteMode, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, SPARE_BYTES, W25N01GV,
+};
+
+pub const SPARE_SECTION_BYTES: usize = 16;
+/// User bytes at the start of each spare section when ECC is enabled
+pub const SPARE_SECTION_USER_BYTES: usize = 8;
+
+/// A column in the data buffer, in either coordinate system (see the module docs)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Column {
+    Physical(u16),
+    Logical(u16),
+}
+
+/// Refuses an access of `len` bytes from physical `column` that would run past the data buffer
+pub(crate) fn check_buffer_end(column: u16, len: usize) -> Result<(), FlashError> {
+    if column as usize + len > PAGE_SIZE_WITH_ECC_BYTES {
+        return Err(FlashError::WouldWrapPageBuffer { column, len });
+    }
+
+    Ok(())
+}
+
+/// With ECC enabled the last 8 bytes of each 16 byte spare section hold the ECC codes and can't
+/// be written by the user.
+pub(crate) fn is_ecc_reserved_spare_byte(spare_index: usize) -> bool {
+    spare_index % SPARE_SECTION_BYTES >= SPARE_SECTION_USER_BYTES
+}
+
+/// The number of logical columns, main area included, under the default OOB layout
+pub fn logical_page_bytes(ecc_enabled: bool) -> usize {
+    logical_page_bytes_in(OobLayout::WinbondDefault.user_mask(ecc_enabled))
+}
+
+/// Translates a logical column to its physical column under the default OOB layout, or returns
+/// None if it's past the end of the logical page
+pub fn logical_to_physical(column: u16, ecc_enabled: bool) -> Option<u16> {
+    logical_to_physical_in(column, OobLayout::WinbondDefault.user_mask(ecc_enabled))
+}
+
+/// Translates a physical column to its logical column under the default OOB layout, or returns
+/// None if it's a spare byte hidden from the logical layout or past the end of the page
+pub fn physical_to_logical(column: u16, ecc_enabled: bool) -> Option<u16> {
+    physical_to_logical_in(column, OobLayout::WinbondDefault.user_mask(ecc_enabled))
+}
+
+/// Is true if the physical column is in the main area or a user byte of `user_mask`
+fn is_user_column(column: usize, user_mask: u64) -> bool {
+    column < PAGE_SIZE_BYTES
+        || (column < PAGE_SIZE_WITH_ECC_BYTES && user_mask & (1 << (column - PAGE_SIZE_BYTES)) != 0)
+}
+
+/// Like `logical_page_bytes`, with the spare area packed from the user bytes of `user_mask`
+pub(crate) fn logical_page_bytes_in(user_mask: u64) -> usize {
+    PAGE_SIZE_BYTES + user_mask.count_ones() as usize
+}
+
+pub(crate) fn logical_to_physical_in(column: u16, user_mask: u64) -> Option<u16> {
+    let column = column as usize;
+
+    if column < PAGE_SIZE_BYTES {
+        return Some(column as u16);
+    }
+
+    (0..SPARE_BYTES)
+        .filter(|spare_index| user_mask & (1 << spare_index) != 0)
+        .nth(column - PAGE_SIZE_BYTES)
+        .map(|spare_index| (PAGE_SIZE_BYTES + spare_index) as u16)
+}
+
+pub(crate) fn physical_to_logical_in(column: u16, user_mask: u64) -> Option<u16> {
+    let column = column as usize;
+
+    if !is_user_column(column, user_mask) {
+        None
+    } else if column < PAGE_SIZE_BYTES {
+        Some(column as u16)
+    } else {
+        let below = user_mask & ((1 << (column - PAGE_SIZE_BYTES)) - 1);
+
+        Some((PAGE_SIZE_BYTES + below.count_ones() as usize) as u16)
+    }
+}
+
+/// Splits `len` bytes starting at a logical column into contiguous physical runs, calling `f`
+/// with the physical column and the offset into the caller's buffer for each one
+fn for_each_physical_run<F>(
+    column: u16,
+    len: usize,
+    user_mask: u64,
+    mut f: F,
+) -> Result<(), FlashError>
+where
+    F: FnMut(u16, usize, usize) -> Result<(), FlashError>,
+{
+    if column as usize + len > logical_page_bytes_in(user_mask) {
+        return Err(FlashError::OutOfBounds);
+    }
+
+    let mut offset = 0;
+    while offset < len {
+        let logical = column as usize + offset;
+        let physical =
+            logical_to_physical_in(logical as u16, user_mask).ok_or(FlashError::OutOfBounds)?;
+
+        let mut run_end = physical as usize + 1;
+        while is_user_column(run_end, user_mask) {
+            run_end += 1;
+        }
+        let run_len = (run_end - physical as usize).min(len - offset);
+
+        f(physical, offset, run_len)?;
+        offset += run_len;
+    }
+
+    Ok(())
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Whether on-chip ECC is enabled, from the driver's cache if it has one. The cache is filled
+    /// by reading the configuration register and dropped whenever the driver writes it. Always
+    /// false without touching the bus under `EccMode::Disabled`.
+    pub fn ecc_enabled(&self) -> Result<bool, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.ecc_enabled_unguarded()
+    }
+
+    pub(crate) fn ecc_enabled_unguarded(&self) -> Result<bool, FlashError> {
+        if self.ecc_mode == EccMode::Disabled {
+            return Ok(false);
+        }
+
+        match self.ecc_enabled.get() {
+            Some(ecc_enabled) => Ok(ecc_enabled),
+            None => Ok(self.read_configuration_register_unguarded()?.ecc_e),
+        }
+    }
+
+    /// Whether buffered read mode is on, from the driver's cache if it has one, which is kept like
+    /// the ECC one
+    pub(crate) fn buffer_mode_unguarded(&self) -> Result<bool, FlashError> {
+        match self.buffer_mode.get() {
+            Some(buffer_mode) => Ok(buffer_mode),
+            None => Ok(self.read_configuration_register_unguarded()?.buf),
+        }
+    }
+
+    /// How many bytes of each page a storage layer can use in the current ECC mode. With ECC
+    /// enabled that's the main area, leaving the spare area to the device's ECC and bad block
+    /// markers. With ECC disabled it's the whole page minus room for the software ECC from
+    /// `soft_ecc`, which is needed to keep the data protected.
+    pub fn usable_page_bytes(&self) -> Result<usize, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if self.ecc_enabled_unguarded()? {
+            Ok(PAGE_SIZE_BYTES)
+        } else {
+            Ok(PAGE_SIZE_WITH_ECC_BYTES - SOFT_ECC_BYTES)
+        }
+    }
+
+    /// Reads `buffer.len()` bytes out of the data buffer starting at `column`. A logical read that
+    /// spans several spare sections is done as one transfer per section. An empty buffer does
+    /// nothing and sends nothing.
+    pub fn read_columns(
+        &self,
+        column: Column,
+        buffer: &mut [u8],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_columns_unguarded(column, buffer, method)
+    }
+
+    pub(crate) fn read_columns_unguarded(
+        &self,
+        column: Column,
+        buffer: &mut [u8],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        match column {
+            Column::Physical(column) => {
+                check_buffer_end(column, buffer.len())?;
+
+                self.read_physical_columns(column, buffer, method)
+            }
+            Column::Logical(column) => {
+                let user_mask = self.oob_layout.user_mask(self.ecc_enabled_unguarded()?);
+
+                for_each_physical_run(column, buffer.len(), user_mask, |physical, offset, len| {
+                    self.read_physical_columns(physical, &mut buffer[offset..offset + len], method)
+                })
+            }
+        }
+    }
+
+    pub(crate) fn read_physical_columns(
+        &self,
+        column: u16,
+        buffer: &mut [u8],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        // The QSPI peripheral rejects a zero length data phase
+        if buffer.is_empty() {
+            return Ok(());
+        }
+
+        check_buffer_end(column, buffer.len())?;
+
+        if column != 0 && !self.buffer_mode_unguarded()? {
+            return Err(FlashError::ColumnIgnoredInContinuousRead { column });
+        }
+
+        match self.check_busy() {
+            Ok(busy) => {
+                if busy {
+                    return Err(FlashError::DeviceBusy);
+                }
+            }
+            Err(err) => return Err(err),
+        }
+
+        self.transfer_split(column, buffer, method)
+    }
+}
+
+impl<BUS: QspiBus> W25N01GV<BUS, WriteMode> {
+    /// Loads `bytes` into the data buffer starting at `column`. With ECC enabled, a physical load
+    /// that touches an ECC byte returns `FlashError::WriteToECCReservedColumn`, and one
+    /// that touches a byte the OOB layout reserves returns
+    /// `FlashError::WriteToLayoutReservedColumn`. A logical load that spans several spare
+    /// sections is done as one load per section, with every load after the first preserving the
+    /// buffer. Empty `bytes` does nothing and sends nothing.
+    pub fn load_columns(
+        &self,
+        column: Column,
+        bytes: &[u8],
+        write_method: WriteMethod,
+        load_mode: LoadMode,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        match column {
+            Column::Physical(column) => {
+                check_buffer_end(column, bytes.len())?;
+                let end = column as usize + bytes.len();
+
+                if end > PAGE_SIZE_BYTES {
+                    let ecc_enabled = self.ecc_enabled_unguarded()?;
+                    let first_spare = (column as usize).max(PAGE_SIZE_BYTES) - PAGE_SIZE_BYTES;
+                    let last_spare = end - PAGE_SIZE_BYTES;
+
+                    for spare_index in first_spare..last_spare {
+                        self.oob_layout.check_user_byte(spare_index, ecc_enabled)?;
+                    }
+                }
+
+                self.load_to_data_buffer_unguarded(bytes, column, write_method, load_mode)
+            }
+            Column::Logical(column) => {
+                let user_mask = self.oob_layout.user_mask(self.ecc_enabled_unguarded()?);
+
+                for_each_physical_run(column, bytes.len(), user_mask, |physical, offset, len| {
+                    let load_mode = if offset == 0 {
+                        load_mode
+                    } else {
+                        LoadMode::PreserveAndLoad
+                    };
+
+                    self.load_to_data_buffer_unguarded(
+                        &bytes[offset..offset + len],
+                        physical,
+                        write_method,
+                        load_mode,
+                    )
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+    use std::vec::Vec;
+
+    const READ_METHODS: [ReadMethod; 5] = [
+        ReadMethod::FastRead,
+        ReadMethod::DualFastRead,
+        ReadMethod::QuadFastRead,
+        ReadMethod::FastReadDualIO,
+        ReadMethod::FastReadQuadIO,
+    ];
+
+    const WRITE_METHODS: [WriteMethod; 4] = [
+        WriteMethod::SingleLoad,
+        WriteMethod::RandomSingleLoad,
+        WriteMethod::QuadLoad,
+        WriteMethod::RandomQuadLoad,
+    ];
+
+    fn pattern() -> Vec<u8> {
+        (0..PAGE_SIZE_WITH_ECC_BYTES)
+            .map(|column| (column * 7) as u8)
+            .collect()
+    }
+
+    fn would_wrap(result: Result<(), FlashError>, column: u16, len: usize) -> bool {
+        result == Err(FlashError::WouldWrapPageBuffer { column, len })
+    }
+
+    #[test]
+    fn loads_stop_at_the_last_column() {
+        for method in WRITE_METHODS.iter() {
+            let sim = SimFlash::new();
+            let flash = sim.driver().into_write_mode().unwrap();
+            let load = |bytes: &[u8], column| {
+                flash.load_to_data_buffer(bytes, column, *method, LoadMode::PreserveAndLoad)
+            };
+
+            assert_eq!(load(&[0xA5], 2111), Ok(()), "{:?}", method);
+            assert_eq!(load(&[0x5A; SPARE_BYTES], 2048), Ok(()));
+            assert_eq!(load(&[0; PAGE_SIZE_WITH_ECC_BYTES], 0), Ok(()));
+
+            sim.clear_log();
+            assert!(would_wrap(load(&[0; 2], 2111), 2111, 2));
+            assert!(would_wrap(load(&[0; 1], 2112), 2112, 1));
+            assert!(would_wrap(load(&[0; SPARE_BYTES + 1], 2048), 2048, 65));
+            assert!(would_wrap(
+                load(&[0; PAGE_SIZE_WITH_ECC_BYTES + 1], 0),
+                0,
+                2113
+            ));
+            assert!(sim.commands().is_empty());
+        }
+    }
+
+    #[test]
+    fn buffered_reads_stop_at_the_last_column() {
+        let sim = SimFlash::new();
+        let page = pattern();
+        sim.set_page(5, &page);
+        let flash = sim.driver();
+        flash.read_memory_to_data_buffer(5).unwrap();
+        flash.wait_while_busy().unwrap();
+
+        for method in READ_METHODS.iter() {
+            let read = |column, buffer: &mut [u8]| {
+                flash.read_columns(Column::Physical(column), buffer, *method)
+            };
+
+            let mut last = [0_u8; 1];
+            assert_eq!(read(2111, &mut last), Ok(()), "{:?}", method);
+            assert_eq!(last[0], page[2111]);
+
+            let mut spare = [0_u8; SPARE_BYTES];
+            assert_eq!(read(2048, &mut spare), Ok(()));
+            assert_eq!(&spare[..], &page[2048..]);
+
+            assert!(would_wrap(read(2111, &mut [0; 2]), 2111, 2));
+            assert!(would_wrap(read(2112, &mut [0; 1]), 2112, 1));
+            assert!(would_wrap(read(2047, &mut [0; 66]), 2047, 66));
+        }
+    }
+
+    #[test]
+    fn continuous_reads_only_start_at_column_zero() {
+        let sim = SimFlash::new();
+        let page = pattern();
+        sim.set_page(5, &page);
+        let flash = sim.driver();
+        flash.set_continuous_read_mode(true).unwrap();
+        flash.read_memory_to_data_buffer(5).unwrap();
+        flash.wait_while_busy().unwrap();
+
+        for method in READ_METHODS.iter() {
+            sim.clear_log();
+            for column in [1, 2047, 2048, 2111].iter() {
+                assert_eq!(
+                    flash.read_columns(Column::Physical(*column), &mut [0; 1], *method),
+                    Err(FlashError::ColumnIgnoredInContinuousRead { column: *column }),
+                    "{:?}",
+                    method
+                );
+            }
+            assert_eq!(sim.count(*method as u8), 0);
+
+            let mut start = [0_u8; 16];
+            flash
+                .read_columns(Column::Physical(0), &mut start, *method)
+                .unwrap();
+            assert_eq!(&start[..], &page[..16]);
+        }
+
+        // Back in buffered read mode the same columns are fine
+        flash.set_continuous_read_mode(false).unwrap();
+        flash.read_memory_to_data_buffer(5).unwrap();
+        flash.wait_while_busy().unwrap();
+        let mut last = [0_u8; 1];
+        flash
+            .read_columns(Column::Physical(2111), &mut last, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(last[0], page[2111]);
+    }
+
+    /// The physical columns of each spare section's user bytes with ECC enabled, the logical
+    /// column they start at, and the ECC bytes hidden after them
+    const ECC_SECTIONS: [(u16, u16, u16); 4] = [
+        (2048, 2048, 2056),
+        (2064, 2056, 2072),
+        (2080, 2064, 2088),
+        (2096, 2072, 2104),
+    ];
+
+    #[test]
+    fn every_spare_column_maps_through_the_ecc_layout() {
+        for (physical, logical, hidden) in ECC_SECTIONS.iter() {
+            for offset in 0..SPARE_SECTION_USER_BYTES as u16 {
+                assert_eq!(
+                    physical_to_logical(physical + offset, true),
+                    Some(logical + offset)
+                );
+                assert_eq!(
+                    logical_to_physical(logical + offset, true),
+                    Some(physical + offset)
+                );
+                assert_eq!(physical_to_logical(hidden + offset, true), None);
+            }
+        }
+
+        let mapped = (2048..2112)
+            .filter(|column| physical_to_logical(*column, true).is_some())
+            .count();
+        assert_eq!(mapped, 32);
+        assert_eq!(logical_page_bytes(true), 2080);
+        assert_eq!(logical_to_physical(2080, true), None);
+        assert_eq!(physical_to_logical(2112, true), None);
+    }
+
+    #[test]
+    fn every_spare_column_maps_to_itself_without_ecc() {
+        for column in 2048..2112 {
+            assert_eq!(physical_to_logical(column, false), Some(column));
+            assert_eq!(logical_to_physical(column, false), Some(column));
+        }
+
+        assert_eq!(logical_page_bytes(false), 2112);
+        assert_eq!(logical_to_physical(2112, false), None);
+        assert_eq!(physical_to_logical(2112, false), None);
+    }
+
+    #[test]
+    fn the_main_area_is_the_same_in_both_systems() {
+        for ecc_enabled in [true, false].iter() {
+            for column in [0, 1, 1024, 2047].iter() {
+                assert_eq!(logical_to_physical(*column, *ecc_enabled), Some(*column));
+                assert_eq!(physical_to_logical(*column, *ecc_enabled), Some(*column));
+            }
+        }
+    }
+
+    fn loads(sim: &SimFlash) -> Vec<(u32, usize)> {
+        sim.commands()
+            .iter()
+            .filter(|command| [0x02, 0x84, 0x32, 0x34].contains(&command.opcode))
+            .map(|command| (command.address.unwrap().0, command.data.len()))
+            .collect()
+    }
+
+    #[test]
+    fn physical_loads_straddling_ecc_bytes_are_refused() {
+        let sim = SimFlash::new();
+        let flash = sim.driver().into_write_mode().unwrap();
+        let load = |column, len| {
+            flash.load_columns(
+                Column::Physical(column),
+                &[0xA5; 16][..len],
+                WriteMethod::SingleLoad,
+                LoadMode::PreserveAndLoad,
+            )
+        };
+
+        sim.clear_log();
+        for (column, len) in [(2055, 2), (2052, 8), (2045, 12), (2100, 12)].iter() {
+            assert_eq!(
+                load(*column, *len),
+                Err(FlashError::WriteToECCReservedColumn),
+                "{} + {}",
+                column,
+                len
+            );
+        }
+        assert!(loads(&sim).is_empty());
+
+        // Up to the last user byte of a section is fine
+        assert_eq!(load(2040, 16), Ok(()));
+        assert_eq!(load(2064, 8), Ok(()));
+        assert_eq!(loads(&sim), [(2040, 16), (2064, 8)]);
+    }
+
+    #[test]
+    fn logical_loads_split_around_ecc_bytes() {
+        let sim = SimFlash::new();
+        let flash = sim.driver().into_write_mode().unwrap();
+        let bytes: Vec<u8> = (1..=20).collect();
+
+        sim.clear_log();
+        flash
+            .load_columns(
+                Column::Logical(2052),
+                &bytes,
+                WriteMethod::SingleLoad,
+                LoadMode::PreserveAndLoad,
+            )
+            .unwrap();
+        assert_eq!(loads(&sim), [(2052, 4), (2064, 8), (2080, 8)]);
+
+        let mut read_back = [0_u8; 20];
+        flash
+            .read_columns(Column::Logical(2052), &mut read_back, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(&read_back[..], &bytes[..]);
+    }
+
+    #[test]
+    fn without_ecc_every_spare_column_loads_physically() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_ecc_mode(EccMode::Disabled).unwrap();
+        let flash = flash.into_write_mode().unwrap();
+
+        sim.clear_log();
+        flash
+            .load_columns(
+                Column::Physical(2048),
+                &[0x5A; SPARE_BYTES],
+                WriteMethod::SingleLoad,
+                LoadMode::PreserveAndLoad,
+            )
+            .unwrap();
+        flash
+            .load_columns(
+                Column::Logical(2052),
+                &[0xA5; 20],
+                WriteMethod::SingleLoad,
+                LoadMode::PreserveAndLoad,
+            )
+            .unwrap();
+        assert_eq!(loads(&sim), [(2048, 64), (2052, 20)]);
+    }
+}
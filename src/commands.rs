@@ -0,0 +1,428 @@
+//! One function per instruction in the W25N01GV datasheet's command table, each building the
+//! QSPI command exactly as it goes out on the wire. The rest of the driver builds every command it
+//! sends through here, and they can be sent directly with `send_raw_command` and
+//! `send_raw_read_command` for anything the higher level API doesn't cover.
+//!
+//! Commands that carry a page or register address take it as a byte array owned by the caller,
+//! since the QSPI command only borrows its data. Page addresses are sent big endian.
+//!
+//! The "with 4-byte address" fast read variants (0x0C, 0x3C, 0x6C, 0xBC and 0xEC) aren't
+//! included, the QSPI peripheral's address size is fixed when it's set up and the driver expects
+//! 16 bit column addresses.
+
+use crate::{
+    bus::{QspiMode, QspiReadCommand, QspiWriteCommand},
+    FlashCommands, FlashError, QspiBus, ReadMethod, WriteMethod, MAX_BBM_LUT_ENTIRES, W25N01GV,
+};
+
+fn instruction_only(command: FlashCommands) -> QspiWriteCommand<'static> {
+    QspiWriteCommand {
+        instruction: Some((command as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 0,
+        data: None,
+        double_data_rate: false,
+    }
+}
+
+/// Page addressed commands send a dummy byte ahead of the page address
+fn page_addressed(command: FlashCommands, page_address: &[u8; 2]) -> QspiWriteCommand<'_> {
+    QspiWriteCommand {
+        instruction: Some((command as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 8,
+        data: Some((page_address, QspiMode::SingleChannel)),
+        double_data_rate: false,
+    }
+}
+
+fn status_register_read(command: FlashCommands, sar_address: &[u8; 1]) -> QspiReadCommand<'_> {
+    QspiReadCommand {
+        instruction: Some((command as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: Some((sar_address, QspiMode::SingleChannel)),
+        dummy_cycles: 0,
+        data_mode: QspiMode::SingleChannel,
+        receive_length: 1,
+        double_data_rate: false,
+    }
+}
+
+fn status_register_write(command: FlashCommands, bytes: &[u8; 2]) -> QspiWriteCommand<'_> {
+    QspiWriteCommand {
+        instruction: Some((command as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 0,
+        data: Some((bytes, QspiMode::SingleChannel)),
+        double_data_rate: false,
+    }
+}
+
+/// Device Reset (0xFF)
+pub fn device_reset() -> QspiWriteCommand<'static> {
+    instruction_only(FlashCommands::DeviceReset)
+}
+
+/// JEDEC ID (0x9F), returns the manufacturer ID and the two byte device ID
+pub fn jedec_id() -> QspiReadCommand<'static> {
+    QspiReadCommand {
+        instruction: Some((FlashCommands::JEDECId as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 8,
+        data_mode: QspiMode::SingleChannel,
+        receive_length: 3,
+        double_data_rate: false,
+    }
+}
+
+/// Read Status Register (0x05), reads the register at `sar_address`
+pub fn read_status_register(sar_address: &[u8; 1]) -> QspiReadCommand<'_> {
+    status_register_read(FlashCommands::ReadStatusRegister, sar_address)
+}
+
+/// Read Status Register (0x0F), the alternate opcode for 0x05
+pub fn read_status_register_alt(sar_address: &[u8; 1]) -> QspiReadCommand<'_> {
+    status_register_read(FlashCommands::ReadStatusRegisterAlt, sar_address)
+}
+
+/// Write Status Register (0x01), `bytes` is the register address followed by the new value
+pub fn write_status_register(bytes: &[u8; 2]) -> QspiWriteCommand<'_> {
+    status_register_write(FlashCommands::WriteStatusRegister, bytes)
+}
+
+/// Write Status Register (0x1F), the alternate opcode for 0x01
+pub fn write_status_register_alt(bytes: &[u8; 2]) -> QspiWriteCommand<'_> {
+    status_register_write(FlashCommands::WriteStatusRegisterAlt, bytes)
+}
+
+/// Write Enable (0x06)
+pub fn write_enable() -> QspiWriteCommand<'static> {
+    instruction_only(FlashCommands::EnableWrite)
+}
+
+/// Write Disable (0x04)
+pub fn write_disable() -> QspiWriteCommand<'static> {
+    instruction_only(FlashCommands::DisableWrite)
+}
+
+/// Bad Block Management (0xA1), links a logical block to a replacement physical block.
+/// `addresses` is the logical block number followed by the physical block number, both big endian.
+pub fn swap_blocks(addresses: &[u8; 4]) -> QspiWriteCommand<'_> {
+    QspiWriteCommand {
+        instruction: Some((FlashCommands::SwapBlocks as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 0,
+        data: Some((addresses, QspiMode::SingleChannel)),
+        double_data_rate: false,
+    }
+}
+
+/// Read BBM Look Up Table (0xA5), returns 4 bytes per link
+pub fn read_bbm_lookup_table() -> QspiReadCommand<'static> {
+    QspiReadCommand {
+        instruction: Some((FlashCommands::ReadBBM as u8, QspiMode::SingleChannel)),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 8,
+        data_mode: QspiMode::SingleChannel,
+        receive_length: (MAX_BBM_LUT_ENTIRES * 4) as u32,
+        double_data_rate: false,
+    }
+}
+
+/// Last ECC Failure Page Address (0xA9), returns the two byte page address
+pub fn last_ecc_failure_page_address() -> QspiReadCommand<'static> {
+    QspiReadCommand {
+        instruction: Some((
+            FlashCommands::LastECCFailurePageAddress as u8,
+            QspiMode::SingleChannel,
+        )),
+        address: None,
+        alternative_bytes: None,
+        dummy_cycles: 8,
+        data_mode: QspiMode::SingleChannel,
+        receive_length: 2,
+        double_data_rate: false,
+    }
+}
+
+/// Block Erase (0xD8), erases the block containing the page
+pub fn block_erase(page_address: &[u8; 2]) -> QspiWriteCommand<'_> {
+    page_addressed(FlashCommands::Erase128KBBlock, page_address)
+}
+
+/// Program Data Load (0x02), Random Program Data Load (0x84), Quad Program Data Load (0x32), or
+/// Random Quad Program Data Load (0x34), depending on the write method
+pub fn program_data_load(method: WriteMethod, column: u16, bytes: &[u8]) -> QspiWriteCommand<'_> {
+    QspiWriteCommand {
+        instruction: Some((method as u8, method.address_mode())),
+        address: Some((column as u32, QspiMode::SingleChannel)),
+        alternative_bytes: None,
+        dummy_cycles: method.dummy_cycles(),
+        data: Some((bytes, method.data_mode())),
+        double_data_rate: false,
+    }
+}
+
+/// Program Execute (0x10)
+pub fn program_execute(page_address: &[u8; 2]) -> QspiWriteCommand<'_> {
+    page_addressed(FlashCommands::ProgramExecute, page_address)
+}
+
+/// Page Data Read (0x13)
+pub fn page_data_read(page_address: &[u8; 2]) -> QspiWriteCommand<'_> {
+    page_addressed(FlashCommands::PageDataRead, page_address)
+}
+
+/// Read (0x03), the single line read without the fast read's higher clock rate
+pub fn read_data(column: u16, len: u32) -> QspiReadCommand<'static> {
+    QspiReadCommand {
+        instruction: Some((FlashCommands::ReadData as u8, QspiMode::SingleChannel)),
+        address: Some((column as u32, QspiMode::SingleChannel)),
+        alternative_bytes: None,
+        dummy_cycles: 8,
+        data_mode: QspiMode::SingleChannel,
+        receive_length: len,
+        double_data_rate: false,
+    }
+}
+
+/// Fast Read (0x0B), Fast Read Dual Output (0x3B), Fast Read Quad Output (0x6B), Fast Read Dual
+/// I/O (0xBB), or Fast Read Quad I/O (0xEB), depending on the read method
+pub fn fast_read(method: ReadMethod, column: u16, len: u32) -> QspiReadCommand<'static> {
+    QspiReadCommand {
+        instruction: Some((method as u8, QspiMode::SingleChannel)),
+        address: Some((column as u32, method.address_mode())),
+        alternative_bytes: None,
+        dummy_cycles: method.dummy_cycles(),
+        data_mode: method.data_mode(),
+        receive_length: len,
+        double_data_rate: false,
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Sends a command built with this module (or by hand) as is. The driver doesn't check the
+    /// device is idle or track what the command does beyond its usual stats and dry run handling.
+    pub fn send_raw_command(&self, command: QspiWriteCommand) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        self.qspi_write(command)
+    }
+
+    /// Sends a command that reads data back, see `send_raw_command`
+    pub fn send_raw_read_command(
+        &self,
+        command: QspiReadCommand,
+        buffer: &mut [u8],
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        self.qspi_transfer(command, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use QspiMode::{DualChannel as Dual, QuadChannel as Quad, SingleChannel as Single};
+
+    /// Everything a command puts on the wire, written or read
+    #[derive(Debug, PartialEq)]
+    struct Wire<'a> {
+        instruction: Option<(u8, QspiMode)>,
+        address: Option<(u32, QspiMode)>,
+        alternative_bytes: Option<(&'a [u8], QspiMode)>,
+        dummy_cycles: u8,
+        data: Option<(&'a [u8], QspiMode)>,
+        receive: Option<(u32, QspiMode)>,
+    }
+
+    impl<'a> Wire<'a> {
+        fn opcode(opcode: u8) -> Wire<'a> {
+            Wire {
+                instruction: Some((opcode, Single)),
+                address: None,
+                alternative_bytes: None,
+                dummy_cycles: 0,
+                data: None,
+                receive: None,
+            }
+        }
+
+        fn dummy_cycles(self, dummy_cycles: u8) -> Wire<'a> {
+            Wire {
+                dummy_cycles,
+                ..self
+            }
+        }
+
+        fn data(self, data: &'a [u8], mode: QspiMode) -> Wire<'a> {
+            Wire {
+                data: Some((data, mode)),
+                ..self
+            }
+        }
+
+        fn receive(self, len: u32) -> Wire<'a> {
+            Wire {
+                receive: Some((len, Single)),
+                ..self
+            }
+        }
+    }
+
+    fn written(command: QspiWriteCommand<'_>) -> Wire<'_> {
+        assert!(!command.double_data_rate);
+        Wire {
+            instruction: command.instruction,
+            address: command.address,
+            alternative_bytes: command.alternative_bytes,
+            dummy_cycles: command.dummy_cycles,
+            data: command.data,
+            receive: None,
+        }
+    }
+
+    fn read(command: QspiReadCommand<'_>) -> Wire<'_> {
+        assert!(!command.double_data_rate);
+        Wire {
+            instruction: command.instruction,
+            address: command.address,
+            alternative_bytes: command.alternative_bytes,
+            dummy_cycles: command.dummy_cycles,
+            data: None,
+            receive: Some((command.receive_length, command.data_mode)),
+        }
+    }
+
+    #[test]
+    fn instruction_only_commands() {
+        assert_eq!(written(device_reset()), Wire::opcode(0xFF));
+        assert_eq!(written(write_enable()), Wire::opcode(0x06));
+        assert_eq!(written(write_disable()), Wire::opcode(0x04));
+    }
+
+    #[test]
+    fn identification_and_look_up_table_reads() {
+        assert_eq!(
+            read(jedec_id()),
+            Wire::opcode(0x9F).dummy_cycles(8).receive(3)
+        );
+        assert_eq!(
+            read(read_bbm_lookup_table()),
+            Wire::opcode(0xA5).dummy_cycles(8).receive(80)
+        );
+        assert_eq!(
+            read(last_ecc_failure_page_address()),
+            Wire::opcode(0xA9).dummy_cycles(8).receive(2)
+        );
+    }
+
+    #[test]
+    fn status_register_commands_send_the_register_address() {
+        let sar = [0xC0];
+        let expected = |opcode| Wire {
+            alternative_bytes: Some((&sar[..], Single)),
+            ..Wire::opcode(opcode).receive(1)
+        };
+        assert_eq!(read(read_status_register(&sar)), expected(0x05));
+        assert_eq!(read(read_status_register_alt(&sar)), expected(0x0F));
+
+        let bytes = [0xA0, 0x7C];
+        assert_eq!(
+            written(write_status_register(&bytes)),
+            Wire::opcode(0x01).data(&bytes, Single)
+        );
+        assert_eq!(
+            written(write_status_register_alt(&bytes)),
+            Wire::opcode(0x1F).data(&bytes, Single)
+        );
+    }
+
+    #[test]
+    fn page_addressed_commands_send_a_dummy_byte_then_the_page() {
+        let page_address = 4241_u16.to_be_bytes();
+        let expected = |opcode| {
+            Wire::opcode(opcode)
+                .dummy_cycles(8)
+                .data(&page_address, Single)
+        };
+
+        assert_eq!(written(block_erase(&page_address)), expected(0xD8));
+        assert_eq!(written(program_execute(&page_address)), expected(0x10));
+        assert_eq!(written(page_data_read(&page_address)), expected(0x13));
+        assert_eq!(page_address, [0x10, 0x91]);
+    }
+
+    #[test]
+    fn swap_blocks_sends_both_block_numbers() {
+        // Block 1 linked to block 1023
+        let addresses = [0x00, 0x01, 0x03, 0xFF];
+        assert_eq!(
+            written(swap_blocks(&addresses)),
+            Wire::opcode(0xA1).data(&addresses, Single)
+        );
+    }
+
+    #[test]
+    fn program_data_loads_follow_the_write_method() {
+        let bytes = [1, 2, 3];
+        let table = [
+            (WriteMethod::SingleLoad, 0x02, Single),
+            (WriteMethod::RandomSingleLoad, 0x84, Single),
+            (WriteMethod::QuadLoad, 0x32, Quad),
+            (WriteMethod::RandomQuadLoad, 0x34, Quad),
+        ];
+
+        for (method, opcode, data_mode) in table.iter() {
+            assert_eq!(
+                written(program_data_load(*method, 2111, &bytes)),
+                Wire {
+                    address: Some((2111, Single)),
+                    ..Wire::opcode(*opcode).data(&bytes, *data_mode)
+                },
+                "{:?}",
+                method
+            );
+        }
+    }
+
+    #[test]
+    fn reads_follow_the_read_method() {
+        assert_eq!(
+            read(read_data(64, 2048)),
+            Wire {
+                address: Some((64, Single)),
+                receive: Some((2048, Single)),
+                ..Wire::opcode(0x03).dummy_cycles(8)
+            }
+        );
+
+        let table = [
+            (ReadMethod::FastRead, 0x0B, Single, 8, Single),
+            (ReadMethod::DualFastRead, 0x3B, Single, 8, Dual),
+            (ReadMethod::QuadFastRead, 0x6B, Single, 8, Quad),
+            (ReadMethod::FastReadDualIO, 0xBB, Dual, 4, Dual),
+            (ReadMethod::FastReadQuadIO, 0xEB, Quad, 4, Quad),
+        ];
+
+        for (method, opcode, address_mode, dummy_cycles, data_mode) in table.iter() {
+            assert_eq!(
+                read(fast_read(*method, 2048, 64)),
+                Wire {
+                    address: Some((2048, *address_mode)),
+                    receive: Some((64, *data_mode)),
+                    ..Wire::opcode(*opcode).dummy_cycles(*dummy_cycles)
+                },
+                "{:?}",
+                method
+            );
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use crate::digest::Crc32;
+
+/// CRC-32 (IEEE 802.3, the same as zlib and most tools) of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    Crc32::new().checksum(data)
+}
+
+/// Feeds more data into a running CRC-32, for data that isn't all in memory at once. Start from
+/// 0xFFFF_FFFF and invert the result at the end.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    crc
+}
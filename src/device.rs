@@ -0,0 +1,83 @@
+//! Identification of the attached part from its JEDEC ID.
+//!
+//! The ID is the manufacturer (0xEF for Winbond) followed by a two byte device ID, whose first
+//! byte is the family and voltage and whose second is the density. The temperature grade (-IG vs
+//! -IT) is only printed on the package and isn't part of the ID, so it can't be detected.
+
+use crate::{FlashError, QspiBus, W25N01GV};
+
+pub const WINBOND_MANUFACTURER_ID: u8 = 0xEF;
+
+/// A part in the Winbond serial NAND family, decoded from its JEDEC ID
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceVariant {
+    /// 512M-bit, 3V
+    W25N512GV,
+    /// 1G-bit, 3V, the part this driver is written for
+    W25N01GV,
+    /// 1G-bit, 1.8V
+    W25N01GW,
+    /// 1G-bit, 3V, KV series
+    W25N01KV,
+    /// 2G-bit, 3V, KV series
+    W25N02KV,
+    /// 2G-bit, 3V, two W25N01GV dies stacked behind one chip select
+    W25M02GV,
+    /// Any other ID, with the raw bytes
+    Unknown([u8; 3]),
+}
+
+impl DeviceVariant {
+    pub fn from_jedec_id(id: [u8; 3]) -> DeviceVariant {
+        match id {
+            [WINBOND_MANUFACTURER_ID, 0xAA, 0x20] => DeviceVariant::W25N512GV,
+            [WINBOND_MANUFACTURER_ID, 0xAA, 0x21] => DeviceVariant::W25N01GV,
+            [WINBOND_MANUFACTURER_ID, 0xBA, 0x21] => DeviceVariant::W25N01GW,
+            [WINBOND_MANUFACTURER_ID, 0xAE, 0x21] => DeviceVariant::W25N01KV,
+            [WINBOND_MANUFACTURER_ID, 0xAA, 0x22] => DeviceVariant::W25N02KV,
+            [WINBOND_MANUFACTURER_ID, 0xAB, 0x21] => DeviceVariant::W25M02GV,
+            _ => DeviceVariant::Unknown(id),
+        }
+    }
+
+    /// KV series parts have stronger on-chip ECC and report its status differently from the GV
+    /// and GW parts
+    pub fn is_kv_series(&self) -> bool {
+        matches!(self, DeviceVariant::W25N01KV | DeviceVariant::W25N02KV)
+    }
+
+    /// The number of 128KB blocks, or None for unknown parts
+    pub fn block_count(&self) -> Option<usize> {
+        match self {
+            DeviceVariant::W25N512GV => Some(512),
+            DeviceVariant::W25N01GV | DeviceVariant::W25N01GW | DeviceVariant::W25N01KV => {
+                Some(1024)
+            }
+            DeviceVariant::W25N02KV | DeviceVariant::W25M02GV => Some(2048),
+            DeviceVariant::Unknown(_) => None,
+        }
+    }
+}
+
+/// What's known about the attached part
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    pub jedec_id: [u8; 3],
+    pub variant: DeviceVariant,
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads the JEDEC ID and decodes which part is attached
+    pub fn device_info(&mut self) -> Result<DeviceInfo, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let jedec_id = self.read_jedec_id()?;
+
+        Ok(DeviceInfo {
+            jedec_id,
+            variant: DeviceVariant::from_jedec_id(jedec_id),
+        })
+    }
+}
@@ -0,0 +1,432 @@
+//! Streaming digests behind one trait, so everything in the driver that checksums data shares an
+//! implementation and an application can hand all of it a hardware CRC unit.
+//!
+//! The driver's structures (block headers, allocator and layout descriptors, log records,
+//! spanning records, commit records) and readback CRC verification all compute their CRC-32 with a
+//! `Crc32` from `crc32_digest`, which runs on the engine set with `set_crc32_engine`. The engine
+//! defaults to the software `crc32_update`, and a replacement, e.g. wrapping the STM32L4 CRC
+//! peripheral, has to compute exactly the same CRC-32 so data written with one engine still checks
+//! out with the other. Serializers that work without a driver at hand, like `BlockHeader::to_bytes`
+//! and `LutImage::to_bytes`, use the software engine, and their `_with` forms take a `Crc32` to run
+//! on another one.
+//!
+//! SHA-256 is there for images and anything else that needs more than error detection, with the
+//! `sha256` feature.
+
+use crate::{crc::crc32_update, W25N01GV};
+
+/// Data fed in pieces, with the result taken at the end
+pub trait StreamingDigest {
+    type Output;
+
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self) -> Self::Output;
+}
+
+/// Feeds more data into a running CRC-32, with the same contract as `crc32_update`: the running
+/// value starts at 0xFFFF_FFFF and is inverted once at the end, by `Crc32`.
+pub type Crc32Engine = fn(crc: u32, data: &[u8]) -> u32;
+
+/// CRC-32 (IEEE 802.3) run on an engine, see the module docs
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32 {
+    crc: u32,
+    engine: Crc32Engine,
+}
+
+impl Crc32 {
+    /// A CRC-32 on the software engine
+    pub fn new() -> Crc32 {
+        Crc32::with_engine(crc32_update)
+    }
+
+    pub fn with_engine(engine: Crc32Engine) -> Crc32 {
+        Crc32 {
+            crc: 0xFFFF_FFFF,
+            engine,
+        }
+    }
+
+    /// The CRC-32 of `data` alone
+    pub fn checksum(mut self, data: &[u8]) -> u32 {
+        self.update(data);
+        self.finalize()
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl StreamingDigest for Crc32 {
+    type Output = u32;
+
+    fn update(&mut self, data: &[u8]) {
+        self.crc = (self.engine)(self.crc, data);
+    }
+
+    fn finalize(self) -> u32 {
+        !self.crc
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Sets the engine every CRC-32 the driver computes runs on, see the module docs
+    pub fn set_crc32_engine(&mut self, engine: Crc32Engine) {
+        self.crc32_engine = engine;
+    }
+
+    /// A new CRC-32 on the driver's engine
+    pub fn crc32_digest(&self) -> Crc32 {
+        Crc32::with_engine(self.crc32_engine)
+    }
+}
+
+#[cfg(feature = "sha256")]
+pub use sha256::Sha256;
+
+#[cfg(feature = "sha256")]
+mod sha256 {
+    use core::convert::TryInto;
+
+    use super::StreamingDigest;
+
+    const BLOCK_BYTES: usize = 64;
+
+    const INITIAL_STATE: [u32; 8] = [
+        0x6A09_E667,
+        0xBB67_AE85,
+        0x3C6E_F372,
+        0xA54F_F53A,
+        0x510E_527F,
+        0x9B05_688C,
+        0x1F83_D9AB,
+        0x5BE0_CD19,
+    ];
+
+    const ROUND_CONSTANTS: [u32; 64] = [
+        0x428A_2F98,
+        0x7137_4491,
+        0xB5C0_FBCF,
+        0xE9B5_DBA5,
+        0x3956_C25B,
+        0x59F1_11F1,
+        0x923F_82A4,
+        0xAB1C_5ED5,
+        0xD807_AA98,
+        0x1283_5B01,
+        0x2431_85BE,
+        0x550C_7DC3,
+        0x72BE_5D74,
+        0x80DE_B1FE,
+        0x9BDC_06A7,
+        0xC19B_F174,
+        0xE49B_69C1,
+        0xEFBE_4786,
+        0x0FC1_9DC6,
+        0x240C_A1CC,
+        0x2DE9_2C6F,
+        0x4A74_84AA,
+        0x5CB0_A9DC,
+        0x76F9_88DA,
+        0x983E_5152,
+        0xA831_C66D,
+        0xB003_27C8,
+        0xBF59_7FC7,
+        0xC6E0_0BF3,
+        0xD5A7_9147,
+        0x06CA_6351,
+        0x1429_2967,
+        0x27B7_0A85,
+        0x2E1B_2138,
+        0x4D2C_6DFC,
+        0x5338_0D13,
+        0x650A_7354,
+        0x766A_0ABB,
+        0x81C2_C92E,
+        0x9272_2C85,
+        0xA2BF_E8A1,
+        0xA81A_664B,
+        0xC24B_8B70,
+        0xC76C_51A3,
+        0xD192_E819,
+        0xD699_0624,
+        0xF40E_3585,
+        0x106A_A070,
+        0x19A4_C116,
+        0x1E37_6C08,
+        0x2748_774C,
+        0x34B0_BCB5,
+        0x391C_0CB3,
+        0x4ED8_AA4A,
+        0x5B9C_CA4F,
+        0x682E_6FF3,
+        0x748F_82EE,
+        0x78A5_636F,
+        0x84C8_7814,
+        0x8CC7_0208,
+        0x90BE_FFFA,
+        0xA450_6CEB,
+        0xBEF9_A3F7,
+        0xC671_78F2,
+    ];
+
+    /// SHA-256, in software
+    #[derive(Debug, Clone, Copy)]
+    pub struct Sha256 {
+        state: [u32; 8],
+        block: [u8; BLOCK_BYTES],
+        block_len: usize,
+        total_len: u64,
+    }
+
+    impl Sha256 {
+        pub const fn new() -> Sha256 {
+            Sha256 {
+                state: INITIAL_STATE,
+                block: [0; BLOCK_BYTES],
+                block_len: 0,
+                total_len: 0,
+            }
+        }
+
+        fn compress(&mut self) {
+            let mut schedule = [0_u32; 64];
+            for (word, bytes) in schedule.iter_mut().zip(self.block.chunks_exact(4)) {
+                *word = u32::from_be_bytes(bytes.try_into().unwrap_or([0; 4]));
+            }
+            for index in 16..64 {
+                let s0 = schedule[index - 15].rotate_right(7)
+                    ^ schedule[index - 15].rotate_right(18)
+                    ^ (schedule[index - 15] >> 3);
+                let s1 = schedule[index - 2].rotate_right(17)
+                    ^ schedule[index - 2].rotate_right(19)
+                    ^ (schedule[index - 2] >> 10);
+                schedule[index] = schedule[index - 16]
+                    .wrapping_add(s0)
+                    .wrapping_add(schedule[index - 7])
+                    .wrapping_add(s1);
+            }
+
+            let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = self.state;
+            for (constant, word) in ROUND_CONSTANTS.iter().zip(schedule.iter()) {
+                let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+                let choice = (e & f) ^ (!e & g);
+                let temp1 = h
+                    .wrapping_add(s1)
+                    .wrapping_add(choice)
+                    .wrapping_add(*constant)
+                    .wrapping_add(*word);
+                let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+                let majority = (a & b) ^ (a & c) ^ (b & c);
+                let temp2 = s0.wrapping_add(majority);
+
+                h = g;
+                g = f;
+                f = e;
+                e = d.wrapping_add(temp1);
+                d = c;
+                c = b;
+                b = a;
+                a = temp1.wrapping_add(temp2);
+            }
+
+            for (state, value) in self.state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+                *state = state.wrapping_add(*value);
+            }
+        }
+    }
+
+    impl Default for Sha256 {
+        fn default() -> Sha256 {
+            Sha256::new()
+        }
+    }
+
+    impl StreamingDigest for Sha256 {
+        type Output = [u8; 32];
+
+        fn update(&mut self, mut data: &[u8]) {
+            self.total_len = self.total_len.wrapping_add(data.len() as u64);
+
+            while !data.is_empty() {
+                let len = (BLOCK_BYTES - self.block_len).min(data.len());
+                self.block[self.block_len..self.block_len + len].copy_from_slice(&data[..len]);
+                self.block_len += len;
+                data = &data[len..];
+
+                if self.block_len == BLOCK_BYTES {
+                    self.compress();
+                    self.block_len = 0;
+                }
+            }
+        }
+
+        fn finalize(mut self) -> [u8; 32] {
+            let bit_len = self.total_len.wrapping_mul(8);
+
+            // Padding is a single 1 bit, zeros, then the message length in the last 8 bytes
+            self.block[self.block_len] = 0x80;
+            self.block[self.block_len + 1..].fill(0);
+            if self.block_len + 1 > BLOCK_BYTES - 8 {
+                self.compress();
+                self.block.fill(0);
+            }
+            self.block[BLOCK_BYTES - 8..].copy_from_slice(&bit_len.to_be_bytes());
+            self.compress();
+
+            let mut digest = [0_u8; 32];
+            for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state.iter()) {
+                bytes.copy_from_slice(&word.to_be_bytes());
+            }
+
+            digest
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block_header::{BlockHeader, StructureKind, BLOCK_HEADER_BYTES, BLOCK_HEADER_COLUMN},
+        crc::crc32_update,
+        sim::{NoDelay, SimFlash},
+        Geometry, ReadMethod, WriteMethod,
+    };
+    use core::cell::Cell;
+
+    std::thread_local! {
+        static ENGINE_CALLS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    // Not a real CRC-32, so anything checked in software instead shows up as a mismatch
+    fn counting_engine(crc: u32, data: &[u8]) -> u32 {
+        ENGINE_CALLS.with(|calls| calls.set(calls.get() + 1));
+        crc32_update(crc, data).rotate_left(7)
+    }
+
+    #[test]
+    fn crc32_known_answers() {
+        assert_eq!(Crc32::new().checksum(b""), 0);
+        assert_eq!(Crc32::new().checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(
+            Crc32::new().checksum(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+
+        let mut streamed = Crc32::new();
+        streamed.update(b"1234");
+        streamed.update(b"");
+        streamed.update(b"56789");
+        assert_eq!(streamed.finalize(), 0xCBF4_3926);
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn sha256_known_answers() {
+        fn sha256(pieces: &[&[u8]]) -> [u8; 32] {
+            let mut digest = Sha256::new();
+            for piece in pieces {
+                digest.update(piece);
+            }
+            digest.finalize()
+        }
+
+        assert_eq!(
+            sha256(&[b""]),
+            [
+                0xE3, 0xB0, 0xC4, 0x42, 0x98, 0xFC, 0x1C, 0x14, 0x9A, 0xFB, 0xF4, 0xC8, 0x99, 0x6F,
+                0xB9, 0x24, 0x27, 0xAE, 0x41, 0xE4, 0x64, 0x9B, 0x93, 0x4C, 0xA4, 0x95, 0x99, 0x1B,
+                0x78, 0x52, 0xB8, 0x55
+            ]
+        );
+        assert_eq!(
+            sha256(&[b"abc"]),
+            [
+                0xBA, 0x78, 0x16, 0xBF, 0x8F, 0x01, 0xCF, 0xEA, 0x41, 0x41, 0x40, 0xDE, 0x5D, 0xAE,
+                0x22, 0x23, 0xB0, 0x03, 0x61, 0xA3, 0x96, 0x17, 0x7A, 0x9C, 0xB4, 0x10, 0xFF, 0x61,
+                0xF2, 0x00, 0x15, 0xAD
+            ]
+        );
+
+        // Two blocks of padding, fed across a block boundary
+        let two_blocks = [
+            0x24, 0x8D, 0x6A, 0x61, 0xD2, 0x06, 0x38, 0xB8, 0xE5, 0xC0, 0x26, 0x93, 0x0C, 0x3E,
+            0x60, 0x39, 0xA3, 0x3C, 0xE4, 0x59, 0x64, 0xFF, 0x21, 0x67, 0xF6, 0xEC, 0xED, 0xD4,
+            0x19, 0xDB, 0x06, 0xC1,
+        ];
+        let message: &[u8] = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        assert_eq!(sha256(&[message]), two_blocks);
+        assert_eq!(sha256(&[&message[..5], &message[5..]]), two_blocks);
+    }
+
+    #[test]
+    fn injected_engine_is_used_for_block_headers() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_crc32_engine(counting_engine);
+
+        let header = BlockHeader {
+            kind: StructureKind::Log,
+            region_id: 0,
+            sequence: 9,
+        };
+        let calls_before = ENGINE_CALLS.with(|calls| calls.get());
+        let flash = flash
+            .into_write_mode()
+            .unwrap()
+            .write_block_header(3, &header, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+
+        // The stored CRC came from the injected engine, so software disagrees with it
+        let page = sim.page(Geometry::W25N01GV.block_first_page(3));
+        let column = BLOCK_HEADER_COLUMN as usize;
+        let mut bytes = [0_u8; BLOCK_HEADER_BYTES];
+        bytes.copy_from_slice(&page[column..column + BLOCK_HEADER_BYTES]);
+        assert!(BlockHeader::from_bytes(&bytes).is_err());
+        assert_eq!(
+            BlockHeader::from_bytes_with(&bytes, Crc32::with_engine(counting_engine)),
+            Ok(Some(header))
+        );
+
+        assert_eq!(
+            flash.read_block_header(3, ReadMethod::FastRead),
+            Ok(Some(header))
+        );
+        assert!(ENGINE_CALLS.with(|calls| calls.get()) >= calls_before + 2);
+    }
+
+    #[test]
+    fn injected_engine_is_used_for_log_records() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_crc32_engine(counting_engine);
+
+        let mut log =
+            crate::log_sink::FlashLogSink::mount(&flash, 4, 1, ReadMethod::FastRead).unwrap();
+        assert!(log.push(1, 2, b"hardware crc"));
+        let flash = log
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+
+        let mut read_back = 0;
+        log.read_logs(&flash, ReadMethod::FastRead, |record| {
+            assert_eq!(record.message, b"hardware crc");
+            read_back += 1;
+        })
+        .unwrap();
+        assert_eq!(read_back, 1);
+
+        // The same log seen with the software engine has no valid records
+        let mut flash = flash;
+        flash.set_crc32_engine(crc32_update);
+        let mut software = 0;
+        log.read_logs(&flash, ReadMethod::FastRead, |_| software += 1)
+            .unwrap();
+        assert_eq!(software, 0);
+    }
+}
@@ -0,0 +1,301 @@
+//! Running a sequence of driver calls without the destructive commands reaching the device, so a
+//! provisioning or bring-up script can be checked on the bench first.
+//!
+//! `set_dry_run` picks what's kept off the bus. The driver still validates addresses, lengths and
+//! protection as normal, and every command it blocks is recorded in a plan read back with
+//! `planned_ops`. The check happens where every command goes out, so it covers commands sent with
+//! `send_raw_command` too. A command without an instruction phase can't be told apart, so the dry
+//! run blocks it whatever it might be and records it with opcode 0.
+
+use crate::{
+    bus::{QspiMode, QspiReadCommand, QspiWriteCommand},
+    FlashCommands, W25N01GV,
+};
+
+pub const MAX_PLANNED_OPS: usize = 32;
+
+/// Decides which commands are kept off the bus while in a dry run. Blocked commands are recorded
+/// in the plan instead of being sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DryRunPolicy {
+    /// Every command is sent to the device as normal
+    Off,
+    /// Program Execute, block erase, bad block swap, reset, register writes, and commands without
+    /// an instruction are recorded instead of sent. Everything else, including data buffer loads
+    /// and every read, goes to the device.
+    BlockDestructive,
+    /// Nothing is sent to the device. Status register reads are stubbed as 0x00 (idle with no
+    /// failures) and every other read is stubbed as 0xFF (erased).
+    BlockAll,
+}
+
+/// A command that was blocked during a dry run
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PlannedOp {
+    /// The instruction opcode, or 0 for a command sent without one
+    pub op: u8,
+    /// The column address, page address, or register address the command targets
+    pub address: u32,
+    /// The number of data bytes sent or received
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DryRunPlan {
+    ops: [Option<PlannedOp>; MAX_PLANNED_OPS],
+    len: usize,
+    overflowed: bool,
+}
+
+impl DryRunPlan {
+    pub(crate) fn new() -> DryRunPlan {
+        DryRunPlan {
+            ops: [None; MAX_PLANNED_OPS],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    fn record(&mut self, op: PlannedOp) {
+        if self.len < MAX_PLANNED_OPS {
+            self.ops[self.len] = Some(op);
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+}
+
+fn is_destructive(opcode: u8) -> bool {
+    opcode == FlashCommands::ProgramExecute as u8
+        || opcode == FlashCommands::Erase128KBBlock as u8
+        || opcode == FlashCommands::WriteStatusRegister as u8
+        || opcode == FlashCommands::WriteStatusRegisterAlt as u8
+        || opcode == FlashCommands::SwapBlocks as u8
+        || opcode == FlashCommands::DeviceReset as u8
+}
+
+fn is_status_register_read(opcode: u8) -> bool {
+    opcode == FlashCommands::ReadStatusRegister as u8
+        || opcode == FlashCommands::ReadStatusRegisterAlt as u8
+}
+
+/// Works out which address a command targets. Commands without an address phase carry their
+/// page or register address as the first data bytes.
+fn command_address(address: Option<(u32, QspiMode)>, data: &[u8]) -> u32 {
+    match (address, data.len()) {
+        (Some((address, _)), _) => address,
+        (None, 0) => 0,
+        (None, 1) => data[0] as u32,
+        (None, _) => u16::from_be_bytes([data[0], data[1]]) as u32,
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Puts the driver into (or takes it out of) a dry run. While in a dry run, commands blocked
+    /// by the policy are validated by the driver as normal but recorded instead of being sent to
+    /// the device, so a destructive sequence can be reviewed before running it for real.
+    /// Changing the policy clears the recorded plan.
+    pub fn set_dry_run(&mut self, policy: DryRunPolicy) {
+        self.dry_run_policy = policy;
+        *self.dry_run_plan.borrow_mut() = DryRunPlan::new();
+    }
+
+    pub fn dry_run_policy(&self) -> DryRunPolicy {
+        self.dry_run_policy
+    }
+
+    /// The commands recorded since the dry run started, in the order they would have been sent
+    pub fn planned_ops(&self) -> impl Iterator<Item = PlannedOp> {
+        let plan = *self.dry_run_plan.borrow();
+
+        (0..plan.len).filter_map(move |index| plan.ops[index])
+    }
+
+    /// Is true if more commands were blocked than the plan could record
+    pub fn planned_ops_overflowed(&self) -> bool {
+        self.dry_run_plan.borrow().overflowed
+    }
+
+    /// Returns true if the dry run blocked the write command, otherwise it should go to the bus
+    pub(crate) fn dry_run_write(&self, command: &QspiWriteCommand) -> bool {
+        let opcode = command.instruction.map(|(opcode, _)| opcode);
+
+        let blocked = match (self.dry_run_policy, opcode) {
+            (DryRunPolicy::Off, _) => false,
+            (DryRunPolicy::BlockDestructive, Some(opcode)) => is_destructive(opcode),
+            // No instruction, so no telling what it does
+            (DryRunPolicy::BlockDestructive, None) => true,
+            (DryRunPolicy::BlockAll, _) => true,
+        };
+
+        if blocked {
+            let data = match command.data {
+                Some((data, _)) => data,
+                None => &[],
+            };
+
+            self.dry_run_plan.borrow_mut().record(PlannedOp {
+                op: opcode.unwrap_or(0),
+                address: command_address(command.address, data),
+                len: data.len() as u32,
+            });
+        }
+
+        blocked
+    }
+
+    /// Returns true if the dry run blocked the read command and stubbed its result into `buffer`,
+    /// otherwise it should go to the bus
+    pub(crate) fn dry_run_transfer(&self, command: &QspiReadCommand, buffer: &mut [u8]) -> bool {
+        if self.dry_run_policy != DryRunPolicy::BlockAll {
+            return false;
+        }
+
+        let opcode = command.instruction.map(|(opcode, _)| opcode);
+
+        if matches!(opcode, Some(opcode) if is_status_register_read(opcode)) {
+            buffer.iter_mut().for_each(|byte| *byte = 0x00);
+        } else {
+            buffer.iter_mut().for_each(|byte| *byte = 0xFF);
+
+            let alternative_bytes = match command.alternative_bytes {
+                Some((alternative_bytes, _)) => alternative_bytes,
+                None => &[],
+            };
+
+            self.dry_run_plan.borrow_mut().record(PlannedOp {
+                op: opcode.unwrap_or(0),
+                address: command_address(command.address, alternative_bytes),
+                len: command.receive_length,
+            });
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use crate::{
+        commands, ReadMethod, ReadMode, WriteMethod, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES,
+    };
+
+    /// Opcodes that change the array or a register
+    const DESTRUCTIVE: [u8; 6] = [0x10, 0xD8, 0x01, 0x1F, 0xA1, 0xFF];
+
+    /// A provisioning style sequence touching every kind of destructive command
+    fn provision(flash: W25N01GV<SimFlash, ReadMode>) -> W25N01GV<SimFlash, ReadMode> {
+        flash.reset_device().unwrap();
+        flash
+            .set_write_protection(false, false, false, false, false)
+            .unwrap();
+
+        let flash = flash.erase_block(5, &mut NoDelay).unwrap();
+        let flash = flash
+            .into_write_mode()
+            .unwrap()
+            .write_page_split(
+                320,
+                &[0x5A; PAGE_SIZE_BYTES],
+                &[],
+                WriteMethod::QuadLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+
+        let flash = flash.into_write_mode().unwrap();
+        flash.swap_bad_block(7, 1000).unwrap();
+        flash.into_read_mode().unwrap()
+    }
+
+    fn raw_command_without_instruction(data: &[u8]) -> QspiWriteCommand<'_> {
+        QspiWriteCommand {
+            instruction: None,
+            address: None,
+            alternative_bytes: None,
+            dummy_cycles: 0,
+            data: Some((data, QspiMode::SingleChannel)),
+            double_data_rate: false,
+        }
+    }
+
+    #[test]
+    fn blocking_destructive_commands_keeps_them_all_off_the_bus() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_dry_run(DryRunPolicy::BlockDestructive);
+        sim.clear_log();
+
+        let flash = provision(flash);
+
+        for opcode in DESTRUCTIVE {
+            assert_eq!(sim.count(opcode), 0, "{:#04x} reached the bus", opcode);
+        }
+        assert_eq!(sim.destructive_ops(), 0);
+        assert!(sim.count(0x32) > 0, "loads still go out");
+
+        let planned: Vec<u8> = flash.planned_ops().map(|op| op.op).collect();
+        for opcode in [0xFF, 0xD8, 0x10, 0xA1] {
+            assert!(planned.contains(&opcode), "{:#04x} wasn't planned", opcode);
+        }
+        assert!(planned.contains(&0x01) || planned.contains(&0x1F));
+    }
+
+    #[test]
+    fn blocking_everything_sends_nothing() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_dry_run(DryRunPolicy::BlockAll);
+        sim.clear_log();
+
+        let flash = provision(flash);
+        let mut page = [0; PAGE_SIZE_WITH_ECC_BYTES];
+        flash
+            .read_page(320, &mut page, ReadMethod::FastRead)
+            .unwrap();
+
+        assert!(sim.commands().is_empty());
+        assert!(page.iter().all(|byte| *byte == 0xFF));
+        assert!(flash
+            .planned_ops()
+            .any(|op| op.op == 0x13 && op.address == 320));
+    }
+
+    #[test]
+    fn commands_without_an_instruction_are_blocked() {
+        for policy in [DryRunPolicy::BlockDestructive, DryRunPolicy::BlockAll] {
+            let sim = SimFlash::new();
+            let mut flash = sim.driver();
+            flash.set_dry_run(policy);
+            sim.clear_log();
+
+            flash
+                .send_raw_command(raw_command_without_instruction(&[0xD8, 0x00, 0x40]))
+                .unwrap();
+
+            assert!(sim.commands().is_empty(), "{:?}", policy);
+            let planned: Vec<PlannedOp> = flash.planned_ops().collect();
+            assert_eq!(
+                planned,
+                [PlannedOp {
+                    op: 0,
+                    address: 0xD800,
+                    len: 3
+                }]
+            );
+        }
+
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        sim.clear_log();
+        flash.send_raw_command(commands::write_enable()).unwrap();
+        assert_eq!(sim.count(0x06), 1);
+    }
+}
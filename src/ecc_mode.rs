@@ -0,0 +1,176 @@
+//! Running with on-chip ECC off for the life of the device, for products that protect their data
+//! with their own end to end coding.
+//!
+//! `set_ecc_mode(EccMode::Disabled)` clears ECC-E once at init and pins it off from then on. Every
+//! configuration register write the driver makes keeps ECC-E cleared, `ecc_enabled` answers
+//! without reading the register, so column validation and `usable_page_bytes` always use the whole
+//! 2112 byte page, and the status register's ECC bits are reported as `ECCStatus::EccDisabled`
+//! instead of a misleading `Successful`. `classify_page` then tells erased pages apart by content
+//! alone.
+//!
+//! A device reset puts ECC-E back to its power-on default of enabled, so call `set_ecc_mode` again
+//! once the reset has finished.
+
+use crate::{FlashError, QspiBus, W25N01GV};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EccMode {
+    /// ECC is whatever the configuration register says, the default
+    FollowDevice,
+    /// ECC is off and kept off
+    Disabled,
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    pub fn ecc_mode(&self) -> EccMode {
+        self.ecc_mode
+    }
+
+    /// Is true if the device's ECC bits can't mean anything, either because ECC is pinned off or
+    /// the configuration register was last read with ECC disabled
+    pub(crate) fn ecc_known_disabled(&self) -> bool {
+        self.ecc_mode == EccMode::Disabled || self.ecc_enabled.get() == Some(false)
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Sets the driver wide ECC mode. `EccMode::Disabled` clears ECC-E in the configuration
+    /// register before taking effect. Going back to `EccMode::FollowDevice` leaves the register as
+    /// it is, so ECC stays off until the register is written with ECC-E set.
+    pub fn set_ecc_mode(&mut self, mode: EccMode) -> Result<(), FlashError> {
+        if mode == EccMode::Disabled {
+            let _guard = self.begin_operation()?;
+
+            let mut configuration_register = self.read_configuration_register_unguarded()?;
+            configuration_register.ecc_e = false;
+            self.write_configuration_register_unguarded(configuration_register)?;
+        }
+
+        self.ecc_mode = mode;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        patrol::Patrol, sim::SimFlash, soft_ecc::SOFT_ECC_BYTES, status::ECCStatus, PageClass,
+        ReadMethod, ReadMode, PAGE_SIZE_WITH_ECC_BYTES,
+    };
+
+    fn disabled(sim: &SimFlash) -> W25N01GV<SimFlash, ReadMode> {
+        let mut flash = sim.driver();
+        flash.set_ecc_mode(EccMode::Disabled).unwrap();
+        flash
+    }
+
+    #[test]
+    fn disabling_ecc_clears_ecc_e_and_answers_from_the_mode() {
+        let sim = SimFlash::new();
+        let flash = disabled(&sim);
+        assert_eq!(flash.ecc_mode(), EccMode::Disabled);
+        assert!(!sim.driver().read_configuration_register().unwrap().ecc_e);
+
+        sim.clear_log();
+        assert_eq!(flash.ecc_enabled(), Ok(false));
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn configuration_register_writes_keep_ecc_e_cleared() {
+        let sim = SimFlash::new();
+        let flash = disabled(&sim);
+
+        let mut configuration_register = flash.read_configuration_register().unwrap();
+        configuration_register.ecc_e = true;
+        flash
+            .write_configuration_register(configuration_register)
+            .unwrap();
+
+        assert!(!flash.read_configuration_register().unwrap().ecc_e);
+    }
+
+    #[test]
+    fn going_back_to_follow_device_leaves_ecc_off() {
+        let sim = SimFlash::new();
+        let mut flash = disabled(&sim);
+        flash.set_ecc_mode(EccMode::FollowDevice).unwrap();
+
+        assert_eq!(flash.ecc_mode(), EccMode::FollowDevice);
+        assert_eq!(flash.ecc_enabled(), Ok(false));
+
+        let mut configuration_register = flash.read_configuration_register().unwrap();
+        configuration_register.ecc_e = true;
+        flash
+            .write_configuration_register(configuration_register)
+            .unwrap();
+        assert_eq!(flash.ecc_enabled(), Ok(true));
+    }
+
+    #[test]
+    fn page_reads_report_ecc_disabled_instead_of_successful() {
+        let sim = SimFlash::new();
+        sim.set_page(64, &[0x12, 0x34]);
+        let flash = sim.driver();
+
+        let mut buffer = [0u8; PAGE_SIZE_WITH_ECC_BYTES];
+        assert_eq!(
+            flash.read_page(64, &mut buffer, ReadMethod::FastRead),
+            Ok(ECCStatus::Successful)
+        );
+
+        let flash = disabled(&sim);
+        assert_eq!(
+            flash.read_page(64, &mut buffer, ReadMethod::FastRead),
+            Ok(ECCStatus::EccDisabled)
+        );
+        assert_eq!(buffer[..2], [0x12, 0x34]);
+        assert_eq!(
+            flash.read_status_register().unwrap().ecc_status,
+            ECCStatus::EccDisabled
+        );
+    }
+
+    #[test]
+    fn the_whole_page_is_usable_without_ecc() {
+        let sim = SimFlash::new();
+        let flash = disabled(&sim);
+
+        assert_eq!(
+            flash.usable_page_bytes(),
+            Ok(PAGE_SIZE_WITH_ECC_BYTES - SOFT_ECC_BYTES)
+        );
+    }
+
+    #[test]
+    fn classify_page_goes_by_content_alone() {
+        let sim = SimFlash::new();
+        sim.set_page(65, &[0x00]);
+        let flash = disabled(&sim);
+
+        assert_eq!(
+            flash.classify_page(64, ReadMethod::FastRead),
+            Ok(PageClass::Erased)
+        );
+        assert_eq!(
+            flash.classify_page(65, ReadMethod::FastRead),
+            Ok(PageClass::Programmed)
+        );
+        // No ECC-off retry, since there's no ECC to turn off
+        assert!(!sim.driver().read_configuration_register().unwrap().ecc_e);
+    }
+
+    #[test]
+    fn a_patrol_records_nothing_without_ecc() {
+        let sim = SimFlash::new();
+        sim.set_page(64, &[0x00]);
+        let flash = disabled(&sim);
+
+        let mut patrol = Patrol::new(1, 1, 64);
+        assert_eq!(patrol.step(&flash), Ok(64));
+        assert_eq!(patrol.findings().count(), 0);
+    }
+}
@@ -0,0 +1,445 @@
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    status::ECCStatus, FlashError, FlashEventKind, Geometry, LoadMode, QspiBus, ReadMethod,
+    ReadMode, WriteMethod, BLOCK_COUNT, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+pub const MAX_ENDURANCE_BLOCKS: usize = 8;
+
+/// The first cycle each kind of trouble showed up in a block, counting from 0
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BlockEndurance {
+    pub block: u16,
+    /// A page read back needing ECC correction
+    pub first_corrected: Option<u32>,
+    /// A page read back with uncorrectable errors or different from what was programmed
+    pub first_data_error: Option<u32>,
+    pub first_program_failure: Option<u32>,
+    pub first_erase_failure: Option<u32>,
+}
+
+/// Where an `EnduranceTest` is, passed to the progress callback after every block cycle
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnduranceProgress {
+    pub cycle: u32,
+    pub block: u16,
+    /// Block cycles done so far out of `cycles * blocks`
+    pub steps_done: u32,
+    pub steps_total: u32,
+}
+
+/// Wears a set of blocks out on purpose, erasing, programming every page with a pattern that
+/// changes each cycle, and reading it back, to find out how many cycles they last. Each call to
+/// `run_for` does a few block cycles, so the test can run alongside everything else and be spread
+/// over days.
+///
+/// Progress only lives in RAM. To resume after a reboot, save `position` and the `results`
+/// somewhere persistent and hand the position to `resume_from`. Failures are recorded and the test
+/// carries on, so a block keeps being cycled after it first fails.
+pub struct EnduranceTest {
+    blocks: [u16; MAX_ENDURANCE_BLOCKS],
+    block_count: usize,
+    cycles: u32,
+    next_step: u32,
+    results: [BlockEndurance; MAX_ENDURANCE_BLOCKS],
+}
+
+/// The byte programmed at `index` of a page, different for every cycle, block, and page so stuck
+/// bits and address faults both show up
+fn pattern_byte(cycle: u32, block: u16, page_index: u16, index: usize) -> u8 {
+    let page = block as u32 * Geometry::W25N01GV.pages_per_block as u32 + page_index as u32;
+    let seed = cycle.wrapping_mul(0x9E37_79B9) ^ page.wrapping_mul(0x85EB_CA6B);
+    // Folded down so every bit of the seed reaches the byte
+    let byte = (index as u32).wrapping_add(seed ^ (seed >> 8) ^ (seed >> 16) ^ (seed >> 24)) as u8;
+
+    // Every other cycle flips every bit, so each cell gets programmed both ways
+    if cycle.is_multiple_of(2) {
+        byte
+    } else {
+        !byte
+    }
+}
+
+impl EnduranceTest {
+    /// Starts a test of `cycles` cycles over the first `MAX_ENDURANCE_BLOCKS` of `blocks`
+    pub fn start(blocks: &[u16], cycles: u32) -> EnduranceTest {
+        let mut test = EnduranceTest {
+            blocks: [0; MAX_ENDURANCE_BLOCKS],
+            block_count: blocks.len().min(MAX_ENDURANCE_BLOCKS),
+            cycles,
+            next_step: 0,
+            results: [BlockEndurance::default(); MAX_ENDURANCE_BLOCKS],
+        };
+
+        for (index, block) in blocks.iter().take(MAX_ENDURANCE_BLOCKS).enumerate() {
+            test.blocks[index] = *block;
+            test.results[index].block = *block;
+        }
+
+        test
+    }
+
+    /// Runs up to `max_steps` more block cycles, calling `report` after each one, which is the
+    /// place to pet a watchdog or yield. Failures reported by the device are recorded in the
+    /// results, other errors are returned as is.
+    ///
+    /// Returns `FlashError::Block0Reserved` when cycling block 0 while the block 0 policy
+    /// reserves it.
+    pub fn run_for<BUS: QspiBus, D, F>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        max_steps: u32,
+        write_method: WriteMethod,
+        read_method: ReadMethod,
+        delay: &mut D,
+        mut report: F,
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(EnduranceProgress),
+    {
+        let mut flash = flash;
+
+        for _ in 0..max_steps {
+            if self.is_complete() {
+                break;
+            }
+
+            let cycle = self.next_step / self.block_count as u32;
+            let index = self.next_step as usize % self.block_count;
+            let block = self.blocks[index];
+
+            if block as usize >= BLOCK_COUNT {
+                return Err(FlashError::OutOfBounds);
+            }
+            flash.check_block0(block, 1)?;
+
+            flash = self.cycle_block(flash, index, cycle, write_method, read_method, delay)?;
+            self.next_step += 1;
+
+            report(EnduranceProgress {
+                cycle,
+                block,
+                steps_done: self.next_step,
+                steps_total: self.steps_total(),
+            });
+        }
+
+        Ok(flash)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_step >= self.steps_total()
+    }
+
+    /// The number of block cycles done, to save for `resume_from`
+    pub fn position(&self) -> u32 {
+        self.next_step
+    }
+
+    /// Continues from a previously saved position. Positions past the end leave the test complete.
+    pub fn resume_from(&mut self, position: u32) {
+        self.next_step = position.min(self.steps_total());
+    }
+
+    /// What's been found so far, one entry per block under test
+    pub fn results(&self) -> &[BlockEndurance] {
+        &self.results[..self.block_count]
+    }
+
+    /// Puts back results saved alongside the position, matched up by block
+    pub fn restore_results(&mut self, results: &[BlockEndurance]) {
+        for saved in results {
+            if let Some(result) = self.results[..self.block_count]
+                .iter_mut()
+                .find(|result| result.block == saved.block)
+            {
+                *result = *saved;
+            }
+        }
+    }
+
+    fn steps_total(&self) -> u32 {
+        self.cycles.saturating_mul(self.block_count as u32)
+    }
+
+    fn cycle_block<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        index: usize,
+        cycle: u32,
+        write_method: WriteMethod,
+        read_method: ReadMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError>
+    where
+        D: DelayUs<u32>,
+    {
+        let result = &mut self.results[index];
+        let block = result.block;
+        let record = |first: &mut Option<u32>| {
+            if first.is_none() {
+                *first = Some(cycle);
+            }
+        };
+
+        // Not using erase_block or commit, which give up the driver when they fail
+        let mut flash = flash
+            .into_write_mode()?
+            .erase_128kb_block(Geometry::W25N01GV.block_first_page(block))?;
+        flash.wait_while_busy_with_delay(delay)?;
+
+        if flash.read_status_register()?.erase_failure {
+            flash.log_event(FlashEventKind::EraseFailure { block });
+            record(&mut result.first_erase_failure);
+            return Ok(flash);
+        }
+
+        let mut page = [0_u8; PAGE_SIZE_BYTES];
+        let mut read_back = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+        for (page_index, page_address) in Geometry::W25N01GV.block_pages(block).enumerate() {
+            let page_index = page_index as u16;
+            let page_address = page_address as u16;
+
+            for (byte_index, byte) in page.iter_mut().enumerate() {
+                *byte = pattern_byte(cycle, block, page_index, byte_index);
+            }
+
+            let write_flash = flash.into_write_mode()?;
+            write_flash.load_to_data_buffer(&page, 0, write_method, LoadMode::ResetThenLoad)?;
+            flash = write_flash.write_data_buffer_to_memory(page_address)?;
+            flash.wait_while_busy_with_delay(delay)?;
+
+            if flash.read_status_register()?.write_failure {
+                flash.log_event(FlashEventKind::ProgramFailure { page_address });
+                record(&mut result.first_program_failure);
+                continue;
+            }
+
+            flash.read_memory_to_data_buffer(page_address)?;
+            flash.wait_while_busy_with_delay(delay)?;
+
+            match flash.read_status_register()?.ecc_status {
+                ECCStatus::CorrectedSuccessfully => record(&mut result.first_corrected),
+                ECCStatus::SinglePageError | ECCStatus::MultiPageError => {
+                    record(&mut result.first_data_error)
+                }
+                ECCStatus::Successful | ECCStatus::EccDisabled => {}
+            }
+
+            flash.read_data_buffer(&mut read_back, read_method)?;
+            if read_back[..PAGE_SIZE_BYTES] != page[..] {
+                record(&mut result.first_data_error);
+            }
+        }
+
+        Ok(flash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{NoDelay, SimFlash},
+        Block0Policy,
+    };
+    use std::vec::Vec;
+
+    fn run(
+        test: &mut EnduranceTest,
+        flash: W25N01GV<SimFlash, ReadMode>,
+        max_steps: u32,
+        progress: &mut Vec<EnduranceProgress>,
+    ) -> Result<W25N01GV<SimFlash, ReadMode>, FlashError> {
+        test.run_for(
+            flash,
+            max_steps,
+            WriteMethod::SingleLoad,
+            ReadMethod::FastRead,
+            &mut NoDelay,
+            |step| progress.push(step),
+        )
+    }
+
+    fn programmed_pattern(cycle: u32, block: u16, page_index: u16) -> Vec<u8> {
+        (0..PAGE_SIZE_BYTES)
+            .map(|index| pattern_byte(cycle, block, page_index, index))
+            .collect()
+    }
+
+    #[test]
+    fn a_clean_run_cycles_every_block_in_turn() {
+        let sim = SimFlash::new();
+        let mut test = EnduranceTest::start(&[3, 7], 3);
+        let mut progress = Vec::new();
+
+        run(&mut test, sim.driver(), u32::MAX, &mut progress).unwrap();
+
+        assert!(test.is_complete());
+        assert_eq!(test.position(), 6);
+        assert_eq!(
+            progress
+                .iter()
+                .map(|step| (step.cycle, step.block, step.steps_done))
+                .collect::<Vec<_>>(),
+            [
+                (0, 3, 1),
+                (0, 7, 2),
+                (1, 3, 3),
+                (1, 7, 4),
+                (2, 3, 5),
+                (2, 7, 6)
+            ]
+        );
+        assert!(progress.iter().all(|step| step.steps_total == 6));
+        assert_eq!(
+            test.results(),
+            [
+                BlockEndurance {
+                    block: 3,
+                    ..Default::default()
+                },
+                BlockEndurance {
+                    block: 7,
+                    ..Default::default()
+                },
+            ]
+        );
+
+        // The last cycle's pattern is what's left in the block
+        let last_page = Geometry::W25N01GV.block_pages(7).end as u16 - 1;
+        assert_eq!(
+            sim.page(last_page)[..PAGE_SIZE_BYTES],
+            programmed_pattern(2, 7, 63)[..]
+        );
+    }
+
+    #[test]
+    fn patterns_change_every_cycle() {
+        let first = programmed_pattern(0, 3, 0);
+        let second = programmed_pattern(1, 3, 0);
+
+        assert_ne!(first, second);
+        assert_ne!(first, programmed_pattern(0, 3, 1));
+        assert_ne!(first, programmed_pattern(0, 4, 0));
+    }
+
+    #[test]
+    fn each_kind_of_failure_is_recorded_at_the_cycle_it_first_showed_up() {
+        let sim = SimFlash::new();
+        let mut test = EnduranceTest::start(&[3, 7, 9], 4);
+        let mut progress = Vec::new();
+
+        // Cycle 0 goes through cleanly
+        let flash = run(&mut test, sim.driver(), 3, &mut progress).unwrap();
+        assert!(test.results().iter().all(|result| *result
+            == BlockEndurance {
+                block: result.block,
+                ..Default::default()
+            }));
+
+        // Then the blocks start wearing out
+        sim.fail_erase(3);
+        sim.fail_program(Geometry::W25N01GV.block_first_page(7) + 5);
+        let flash = run(&mut test, flash, 4, &mut progress).unwrap();
+        sim.set_uncorrectable(Geometry::W25N01GV.block_first_page(9) + 2);
+        run(&mut test, flash, u32::MAX, &mut progress).unwrap();
+
+        assert!(test.is_complete());
+        assert_eq!(
+            test.results(),
+            [
+                BlockEndurance {
+                    block: 3,
+                    first_erase_failure: Some(1),
+                    ..Default::default()
+                },
+                BlockEndurance {
+                    block: 7,
+                    first_program_failure: Some(1),
+                    ..Default::default()
+                },
+                BlockEndurance {
+                    block: 9,
+                    first_data_error: Some(2),
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn a_stopped_test_resumes_from_its_saved_position() {
+        let sim = SimFlash::new();
+        sim.fail_erase(7);
+        let mut test = EnduranceTest::start(&[3, 7], 3);
+        let mut progress = Vec::new();
+
+        let flash = run(&mut test, sim.driver(), 3, &mut progress).unwrap();
+        assert!(!test.is_complete());
+        let position = test.position();
+        let results: Vec<BlockEndurance> = test.results().to_vec();
+
+        // After a reboot
+        let mut test = EnduranceTest::start(&[3, 7], 3);
+        test.resume_from(position);
+        test.restore_results(&results);
+        progress.clear();
+        run(&mut test, flash, u32::MAX, &mut progress).unwrap();
+
+        assert_eq!(
+            progress
+                .iter()
+                .map(|step| (step.cycle, step.block))
+                .collect::<Vec<_>>(),
+            [(1, 7), (2, 3), (2, 7)]
+        );
+        assert_eq!(test.results()[1].first_erase_failure, Some(0));
+
+        test.resume_from(100);
+        assert!(test.is_complete());
+        assert_eq!(test.position(), 6);
+    }
+
+    #[test]
+    fn blocks_that_cant_be_cycled_are_refused() {
+        let sim = SimFlash::new();
+        let mut progress = Vec::new();
+
+        let mut test = EnduranceTest::start(&[BLOCK_COUNT as u16], 1);
+        assert_eq!(
+            run(&mut test, sim.driver(), 1, &mut progress).err(),
+            Some(FlashError::OutOfBounds)
+        );
+
+        let mut flash = sim.driver();
+        flash.set_block0_policy(Block0Policy::Reserved);
+        let mut test = EnduranceTest::start(&[0], 1);
+        assert_eq!(
+            run(&mut test, flash, 1, &mut progress).err(),
+            Some(FlashError::Block0Reserved)
+        );
+
+        assert!(progress.is_empty());
+        assert_eq!(sim.destructive_ops(), 0);
+    }
+
+    #[test]
+    fn only_the_first_blocks_that_fit_are_tested() {
+        let blocks: Vec<u16> = (10..10 + MAX_ENDURANCE_BLOCKS as u16 + 2).collect();
+        let test = EnduranceTest::start(&blocks, 1);
+
+        assert_eq!(
+            test.results()
+                .iter()
+                .map(|result| result.block)
+                .collect::<Vec<_>>(),
+            blocks[..MAX_ENDURANCE_BLOCKS]
+        );
+    }
+}
@@ -0,0 +1,369 @@
+use core::ops::{ControlFlow, Range};
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    scan::{Findings, ScanDepth, ScanSkip, ScanVisitor},
+    FlashError, FlashEventKind, Geometry, QspiBus, ReadMethod, ReadMode, BLOCK_COUNT, W25N01GV,
+};
+
+pub const MAX_ERASE_FAILURES: usize = 16;
+
+/// Where an `IncrementalEraser` is after a call to `run_for`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EraseProgress {
+    /// Blocks erased (or attempted) by this call
+    pub blocks_erased: u16,
+    /// Blocks left before the range is fully erased
+    pub blocks_remaining: u16,
+    /// Blocks that have failed to erase since the eraser was started
+    pub failed_blocks: u16,
+    /// Blocks left unerased since the eraser was started because their bad block marker is set
+    pub bad_blocks_skipped: u16,
+}
+
+/// Erases a range of blocks a few at a time, so a full format can be spread across idle windows
+/// instead of blocking for the several seconds a full chip erase takes. Each call to `run_for`
+/// erases the next few blocks using the same blocking path as `erase_block`, walking the range
+/// with `scan` at `ScanDepth::Blocks`.
+///
+/// Progress only lives in RAM. To resume after a reboot, save `position` somewhere persistent and
+/// hand it to `resume_from`. Blocks that fail to erase are recorded and skipped rather than ending
+/// the format. Blocks with their bad block marker set are skipped, since erasing them would lose
+/// the marker. Block 0 is skipped while the block 0 policy reserves it, unless `include_block0` is
+/// called.
+pub struct IncrementalEraser {
+    first_block: u16,
+    end_block: u16,
+    next_block: u16,
+    failures: Findings<u16, MAX_ERASE_FAILURES>,
+    failure_count: u16,
+    bad_blocks_skipped: u16,
+    include_block0: bool,
+}
+
+impl IncrementalEraser {
+    /// Starts erasing `blocks`, which is clamped to the blocks on the device
+    pub fn start(blocks: Range<u16>) -> IncrementalEraser {
+        let end_block = blocks.end.min(BLOCK_COUNT as u16);
+        let first_block = blocks.start.min(end_block);
+
+        IncrementalEraser {
+            first_block,
+            end_block,
+            next_block: first_block,
+            failures: Findings::new(),
+            failure_count: 0,
+            bad_blocks_skipped: 0,
+            include_block0: false,
+        }
+    }
+
+    /// Erases up to `max_blocks` more blocks of the range. Blocks whose erase reports a failure
+    /// are recorded in `failures`, other errors are returned as is. `method` reads the bad block
+    /// markers.
+    pub fn run_for<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        max_blocks: u16,
+        method: ReadMethod,
+        delay: &mut D,
+    ) -> Result<(W25N01GV<BUS, ReadMode>, EraseProgress), FlashError>
+    where
+        D: DelayUs<u32>,
+    {
+        let mut erase = Erase {
+            eraser: self,
+            flash: &flash,
+            delay,
+            max_blocks,
+            blocks_erased: 0,
+            error: None,
+        };
+
+        {
+            let _guard = flash.begin_operation()?;
+            let pages = Geometry::W25N01GV
+                .block_pages(erase.eraser.next_block)
+                .start
+                ..Geometry::W25N01GV.block_pages(erase.eraser.end_block).start;
+            flash.scan_pages_unguarded(
+                pages,
+                ScanSkip::Nothing,
+                ScanDepth::Blocks(method),
+                &mut erase,
+            )?;
+        }
+
+        if let Some(err) = erase.error {
+            return Err(err);
+        }
+
+        let blocks_erased = erase.blocks_erased;
+        Ok((
+            flash,
+            EraseProgress {
+                blocks_erased,
+                blocks_remaining: self.end_block - self.next_block,
+                failed_blocks: self.failure_count,
+                bad_blocks_skipped: self.bad_blocks_skipped,
+            },
+        ))
+    }
+
+    /// Erases block 0 as part of the range even while the block 0 policy reserves it
+    pub fn include_block0(&mut self) {
+        self.include_block0 = true;
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_block >= self.end_block
+    }
+
+    /// The next block to be erased, to save for `resume_from`
+    pub fn position(&self) -> u16 {
+        self.next_block
+    }
+
+    /// Continues erasing from a previously saved position. Positions before the range restart it
+    /// from the beginning, and positions past it leave the eraser complete.
+    pub fn resume_from(&mut self, block: u16) {
+        self.next_block = block.max(self.first_block).min(self.end_block);
+    }
+
+    /// The blocks that failed to erase, lowest first, up to `MAX_ERASE_FAILURES` of them
+    pub fn failures(&self) -> impl Iterator<Item = u16> + '_ {
+        self.failures.iter().copied()
+    }
+
+    /// Is true if more blocks failed than could be stored in `failures`
+    pub fn failures_overflowed(&self) -> bool {
+        self.failures.overflowed()
+    }
+
+    fn record_failure(&mut self, block: u16) {
+        self.failures.push(block);
+        self.failure_count += 1;
+    }
+}
+
+/// `IncrementalEraser::run_for` as a visitor, erasing each block as it's announced
+struct Erase<'a, BUS, D> {
+    eraser: &'a mut IncrementalEraser,
+    flash: &'a W25N01GV<BUS, ReadMode>,
+    delay: &'a mut D,
+    max_blocks: u16,
+    blocks_erased: u16,
+    error: Option<FlashError>,
+}
+
+impl<BUS: QspiBus, D: DelayUs<u32>> ScanVisitor for Erase<'_, BUS, D> {
+    fn visit_block(&mut self, block: u16, marked_bad: bool) -> ControlFlow<()> {
+        if self.blocks_erased == self.max_blocks {
+            return ControlFlow::Break(());
+        }
+
+        if marked_bad {
+            self.eraser.bad_blocks_skipped += 1;
+        } else if block == 0 && self.flash.block0_reserved() && !self.eraser.include_block0 {
+            // Left alone without counting towards `max_blocks`
+        } else {
+            match self.flash.erase_block_unguarded(block, self.delay) {
+                Ok(()) => {}
+                Err(FlashError::EraseFailed { .. }) => {
+                    self.flash.log_event(FlashEventKind::EraseFailure { block });
+                    self.eraser.record_failure(block);
+                }
+                Err(err) => {
+                    self.error = Some(err);
+                    return ControlFlow::Break(());
+                }
+            }
+
+            self.blocks_erased += 1;
+        }
+
+        self.eraser.next_block = block + 1;
+        ControlFlow::Continue(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{NoDelay, SimFlash},
+        Block0Policy, PAGE_SIZE_BYTES,
+    };
+    use std::vec::Vec;
+
+    fn erased_blocks(sim: &SimFlash) -> Vec<u16> {
+        sim.commands()
+            .iter()
+            .filter(|command| command.opcode == 0xD8)
+            .map(|command| Geometry::W25N01GV.block_of_page(command.page_address().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn bad_blocks_keep_their_markers() {
+        let sim = SimFlash::new();
+        sim.mark_bad(5);
+        sim.fail_erase(6);
+        let mut flash = sim.driver();
+
+        let mut eraser = IncrementalEraser::start(4..9);
+        let mut steps = 0;
+        while !eraser.is_complete() {
+            let (next, progress) = eraser
+                .run_for(flash, 2, ReadMethod::FastRead, &mut NoDelay)
+                .unwrap();
+            flash = next;
+            assert!(progress.blocks_erased <= 2);
+            steps += 1;
+        }
+
+        // 4 and 6, then 7 and 8, with 5 skipped along the way
+        assert_eq!(steps, 2);
+        let erased: std::vec::Vec<u16> = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0xD8)
+            .map(|command| Geometry::W25N01GV.block_of_page(command.page_address().unwrap()))
+            .collect();
+        assert_eq!(erased, [4, 6, 7, 8]);
+        assert_eq!(
+            sim.page(Geometry::W25N01GV.block_first_page(5))[PAGE_SIZE_BYTES],
+            0
+        );
+        assert_eq!(eraser.failures().collect::<std::vec::Vec<_>>(), [6]);
+
+        let (_, progress) = eraser
+            .run_for(flash, 2, ReadMethod::FastRead, &mut NoDelay)
+            .unwrap();
+        assert_eq!(
+            progress,
+            EraseProgress {
+                blocks_erased: 0,
+                blocks_remaining: 0,
+                failed_blocks: 1,
+                bad_blocks_skipped: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn a_resumed_eraser_picks_up_where_the_saved_position_left_off() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+
+        let mut eraser = IncrementalEraser::start(10..20);
+        let (flash, progress) = eraser
+            .run_for(flash, 4, ReadMethod::FastRead, &mut NoDelay)
+            .unwrap();
+        assert_eq!(progress.blocks_remaining, 6);
+        let saved = eraser.position();
+        assert_eq!(saved, 14);
+
+        // A reboot later
+        drop(flash);
+        sim.power_cycle();
+        let mut flash = sim.driver();
+        let mut eraser = IncrementalEraser::start(10..20);
+        eraser.resume_from(saved);
+        while !eraser.is_complete() {
+            let (next, _) = eraser
+                .run_for(flash, 4, ReadMethod::FastRead, &mut NoDelay)
+                .unwrap();
+            flash = next;
+        }
+
+        // Every block once, in order
+        assert_eq!(erased_blocks(&sim), (10..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn resume_from_clamps_to_the_range() {
+        let mut eraser = IncrementalEraser::start(10..20);
+        eraser.resume_from(3);
+        assert_eq!(eraser.position(), 10);
+        eraser.resume_from(25);
+        assert_eq!(eraser.position(), 20);
+        assert!(eraser.is_complete());
+
+        let eraser = IncrementalEraser::start(1020..2000);
+        assert_eq!(eraser.position(), 1020);
+        let eraser = IncrementalEraser::start(2000..3000);
+        assert!(eraser.is_complete());
+    }
+
+    #[test]
+    fn failures_accumulate_across_calls_past_what_can_be_stored() {
+        let sim = SimFlash::new();
+        let failing = MAX_ERASE_FAILURES as u16 + 2;
+        for block in 100..100 + failing {
+            sim.fail_erase(block);
+        }
+        let mut flash = sim.driver();
+
+        let mut eraser = IncrementalEraser::start(100..100 + failing + 1);
+        let mut last = None;
+        while !eraser.is_complete() {
+            let (next, progress) = eraser
+                .run_for(flash, 5, ReadMethod::FastRead, &mut NoDelay)
+                .unwrap();
+            flash = next;
+            last = Some(progress);
+        }
+
+        assert_eq!(last.unwrap().failed_blocks, failing);
+        assert!(eraser.failures_overflowed());
+        assert_eq!(
+            eraser.failures().collect::<Vec<_>>(),
+            (100..100 + MAX_ERASE_FAILURES as u16).collect::<Vec<_>>()
+        );
+        // The block after them was still erased
+        assert_eq!(erased_blocks(&sim).last(), Some(&(100 + failing)));
+    }
+
+    #[test]
+    fn a_bus_error_stops_the_eraser_at_the_block_it_was_on() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        let mut eraser = IncrementalEraser::start(10..20);
+        let (flash, _) = eraser
+            .run_for(flash, 2, ReadMethod::FastRead, &mut NoDelay)
+            .unwrap();
+
+        sim.disconnect();
+        assert!(eraser
+            .run_for(flash, 2, ReadMethod::FastRead, &mut NoDelay)
+            .is_err());
+        assert_eq!(eraser.position(), 12);
+        assert_eq!(eraser.failures().count(), 0);
+    }
+
+    #[test]
+    fn block_0_is_only_erased_when_included_or_not_reserved() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_block0_policy(Block0Policy::Reserved);
+
+        let mut eraser = IncrementalEraser::start(0..2);
+        let (flash, progress) = eraser
+            .run_for(flash, 1, ReadMethod::FastRead, &mut NoDelay)
+            .unwrap();
+        // Skipping block 0 didn't use up the call's one block
+        assert_eq!(progress.blocks_erased, 1);
+        assert_eq!(erased_blocks(&sim), [1]);
+
+        sim.clear_log();
+        let mut eraser = IncrementalEraser::start(0..2);
+        eraser.include_block0();
+        eraser
+            .run_for(flash, 2, ReadMethod::FastRead, &mut NoDelay)
+            .unwrap();
+        assert_eq!(erased_blocks(&sim), [0, 1]);
+    }
+}
@@ -0,0 +1,630 @@
+use core::fmt;
+
+use crate::{
+    bus::QspiError, recovery::RecoveryStatuses, status::ECCStatus, BlockAddress, PageAddress,
+    PAGE_SIZE_WITH_ECC_BYTES,
+};
+
+/// The operation an async driver call was in the middle of when its future was dropped
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InterruptedOp {
+    PageRead,
+    Program,
+    Erase,
+}
+
+/// What became of an operation whose future was dropped, worked out once the device finished
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InterruptedOutcome {
+    /// The device never started it, and the write enable latch it left set has been cleared
+    NotStarted,
+    /// The device finished it without reporting a failure
+    Completed,
+    /// The device finished it and set its program or erase failure bit
+    Failed,
+}
+
+/// The likely cause of a `FlashError::QSPIAddress`. The peripheral's configuration isn't
+/// readable back from the HAL, so this is worked out from the command that was rejected.
+///
+/// The driver only ever sends 16 bit column addresses within the 2,112 byte data buffer, so:
+/// a column range past the end of the buffer is the command's own fault, a rejected column 0
+/// (or a command without an address) can't be a window problem and points at the address size,
+/// and anything else points at the peripheral's flash size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ConfigHint {
+    /// The QSPI address size isn't set to 16 bits
+    LikelyAddressSizeMismatch,
+    /// The QSPI flash size is set smaller than the data buffer
+    LikelyFlashSizeTooSmall,
+    /// The command addresses columns past the end of the data buffer
+    OutOfWindow,
+}
+
+impl ConfigHint {
+    fn classify(address: Option<u32>, len: u32) -> ConfigHint {
+        match address {
+            Some(address) if address as u64 + len as u64 > PAGE_SIZE_WITH_ECC_BYTES as u64 => {
+                ConfigHint::OutOfWindow
+            }
+            Some(address) if address > 0 => ConfigHint::LikelyFlashSizeTooSmall,
+            _ => ConfigHint::LikelyAddressSizeMismatch,
+        }
+    }
+}
+
+impl fmt::Display for ConfigHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigHint::LikelyAddressSizeMismatch => {
+                write!(f, "check the QSPI address size is configured as 16 bits")
+            }
+            ConfigHint::LikelyFlashSizeTooSmall => write!(
+                f,
+                "check the QSPI flash size covers at least the 2112 byte data buffer"
+            ),
+            ConfigHint::OutOfWindow => {
+                write!(f, "the command runs past the end of the data buffer")
+            }
+        }
+    }
+}
+
+/// The errors of the driver itself, the core of the error hierarchy. Layers built on the driver
+/// have error types of their own that carry this as a `source` field, like `StorageError` for the
+/// structures kept on the device, and convert from it with `From` so `?` works across the
+/// boundary. Every type in the hierarchy maps onto the one `code` table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum FlashError {
+    QSPIBusy,
+    /// The QSPI peripheral rejected the address of a command. This is almost always down to how
+    /// the peripheral was configured rather than the driver, `hint` says what to look at.
+    QSPIAddress {
+        address: u32,
+        len: u32,
+        hint: ConfigHint,
+    },
+    QSPIUnknown,
+    DeviceBusy,
+    /// The device was still busy after waiting for as long as allowed
+    Timeout,
+    /// The device reported a program failure for the page
+    ProgramFailed {
+        page_address: u16,
+    },
+    /// The device reported an erase failure for the block containing the page
+    EraseFailed {
+        page_address: u16,
+    },
+    /// A read had more bit errors than ECC could correct
+    ECC {
+        status: ECCStatus,
+        page_address: u16,
+    },
+    /// An address, column, or length falls outside of the device or buffer
+    OutOfBounds,
+    /// The operation targets memory or a register the device currently protects
+    Protected,
+    WriteToECCReservedColumn,
+    NoDeviceDetected,
+    /// A chunk of the page had more bit errors than the software ECC could correct
+    UncorrectableSoftwareECC,
+    /// Programming the page again would go over the number of partial page programs allowed
+    /// between erases
+    PartialProgramBudgetExceeded {
+        page_address: u16,
+    },
+    /// A register can't be changed because its lock bit has been permanently set
+    RegisterLocked,
+    /// Every attempt of a recovery read had uncorrectable ECC errors
+    RecoveryFailed {
+        page_address: u16,
+        statuses: RecoveryStatuses,
+    },
+    /// A verified addressing check found the device in a state the command just sent doesn't
+    /// explain, so the command may have been corrupted on the bus
+    CommandIntegritySuspect,
+    /// A managed API was asked to use block 0 while the block 0 policy reserves it
+    Block0Reserved,
+    /// State restored by `resume_fast` no longer matched the device, the caches have been cleared
+    StaleStateDetected,
+    /// A page read back after programming didn't match what was programmed
+    VerifyFailed {
+        page_address: u16,
+    },
+    /// The driver was used again while it was in the middle of an operation, e.g. from an
+    /// interrupt
+    ReentrantCall,
+    /// A helper was given less scratch space than it needs
+    InsufficientScratch {
+        needed: usize,
+        available: usize,
+    },
+    /// A load or buffered read would run past column 2111, where revisions of the device disagree
+    /// on whether it wraps or truncates
+    WouldWrapPageBuffer {
+        column: u16,
+        len: usize,
+    },
+    /// An operation was refused because its worst case would exceed the latency budget
+    WouldExceedBudget {
+        estimated_us: u32,
+        budget_us: u32,
+    },
+    /// A bad block look up table link conflicts with one the device already has
+    LutConflict {
+        logical_block: u16,
+    },
+    /// The device's bad block look up table has no room for another link
+    LutFull,
+    /// An offset or length isn't a multiple of the page or block size the operation works in
+    NotAligned,
+    /// A load touches a spare byte the OOB layout reserves for the marker or other software
+    WriteToLayoutReservedColumn {
+        column: u16,
+    },
+    /// The command needs more than the bus can do, e.g. quad data lines on a single line SPI bus
+    UnsupportedOnThisBus,
+    /// A read from a column other than 0 was asked for in continuous read mode, where the device
+    /// ignores the column and reads from the start of the page
+    ColumnIgnoredInContinuousRead {
+        column: u16,
+    },
+    /// The future of an earlier async operation was dropped after it reached the device. This
+    /// call waited for the device to finish it and reports what became of it instead of running,
+    /// `address` being the page address of the read, program or (first page of the) erase.
+    InterruptedOperation {
+        op: InterruptedOp,
+        address: u16,
+        outcome: InterruptedOutcome,
+    },
+}
+
+impl FlashError {
+    /// Converts an error from the QSPI peripheral, given the address (if the command had an
+    /// address phase) and data length of the command that failed
+    pub(crate) fn from_qspi_error(err: QspiError, address: Option<u32>, len: u32) -> FlashError {
+        match err {
+            QspiError::Busy => FlashError::QSPIBusy,
+            QspiError::Address => FlashError::QSPIAddress {
+                address: address.unwrap_or(0),
+                len,
+                hint: ConfigHint::classify(address, len),
+            },
+            QspiError::Unsupported => FlashError::UnsupportedOnThisBus,
+            QspiError::Unknown => FlashError::QSPIUnknown,
+        }
+    }
+
+    /// A compact, stable numeric code for the error, small enough to fit in telemetry frames.
+    /// Codes are never reused, new errors always get a new code. The table is shared with the
+    /// layer errors, so codes 17, 18, 22, 31 and 34 are `StorageError`'s.
+    pub fn code(&self) -> u8 {
+        match self {
+            FlashError::QSPIBusy => 1,
+            FlashError::QSPIAddress { .. } => 2,
+            FlashError::QSPIUnknown => 3,
+            FlashError::DeviceBusy => 4,
+            FlashError::Timeout => 5,
+            FlashError::ProgramFailed { .. } => 6,
+            FlashError::EraseFailed { .. } => 7,
+            FlashError::ECC { .. } => 8,
+            FlashError::OutOfBounds => 9,
+            FlashError::Protected => 10,
+            FlashError::WriteToECCReservedColumn => 11,
+            FlashError::NoDeviceDetected => 12,
+            FlashError::UncorrectableSoftwareECC => 13,
+            FlashError::PartialProgramBudgetExceeded { .. } => 14,
+            FlashError::RegisterLocked => 15,
+            FlashError::RecoveryFailed { .. } => 16,
+            FlashError::CommandIntegritySuspect => 19,
+            FlashError::Block0Reserved => 20,
+            FlashError::StaleStateDetected => 21,
+            FlashError::VerifyFailed { .. } => 23,
+            FlashError::ReentrantCall => 24,
+            FlashError::InsufficientScratch { .. } => 25,
+            FlashError::WouldWrapPageBuffer { .. } => 26,
+            FlashError::WouldExceedBudget { .. } => 27,
+            FlashError::LutConflict { .. } => 28,
+            FlashError::LutFull => 29,
+            FlashError::NotAligned => 30,
+            FlashError::WriteToLayoutReservedColumn { .. } => 32,
+            FlashError::UnsupportedOnThisBus => 33,
+            FlashError::ColumnIgnoredInContinuousRead { .. } => 35,
+            FlashError::InterruptedOperation { .. } => 36,
+        }
+    }
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::QSPIBusy => write!(f, "QSPI peripheral busy"),
+            FlashError::QSPIAddress { address, len, hint } => write!(
+                f,
+                "QSPI address error at {:#x} ({} bytes), {}",
+                address, len, hint
+            ),
+            FlashError::QSPIUnknown => write!(f, "unknown QSPI error"),
+            FlashError::DeviceBusy => write!(f, "flash device busy"),
+            FlashError::Timeout => write!(f, "timed out waiting for flash device"),
+            FlashError::ProgramFailed { page_address } => {
+                write!(f, "program failed for {}", PageAddress(*page_address))
+            }
+            FlashError::EraseFailed { page_address } => {
+                write!(
+                    f,
+                    "erase failed for block of {}",
+                    PageAddress(*page_address)
+                )
+            }
+            FlashError::ECC {
+                status,
+                page_address,
+            } => write!(
+                f,
+                "ECC error ({:?}) reading {}",
+                status,
+                PageAddress(*page_address)
+            ),
+            FlashError::OutOfBounds => write!(f, "address or length out of bounds"),
+            FlashError::Protected => write!(f, "target is write protected"),
+            FlashError::WriteToECCReservedColumn => {
+                write!(f, "write to a spare column reserved for ECC")
+            }
+            FlashError::NoDeviceDetected => write!(f, "no flash device detected"),
+            FlashError::UncorrectableSoftwareECC => {
+                write!(f, "uncorrectable software ECC error")
+            }
+            FlashError::PartialProgramBudgetExceeded { page_address } => write!(
+                f,
+                "{} has used its partial program budget since the last erase",
+                PageAddress(*page_address)
+            ),
+            FlashError::RegisterLocked => write!(f, "register is permanently locked"),
+            FlashError::RecoveryFailed {
+                page_address,
+                statuses,
+            } => write!(
+                f,
+                "no recovery attempt could read {} ({:?})",
+                PageAddress(*page_address),
+                statuses
+            ),
+            FlashError::CommandIntegritySuspect => {
+                write!(f, "command may have been corrupted on the bus")
+            }
+            FlashError::Block0Reserved => write!(f, "block 0 is reserved"),
+            FlashError::StaleStateDetected => {
+                write!(f, "restored driver state no longer matches the device")
+            }
+            FlashError::VerifyFailed { page_address } => write!(
+                f,
+                "{} failed verification after programming",
+                PageAddress(*page_address)
+            ),
+            FlashError::ReentrantCall => {
+                write!(f, "driver re-entered during an operation")
+            }
+            FlashError::InsufficientScratch { needed, available } => write!(
+                f,
+                "needed {} bytes of scratch but only {} were available",
+                needed, available
+            ),
+            FlashError::WouldWrapPageBuffer { column, len } => write!(
+                f,
+                "{} bytes from column {} would run past the end of the data buffer",
+                len, column
+            ),
+            FlashError::WouldExceedBudget {
+                estimated_us,
+                budget_us,
+            } => write!(
+                f,
+                "worst case of {}us exceeds the {}us latency budget",
+                estimated_us, budget_us
+            ),
+            FlashError::LutConflict { logical_block } => write!(
+                f,
+                "block {} is already linked differently in the bad block look up table",
+                logical_block
+            ),
+            FlashError::LutFull => write!(f, "bad block look up table full"),
+            FlashError::NotAligned => write!(f, "offset or length not aligned"),
+            FlashError::WriteToLayoutReservedColumn { column } => {
+                write!(f, "column {} is reserved by the OOB layout", column)
+            }
+            FlashError::UnsupportedOnThisBus => {
+                write!(f, "command not supported on this bus")
+            }
+            FlashError::ColumnIgnoredInContinuousRead { column } => {
+                write!(f, "column {} is ignored in continuous read mode", column)
+            }
+            FlashError::InterruptedOperation {
+                op,
+                address,
+                outcome,
+            } => write!(
+                f,
+                "interrupted {:?} of {} was {:?}",
+                op,
+                PageAddress(*address),
+                outcome
+            ),
+        }
+    }
+}
+
+/// Code that was written against the error's name before the layer errors were split out of it
+pub type FlashCommandError = FlashError;
+
+/// The errors of the structures the crate keeps on the device: block headers, the allocator,
+/// layouts, the log sink and event log, the spanning record writer and two phase updates. A
+/// failure of the driver underneath one of them is carried as is in `Flash`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum StorageError {
+    /// The driver failed underneath the structure
+    Flash { source: FlashError },
+    /// A layout has overlapping regions, or more regions than can be described
+    InvalidLayout,
+    /// A region of a layout has fewer good blocks than it needs
+    InsufficientGoodBlocks { first_block: u16, good_blocks: u16 },
+    /// A block header was neither erased nor valid
+    CorruptBlockHeader,
+    /// A structure's region has no room for what was asked of it. `region` is its first block,
+    /// `needed` is the pages it would take, and `reclaimable` is true if compacting the structure
+    /// could make the room.
+    StorageFull {
+        region: u16,
+        needed: u32,
+        reclaimable: bool,
+    },
+    /// A block header was given an application structure kind from the range kept for the driver
+    ReservedStructureKind { value: u8 },
+}
+
+impl StorageError {
+    /// The driver error underneath, if it was the driver that failed
+    pub fn flash_error(&self) -> Option<FlashError> {
+        match self {
+            StorageError::Flash { source } => Some(*source),
+            _ => None,
+        }
+    }
+
+    /// The error's code in the table `FlashError::code` uses, the driver error's own code for
+    /// `Flash`
+    pub fn code(&self) -> u8 {
+        match self {
+            StorageError::Flash { source } => source.code(),
+            StorageError::InvalidLayout => 17,
+            StorageError::InsufficientGoodBlocks { .. } => 18,
+            StorageError::CorruptBlockHeader => 22,
+            StorageError::StorageFull { .. } => 31,
+            StorageError::ReservedStructureKind { .. } => 34,
+        }
+    }
+}
+
+impl From<FlashError> for StorageError {
+    fn from(source: FlashError) -> StorageError {
+        StorageError::Flash { source }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Flash { source } => write!(f, "{}", source),
+            StorageError::InvalidLayout => write!(f, "invalid layout"),
+            StorageError::InsufficientGoodBlocks {
+                first_block,
+                good_blocks,
+            } => write!(
+                f,
+                "region at {} only has {} good blocks",
+                BlockAddress(*first_block),
+                good_blocks
+            ),
+            StorageError::CorruptBlockHeader => write!(f, "corrupt block header"),
+            StorageError::StorageFull {
+                region,
+                needed,
+                reclaimable,
+            } => write!(
+                f,
+                "region at {} has no room for {} pages ({})",
+                BlockAddress(*region),
+                needed,
+                if *reclaimable {
+                    "reclaimable"
+                } else {
+                    "not reclaimable"
+                }
+            ),
+            StorageError::ReservedStructureKind { value } => {
+                write!(f, "structure kind {:#x} is reserved for the driver", value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{format, vec::Vec};
+
+    #[test]
+    fn codes_are_stable_and_unique() {
+        let errors = [
+            (FlashError::QSPIBusy, 1),
+            (FlashError::Timeout, 5),
+            (FlashError::ProgramFailed { page_address: 7 }, 6),
+            (FlashError::EraseFailed { page_address: 64 }, 7),
+            (
+                FlashError::ECC {
+                    status: ECCStatus::SinglePageError,
+                    page_address: 3,
+                },
+                8,
+            ),
+            (FlashError::OutOfBounds, 9),
+            (FlashError::Protected, 10),
+            (FlashError::ReentrantCall, 24),
+            (FlashError::UnsupportedOnThisBus, 33),
+            (
+                FlashError::ColumnIgnoredInContinuousRead { column: 2048 },
+                35,
+            ),
+            (
+                FlashError::InterruptedOperation {
+                    op: InterruptedOp::Program,
+                    address: 5,
+                    outcome: InterruptedOutcome::Completed,
+                },
+                36,
+            ),
+        ];
+
+        let storage_errors = [
+            (StorageError::InvalidLayout, 17),
+            (
+                StorageError::InsufficientGoodBlocks {
+                    first_block: 4,
+                    good_blocks: 1,
+                },
+                18,
+            ),
+            (StorageError::CorruptBlockHeader, 22),
+            (
+                StorageError::StorageFull {
+                    region: 6,
+                    needed: 2,
+                    reclaimable: true,
+                },
+                31,
+            ),
+            (StorageError::ReservedStructureKind { value: 0x10 }, 34),
+        ];
+
+        for (error, code) in errors.iter() {
+            assert_eq!(error.code(), *code, "{:?}", error);
+        }
+        for (error, code) in storage_errors.iter() {
+            assert_eq!(error.code(), *code, "{:?}", error);
+        }
+
+        // One table across the hierarchy
+        let mut codes: Vec<u8> = errors
+            .iter()
+            .map(|(error, _)| error.code())
+            .chain(storage_errors.iter().map(|(error, _)| error.code()))
+            .collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(codes.len(), errors.len() + storage_errors.len());
+    }
+
+    #[test]
+    fn driver_errors_cross_into_the_storage_layer_intact() {
+        let errors = [
+            FlashError::QSPIAddress {
+                address: 2048,
+                len: 100,
+                hint: ConfigHint::OutOfWindow,
+            },
+            FlashError::DeviceBusy,
+            FlashError::Timeout,
+            FlashError::ProgramFailed { page_address: 7 },
+            FlashError::EraseFailed { page_address: 64 },
+            FlashError::ECC {
+                status: ECCStatus::MultiPageError,
+                page_address: 3,
+            },
+            FlashError::OutOfBounds,
+            FlashError::Protected,
+        ];
+
+        for error in errors.iter() {
+            let storage = StorageError::from(*error);
+
+            assert_eq!(storage, StorageError::Flash { source: *error });
+            assert_eq!(storage.flash_error(), Some(*error));
+            assert_eq!(storage.code(), error.code());
+            assert_eq!(format!("{}", storage), format!("{}", error));
+        }
+
+        assert_eq!(StorageError::CorruptBlockHeader.flash_error(), None);
+    }
+
+    #[test]
+    fn question_mark_converts_at_the_layer_boundary() {
+        fn driver() -> Result<(), FlashError> {
+            Err(FlashError::EraseFailed { page_address: 128 })
+        }
+
+        fn layer() -> Result<(), StorageError> {
+            driver()?;
+            Ok(())
+        }
+
+        assert_eq!(
+            layer(),
+            Err(StorageError::Flash {
+                source: FlashError::EraseFailed { page_address: 128 }
+            })
+        );
+    }
+
+    #[test]
+    fn qspi_errors_keep_the_failed_command() {
+        assert_eq!(
+            FlashError::from_qspi_error(QspiError::Address, Some(2048), 100),
+            FlashError::QSPIAddress {
+                address: 2048,
+                len: 100,
+                hint: ConfigHint::OutOfWindow,
+            }
+        );
+        assert_eq!(
+            FlashError::from_qspi_error(QspiError::Address, Some(512), 4),
+            FlashError::QSPIAddress {
+                address: 512,
+                len: 4,
+                hint: ConfigHint::LikelyFlashSizeTooSmall,
+            }
+        );
+        assert_eq!(
+            FlashError::from_qspi_error(QspiError::Address, None, 1),
+            FlashError::QSPIAddress {
+                address: 0,
+                len: 1,
+                hint: ConfigHint::LikelyAddressSizeMismatch,
+            }
+        );
+        assert_eq!(
+            FlashError::from_qspi_error(QspiError::Busy, Some(0), 1),
+            FlashError::QSPIBusy
+        );
+        assert_eq!(
+            FlashError::from_qspi_error(QspiError::Unsupported, None, 0),
+            FlashError::UnsupportedOnThisBus
+        );
+    }
+
+    #[test]
+    fn display_names_the_address() {
+        let error = FlashError::ProgramFailed { page_address: 130 };
+        assert_eq!(
+            format!("{}", error),
+            format!("program failed for {}", PageAddress(130))
+        );
+    }
+}
@@ -0,0 +1,489 @@
+//! A persistent history of the device's failures, for working out after the fact when and why
+//! blocks went bad on a returned unit.
+//!
+//! The driver queues a `FlashEvent` in RAM whenever one of the paths that keeps hold of the driver
+//! sees a failure: `IncrementalEraser` finding a block that won't erase, `read_page_with_recovery`
+//! giving up on a page, and `register_bad_block_links` relocating a block. Applications add their
+//! own with `log_event`, e.g. when their allocator marks a block bad. Each event is stamped with
+//! the time source from `set_time_source`, or 0 without one.
+//!
+//! Queueing never fails the operation that triggered it. When the queue is full, further events
+//! are counted in `dropped_events` and lost, as are events `flush` can't stage in the sink.
+//! `EventLog::flush` moves queued events into a `FlashLogSink` kept in its own blocks, so call it
+//! from the main loop after anything that may have failed.
+//!
+//! Like the sink, the log overwrites its oldest events unless `set_retention` is on, in which case
+//! events stay until `release_flushed` releases them and `reclaim` erases their blocks. A full
+//! retained log fails `flush` with `StorageError::StorageFull` and keeps the events staged for the
+//! next one; `check_room` reports the same beforehand without giving up the driver.
+//!
+//! Methods that give up the driver when they fail, like `erase_block` and `commit`, can't queue
+//! anything since the driver goes with the error.
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    log_sink::FlashLogSink, QspiBus, ReadMethod, ReadMode, StorageError, WriteMethod, W25N01GV,
+};
+
+/// How many events can wait in RAM for `EventLog::flush`
+pub const MAX_PENDING_EVENTS: usize = 4;
+
+/// The log record level that marks a record as an event
+const EVENT_RECORD_LEVEL: u8 = 0xE0;
+/// Kind, timestamp, and two addresses
+const EVENT_RECORD_BYTES: usize = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlashEventKind {
+    BlockMarkedBad {
+        block: u16,
+    },
+    ProgramFailure {
+        page_address: u16,
+    },
+    EraseFailure {
+        block: u16,
+    },
+    UncorrectableEcc {
+        page_address: u16,
+    },
+    /// A bad block was swapped for a good one in the device's bad block look up table
+    Relocation {
+        logical_block: u16,
+        physical_block: u16,
+    },
+}
+
+impl FlashEventKind {
+    fn to_parts(self) -> (u8, u16, u16) {
+        match self {
+            FlashEventKind::BlockMarkedBad { block } => (1, block, 0),
+            FlashEventKind::ProgramFailure { page_address } => (2, page_address, 0),
+            FlashEventKind::EraseFailure { block } => (3, block, 0),
+            FlashEventKind::UncorrectableEcc { page_address } => (4, page_address, 0),
+            FlashEventKind::Relocation {
+                logical_block,
+                physical_block,
+            } => (5, logical_block, physical_block),
+        }
+    }
+
+    fn from_parts(kind: u8, first: u16, second: u16) -> Option<FlashEventKind> {
+        match kind {
+            1 => Some(FlashEventKind::BlockMarkedBad { block: first }),
+            2 => Some(FlashEventKind::ProgramFailure {
+                page_address: first,
+            }),
+            3 => Some(FlashEventKind::EraseFailure { block: first }),
+            4 => Some(FlashEventKind::UncorrectableEcc {
+                page_address: first,
+            }),
+            5 => Some(FlashEventKind::Relocation {
+                logical_block: first,
+                physical_block: second,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FlashEvent {
+    pub timestamp: u64,
+    pub kind: FlashEventKind,
+}
+
+impl FlashEvent {
+    fn to_bytes(self) -> [u8; EVENT_RECORD_BYTES] {
+        let (kind, first, second) = self.kind.to_parts();
+
+        let mut bytes = [0_u8; EVENT_RECORD_BYTES];
+        bytes[0] = kind;
+        bytes[1..9].copy_from_slice(&self.timestamp.to_le_bytes());
+        bytes[9..11].copy_from_slice(&first.to_le_bytes());
+        bytes[11..13].copy_from_slice(&second.to_le_bytes());
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<FlashEvent> {
+        if bytes.len() != EVENT_RECORD_BYTES {
+            return None;
+        }
+
+        let mut timestamp = [0_u8; 8];
+        timestamp.copy_from_slice(&bytes[1..9]);
+
+        Some(FlashEvent {
+            timestamp: u64::from_le_bytes(timestamp),
+            kind: FlashEventKind::from_parts(
+                bytes[0],
+                u16::from_le_bytes([bytes[9], bytes[10]]),
+                u16::from_le_bytes([bytes[11], bytes[12]]),
+            )?,
+        })
+    }
+}
+
+pub(crate) struct PendingEvents {
+    events: [Option<FlashEvent>; MAX_PENDING_EVENTS],
+    dropped: u32,
+}
+
+impl PendingEvents {
+    pub(crate) fn new() -> PendingEvents {
+        PendingEvents {
+            events: [None; MAX_PENDING_EVENTS],
+            dropped: 0,
+        }
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Sets where event timestamps come from, e.g. an RTC or uptime counter
+    pub fn set_time_source(&mut self, time_source: Option<fn() -> u64>) {
+        self.time_source = time_source;
+    }
+
+    /// Queues an event for `EventLog::flush`, stamped with the current time. Never fails, see the
+    /// module docs.
+    pub fn log_event(&self, kind: FlashEventKind) {
+        let event = FlashEvent {
+            timestamp: self.time_source.map(|now| now()).unwrap_or(0),
+            kind,
+        };
+
+        let mut pending = self.pending_events.borrow_mut();
+        match pending.events.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(event),
+            None => pending.dropped = pending.dropped.wrapping_add(1),
+        }
+    }
+
+    /// The number of events lost because the queue was full, or because `EventLog::flush` found
+    /// no room left in the log's staging buffer
+    pub fn dropped_events(&self) -> u32 {
+        self.pending_events.borrow().dropped
+    }
+}
+
+/// The on-device history of `FlashEvent`s, see the module docs
+pub struct EventLog {
+    sink: FlashLogSink,
+}
+
+impl EventLog {
+    /// Opens the event log kept in `block_count` blocks starting at `first_block`
+    pub fn mount<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        first_block: u16,
+        block_count: u16,
+        method: ReadMethod,
+    ) -> Result<EventLog, StorageError> {
+        Ok(EventLog {
+            sink: FlashLogSink::mount(flash, first_block, block_count, method)?,
+        })
+    }
+
+    /// Sets whether the log keeps events until they're released rather than overwriting the oldest
+    pub fn set_retention(&mut self, retain: bool) {
+        self.sink.set_retention(retain);
+    }
+
+    /// Releases every event flushed so far, so `reclaim` can erase them
+    pub fn release_flushed(&mut self) {
+        self.sink.release_written();
+    }
+
+    /// Returns `StorageError::StorageFull` if there are events to flush but, with retention on,
+    /// nowhere to write them. `reclaimable` is true if `reclaim` would make the room.
+    pub fn check_room<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+    ) -> Result<(), StorageError> {
+        let queued = flash
+            .pending_events
+            .borrow()
+            .events
+            .iter()
+            .any(Option::is_some);
+
+        if queued {
+            self.sink.check_next_page(flash)
+        } else {
+            self.sink.check_room(flash)
+        }
+    }
+
+    /// Erases the oldest blocks of the log as long as they hold only released events, and returns
+    /// how many it erased
+    pub fn reclaim<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        delay: &mut D,
+    ) -> Result<u16, StorageError> {
+        self.sink.reclaim(flash, delay)
+    }
+
+    /// Writes every queued event to the log, in the order they happened
+    pub fn flush<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        {
+            let pending = &mut *flash.pending_events.borrow_mut();
+            for slot in pending.events.iter_mut() {
+                if let Some(event) = slot.take() {
+                    // The low half of the timestamp doubles as the record's own timestamp
+                    let staged = self.sink.push(
+                        event.timestamp as u32,
+                        EVENT_RECORD_LEVEL,
+                        &event.to_bytes(),
+                    );
+                    if !staged {
+                        pending.dropped = pending.dropped.wrapping_add(1);
+                    }
+                }
+            }
+        }
+
+        self.sink.pump(flash, write_method, delay)
+    }
+
+    /// Reads every flushed event, oldest first
+    pub fn events<BUS: QspiBus, MODE, F>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        method: ReadMethod,
+        mut f: F,
+    ) -> Result<(), StorageError>
+    where
+        F: FnMut(&FlashEvent),
+    {
+        self.sink.read_logs(flash, method, |record| {
+            if record.level != EVENT_RECORD_LEVEL {
+                return;
+            }
+
+            if let Some(event) = FlashEvent::from_bytes(record.message) {
+                f(&event);
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::BlockAllocator,
+        endurance::EnduranceTest,
+        eraser::IncrementalEraser,
+        reconcile::ReconcilePolicy,
+        recovery::RecoveryPolicy,
+        sim::{NoDelay, SimFlash},
+        PAGE_SIZE_WITH_ECC_BYTES,
+    };
+    use core::sync::atomic::{AtomicU64, Ordering};
+    use std::vec::Vec;
+
+    const FIRST_BLOCK: u16 = 6;
+    const BLOCK_COUNT: u16 = 2;
+
+    static NOW: AtomicU64 = AtomicU64::new(1_000);
+
+    fn now() -> u64 {
+        NOW.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn mount(flash: &W25N01GV<SimFlash, ReadMode>) -> EventLog {
+        EventLog::mount(flash, FIRST_BLOCK, BLOCK_COUNT, ReadMethod::FastRead).unwrap()
+    }
+
+    fn flush(
+        log: &mut EventLog,
+        flash: W25N01GV<SimFlash, ReadMode>,
+    ) -> W25N01GV<SimFlash, ReadMode> {
+        log.flush(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap()
+    }
+
+    fn events(log: &EventLog, flash: &W25N01GV<SimFlash, ReadMode>) -> Vec<FlashEvent> {
+        let mut events = Vec::new();
+        log.events(flash, ReadMethod::FastRead, |event| events.push(*event))
+            .unwrap();
+
+        events
+    }
+
+    #[test]
+    fn every_kind_of_event_survives_a_reboot() {
+        let sim = SimFlash::new();
+        sim.fail_erase(10);
+        sim.fail_program(11 * 64 + 3);
+        sim.set_uncorrectable(20 * 64);
+        sim.mark_bad(30);
+
+        let mut flash = sim.driver();
+        flash.set_time_source(Some(now));
+        let mut log = mount(&flash);
+
+        // Each managed layer queues its own event, flushed as soon as it's done
+        let mut eraser = IncrementalEraser::start(10..11);
+        let (next, _) = eraser
+            .run_for(flash, 1, ReadMethod::FastRead, &mut NoDelay)
+            .unwrap();
+        flash = flush(&mut log, next);
+
+        let mut endurance = EnduranceTest::start(&[11], 1);
+        let next = endurance
+            .run_for(
+                flash,
+                u32::MAX,
+                WriteMethod::SingleLoad,
+                ReadMethod::FastRead,
+                &mut NoDelay,
+                |_| {},
+            )
+            .unwrap();
+        flash = flush(&mut log, next);
+
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        assert!(flash
+            .read_page_with_recovery(20 * 64, &mut buffer, RecoveryPolicy::default())
+            .is_err());
+        flash = flush(&mut log, flash);
+
+        let write_flash = flash.into_write_mode().unwrap();
+        let mut allocator = BlockAllocator::new([1, 2]);
+        allocator
+            .reconcile_bad_blocks(
+                &write_flash,
+                ReconcilePolicy::default(),
+                ReadMethod::FastRead,
+            )
+            .unwrap();
+        assert_eq!(write_flash.register_bad_block_links(&[(40, 1000)]), Ok(1));
+        flash = flush(&mut log, write_flash.into_read_mode().unwrap());
+
+        let expected = [
+            FlashEventKind::EraseFailure { block: 10 },
+            FlashEventKind::ProgramFailure {
+                page_address: 11 * 64 + 3,
+            },
+            FlashEventKind::UncorrectableEcc {
+                page_address: 20 * 64,
+            },
+            FlashEventKind::BlockMarkedBad { block: 30 },
+            FlashEventKind::Relocation {
+                logical_block: 40,
+                physical_block: 1000,
+            },
+        ];
+        assert_eq!(flash.dropped_events(), 0);
+        drop(flash);
+
+        sim.power_cycle();
+        let flash = sim.driver();
+        let log = mount(&flash);
+        let events = events(&log, &flash);
+
+        let kinds: Vec<_> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(kinds, expected);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].timestamp < pair[1].timestamp));
+        assert!(events.iter().all(|event| event.timestamp >= 1_000));
+    }
+
+    #[test]
+    fn events_the_sink_cant_stage_are_counted_as_dropped() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        let mut log = mount(&flash);
+
+        while log.sink.push(0, 1, &[0x55; 64]) {}
+
+        flash.log_event(FlashEventKind::EraseFailure { block: 12 });
+        let flash = flush(&mut log, flash);
+        assert_eq!(flash.dropped_events(), 1);
+
+        // The queue was emptied either way, and the staged records went out, so the next one fits
+        flash.log_event(FlashEventKind::EraseFailure { block: 13 });
+        let flash = flush(&mut log, flash);
+        assert_eq!(flash.dropped_events(), 1);
+        drop(flash);
+
+        sim.power_cycle();
+        let flash = sim.driver();
+        let kinds: Vec<_> = events(&mount(&flash), &flash)
+            .iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(kinds, [FlashEventKind::EraseFailure { block: 13 }]);
+    }
+
+    #[test]
+    fn a_retained_log_keeps_events_until_released_and_reclaimed() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut log = mount(&flash);
+        log.set_retention(true);
+
+        let capacity = BLOCK_COUNT * 64;
+        for block in 0..capacity {
+            flash.log_event(FlashEventKind::EraseFailure { block });
+            log.check_room(&flash).unwrap();
+            flash = flush(&mut log, flash);
+        }
+
+        let full = |reclaimable| {
+            Err(StorageError::StorageFull {
+                region: FIRST_BLOCK,
+                needed: 1,
+                reclaimable,
+            })
+        };
+
+        // Nothing queued needs no room
+        assert_eq!(log.check_room(&flash), Ok(()));
+        flash.log_event(FlashEventKind::EraseFailure { block: capacity });
+        assert_eq!(log.check_room(&flash), full(false));
+        assert_eq!(log.reclaim(&flash, &mut NoDelay), Ok(0));
+
+        log.release_flushed();
+        assert_eq!(log.check_room(&flash), full(true));
+        assert_eq!(log.reclaim(&flash, &mut NoDelay), Ok(BLOCK_COUNT));
+        assert_eq!(log.check_room(&flash), Ok(()));
+
+        // Both blocks fill again exactly, and the next event finds the log full
+        flash = flush(&mut log, flash);
+        for block in capacity + 1..2 * capacity {
+            flash.log_event(FlashEventKind::EraseFailure { block });
+            flash = flush(&mut log, flash);
+        }
+        flash.log_event(FlashEventKind::EraseFailure { block: 0 });
+        assert_eq!(log.check_room(&flash), full(false));
+        assert_eq!(flash.dropped_events(), 0);
+        drop(flash);
+
+        sim.power_cycle();
+        let flash = sim.driver();
+        let blocks: Vec<_> = events(&mount(&flash), &flash)
+            .iter()
+            .map(|event| match event.kind {
+                FlashEventKind::EraseFailure { block } => block,
+                kind => panic!("unexpected {:?}", kind),
+            })
+            .collect();
+        assert_eq!(blocks, (capacity..2 * capacity).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,397 @@
+//! The array's geometry and the address math built on it.
+//!
+//! This module only depends on `core`, so host tools (e.g. an image generator computing partition
+//! offsets) can share it with the firmware by including the file directly with
+//! `#[path = ".../geometry.rs"] mod geometry;` instead of pulling in the driver and its HAL. The
+//! rest of the driver does its page and block math through here so there's only one copy of it.
+
+use core::{fmt, ops::Range};
+
+/// The shape of a NAND array. Linear addresses are either a `MainAddress`, which leaves the spare
+/// areas out, or a `RawAddress`, which includes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// Bytes in the main array of a page
+    pub page_size: usize,
+    /// Bytes in a page's spare area
+    pub spare_size: usize,
+    pub pages_per_block: usize,
+    pub block_count: usize,
+}
+
+impl Geometry {
+    pub const W25N01GV: Geometry = Geometry {
+        page_size: 2048,
+        spare_size: 64,
+        pages_per_block: 64,
+        block_count: 1024,
+    };
+
+    /// Bytes in a page including its spare area, i.e. the size of the data buffer
+    pub const fn page_size_with_spare(&self) -> usize {
+        self.page_size + self.spare_size
+    }
+
+    pub const fn page_count(&self) -> usize {
+        self.pages_per_block * self.block_count
+    }
+
+    pub const fn block_size(&self) -> usize {
+        self.page_size * self.pages_per_block
+    }
+
+    /// Bytes in the main array of the whole device
+    pub const fn capacity(&self) -> u64 {
+        self.page_size as u64 * self.page_count() as u64
+    }
+
+    /// The first page of `block`, which has to be on the device. Past the last block the page
+    /// address doesn't fit in a u16, so check the block first, e.g. with `contains_block`.
+    pub const fn block_first_page(&self, block: u16) -> u16 {
+        debug_assert!((block as usize) < self.block_count);
+        (block as usize * self.pages_per_block) as u16
+    }
+
+    pub const fn block_of_page(&self, page_address: u16) -> u16 {
+        (page_address as usize / self.pages_per_block) as u16
+    }
+
+    /// The pages of a block, as u32 since the end of the last block is one past the largest page
+    /// address
+    pub const fn block_pages(&self, block: u16) -> Range<u32> {
+        let first_page = block as u32 * self.pages_per_block as u32;
+
+        first_page..first_page + self.pages_per_block as u32
+    }
+
+    /// Is true if the page is the first page of a block
+    pub const fn is_block_aligned(&self, page_address: u32) -> bool {
+        page_address.is_multiple_of(self.pages_per_block as u32)
+    }
+
+    /// Is true if the address is the start of a page
+    pub const fn is_page_aligned(&self, address: MainAddress) -> bool {
+        address.0.is_multiple_of(self.page_size as u32)
+    }
+
+    pub const fn contains_block(&self, block: u32) -> bool {
+        (block as usize) < self.block_count
+    }
+
+    pub const fn contains_page(&self, page_address: u32) -> bool {
+        (page_address as usize) < self.page_count()
+    }
+
+    /// Is true if `count` blocks starting at `first_block` are all on the device
+    pub const fn contains_blocks(&self, first_block: u16, count: u16) -> bool {
+        first_block as usize + count as usize <= self.block_count
+    }
+
+    /// Bytes in the whole device, spare areas included
+    pub const fn raw_capacity(&self) -> u64 {
+        self.page_size_with_spare() as u64 * self.page_count() as u64
+    }
+
+    /// The address of a column in the main array of a page, or None if the column is in the spare
+    /// area or the page is past the end of the device
+    pub const fn main_address(&self, page_address: u32, column: u16) -> Option<MainAddress> {
+        if !self.contains_page(page_address) || column as usize >= self.page_size {
+            return None;
+        }
+
+        Some(MainAddress(
+            page_address * self.page_size as u32 + column as u32,
+        ))
+    }
+
+    /// The address of a column anywhere in a page, or None if the column or page is past the end
+    pub const fn raw_address(&self, page_address: u32, column: u16) -> Option<RawAddress> {
+        if !self.contains_page(page_address) || column as usize >= self.page_size_with_spare() {
+            return None;
+        }
+
+        Some(RawAddress(
+            page_address * self.page_size_with_spare() as u32 + column as u32,
+        ))
+    }
+
+    /// The page and column of a main address, or None past the end of the device
+    pub const fn split_main(&self, address: MainAddress) -> Option<(u32, u16)> {
+        if address.0 as u64 >= self.capacity() {
+            return None;
+        }
+
+        Some((
+            address.0 / self.page_size as u32,
+            (address.0 % self.page_size as u32) as u16,
+        ))
+    }
+
+    /// The page and column of a raw address, or None past the end of the device
+    pub const fn split_raw(&self, address: RawAddress) -> Option<(u32, u16)> {
+        if address.0 as u64 >= self.raw_capacity() {
+            return None;
+        }
+
+        Some((
+            address.0 / self.page_size_with_spare() as u32,
+            (address.0 % self.page_size_with_spare() as u32) as u16,
+        ))
+    }
+
+    /// The raw address of the same byte as a main address. Every main byte has one.
+    pub const fn main_to_raw(&self, address: MainAddress) -> Option<RawAddress> {
+        match self.split_main(address) {
+            Some((page_address, column)) => self.raw_address(page_address, column),
+            None => None,
+        }
+    }
+
+    /// The main address of the same byte as a raw address, or None if the byte is in a spare area
+    pub const fn raw_to_main(&self, address: RawAddress) -> Option<MainAddress> {
+        match self.split_raw(address) {
+            Some((page_address, column)) => self.main_address(page_address, column),
+            None => None,
+        }
+    }
+}
+
+/// A byte in the main arrays of the pages laid end to end, 2048 bytes a page with the spare areas
+/// left out. Byte 2048 is the first byte of page 1.
+///
+/// The two kinds of address don't mix without going through `Geometry`:
+///
+/// ```compile_fail
+/// use w25n01gv_rs::{MainAddress, RawAddress};
+///
+/// let raw: RawAddress = MainAddress(2048);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MainAddress(pub u32);
+
+/// A byte in the whole pages laid end to end, 2112 bytes a page with each page's spare area
+/// after its main array. Byte 2048 is the first spare byte of page 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RawAddress(pub u32);
+
+/// A page, formatted with the block it's in, its index in that block, and its main address, e.g.
+/// `page 4241 (blk 66 +17, 0x00848800)`. Wrap a bare page address in it to log it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PageAddress(pub u16);
+
+/// A block, formatted with its first page and main address, e.g. `blk 66 (page 4224, 0x00840000)`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockAddress(pub u16);
+
+impl PageAddress {
+    /// The block, index in the block, and main address every format of a page is built from
+    const fn parts(self) -> (u16, u16, u32) {
+        let geometry = Geometry::W25N01GV;
+        let page_address = self.0 as u32;
+
+        (
+            geometry.block_of_page(self.0),
+            (page_address % geometry.pages_per_block as u32) as u16,
+            page_address * geometry.page_size as u32,
+        )
+    }
+}
+
+impl BlockAddress {
+    /// As u32, so a block past the end of the device formats without wrapping
+    const fn parts(self) -> (u32, u32) {
+        let geometry = Geometry::W25N01GV;
+        let first_page = geometry.block_pages(self.0).start;
+
+        (first_page, first_page * geometry.page_size as u32)
+    }
+}
+
+impl fmt::Display for PageAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (block, index, address) = self.parts();
+        write!(
+            f,
+            "page {} (blk {} +{}, {:#010x})",
+            self.0, block, index, address
+        )
+    }
+}
+
+impl fmt::Debug for PageAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for BlockAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (first_page, address) = self.parts();
+        write!(f, "blk {} (page {}, {:#010x})", self.0, first_page, address)
+    }
+}
+
+impl fmt::Debug for BlockAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for PageAddress {
+    fn format(&self, f: defmt::Formatter) {
+        let (block, index, address) = self.parts();
+        defmt::write!(
+            f,
+            "page {=u16} (blk {=u16} +{=u16}, {=u32:#010x})",
+            self.0,
+            block,
+            index,
+            address
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for BlockAddress {
+    fn format(&self, f: defmt::Formatter) {
+        let (first_page, address) = self.parts();
+        defmt::write!(
+            f,
+            "blk {=u16} (page {=u32}, {=u32:#010x})",
+            self.0,
+            first_page,
+            address
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    const GEOMETRY: Geometry = Geometry::W25N01GV;
+
+    #[test]
+    fn blocks_and_pages_agree() {
+        for block in 0..GEOMETRY.block_count as u16 {
+            let first_page = GEOMETRY.block_first_page(block);
+            let pages = GEOMETRY.block_pages(block);
+
+            assert_eq!(first_page as u32, pages.start);
+            assert_eq!(pages.len(), GEOMETRY.pages_per_block);
+            assert_eq!(GEOMETRY.block_of_page(first_page), block);
+            assert_eq!(GEOMETRY.block_of_page((pages.end - 1) as u16), block);
+            assert!(GEOMETRY.is_block_aligned(pages.start));
+            assert!(!GEOMETRY.is_block_aligned(pages.start + 1));
+        }
+
+        assert_eq!(GEOMETRY.block_first_page(1023), 65472);
+        assert_eq!(GEOMETRY.block_pages(1023).end, GEOMETRY.page_count() as u32);
+        assert!(GEOMETRY.contains_block(1023));
+        assert!(!GEOMETRY.contains_block(1024));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic]
+    fn the_first_page_past_the_device_is_refused() {
+        GEOMETRY.block_first_page(GEOMETRY.block_count as u16);
+    }
+
+    #[test]
+    fn addresses_round_trip() {
+        for page_address in [0, 1, 63, 64, 4241, 65535].iter() {
+            for column in [0, 1, 2047].iter() {
+                let main = GEOMETRY.main_address(*page_address, *column).unwrap();
+                assert_eq!(GEOMETRY.split_main(main), Some((*page_address, *column)));
+
+                let raw = GEOMETRY.main_to_raw(main).unwrap();
+                assert_eq!(GEOMETRY.split_raw(raw), Some((*page_address, *column)));
+                assert_eq!(GEOMETRY.raw_to_main(raw), Some(main));
+            }
+        }
+
+        assert_eq!(GEOMETRY.main_address(65536, 0), None);
+        assert_eq!(GEOMETRY.main_address(0, 2048), None);
+        assert_eq!(
+            GEOMETRY.split_main(MainAddress(GEOMETRY.capacity() as u32)),
+            None
+        );
+    }
+
+    #[test]
+    fn byte_2048_is_a_different_byte_in_each_space() {
+        assert_eq!(GEOMETRY.split_main(MainAddress(2048)), Some((1, 0)));
+        assert_eq!(GEOMETRY.split_raw(RawAddress(2048)), Some((0, 2048)));
+
+        assert_eq!(
+            GEOMETRY.main_to_raw(MainAddress(2048)),
+            Some(RawAddress(2112))
+        );
+        assert_eq!(GEOMETRY.raw_to_main(RawAddress(2048)), None);
+        assert_eq!(
+            GEOMETRY.raw_to_main(RawAddress(2112)),
+            Some(MainAddress(2048))
+        );
+    }
+
+    #[test]
+    fn every_raw_byte_maps_to_main_unless_it_is_spare() {
+        let first_pages = 0..3 * GEOMETRY.page_size_with_spare() as u32;
+        let last_page = GEOMETRY.raw_capacity() as u32 - GEOMETRY.page_size_with_spare() as u32
+            ..GEOMETRY.raw_capacity() as u32;
+
+        let mut previous_main = None;
+        for raw in first_pages.chain(last_page).map(RawAddress) {
+            let (page_address, column) = GEOMETRY.split_raw(raw).unwrap();
+            assert_eq!(GEOMETRY.raw_address(page_address, column), Some(raw));
+
+            match GEOMETRY.raw_to_main(raw) {
+                Some(main) => {
+                    assert!((column as usize) < GEOMETRY.page_size);
+                    assert_eq!(GEOMETRY.main_to_raw(main), Some(raw));
+                    // Main addresses climb in the same order, skipping the spare areas
+                    assert!(previous_main.is_none_or(|previous| main > previous));
+                    previous_main = Some(main);
+                }
+                None => assert!(column as usize >= GEOMETRY.page_size),
+            }
+        }
+    }
+
+    #[test]
+    fn both_spaces_end_at_the_device() {
+        let last_main = MainAddress(GEOMETRY.capacity() as u32 - 1);
+        let last_raw = RawAddress(GEOMETRY.raw_capacity() as u32 - 1);
+
+        assert_eq!(GEOMETRY.split_main(last_main), Some((65535, 2047)));
+        assert_eq!(GEOMETRY.split_raw(last_raw), Some((65535, 2111)));
+        assert_eq!(
+            GEOMETRY.split_raw(RawAddress(GEOMETRY.raw_capacity() as u32)),
+            None
+        );
+        assert_eq!(GEOMETRY.raw_address(0, 2112), None);
+        assert_eq!(GEOMETRY.raw_address(65536, 0), None);
+        assert_eq!(
+            GEOMETRY.main_to_raw(MainAddress(GEOMETRY.capacity() as u32)),
+            None
+        );
+        assert_eq!(GEOMETRY.raw_to_main(last_raw), None);
+    }
+
+    #[test]
+    fn block_addresses_format_past_the_device() {
+        assert_eq!(
+            format!("{}", BlockAddress(66)),
+            "blk 66 (page 4224, 0x00840000)"
+        );
+        assert_eq!(
+            format!("{}", BlockAddress(1024)),
+            "blk 1024 (page 65536, 0x08000000)"
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! Comparing the device against an image that never has to be in RAM all at once, e.g. to check
+//! a factory programmed image on first boot while it's decompressed or streamed in.
+//!
+//! `verify_stream` pulls the expected image from a callback a chunk at a time and reads the same
+//! bytes from the device to compare against. Each call covers at most `VerifyOpts::max_bytes`, so
+//! a long verification can be spread over several calls, or boot cycles, by passing the returned
+//! `VerifyOutcome::position` back in as the next `start`.
+//!
+//! The image covers the main areas of consecutive pages, so it's addressed with `MainAddress`.
+
+use crate::{
+    geometry::MainAddress, FlashError, Geometry, QspiBus, ReadMethod, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+/// How much of the image is compared at a time
+pub const VERIFY_CHUNK_BYTES: usize = 256;
+
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyOpts {
+    /// How many bytes a single call compares at most before returning
+    pub max_bytes: u32,
+    /// Verification stops once this many mismatching bytes have been found, at least 1
+    pub mismatch_limit: u32,
+    pub method: ReadMethod,
+}
+
+/// A byte the device holds differently from the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Mismatch {
+    pub address: MainAddress,
+    pub flash_byte: u8,
+    pub expected_byte: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VerifyOutcome {
+    /// The first mismatch found by this call
+    pub first_mismatch: Option<Mismatch>,
+    /// How many mismatching bytes this call found, up to `VerifyOpts::mismatch_limit`
+    pub mismatches: u32,
+    /// Where to resume from
+    pub position: MainAddress,
+    /// Is true once the callback has run out of image
+    pub complete: bool,
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Compares the device from `start` on against the image supplied by `expected`, see the
+    /// module docs. `expected` fills as much of the slice it's given as it can with the next bytes
+    /// of the image and returns how many it filled, returning 0 once the image has ended.
+    ///
+    /// Returns `FlashError::OutOfBounds` if the image runs past the end of the device.
+    pub fn verify_stream<F>(
+        &self,
+        start: MainAddress,
+        mut expected: F,
+        opts: VerifyOpts,
+    ) -> Result<VerifyOutcome, FlashError>
+    where
+        F: FnMut(&mut [u8]) -> usize,
+    {
+        let _guard = self.begin_operation()?;
+
+        let device_bytes = Geometry::W25N01GV.page_count() as u32 * PAGE_SIZE_BYTES as u32;
+        let end = start.0.saturating_add(opts.max_bytes);
+        let mismatch_limit = opts.mismatch_limit.max(1);
+
+        let mut outcome = VerifyOutcome {
+            first_mismatch: None,
+            mismatches: 0,
+            position: start,
+            complete: false,
+        };
+
+        let mut expected_chunk = [0_u8; VERIFY_CHUNK_BYTES];
+        let mut flash_chunk = [0_u8; VERIFY_CHUNK_BYTES];
+        let mut loaded_page = None;
+
+        while outcome.position.0 < end && outcome.mismatches < mismatch_limit {
+            let page_address = outcome.position.0 / PAGE_SIZE_BYTES as u32;
+            let column = outcome.position.0 % PAGE_SIZE_BYTES as u32;
+
+            // Chunks never cross a page, so each one is a single read of the data buffer
+            let chunk_len = (VERIFY_CHUNK_BYTES as u32)
+                .min(PAGE_SIZE_BYTES as u32 - column)
+                .min(end - outcome.position.0) as usize;
+
+            let supplied = expected(&mut expected_chunk[..chunk_len]).min(chunk_len);
+            if supplied == 0 {
+                outcome.complete = true;
+                break;
+            }
+
+            if outcome.position.0 >= device_bytes {
+                return Err(FlashError::OutOfBounds);
+            }
+
+            if loaded_page != Some(page_address) {
+                self.read_memory_to_data_buffer_unguarded(page_address as u16)?;
+                self.wait_while_busy_unguarded()?;
+                loaded_page = Some(page_address);
+            }
+
+            self.read_physical_columns(column as u16, &mut flash_chunk[..supplied], opts.method)?;
+
+            for (index, (flash_byte, expected_byte)) in flash_chunk[..supplied]
+                .iter()
+                .zip(expected_chunk[..supplied].iter())
+                .enumerate()
+            {
+                if flash_byte != expected_byte {
+                    if outcome.first_mismatch.is_none() {
+                        outcome.first_mismatch = Some(Mismatch {
+                            address: MainAddress(outcome.position.0 + index as u32),
+                            flash_byte: *flash_byte,
+                            expected_byte: *expected_byte,
+                        });
+                    }
+
+                    outcome.mismatches += 1;
+                    if outcome.mismatches == mismatch_limit {
+                        break;
+                    }
+                }
+            }
+
+            outcome.position.0 += supplied as u32;
+        }
+
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+    use std::vec::Vec;
+
+    const IMAGE_START: MainAddress = MainAddress(PAGE_SIZE_BYTES as u32);
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    /// Programs `image` into the main areas of the pages from page 1 on
+    fn programmed(image: &[u8]) -> SimFlash {
+        let sim = SimFlash::new();
+        for (index, page) in image.chunks(PAGE_SIZE_BYTES).enumerate() {
+            sim.set_page(1 + index as u16, page);
+        }
+
+        sim
+    }
+
+    /// Hands out `image` from `offset` on, at most `step` bytes per call
+    fn stream(image: &[u8], offset: usize, step: usize) -> impl FnMut(&mut [u8]) -> usize + '_ {
+        let mut offset = offset;
+        move |chunk| {
+            let len = chunk.len().min(step).min(image.len() - offset);
+            chunk[..len].copy_from_slice(&image[offset..offset + len]);
+            offset += len;
+            len
+        }
+    }
+
+    fn opts(max_bytes: u32, mismatch_limit: u32) -> VerifyOpts {
+        VerifyOpts {
+            max_bytes,
+            mismatch_limit,
+            method: ReadMethod::FastRead,
+        }
+    }
+
+    #[test]
+    fn a_matching_image_verifies_across_pages() {
+        let image = image(2 * PAGE_SIZE_BYTES + 300);
+        let sim = programmed(&image);
+        let flash = sim.driver();
+
+        let outcome = flash
+            .verify_stream(
+                IMAGE_START,
+                stream(&image, 0, usize::MAX),
+                opts(u32::MAX, 1),
+            )
+            .unwrap();
+
+        assert_eq!(
+            outcome,
+            VerifyOutcome {
+                first_mismatch: None,
+                mismatches: 0,
+                position: MainAddress(IMAGE_START.0 + image.len() as u32),
+                complete: true,
+            }
+        );
+    }
+
+    #[test]
+    fn mismatches_at_chunk_and_page_boundaries_are_reported_exactly() {
+        let image = image(2 * PAGE_SIZE_BYTES);
+        let mut flashed = image.clone();
+        let diverging = [
+            VERIFY_CHUNK_BYTES - 1,
+            VERIFY_CHUNK_BYTES,
+            PAGE_SIZE_BYTES - 1,
+            PAGE_SIZE_BYTES,
+        ];
+        for &index in diverging.iter() {
+            flashed[index] ^= 0xFF;
+        }
+        let sim = programmed(&flashed);
+        let flash = sim.driver();
+
+        let outcome = flash
+            .verify_stream(
+                IMAGE_START,
+                stream(&image, 0, usize::MAX),
+                opts(u32::MAX, 100),
+            )
+            .unwrap();
+
+        assert_eq!(
+            outcome.first_mismatch,
+            Some(Mismatch {
+                address: MainAddress(IMAGE_START.0 + VERIFY_CHUNK_BYTES as u32 - 1),
+                flash_byte: flashed[VERIFY_CHUNK_BYTES - 1],
+                expected_byte: image[VERIFY_CHUNK_BYTES - 1],
+            })
+        );
+        assert_eq!(outcome.mismatches, diverging.len() as u32);
+        assert!(outcome.complete);
+
+        // Verifying from the page boundary on only sees the last one
+        let outcome = flash
+            .verify_stream(
+                MainAddress(IMAGE_START.0 + PAGE_SIZE_BYTES as u32),
+                stream(&image, PAGE_SIZE_BYTES, usize::MAX),
+                opts(u32::MAX, 100),
+            )
+            .unwrap();
+        assert_eq!(
+            outcome.first_mismatch.map(|mismatch| mismatch.address),
+            Some(MainAddress(IMAGE_START.0 + PAGE_SIZE_BYTES as u32))
+        );
+        assert_eq!(outcome.mismatches, 1);
+    }
+
+    #[test]
+    fn short_callback_chunks_compare_the_same_bytes() {
+        let image = image(PAGE_SIZE_BYTES + 100);
+        let mut flashed = image.clone();
+        flashed[1000] = !flashed[1000];
+        let sim = programmed(&flashed);
+        let flash = sim.driver();
+
+        let outcome = flash
+            .verify_stream(IMAGE_START, stream(&image, 0, 7), opts(u32::MAX, 100))
+            .unwrap();
+
+        assert_eq!(
+            outcome.first_mismatch.map(|mismatch| mismatch.address),
+            Some(MainAddress(IMAGE_START.0 + 1000))
+        );
+        assert_eq!(outcome.mismatches, 1);
+        assert!(outcome.complete);
+    }
+
+    #[test]
+    fn the_mismatch_limit_stops_verification() {
+        let image = image(PAGE_SIZE_BYTES);
+        let sim = programmed(&[0x00; PAGE_SIZE_BYTES]);
+        let flash = sim.driver();
+
+        let outcome = flash
+            .verify_stream(
+                IMAGE_START,
+                stream(&image, 0, usize::MAX),
+                opts(u32::MAX, 3),
+            )
+            .unwrap();
+
+        assert_eq!(outcome.mismatches, 3);
+        assert!(!outcome.complete);
+        assert_eq!(
+            outcome.position,
+            MainAddress(IMAGE_START.0 + VERIFY_CHUNK_BYTES as u32)
+        );
+    }
+
+    #[test]
+    fn a_verification_can_be_spread_over_several_calls() {
+        let image = image(2 * PAGE_SIZE_BYTES + 300);
+        let mut flashed = image.clone();
+        flashed[3000] ^= 0x01;
+        let sim = programmed(&flashed);
+        let flash = sim.driver();
+
+        let mut position = IMAGE_START;
+        let mut mismatches = Vec::new();
+        let mut calls = 0;
+        loop {
+            let offset = (position.0 - IMAGE_START.0) as usize;
+            let outcome = flash
+                .verify_stream(
+                    position,
+                    stream(&image, offset, usize::MAX),
+                    opts(1000, 100),
+                )
+                .unwrap();
+            calls += 1;
+
+            assert!(outcome.position.0 - position.0 <= 1000);
+            mismatches.extend(outcome.first_mismatch);
+            position = outcome.position;
+
+            if outcome.complete {
+                break;
+            }
+        }
+
+        assert_eq!(calls, 5);
+        assert_eq!(position, MainAddress(IMAGE_START.0 + image.len() as u32));
+        assert_eq!(
+            mismatches
+                .iter()
+                .map(|mismatch| mismatch.address)
+                .collect::<Vec<_>>(),
+            [MainAddress(IMAGE_START.0 + 3000)]
+        );
+    }
+
+    #[test]
+    fn an_image_running_past_the_device_is_refused() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        let device_bytes = Geometry::W25N01GV.page_count() as u32 * PAGE_SIZE_BYTES as u32;
+
+        let image = [0xFF; 32];
+        assert_eq!(
+            flash.verify_stream(
+                MainAddress(device_bytes - 16),
+                stream(&image, 0, usize::MAX),
+                opts(u32::MAX, 1),
+            ),
+            Err(FlashError::OutOfBounds)
+        );
+    }
+}
@@ -0,0 +1,190 @@
+//! Opt-in checks that catch commands corrupted on the bus, for boards with enough EMI that QSPI
+//! transactions occasionally get mangled. A CRC over the data catches a corrupted payload, but a
+//! corrupted command (e.g. a flipped address bit programming the wrong page) would otherwise go
+//! unnoticed.
+//!
+//! With verified addressing on, the driver checks each destructive command right after sending it
+//! and returns `FlashError::CommandIntegritySuspect` if something doesn't add up:
+//!
+//! - After each program data load, the first few loaded bytes are read back from the data buffer
+//!   and compared. This costs a Read Configuration Register (to skip the check in continuous read
+//!   mode, where the buffer can't be read at a column) and a Fast Read of up to
+//!   `PROBE_WINDOW_BYTES` bytes.
+//! - After each Program Execute and Block Erase, the status register is read once. The device
+//!   should either be busy or have cleared the write enable latch by finishing; idle with the latch
+//!   still set means the device didn't take the command as sent.
+//!
+//! This won't catch everything, e.g. a corrupted page address on a Program Execute still looks
+//! like a valid program, but it turns most silent mis-writes into errors. Checks are skipped
+//! during dry runs since nothing reaches the device.
+
+use crate::{DryRunPolicy, FlashError, QspiBus, ReadMethod, W25N01GV};
+
+/// The most bytes read back after a load to check it landed where it was meant to
+pub const PROBE_WINDOW_BYTES: usize = 8;
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Turns verified addressing on or off, see the module docs for what it checks and what it
+    /// costs on the bus. Off by default.
+    pub fn set_verified_addressing(&mut self, enabled: bool) {
+        self.verified_addressing = enabled;
+    }
+
+    pub fn verified_addressing(&self) -> bool {
+        self.verified_addressing
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    fn verification_enabled(&self) -> bool {
+        self.verified_addressing && self.dry_run_policy == DryRunPolicy::Off
+    }
+
+    /// Reads back the start of a load that began at `column` and compares it with `bytes`
+    pub(crate) fn verify_load(&self, column: u16, bytes: &[u8]) -> Result<(), FlashError> {
+        if !self.verification_enabled() || bytes.is_empty() {
+            return Ok(());
+        }
+
+        // Continuous read mode ignores the column, so the probe would read the wrong bytes
+        if !self.read_configuration_register_unguarded()?.buf {
+            return Ok(());
+        }
+
+        let len = bytes.len().min(PROBE_WINDOW_BYTES);
+        let mut probe = [0_u8; PROBE_WINDOW_BYTES];
+        self.read_physical_columns(column, &mut probe[..len], ReadMethod::FastRead)?;
+
+        if probe[..len] != bytes[..len] {
+            return Err(FlashError::CommandIntegritySuspect);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the device took the Program Execute or Block Erase that was just sent
+    pub(crate) fn verify_submission(&self) -> Result<(), FlashError> {
+        if !self.verification_enabled() {
+            return Ok(());
+        }
+
+        let status_register = self.read_status_register_unguarded()?;
+        if !status_register.device_busy && status_register.write_enable_latch {
+            return Err(FlashError::CommandIntegritySuspect);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::*;
+    use crate::{
+        bus::{QspiError, QspiReadCommand, QspiWriteCommand},
+        new_w25_n01_gv,
+        sim::{NoDelay, SimFlash},
+        ReadMode, WriteMethod,
+    };
+
+    /// Corrupts the next command with the `target` opcode on its way to the device: a bit of its
+    /// column address flips, or of the opcode itself if it has no address
+    struct Corrupting {
+        sim: SimFlash,
+        target: Cell<Option<u8>>,
+    }
+
+    impl QspiBus for Corrupting {
+        fn write_command(&self, mut command: QspiWriteCommand) -> Result<(), QspiError> {
+            if let Some((opcode, mode)) = command.instruction {
+                if self.target.get() == Some(opcode) {
+                    self.target.set(None);
+                    match command.address {
+                        Some((address, address_mode)) => {
+                            command.address = Some((address ^ 0x04, address_mode))
+                        }
+                        None => command.instruction = Some((opcode ^ 0x01, mode)),
+                    }
+                }
+            }
+
+            self.sim.write_command(command)
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            self.sim.read_command(command, buffer)
+        }
+    }
+
+    fn corrupting(sim: &SimFlash, target: u8) -> W25N01GV<Corrupting, ReadMode> {
+        let mut flash = new_w25_n01_gv(Corrupting {
+            sim: sim.clone(),
+            target: Cell::new(Some(target)),
+        });
+        flash.set_verified_addressing(true);
+
+        flash
+    }
+
+    #[test]
+    fn a_load_to_the_wrong_column_is_caught_before_the_program() {
+        let sim = SimFlash::new();
+        let flash = corrupting(&sim, 0x02);
+
+        let result = flash.program_page(5, &[0xA5; 16], 0, WriteMethod::SingleLoad);
+        assert!(matches!(result, Err(FlashError::CommandIntegritySuspect)));
+        assert_eq!(sim.count(0x10), 0);
+        assert_eq!(sim.page(5)[0], 0xFF);
+    }
+
+    #[test]
+    fn a_program_execute_the_device_didnt_take_is_caught() {
+        let sim = SimFlash::new();
+        let flash = corrupting(&sim, 0x10);
+
+        let result = flash.program_page(5, &[0xA5; 16], 0, WriteMethod::SingleLoad);
+        assert!(matches!(result, Err(FlashError::CommandIntegritySuspect)));
+        assert_eq!(sim.page(5)[0], 0xFF);
+    }
+
+    #[test]
+    fn a_block_erase_the_device_didnt_take_is_caught() {
+        let sim = SimFlash::new();
+        sim.set_page(64, &[0; 4]);
+        let flash = corrupting(&sim, 0xD8);
+
+        let result = flash.erase_block(1, &mut NoDelay);
+        assert!(matches!(result, Err(FlashError::CommandIntegritySuspect)));
+        assert_eq!(sim.page(64)[0], 0);
+    }
+
+    #[test]
+    fn uncorrupted_commands_pass_and_the_mode_is_off_by_default() {
+        let sim = SimFlash::new();
+
+        // Nothing to corrupt
+        let flash = corrupting(&sim, 0x00);
+        let (flash, _) = flash
+            .program_page(5, &[0xA5; 16], 0, WriteMethod::SingleLoad)
+            .unwrap();
+        flash.erase_block(0, &mut NoDelay).unwrap();
+
+        // Without it the corrupted load programs the wrong bytes without complaint
+        let flash = new_w25_n01_gv(Corrupting {
+            sim: sim.clone(),
+            target: Cell::new(Some(0x02)),
+        });
+        assert!(!flash.verified_addressing());
+        flash
+            .program_page(6, &[0xA5; 16], 0, WriteMethod::SingleLoad)
+            .unwrap();
+        assert_eq!(sim.page(6)[..4], [0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(sim.page(6)[4], 0xA5);
+    }
+}
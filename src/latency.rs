@@ -0,0 +1,342 @@
+//! Refusing operations that could take longer than a fixed budget, for control paths that have to
+//! prove a bound on every flash call.
+//!
+//! With a budget set by `set_latency_budget_us`, each operation that waits on the device works out
+//! its worst case before sending anything: the bus time of its transactions, from the same math as
+//! `max_bus_hold`, plus the datasheet maximum of each busy period it waits through. If that doesn't
+//! fit the budget it returns `FlashError::WouldExceedBudget` without starting, otherwise it
+//! runs as usual. The checked operations are `erase_block`, `erase_range`, `commit`,
+//! `program_page`, `write_page_split`, `read_page_with_recovery`, `dump`, and `verify_against`.
+//! Helpers over many pages or blocks are estimated as a whole, so in practice they only fit when
+//! small, and the incremental helpers, which work through them one operation per call, are the way
+//! to go.
+//!
+//! Bus time is only counted once the bus clock is declared with `set_bus_clock_hz`, so declare it
+//! before relying on the budget. Readback verification is estimated at single line reads, the
+//! slowest.
+
+use crate::{
+    bus_hold::BusOp, recovery::RecoveryAttempt, verification::VerificationLevel, FlashError,
+    ReadMethod, WriteMethod, BUSY_POLL_INTERVAL_US, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES,
+    W25N01GV,
+};
+
+/// Page Data Read with ECC enabled, tRD
+pub const PAGE_READ_MAX_US: u32 = 60;
+/// Program Execute, tPP
+pub const PROGRAM_MAX_US: u32 = 700;
+/// Block Erase, tBE
+pub const BLOCK_ERASE_MAX_US: u32 = 10_000;
+/// Device Reset while an erase is running, tRST
+pub const RESET_MAX_US: u32 = 500;
+
+/// Something the device can stay busy with
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BusyClass {
+    PageRead,
+    Program,
+    BlockErase,
+    Reset,
+}
+
+impl BusyClass {
+    /// The datasheet maximum, in microseconds
+    pub const fn max_busy_us(self) -> u32 {
+        match self {
+            BusyClass::PageRead => PAGE_READ_MAX_US,
+            BusyClass::Program => PROGRAM_MAX_US,
+            BusyClass::BlockErase => BLOCK_ERASE_MAX_US,
+            BusyClass::Reset => RESET_MAX_US,
+        }
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Sets the longest any checked operation may take, see the module docs. None, the default,
+    /// checks nothing.
+    pub fn set_latency_budget_us(&mut self, budget_us: Option<u32>) {
+        self.latency_budget_us = budget_us;
+    }
+
+    pub fn latency_budget_us(&self) -> Option<u32> {
+        self.latency_budget_us
+    }
+
+    /// The worst case of waiting through `busy` after the transactions in `bus`, each an op and
+    /// its data length. Waiting costs a status register poll and up to a poll interval on top of
+    /// the busy time.
+    pub fn worst_case_us(&self, busy: Option<BusyClass>, bus: &[(BusOp, u32)]) -> u32 {
+        let bus_us = bus.iter().fold(0_u32, |total, (op, len)| {
+            total.saturating_add(self.max_bus_hold(*op, *len).unwrap_or(0))
+        });
+
+        match busy {
+            Some(busy) => bus_us
+                .saturating_add(busy.max_busy_us())
+                .saturating_add(self.max_bus_hold(BusOp::RegisterRead, 0).unwrap_or(0))
+                .saturating_add(BUSY_POLL_INTERVAL_US),
+            None => bus_us,
+        }
+    }
+
+    /// Reading a page into the data buffer and all of it out with `method`
+    pub fn page_read_worst_case_us(&self, method: ReadMethod) -> u32 {
+        self.worst_case_us(Some(BusyClass::PageRead), &[(BusOp::PageCommand, 0)])
+            .saturating_add(self.worst_case_us(
+                None,
+                &[
+                    (BusOp::RegisterRead, 0),
+                    (BusOp::Read(method), PAGE_SIZE_WITH_ECC_BYTES as u32),
+                ],
+            ))
+    }
+
+    /// Programming the data buffer into a page and verifying it at `level`
+    pub fn program_worst_case_us(&self, level: VerificationLevel) -> u32 {
+        // Write Enable is a lone instruction, counted as a page command to stay on the safe side
+        let program = self.worst_case_us(
+            Some(BusyClass::Program),
+            &[(BusOp::PageCommand, 0), (BusOp::PageCommand, 0)],
+        );
+
+        match level {
+            VerificationLevel::None => program,
+            VerificationLevel::CheckFailureBits => {
+                program.saturating_add(self.worst_case_us(None, &[(BusOp::RegisterRead, 0)]))
+            }
+            VerificationLevel::ReadbackCompare | VerificationLevel::ReadbackCrc => {
+                let capture = self.worst_case_us(
+                    None,
+                    &[(BusOp::Read(ReadMethod::FastRead), PAGE_SIZE_BYTES as u32)],
+                );
+
+                program
+                    .saturating_add(self.worst_case_us(None, &[(BusOp::RegisterRead, 0)]))
+                    .saturating_add(capture)
+                    .saturating_add(self.page_read_worst_case_us(ReadMethod::FastRead))
+            }
+        }
+    }
+
+    /// Loading `data_len` bytes and programming them at the driver's verification level, as
+    /// `program_page` does
+    pub fn program_page_worst_case_us(&self, data_len: u32, write_method: WriteMethod) -> u32 {
+        self.worst_case_us(None, &[(BusOp::Load(write_method), data_len)])
+            .saturating_add(self.program_worst_case_us(self.verification_level))
+    }
+
+    /// Loading a page's main area and `spare_len` spare bytes, then programming it at the
+    /// driver's verification level, as `write_page_split` does
+    pub fn write_page_worst_case_us(&self, spare_len: u32, write_method: WriteMethod) -> u32 {
+        self.worst_case_us(
+            None,
+            &[
+                (BusOp::RegisterRead, 0),
+                (BusOp::Load(write_method), PAGE_SIZE_BYTES as u32),
+                (BusOp::Load(write_method), spare_len),
+            ],
+        )
+        .saturating_add(self.program_worst_case_us(self.verification_level))
+    }
+
+    /// Erasing a block, as `erase_block` does
+    pub fn erase_worst_case_us(&self) -> u32 {
+        self.worst_case_us(
+            Some(BusyClass::BlockErase),
+            &[
+                (BusOp::PageCommand, 0),
+                (BusOp::PageCommand, 0),
+                (BusOp::RegisterRead, 0),
+            ],
+        )
+    }
+
+    /// Working through every attempt of a recovery policy, as `read_page_with_recovery` does
+    /// when all of them fail
+    pub(crate) fn recovery_worst_case_us(&self, attempts: &[Option<RecoveryAttempt>]) -> u32 {
+        attempts.iter().flatten().fold(0_u32, |total, attempt| {
+            let attempt_us = match attempt {
+                RecoveryAttempt::Read(method) => self.page_read_worst_case_us(*method),
+                // Saving and restoring the registers is a handful of register accesses
+                RecoveryAttempt::ResetThenRead(method) => self
+                    .page_read_worst_case_us(*method)
+                    .saturating_add(self.worst_case_us(
+                        Some(BusyClass::Reset),
+                        &[
+                            (BusOp::PageCommand, 0),
+                            (BusOp::RegisterRead, 0),
+                            (BusOp::RegisterRead, 0),
+                            (BusOp::RegisterRead, 0),
+                            (BusOp::RegisterWrite, 0),
+                            (BusOp::RegisterWrite, 0),
+                            (BusOp::RegisterWrite, 0),
+                        ],
+                    )),
+            };
+
+            total.saturating_add(attempt_us)
+        })
+    }
+
+    /// Returns `FlashError::WouldExceedBudget` if `estimated_us` doesn't fit the budget
+    pub(crate) fn check_latency_budget(&self, estimated_us: u32) -> Result<(), FlashError> {
+        match self.latency_budget_us {
+            Some(budget_us) if estimated_us > budget_us => Err(FlashError::WouldExceedBudget {
+                estimated_us,
+                budget_us,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{NoDelay, SimFlash},
+        LoadMode,
+    };
+
+    /// The extra cost of waiting: a status register poll and a poll interval
+    const WAIT_WITHOUT_CLOCK_US: u32 = BUSY_POLL_INTERVAL_US;
+
+    #[test]
+    fn busy_classes_use_the_datasheet_maximums() {
+        assert_eq!(BusyClass::PageRead.max_busy_us(), 60);
+        assert_eq!(BusyClass::Program.max_busy_us(), 700);
+        assert_eq!(BusyClass::BlockErase.max_busy_us(), 10_000);
+        assert_eq!(BusyClass::Reset.max_busy_us(), 500);
+    }
+
+    #[test]
+    fn without_a_bus_clock_only_busy_time_is_counted() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+
+        assert_eq!(
+            flash.page_read_worst_case_us(ReadMethod::FastRead),
+            PAGE_READ_MAX_US + WAIT_WITHOUT_CLOCK_US
+        );
+        assert_eq!(
+            flash.program_worst_case_us(VerificationLevel::CheckFailureBits),
+            PROGRAM_MAX_US + WAIT_WITHOUT_CLOCK_US
+        );
+        assert_eq!(
+            flash.erase_worst_case_us(),
+            BLOCK_ERASE_MAX_US + WAIT_WITHOUT_CLOCK_US
+        );
+        // Readback waits through a page read as well
+        assert_eq!(
+            flash.program_worst_case_us(VerificationLevel::ReadbackCompare),
+            PROGRAM_MAX_US + PAGE_READ_MAX_US + 2 * WAIT_WITHOUT_CLOCK_US
+        );
+    }
+
+    #[test]
+    fn bus_time_is_added_at_the_declared_clock() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        // One cycle a microsecond, so cycles and microseconds are the same number
+        flash.set_bus_clock_hz(1_000_000);
+
+        let page_command = 8 + 8 + 16;
+        let register_read = 8 + 8 + 8;
+        let page_out = 8 + 16 + 8 + PAGE_SIZE_WITH_ECC_BYTES as u32 * 8;
+
+        assert_eq!(
+            flash.page_read_worst_case_us(ReadMethod::FastRead),
+            page_command
+                + PAGE_READ_MAX_US
+                + register_read
+                + BUSY_POLL_INTERVAL_US
+                + register_read
+                + page_out
+        );
+        assert_eq!(
+            flash.erase_worst_case_us(),
+            2 * page_command
+                + register_read
+                + BLOCK_ERASE_MAX_US
+                + register_read
+                + BUSY_POLL_INTERVAL_US
+        );
+        assert_eq!(
+            flash.program_page_worst_case_us(100, WriteMethod::SingleLoad),
+            8 + 16
+                + 100 * 8
+                + 2 * page_command
+                + PROGRAM_MAX_US
+                + register_read
+                + BUSY_POLL_INTERVAL_US
+                + register_read
+        );
+    }
+
+    #[test]
+    fn a_2ms_budget_permits_page_operations_and_refuses_erases() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_bus_clock_hz(104_000_000);
+        flash.set_latency_budget_us(Some(2000));
+
+        // Page reads and programs fit
+        let (flash, failed) = flash
+            .program_page(64, &[0x5A; 16], 0, WriteMethod::QuadLoad)
+            .unwrap();
+        assert!(!failed);
+        let stats = flash
+            .dump(64, 7, ReadMethod::FastRead, &mut NoDelay, |_, _| {})
+            .unwrap();
+        assert_eq!(stats.pages_dumped, 7);
+
+        let write_flash = flash.into_write_mode().unwrap();
+        write_flash
+            .load_to_data_buffer(
+                &[0xA5; 16],
+                0,
+                WriteMethod::SingleLoad,
+                LoadMode::ResetThenLoad,
+            )
+            .unwrap();
+        let flash = write_flash.commit(65, &mut NoDelay).unwrap();
+
+        // A dump one page longer doesn't, and neither does any erase
+        sim.clear_log();
+        let estimated_us = flash.page_read_worst_case_us(ReadMethod::FastRead) * 9;
+        assert_eq!(
+            flash
+                .dump(64, 8, ReadMethod::FastRead, &mut NoDelay, |_, _| {})
+                .err(),
+            Some(FlashError::WouldExceedBudget {
+                estimated_us,
+                budget_us: 2000,
+            })
+        );
+        let estimated_us = flash.erase_worst_case_us();
+        assert_eq!(
+            flash.erase_block(1, &mut NoDelay).err(),
+            Some(FlashError::WouldExceedBudget {
+                estimated_us,
+                budget_us: 2000,
+            })
+        );
+        // Refused before anything is sent
+        assert!(sim.commands().is_empty());
+        assert_eq!(sim.page(64)[..16], [0x5A; 16]);
+    }
+
+    #[test]
+    fn without_a_budget_nothing_is_refused() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_bus_clock_hz(104_000_000);
+        flash.set_latency_budget_us(Some(2000));
+        flash.set_latency_budget_us(None);
+
+        assert_eq!(flash.latency_budget_us(), None);
+        assert_eq!(flash.check_latency_budget(u32::MAX), Ok(()));
+        flash.erase_block(1, &mut NoDelay).unwrap();
+    }
+}
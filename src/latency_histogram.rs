@@ -0,0 +1,364 @@
+//! Histograms of how long the device actually stays busy, for sizing watchdog windows from the tail
+//! of a population of chips rather than from averages. Behind the `latency-histograms` feature,
+//! since it adds the histograms to the driver.
+//!
+//! A busy period starts when a Program Execute, Block Erase, or Device Reset goes out and ends at
+//! the first status register read that finds the device idle, so every wait path feeds the
+//! histograms, however it polls. The duration is only as tight as that polling: a wait that sleeps
+//! between polls rounds it up by up to a poll interval, and a period nothing waits on is timed to
+//! whatever status read comes next. Both ends are stamped with the time source from
+//! `set_time_source`, which has to count microseconds for the buckets to mean anything. Nothing
+//! is recorded without one.
+//!
+//! Each histogram has `LATENCY_BUCKETS` log spaced buckets, four per decade from 10us to 100ms.
+//! `LatencyHistograms::serialize` packs all three into at most `MAX_SERIALIZED_BYTES` (a few dozen
+//! bytes in practice) for a telemetry frame, and `LatencyHistograms::deserialize` unpacks them on
+//! the ground, since the crate builds for the host as well.
+
+use crate::{latency::BusyClass, FlashCommands, W25N01GV};
+
+pub const LATENCY_BUCKETS: usize = 16;
+
+/// The shortest duration each bucket takes, in microseconds. The first bucket also takes anything
+/// shorter than 10us and the last anything longer than 100ms.
+pub const BUCKET_LOWER_BOUNDS_US: [u32; LATENCY_BUCKETS] = [
+    0, 18, 32, 56, 100, 178, 316, 562, 1_000, 1_778, 3_162, 5_623, 10_000, 17_783, 31_623, 56_234,
+];
+
+/// A format version byte, then each histogram's counts as LEB128 varints
+pub const MAX_SERIALIZED_BYTES: usize = 1 + 3 * LATENCY_BUCKETS * 5;
+
+const SERIALIZATION_VERSION: u8 = 1;
+
+/// Counts of busy periods by duration, see the module docs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LatencyHistogram {
+    pub counts: [u32; LATENCY_BUCKETS],
+}
+
+impl LatencyHistogram {
+    /// The bucket a duration of `duration_us` falls in
+    pub fn bucket_of(duration_us: u32) -> usize {
+        BUCKET_LOWER_BOUNDS_US
+            .iter()
+            .rposition(|lower_bound| duration_us >= *lower_bound)
+            .unwrap_or(0)
+    }
+
+    /// The durations a bucket takes, from its lower bound up to but not including the next
+    /// bucket's. The last bucket has no upper bound.
+    pub fn bucket_range_us(index: usize) -> (u32, Option<u32>) {
+        (
+            BUCKET_LOWER_BOUNDS_US[index],
+            BUCKET_LOWER_BOUNDS_US.get(index + 1).copied(),
+        )
+    }
+
+    pub fn record(&mut self, duration_us: u32) {
+        let count = &mut self.counts[LatencyHistogram::bucket_of(duration_us)];
+        *count = count.saturating_add(1);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(|count| *count as u64).sum()
+    }
+
+    /// The bucket the given quantile, in parts per million, falls in, e.g. 999_000 for p99.9.
+    /// Returns None for an empty histogram.
+    pub fn quantile_bucket(&self, quantile_ppm: u32) -> Option<usize> {
+        let total = self.total();
+        if total == 0 {
+            return None;
+        }
+
+        let rank = (total * quantile_ppm.min(1_000_000) as u64)
+            .div_ceil(1_000_000)
+            .max(1);
+        let mut seen = 0_u64;
+
+        self.counts.iter().position(|count| {
+            seen += *count as u64;
+            seen >= rank
+        })
+    }
+}
+
+/// A histogram for each kind of busy period, see the module docs
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LatencyHistograms {
+    pub program: LatencyHistogram,
+    pub erase: LatencyHistogram,
+    pub reset: LatencyHistogram,
+}
+
+impl LatencyHistograms {
+    /// Packs the histograms into `bytes`, returning how many bytes were used
+    pub fn serialize(&self, bytes: &mut [u8; MAX_SERIALIZED_BYTES]) -> usize {
+        bytes[0] = SERIALIZATION_VERSION;
+        let mut len = 1;
+
+        for histogram in [&self.program, &self.erase, &self.reset].iter() {
+            for count in histogram.counts.iter() {
+                let mut value = *count;
+                loop {
+                    let byte = (value & 0x7F) as u8;
+                    value >>= 7;
+
+                    if value == 0 {
+                        bytes[len] = byte;
+                        len += 1;
+                        break;
+                    }
+
+                    bytes[len] = byte | 0x80;
+                    len += 1;
+                }
+            }
+        }
+
+        len
+    }
+
+    /// Unpacks histograms packed by `serialize`, or returns None if `bytes` doesn't hold them
+    pub fn deserialize(bytes: &[u8]) -> Option<LatencyHistograms> {
+        let (version, mut rest) = bytes.split_first()?;
+        if *version != SERIALIZATION_VERSION {
+            return None;
+        }
+
+        let mut histograms = LatencyHistograms::default();
+        for histogram in [
+            &mut histograms.program,
+            &mut histograms.erase,
+            &mut histograms.reset,
+        ]
+        .iter_mut()
+        {
+            for count in histogram.counts.iter_mut() {
+                let mut value = 0_u32;
+                let mut shift = 0;
+                loop {
+                    let (byte, remaining) = rest.split_first()?;
+                    rest = remaining;
+
+                    if shift > 28 {
+                        return None;
+                    }
+                    value |= ((byte & 0x7F) as u32) << shift;
+                    shift += 7;
+
+                    if byte & 0x80 == 0 {
+                        break;
+                    }
+                }
+
+                *count = value;
+            }
+        }
+
+        Some(histograms)
+    }
+
+    fn histogram_mut(&mut self, class: BusyClass) -> Option<&mut LatencyHistogram> {
+        match class {
+            BusyClass::Program => Some(&mut self.program),
+            BusyClass::BlockErase => Some(&mut self.erase),
+            BusyClass::Reset => Some(&mut self.reset),
+            BusyClass::PageRead => None,
+        }
+    }
+}
+
+/// The busy period being timed, if any, and the histograms so far
+pub(crate) struct BusyTimer {
+    started: Option<(BusyClass, u64)>,
+    histograms: LatencyHistograms,
+}
+
+impl BusyTimer {
+    pub(crate) fn new() -> BusyTimer {
+        BusyTimer {
+            started: None,
+            histograms: LatencyHistograms::default(),
+        }
+    }
+}
+
+fn busy_class_of(opcode: u8) -> Option<BusyClass> {
+    if opcode == FlashCommands::ProgramExecute as u8 {
+        Some(BusyClass::Program)
+    } else if opcode == FlashCommands::Erase128KBBlock as u8 {
+        Some(BusyClass::BlockErase)
+    } else if opcode == FlashCommands::DeviceReset as u8 {
+        Some(BusyClass::Reset)
+    } else {
+        None
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    pub fn latency_histograms(&self) -> LatencyHistograms {
+        self.busy_timer.borrow().histograms
+    }
+
+    pub fn reset_latency_histograms(&mut self) {
+        self.busy_timer.borrow_mut().histograms = LatencyHistograms::default();
+    }
+
+    /// Starts timing a busy period if the command sent starts one
+    pub(crate) fn record_busy_start(&self, opcode: u8) {
+        if let (Some(class), Some(now)) = (busy_class_of(opcode), self.time_source) {
+            self.busy_timer.borrow_mut().started = Some((class, now()));
+        }
+    }
+
+    /// Ends the busy period being timed once a status read finds the device idle
+    pub(crate) fn record_busy_status(&self, busy: bool) {
+        if busy {
+            return;
+        }
+
+        let mut timer = self.busy_timer.borrow_mut();
+        if let (Some((class, started)), Some(now)) = (timer.started.take(), self.time_source) {
+            let duration_us = now().saturating_sub(started).min(u32::MAX as u64) as u32;
+
+            if let Some(histogram) = timer.histograms.histogram_mut(class) {
+                histogram.record(duration_us);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{NoDelay, SimFlash},
+        ReadMethod, ReadMode, WriteMethod, PAGE_SIZE_WITH_ECC_BYTES,
+    };
+    use std::{cell::Cell, thread_local};
+
+    thread_local! {
+        static NOW_US: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn now_us() -> u64 {
+        NOW_US.with(|now| now.get())
+    }
+
+    /// A driver whose clock moves on `step_us` with every status register read
+    fn timed(sim: &SimFlash, step_us: u64) -> W25N01GV<SimFlash, ReadMode> {
+        sim.set_hook(move |command| {
+            if command.opcode == 0x05 && command.alternative_bytes == [0xC0] {
+                NOW_US.with(|now| now.set(now.get() + step_us));
+            }
+        });
+
+        let mut flash = sim.driver();
+        flash.set_time_source(Some(now_us));
+        flash
+    }
+
+    #[test]
+    fn durations_land_in_their_log_spaced_buckets() {
+        assert_eq!(LatencyHistogram::bucket_of(0), 0);
+        assert_eq!(LatencyHistogram::bucket_of(17), 0);
+        assert_eq!(LatencyHistogram::bucket_of(18), 1);
+        assert_eq!(LatencyHistogram::bucket_of(99), 3);
+        assert_eq!(LatencyHistogram::bucket_of(100), 4);
+        assert_eq!(LatencyHistogram::bucket_of(56_234), 15);
+        assert_eq!(LatencyHistogram::bucket_of(u32::MAX), 15);
+
+        assert_eq!(LatencyHistogram::bucket_range_us(4), (100, Some(178)));
+        assert_eq!(LatencyHistogram::bucket_range_us(15), (56_234, None));
+    }
+
+    #[test]
+    fn quantiles_find_the_tail_bucket() {
+        let mut histogram = LatencyHistogram::default();
+        assert_eq!(histogram.quantile_bucket(500_000), None);
+
+        for _ in 0..999 {
+            histogram.record(200);
+        }
+        histogram.record(20_000);
+
+        assert_eq!(histogram.total(), 1000);
+        assert_eq!(histogram.quantile_bucket(0), Some(5));
+        assert_eq!(histogram.quantile_bucket(999_000), Some(5));
+        assert_eq!(histogram.quantile_bucket(999_001), Some(13));
+        assert_eq!(histogram.quantile_bucket(2_000_000), Some(13));
+    }
+
+    #[test]
+    fn histograms_survive_serialization() {
+        let mut histograms = LatencyHistograms::default();
+        histograms.program.counts[3] = 1;
+        histograms.erase.counts[12] = 300;
+        histograms.reset.counts[15] = u32::MAX;
+
+        let mut bytes = [0_u8; MAX_SERIALIZED_BYTES];
+        let len = histograms.serialize(&mut bytes);
+        // A byte a count, 300 takes two and u32::MAX five
+        assert_eq!(len, 1 + 3 * LATENCY_BUCKETS + 1 + 4);
+        assert_eq!(
+            LatencyHistograms::deserialize(&bytes[..len]),
+            Some(histograms)
+        );
+
+        assert_eq!(LatencyHistograms::deserialize(&bytes[..len - 1]), None);
+        bytes[0] = SERIALIZATION_VERSION + 1;
+        assert_eq!(LatencyHistograms::deserialize(&bytes[..len]), None);
+        assert_eq!(LatencyHistograms::deserialize(&[]), None);
+    }
+
+    #[test]
+    fn programs_and_erases_are_timed_to_the_first_idle_status_read() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(3);
+        let flash = timed(&sim, 150);
+
+        let (flash, _) = flash
+            .program_page(64, &[0x5A], 0, WriteMethod::SingleLoad)
+            .unwrap();
+        let flash = flash.erase_block(2, &mut NoDelay).unwrap();
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        flash
+            .read_page(64, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+
+        // Three busy reads and the idle one, 150us apart
+        let histograms = flash.latency_histograms();
+        let bucket = LatencyHistogram::bucket_of(600);
+        assert_eq!(histograms.program.total(), 1);
+        assert_eq!(histograms.program.counts[bucket], 1);
+        assert_eq!(histograms.erase.total(), 1);
+        assert_eq!(histograms.erase.counts[bucket], 1);
+        // The page read has no histogram to land in, and nothing was reset
+        assert_eq!(histograms.reset.total(), 0);
+    }
+
+    #[test]
+    fn nothing_is_recorded_without_a_time_source() {
+        let sim = SimFlash::new();
+        let mut flash = timed(&sim, 150);
+        flash.set_time_source(None);
+
+        let flash = flash.erase_block(2, &mut NoDelay).unwrap();
+        assert_eq!(flash.latency_histograms(), LatencyHistograms::default());
+    }
+
+    #[test]
+    fn histograms_can_be_reset() {
+        let sim = SimFlash::new();
+        let flash = timed(&sim, 150);
+
+        let mut flash = flash.erase_block(2, &mut NoDelay).unwrap();
+        assert_eq!(flash.latency_histograms().erase.total(), 1);
+
+        flash.reset_latency_histograms();
+        assert_eq!(flash.latency_histograms(), LatencyHistograms::default());
+    }
+}
@@ -0,0 +1,697 @@
+//! Declarative provisioning. A `Layout` describes how the device is split into regions, and
+//! `Layout::create` prepares a device to match it: checking the regions, erasing them, setting up
+//! the on-flash state of the structures that live in them, and writing a descriptor page so
+//! `MountedLayout::mount` can find the regions again on later boots.
+//!
+//! Bad blocks are never erased, since that would destroy their factory markers. They're counted
+//! against each region's `min_good_blocks` and reported, and structures that hand out blocks
+//! (like the `BlockAllocator`) are told to skip them.
+//!
+//! `metadata_footprint` lists the blocks of a layout that the crate writes for itself, so they can
+//! be left out of an application's integrity measurement. It's worked out from the layout alone,
+//! so bad blocks are listed too even though nothing is ever written to them. It only knows about
+//! the structures a layout places: the descriptor, the allocator's slots, and the log in the `Log`
+//! region, along with an `EventLog` mounted on that region's blocks. Structures mounted on blocks
+//! the application picks, like a `SpanningRecordWriter`, a `TwoPhase` commit block, or an
+//! `EventLog` elsewhere, are the application's to add to its own list.
+
+use core::convert::TryInto;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    allocator::Bitmap,
+    block_header::{BlockHeader, StructureKind},
+    digest::Crc32,
+    log_sink::FlashLogSink,
+    BlockAddress, BlockAllocator, FlashError, Geometry, QspiBus, ReadMethod, ReadMode,
+    StorageError, WriteMethod, BLOCK_COUNT, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+pub const MAX_LAYOUT_REGIONS: usize = 8;
+
+const LAYOUT_MAGIC: u32 = 0x4C41_594F;
+const HEADER_BYTES: usize = 8;
+const REGION_BYTES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    /// Left erased for the application to manage itself
+    Raw,
+    /// Blocks for the application's data, handed out by the `BlockAllocator`
+    Data,
+    /// The two slot blocks of a `BlockAllocator`, which manages every `Data` region
+    AllocatorSlots,
+    /// A `FlashLogSink`
+    Log,
+}
+
+impl RegionKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            RegionKind::Raw => 0,
+            RegionKind::Data => 1,
+            RegionKind::AllocatorSlots => 2,
+            RegionKind::Log => 3,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<RegionKind> {
+        match value {
+            0 => Some(RegionKind::Raw),
+            1 => Some(RegionKind::Data),
+            2 => Some(RegionKind::AllocatorSlots),
+            3 => Some(RegionKind::Log),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub first_block: u16,
+    pub block_count: u16,
+    /// Creating the layout fails if the region has fewer good blocks than this
+    pub min_good_blocks: u16,
+}
+
+impl Region {
+    fn blocks(&self) -> core::ops::Range<u16> {
+        self.first_block..self.first_block + self.block_count
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        (self.first_block as u32) < other.first_block as u32 + other.block_count as u32
+            && (other.first_block as u32) < self.first_block as u32 + self.block_count as u32
+    }
+}
+
+/// A description of how the device is split into regions. The descriptor block holds the
+/// layout itself and mustn't be part of any region.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout<'a> {
+    pub descriptor_block: u16,
+    pub regions: &'a [Region],
+}
+
+/// What the crate keeps in a block of a layout it writes for itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MetadataKind {
+    /// The layout's descriptor block
+    LayoutDescriptor,
+    /// A slot block of the `BlockAllocator`
+    AllocatorSlot,
+    /// A block of the `FlashLogSink` in a `Log` region, headers and records alike
+    Log,
+}
+
+/// Every block of `layout` the crate writes for itself, the descriptor block first and then the
+/// blocks of each `AllocatorSlots` and `Log` region in region order. `Raw` and `Data` regions are
+/// left to the application, so none of their blocks are listed. `Layout::create` erases their
+/// good blocks once, but never programs them. Structures placed outside the layout aren't
+/// included, see the module docs.
+pub fn metadata_footprint<'a>(
+    layout: &Layout<'a>,
+) -> impl Iterator<Item = (BlockAddress, MetadataKind)> + 'a {
+    let descriptor = (
+        BlockAddress(layout.descriptor_block),
+        MetadataKind::LayoutDescriptor,
+    );
+
+    let regions = layout.regions.iter().filter_map(|region| {
+        let kind = match region.kind {
+            RegionKind::AllocatorSlots => MetadataKind::AllocatorSlot,
+            RegionKind::Log => MetadataKind::Log,
+            RegionKind::Raw | RegionKind::Data => return None,
+        };
+
+        Some(
+            region
+                .blocks()
+                .map(move |block| (BlockAddress(block), kind)),
+        )
+    });
+
+    core::iter::once(descriptor).chain(regions.flatten())
+}
+
+/// What `Layout::create` found in a region
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RegionReport {
+    pub good_blocks: u16,
+    /// Bad blocks within the region, skipped rather than erased
+    pub bad_blocks: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LayoutReport {
+    /// One report per region, in the same order as the layout's regions
+    pub regions: [Option<RegionReport>; MAX_LAYOUT_REGIONS],
+}
+
+impl<'a> Layout<'a> {
+    fn validate(&self) -> Result<(), StorageError> {
+        if self.regions.len() > MAX_LAYOUT_REGIONS
+            || self
+                .regions
+                .iter()
+                .filter(|region| region.kind == RegionKind::AllocatorSlots)
+                .any(|region| region.block_count != 2)
+        {
+            return Err(StorageError::InvalidLayout);
+        }
+
+        let descriptor = Region {
+            kind: RegionKind::Raw,
+            first_block: self.descriptor_block,
+            block_count: 1,
+            min_good_blocks: 0,
+        };
+
+        for (index, region) in self.regions.iter().enumerate() {
+            if region.block_count == 0
+                || region.first_block as usize + region.block_count as usize > BLOCK_COUNT
+            {
+                return Err(FlashError::OutOfBounds.into());
+            }
+
+            if region.overlaps(&descriptor)
+                || self.regions[index + 1..]
+                    .iter()
+                    .any(|other| region.overlaps(other))
+            {
+                return Err(StorageError::InvalidLayout);
+            }
+        }
+
+        if self.descriptor_block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        Ok(())
+    }
+
+    /// Prepares the device to match the layout, replacing whatever was in the regions before.
+    /// Every region is checked for bad blocks before anything is erased, so a layout that doesn't
+    /// fit the device fails without changing it. The descriptor block and both allocator slot
+    /// blocks must be good, since they can't be skipped.
+    pub fn create<BUS: QspiBus, D>(
+        &self,
+        flash: W25N01GV<BUS, ReadMode>,
+        read_method: ReadMethod,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<(W25N01GV<BUS, ReadMode>, LayoutReport), StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        self.validate()?;
+
+        flash.check_block0(self.descriptor_block, 1)?;
+        for region in self.regions.iter() {
+            flash.check_block0(region.first_block, region.block_count)?;
+        }
+
+        if flash.is_bad_block(self.descriptor_block, read_method)? {
+            return Err(StorageError::InsufficientGoodBlocks {
+                first_block: self.descriptor_block,
+                good_blocks: 0,
+            });
+        }
+
+        let mut report = LayoutReport {
+            regions: [None; MAX_LAYOUT_REGIONS],
+        };
+        let mut bad_blocks = Bitmap::new();
+
+        for (index, region) in self.regions.iter().enumerate() {
+            let mut region_report = RegionReport::default();
+            for block in region.blocks() {
+                if flash.is_bad_block(block, read_method)? {
+                    bad_blocks.set(block, true);
+                    region_report.bad_blocks += 1;
+                } else {
+                    region_report.good_blocks += 1;
+                }
+            }
+
+            let min_good_blocks = match region.kind {
+                RegionKind::AllocatorSlots => region.block_count,
+                _ => region.min_good_blocks,
+            };
+            if region_report.good_blocks < min_good_blocks {
+                return Err(StorageError::InsufficientGoodBlocks {
+                    first_block: region.first_block,
+                    good_blocks: region_report.good_blocks,
+                });
+            }
+
+            report.regions[index] = Some(region_report);
+        }
+
+        let mut flash = flash;
+        for region in self.regions.iter() {
+            for block in region.blocks() {
+                if !bad_blocks.get(block) {
+                    flash = flash.erase_block(block, delay)?;
+                }
+            }
+        }
+
+        for region in self.regions.iter() {
+            if region.kind == RegionKind::AllocatorSlots {
+                let mut allocator = self.allocator(region, &bad_blocks);
+                flash = allocator.save(flash, read_method, write_method, delay)?;
+            }
+        }
+
+        let mut page = [0xFF_u8; PAGE_SIZE_BYTES];
+        self.serialize(&mut page, flash.crc32_digest());
+        BlockHeader {
+            kind: StructureKind::LayoutDescriptor,
+            region_id: 0,
+            sequence: 0,
+        }
+        .write_into(&mut page, flash.crc32_digest())?;
+
+        let flash = flash.erase_block(self.descriptor_block, delay)?;
+        let flash = flash.into_write_mode()?.write_page_split(
+            Geometry::W25N01GV.block_first_page(self.descriptor_block),
+            &page,
+            &[],
+            write_method,
+            delay,
+        )?;
+
+        Ok((flash, report))
+    }
+
+    /// A fresh allocator for the slots region that can only hand out good blocks of `Data` regions
+    fn allocator(&self, slots: &Region, bad_blocks: &Bitmap) -> BlockAllocator {
+        let mut allocator = BlockAllocator::new([slots.first_block, slots.first_block + 1]);
+        allocator.mark_reserved(0..BLOCK_COUNT as u16);
+
+        for region in self.regions.iter() {
+            if region.kind != RegionKind::Data {
+                continue;
+            }
+
+            for block in region.blocks() {
+                if !bad_blocks.get(block) {
+                    allocator.unreserve(block);
+                }
+            }
+        }
+
+        allocator
+    }
+
+    fn serialize(&self, page: &mut [u8; PAGE_SIZE_BYTES], digest: Crc32) {
+        page[0..4].copy_from_slice(&LAYOUT_MAGIC.to_le_bytes());
+        page[4..6].copy_from_slice(&self.descriptor_block.to_le_bytes());
+        page[6] = self.regions.len() as u8;
+        page[7] = 0;
+
+        for (index, region) in self.regions.iter().enumerate() {
+            let entry = &mut page[HEADER_BYTES + index * REGION_BYTES..][..REGION_BYTES];
+            entry[0] = region.kind.to_u8();
+            entry[1] = 0;
+            entry[2..4].copy_from_slice(&region.first_block.to_le_bytes());
+            entry[4..6].copy_from_slice(&region.block_count.to_le_bytes());
+            entry[6..8].copy_from_slice(&region.min_good_blocks.to_le_bytes());
+        }
+
+        let crc_offset = HEADER_BYTES + self.regions.len() * REGION_BYTES;
+        let crc = digest.checksum(&page[..crc_offset]);
+        page[crc_offset..crc_offset + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+/// A layout read back from its descriptor block
+#[derive(Debug, Clone, Copy)]
+pub struct MountedLayout {
+    regions: [Option<Region>; MAX_LAYOUT_REGIONS],
+}
+
+impl MountedLayout {
+    /// Reads the layout written by `Layout::create` from the descriptor block, or returns None if
+    /// the block doesn't hold a valid layout
+    pub fn mount<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        descriptor_block: u16,
+        method: ReadMethod,
+    ) -> Result<Option<MountedLayout>, StorageError> {
+        if descriptor_block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        flash.read_memory_to_data_buffer(Geometry::W25N01GV.block_first_page(descriptor_block))?;
+        flash.wait_while_busy()?;
+        flash.read_data_buffer(&mut buffer, method)?;
+
+        Ok(MountedLayout::deserialize(
+            &buffer[..PAGE_SIZE_BYTES],
+            flash.crc32_digest(),
+        ))
+    }
+
+    fn deserialize(page: &[u8], digest: Crc32) -> Option<MountedLayout> {
+        let read_u16 = |offset: usize| u16::from_le_bytes([page[offset], page[offset + 1]]);
+
+        let region_count = page[6] as usize;
+        if u32::from_le_bytes(page[0..4].try_into().ok()?) != LAYOUT_MAGIC
+            || region_count > MAX_LAYOUT_REGIONS
+        {
+            return None;
+        }
+
+        let crc_offset = HEADER_BYTES + region_count * REGION_BYTES;
+        if u32::from_le_bytes(page[crc_offset..crc_offset + 4].try_into().ok()?)
+            != digest.checksum(&page[..crc_offset])
+        {
+            return None;
+        }
+
+        let mut layout = MountedLayout {
+            regions: [None; MAX_LAYOUT_REGIONS],
+        };
+
+        for (index, slot) in layout.regions.iter_mut().take(region_count).enumerate() {
+            let offset = HEADER_BYTES + index * REGION_BYTES;
+
+            *slot = Some(Region {
+                kind: RegionKind::from_u8(page[offset])?,
+                first_block: read_u16(offset + 2),
+                block_count: read_u16(offset + 4),
+                min_good_blocks: read_u16(offset + 6),
+            });
+        }
+
+        Some(layout)
+    }
+
+    pub fn regions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().flatten()
+    }
+
+    /// The first region of a kind, if the layout has one
+    pub fn region(&self, kind: RegionKind) -> Option<&Region> {
+        self.regions().find(|region| region.kind == kind)
+    }
+
+    /// Loads the allocator from the layout's `AllocatorSlots` region
+    pub fn load_allocator<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        method: ReadMethod,
+    ) -> Result<Option<BlockAllocator>, StorageError> {
+        match self.region(RegionKind::AllocatorSlots) {
+            Some(region) => {
+                BlockAllocator::load(flash, [region.first_block, region.first_block + 1], method)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Mounts the log in the layout's `Log` region
+    pub fn mount_log<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        method: ReadMethod,
+    ) -> Result<Option<FlashLogSink>, StorageError> {
+        match self.region(RegionKind::Log) {
+            Some(region) => Ok(Some(FlashLogSink::mount(
+                flash,
+                region.first_block,
+                region.block_count,
+                method,
+            )?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+
+    const REGIONS: [Region; 2] = [
+        Region {
+            kind: RegionKind::AllocatorSlots,
+            first_block: 2,
+            block_count: 2,
+            min_good_blocks: 2,
+        },
+        Region {
+            kind: RegionKind::Data,
+            first_block: 4,
+            block_count: 4,
+            min_good_blocks: 3,
+        },
+    ];
+
+    const LAYOUT: Layout = Layout {
+        descriptor_block: 1,
+        regions: &REGIONS,
+    };
+
+    fn erases_of(sim: &SimFlash, block: u16) -> usize {
+        sim.commands()
+            .iter()
+            .filter(|command| {
+                command.opcode == 0xD8
+                    && command.page_address() == Some(Geometry::W25N01GV.block_first_page(block))
+            })
+            .count()
+    }
+
+    #[test]
+    fn create_skips_bad_blocks_without_reading_their_markers_twice() {
+        let sim = SimFlash::new();
+        sim.mark_bad(5);
+
+        let (flash, report) = LAYOUT
+            .create(
+                sim.driver(),
+                ReadMethod::FastRead,
+                WriteMethod::SingleLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+
+        assert_eq!(
+            report.regions[1],
+            Some(RegionReport {
+                good_blocks: 3,
+                bad_blocks: 1
+            })
+        );
+        assert_eq!(erases_of(&sim, 5), 0);
+        assert_eq!(
+            sim.page(Geometry::W25N01GV.block_first_page(5))[PAGE_SIZE_BYTES],
+            0
+        );
+
+        // One marker read for the descriptor and one per region block, nothing more
+        let marker_reads = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0x0B && command.address.unwrap().0 >= 2048)
+            .count();
+        assert_eq!(marker_reads, 1 + 2 + 4 + 1);
+
+        let allocator = MountedLayout::mount(&flash, 1, ReadMethod::FastRead)
+            .unwrap()
+            .unwrap()
+            .load_allocator(&flash, ReadMethod::FastRead)
+            .unwrap()
+            .unwrap();
+        assert!(allocator.is_free(4));
+        assert!(!allocator.is_free(5));
+    }
+
+    #[test]
+    fn create_refuses_a_bad_descriptor_block() {
+        let sim = SimFlash::new();
+        sim.mark_bad(1);
+
+        let result = LAYOUT.create(
+            sim.driver(),
+            ReadMethod::FastRead,
+            WriteMethod::SingleLoad,
+            &mut NoDelay,
+        );
+
+        assert!(matches!(
+            result,
+            Err(StorageError::InsufficientGoodBlocks {
+                first_block: 1,
+                good_blocks: 0
+            })
+        ));
+        assert_eq!(sim.count(0xD8), 0);
+    }
+
+    #[test]
+    fn create_refuses_a_bad_allocator_slot() {
+        let sim = SimFlash::new();
+        sim.mark_bad(3);
+
+        let result = LAYOUT.create(
+            sim.driver(),
+            ReadMethod::FastRead,
+            WriteMethod::SingleLoad,
+            &mut NoDelay,
+        );
+
+        assert!(matches!(
+            result,
+            Err(StorageError::InsufficientGoodBlocks {
+                first_block: 2,
+                good_blocks: 1
+            })
+        ));
+        assert_eq!(sim.count(0xD8), 0);
+        assert_eq!(
+            sim.page(Geometry::W25N01GV.block_first_page(3))[PAGE_SIZE_BYTES],
+            0
+        );
+    }
+
+    #[test]
+    fn metadata_writes_stay_inside_the_footprint() {
+        use crate::event_log::{EventLog, FlashEventKind};
+        use std::vec::Vec;
+
+        const FULL_REGIONS: [Region; 4] = [
+            Region {
+                kind: RegionKind::AllocatorSlots,
+                first_block: 2,
+                block_count: 2,
+                min_good_blocks: 2,
+            },
+            Region {
+                kind: RegionKind::Data,
+                first_block: 4,
+                block_count: 4,
+                min_good_blocks: 3,
+            },
+            Region {
+                kind: RegionKind::Log,
+                first_block: 8,
+                block_count: 2,
+                min_good_blocks: 2,
+            },
+            Region {
+                kind: RegionKind::Raw,
+                first_block: 10,
+                block_count: 2,
+                min_good_blocks: 0,
+            },
+        ];
+        const FULL_LAYOUT: Layout = Layout {
+            descriptor_block: 1,
+            regions: &FULL_REGIONS,
+        };
+
+        let sim = SimFlash::new();
+        sim.mark_bad(5);
+        let (mut flash, _) = FULL_LAYOUT
+            .create(
+                sim.driver(),
+                ReadMethod::FastRead,
+                WriteMethod::SingleLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+        let created = sim.commands().len();
+
+        // Enough of everything to wrap the log and swap the allocator slots many times over
+        for round in 0..160u32 {
+            let mounted = MountedLayout::mount(&flash, 1, ReadMethod::FastRead)
+                .unwrap()
+                .unwrap();
+
+            let mut allocator = mounted
+                .load_allocator(&flash, ReadMethod::FastRead)
+                .unwrap()
+                .unwrap();
+            match allocator.allocate() {
+                Some(_) => {}
+                None => (4..8).for_each(|block| allocator.free(block)),
+            }
+            flash = allocator
+                .save(
+                    flash,
+                    ReadMethod::FastRead,
+                    WriteMethod::SingleLoad,
+                    &mut NoDelay,
+                )
+                .unwrap();
+
+            let mut log = mounted
+                .mount_log(&flash, ReadMethod::FastRead)
+                .unwrap()
+                .unwrap();
+            assert!(log.push(round, 1, b"metadata footprint"));
+            flash = log
+                .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+                .unwrap();
+
+            let region = mounted.region(RegionKind::Log).unwrap();
+            let mut events = EventLog::mount(
+                &flash,
+                region.first_block,
+                region.block_count,
+                ReadMethod::FastRead,
+            )
+            .unwrap();
+            flash.log_event(FlashEventKind::EraseFailure { block: 4 });
+            flash = events
+                .flush(flash, WriteMethod::SingleLoad, &mut NoDelay)
+                .unwrap();
+        }
+
+        let footprint: Vec<(BlockAddress, MetadataKind)> =
+            metadata_footprint(&FULL_LAYOUT).collect();
+        let kind_of = |page: u16| {
+            let block = page / Geometry::W25N01GV.pages_per_block as u16;
+            footprint
+                .iter()
+                .find(|(address, _)| address.0 == block)
+                .map(|(_, kind)| *kind)
+        };
+
+        // Creating the layout erases the application's regions once, every other write is
+        // metadata
+        let mut written = Vec::new();
+        for (index, command) in sim.commands().iter().enumerate() {
+            if command.opcode == 0x10 || (command.opcode == 0xD8 && index >= created) {
+                let page = command.page_address().unwrap();
+                let kind = kind_of(page);
+                assert!(
+                    kind.is_some(),
+                    "page {} written outside the footprint",
+                    page
+                );
+                written.push(kind.unwrap());
+            }
+        }
+
+        for kind in [
+            MetadataKind::LayoutDescriptor,
+            MetadataKind::AllocatorSlot,
+            MetadataKind::Log,
+        ]
+        .iter()
+        {
+            assert!(written.contains(kind), "{:?} never written", kind);
+        }
+        // The log wrapped, so both of its blocks were erased after creation
+        assert!(erases_of(&sim, 8) > 1 && erases_of(&sim, 9) > 1);
+    }
+}
@@ -2,102 +2,368 @@
 #![forbid(unsafe_code)]
 
 extern crate embedded_hal as hal;
-use core::marker::PhantomData;
+#[cfg(test)]
+extern crate std;
+use core::{
+    cell::{Cell, RefCell},
+    marker::PhantomData,
+};
 
-use stm32l4xx_hal::qspi::{Qspi, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+use hal::blocking::delay::DelayUs;
 
+pub mod allocator;
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod block0;
+pub mod block_header;
+pub mod bus;
+pub mod bus_hold;
+pub mod column;
+pub mod commands;
+pub mod crc;
+pub mod device;
+pub mod digest;
+pub mod dry_run;
+pub mod ecc_mode;
+pub mod endurance;
+pub mod eraser;
+pub mod error;
+pub mod event_log;
+pub mod geometry;
+pub mod image_verify;
+pub mod integrity;
+pub mod latency;
+#[cfg(feature = "latency-histograms")]
+pub mod latency_histogram;
+pub mod layout;
+pub mod log_sink;
+pub mod msc;
+pub mod nand_flash;
+pub mod nop;
+#[cfg(feature = "nor-flash")]
+pub mod nor_flash;
+pub mod octospi;
+pub mod oob;
+pub mod otp;
+#[cfg(feature = "page-cache")]
+pub mod page_cache;
+pub mod patrol;
+pub mod provisioning;
 pub mod read;
+pub mod read_disturb;
+pub mod read_only;
+pub mod reconcile;
+pub mod recovery;
+pub mod reentrancy;
+pub mod resume;
+pub mod scan;
+pub mod scratch;
+#[cfg(test)]
+mod sim;
+pub mod soft_ecc;
+pub mod spanning;
+pub mod stats;
 pub mod status;
+pub mod two_phase;
+pub mod verification;
 pub mod write;
 
-pub use read::ReadMethod;
-pub use write::WriteMethod;
+pub use allocator::BlockAllocator;
+#[cfg(feature = "async")]
+pub use asynch::{AsyncQspiBus, AsyncW25N01GV};
+pub use block0::Block0Policy;
+pub use block_header::{BlockHeader, StructureKind};
+#[cfg(feature = "embassy")]
+pub use bus::EmbassyQspiBus;
+#[cfg(feature = "esp32s3")]
+pub use bus::EspSpiBus;
+#[cfg(feature = "linux")]
+pub use bus::LinuxSpiBus;
+#[cfg(feature = "spi")]
+pub use bus::SpiBus;
+#[cfg(feature = "stm32h7")]
+pub use bus::Stm32h7QspiBus;
+pub use bus::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+pub use bus_hold::BusOp;
+pub use column::Column;
+pub use device::{DeviceInfo, DeviceVariant};
+#[cfg(feature = "sha256")]
+pub use digest::Sha256;
+pub use digest::{Crc32, Crc32Engine, StreamingDigest};
+pub use dry_run::{DryRunPolicy, PlannedOp};
+pub use ecc_mode::EccMode;
+pub use endurance::{BlockEndurance, EnduranceProgress, EnduranceTest};
+pub use eraser::{EraseProgress, IncrementalEraser};
+pub use error::{
+    ConfigHint, FlashCommandError, FlashError, InterruptedOp, InterruptedOutcome, StorageError,
+};
+pub use event_log::{EventLog, FlashEvent, FlashEventKind};
+pub use geometry::{BlockAddress, Geometry, MainAddress, PageAddress, RawAddress};
+pub use image_verify::{Mismatch, VerifyOpts, VerifyOutcome};
+pub use latency::BusyClass;
+#[cfg(feature = "latency-histograms")]
+pub use latency_histogram::{LatencyHistogram, LatencyHistograms};
+pub use layout::{
+    metadata_footprint, Layout, LayoutReport, MetadataKind, MountedLayout, Region, RegionKind,
+    RegionReport,
+};
+pub use log_sink::{FlashLogSink, LogRecord};
+pub use msc::{MscStats, UsbMscBackend, MSC_BLOCK_COUNT, MSC_BLOCK_SIZE};
+pub use nand_flash::{NandFlash, ReadNandFlash};
+#[cfg(feature = "nor-flash")]
+pub use nor_flash::NorFlashAdapter;
+pub use octospi::{OctospiBus, OctospiCommand, OctospiLines, OctospiPeripheral};
+pub use oob::{OobLayout, OobMap};
+#[cfg(feature = "page-cache")]
+pub use page_cache::PageCacheStats;
+pub use provisioning::{AppliedReport, BadBlockMap, LutImage, ProvisioningState};
+pub use read::{BufferMode, DumpStats, PageClass, PageWithSpare, ReadMethod, SweepStats};
+pub use read_disturb::{ReadDisturbMonitor, MAX_MONITORED_BLOCKS};
+pub use read_only::{ReadOnlyRef, ReadOnlyW25N01GV, RestoreKey};
+pub use reconcile::{ReconcileChange, ReconcilePolicy, ReconcileReport};
+pub use recovery::{RecoveryAttempt, RecoveryPolicy};
+#[cfg(feature = "reentrancy-guard")]
+pub use reentrancy::ReentrancyAction;
+pub use resume::SavedDriverState;
+pub use scan::{
+    BadBlockScan, EccScan, Findings, ScanDepth, ScanPage, ScanSkip, ScanStats, ScanVisitor,
+};
+pub use scratch::Scratch;
+pub use spanning::SpanningRecordWriter;
+pub use stats::Stats;
+pub use two_phase::{TwoPhase, TwoPhaseParticipant, TwoPhaseRecovery};
+pub use verification::VerificationLevel;
+pub use write::{LoadMode, WriteMethod};
 
-pub const PAGE_SIZE_BYTES: usize = 2048;
-pub const PAGE_SIZE_WITH_ECC_BYTES: usize = 2112;
+pub const PAGE_SIZE_BYTES: usize = Geometry::W25N01GV.page_size;
+pub const PAGE_SIZE_WITH_ECC_BYTES: usize = Geometry::W25N01GV.page_size_with_spare();
 pub const MAX_BBM_LUT_ENTIRES: usize = 20;
-pub const PAGES_PER_BLOCK: usize = 64;
+pub const PAGES_PER_BLOCK: usize = Geometry::W25N01GV.pages_per_block;
+pub const BLOCK_COUNT: usize = Geometry::W25N01GV.block_count;
+pub const SPARE_BYTES: usize = Geometry::W25N01GV.spare_size;
+
+/// How long to sleep between status register polls when waiting with a delay provider
+const BUSY_POLL_INTERVAL_US: u32 = 10;
+/// Longer than the slowest operation the device can be left running, a 10ms block erase
+const RESYNC_TIMEOUT_US: u32 = 20_000;
 
 enum FlashCommands {
     DeviceReset = 0xFF,
     JEDECId = 0x9F,
     ReadStatusRegister = 0x05,
+    ReadStatusRegisterAlt = 0x0F,
     WriteStatusRegister = 0x01,
+    WriteStatusRegisterAlt = 0x1F,
     EnableWrite = 0x06,
     DisableWrite = 0x04,
     Erase128KBBlock = 0xD8,
+    SwapBlocks = 0xA1,
     ReadBBM = 0xA5,
+    LastECCFailurePageAddress = 0xA9,
     ProgramExecute = 0x10,
     PageDataRead = 0x13,
-}
-
-#[derive(Debug)]
-pub enum FlashCommandError {
-    QSPIBusy,
-    QSPIAddress,
-    QSPIUnknown,
-    DeviceBusy,
-}
-
-impl FlashCommandError {
-    fn from_qspi_error(err: QspiError) -> FlashCommandError {
-        match err {
-            stm32l4xx_hal::qspi::QspiError::Busy => FlashCommandError::QSPIBusy,
-            stm32l4xx_hal::qspi::QspiError::Address => FlashCommandError::QSPIAddress,
-            stm32l4xx_hal::qspi::QspiError::Unknown => FlashCommandError::QSPIUnknown,
-        }
-    }
+    ReadData = 0x03,
 }
 
 pub struct WriteMode;
 pub struct ReadMode;
 
-pub struct W25N01GV<PINS, MODE> {
+pub struct W25N01GV<BUS, MODE> {
     _marker: PhantomData<MODE>,
-    qspi: Qspi<PINS>,
+    qspi: BUS,
+    dry_run_policy: DryRunPolicy,
+    dry_run_plan: RefCell<dry_run::DryRunPlan>,
+    stats: Cell<Stats>,
+    ecc_status_pending: Cell<bool>,
+    nop_tracker: RefCell<nop::NopTracker>,
+    ecc_enabled: Cell<Option<bool>>,
+    buffer_mode: Cell<Option<bool>>,
+    pending_program: Cell<bool>,
+    verified_addressing: bool,
+    block0_policy: Block0Policy,
+    state_unverified: Cell<bool>,
+    verification_level: VerificationLevel,
+    ecc_mode: EccMode,
+    oob_layout: OobLayout,
+    crc32_engine: Crc32Engine,
+    time_source: Option<fn() -> u64>,
+    pending_events: RefCell<event_log::PendingEvents>,
+    bus_clock_hz: u32,
+    bus_hold_limit_us: Option<u32>,
+    latency_budget_us: Option<u32>,
+    #[cfg(feature = "page-cache")]
+    page_cache: RefCell<page_cache::PageCache>,
+    #[cfg(feature = "latency-histograms")]
+    busy_timer: RefCell<latency_histogram::BusyTimer>,
+    #[cfg(feature = "reentrancy-guard")]
+    in_operation: Cell<bool>,
+    #[cfg(feature = "reentrancy-guard")]
+    reentrancy_action: ReentrancyAction,
 }
 
-pub fn new_w25_n01_gv<CLK, NCS, IO0, IO1, IO2, IO3>(
-    qspi: Qspi<(CLK, NCS, IO0, IO1, IO2, IO3)>,
-) -> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), ReadMode> {
+pub fn new_w25_n01_gv<BUS: QspiBus>(qspi: BUS) -> W25N01GV<BUS, ReadMode> {
     W25N01GV {
         _marker: PhantomData {},
         qspi,
+        dry_run_policy: DryRunPolicy::Off,
+        dry_run_plan: RefCell::new(dry_run::DryRunPlan::new()),
+        stats: Cell::new(Stats::default()),
+        ecc_status_pending: Cell::new(false),
+        nop_tracker: RefCell::new(nop::NopTracker::new()),
+        ecc_enabled: Cell::new(None),
+        buffer_mode: Cell::new(None),
+        pending_program: Cell::new(false),
+        verified_addressing: false,
+        block0_policy: Block0Policy::Normal,
+        state_unverified: Cell::new(false),
+        verification_level: VerificationLevel::CheckFailureBits,
+        ecc_mode: EccMode::FollowDevice,
+        oob_layout: OobLayout::WinbondDefault,
+        crc32_engine: crc::crc32_update,
+        time_source: None,
+        pending_events: RefCell::new(event_log::PendingEvents::new()),
+        bus_clock_hz: 0,
+        bus_hold_limit_us: None,
+        latency_budget_us: None,
+        #[cfg(feature = "page-cache")]
+        page_cache: RefCell::new(page_cache::PageCache::new()),
+        #[cfg(feature = "latency-histograms")]
+        busy_timer: RefCell::new(latency_histogram::BusyTimer::new()),
+        #[cfg(feature = "reentrancy-guard")]
+        in_operation: Cell::new(false),
+        #[cfg(feature = "reentrancy-guard")]
+        reentrancy_action: ReentrancyAction::Error,
     }
 }
 
-impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), MODE> {
-    pub fn reset_device(&self) -> Result<(), FlashCommandError> {
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Moves the driver into another typestate, carrying all driver state across
+    fn into_mode<NewMode>(self) -> W25N01GV<BUS, NewMode> {
+        W25N01GV {
+            _marker: PhantomData {},
+            qspi: self.qspi,
+            dry_run_policy: self.dry_run_policy,
+            dry_run_plan: self.dry_run_plan,
+            stats: self.stats,
+            ecc_status_pending: self.ecc_status_pending,
+            nop_tracker: self.nop_tracker,
+            ecc_enabled: self.ecc_enabled,
+            buffer_mode: self.buffer_mode,
+            pending_program: self.pending_program,
+            verified_addressing: self.verified_addressing,
+            block0_policy: self.block0_policy,
+            state_unverified: self.state_unverified,
+            verification_level: self.verification_level,
+            ecc_mode: self.ecc_mode,
+            oob_layout: self.oob_layout,
+            crc32_engine: self.crc32_engine,
+            time_source: self.time_source,
+            pending_events: self.pending_events,
+            bus_clock_hz: self.bus_clock_hz,
+            bus_hold_limit_us: self.bus_hold_limit_us,
+            latency_budget_us: self.latency_budget_us,
+            #[cfg(feature = "page-cache")]
+            page_cache: self.page_cache,
+            #[cfg(feature = "latency-histograms")]
+            busy_timer: self.busy_timer,
+            #[cfg(feature = "reentrancy-guard")]
+            in_operation: self.in_operation,
+            #[cfg(feature = "reentrancy-guard")]
+            reentrancy_action: self.reentrancy_action,
+        }
+    }
+
+    /// Every command sent to the device goes through here (or `qspi_transfer`) so driver wide
+    /// policies like dry runs and stats apply to all of them. QSPI errors are converted here too,
+    /// while the command's address and length are still known.
+    fn qspi_write(&self, command: QspiWriteCommand) -> Result<(), FlashError> {
+        if self.dry_run_write(&command) {
+            return Ok(());
+        }
+
+        let opcode = command.instruction.map(|(opcode, _)| opcode);
+        let address = command.address.map(|(address, _)| address);
+        let data = command.data.map(|(data, _)| data);
+        let len = data.map(|data| data.len() as u32).unwrap_or(0);
+
+        self.qspi
+            .write_command(command)
+            .map_err(|err| FlashError::from_qspi_error(err, address, len))?;
+
+        if let Some(opcode) = opcode {
+            self.record_write_command(opcode);
+            self.record_nop_command(opcode, data.unwrap_or(&[]));
+            self.record_pending_program(opcode);
+            #[cfg(feature = "page-cache")]
+            self.record_page_cache_command(opcode);
+            #[cfg(feature = "latency-histograms")]
+            self.record_busy_start(opcode);
+        }
+
+        Ok(())
+    }
+
+    fn qspi_transfer(&self, command: QspiReadCommand, buffer: &mut [u8]) -> Result<(), FlashError> {
+        if self.dry_run_transfer(&command, buffer) {
+            return Ok(());
+        }
+
+        let opcode = command.instruction.map(|(opcode, _)| opcode);
+        let address = command.address.map(|(address, _)| address);
+        let len = command.receive_length;
+
+        self.qspi
+            .read_command(command, buffer)
+            .map_err(|err| FlashError::from_qspi_error(err, address, len))?;
+
+        if let Some(opcode) = opcode {
+            self.record_read_command(opcode, len);
+        }
+
+        Ok(())
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    pub fn reset_device(&self) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.reset_device_unguarded()
+    }
+
+    pub(crate) fn reset_device_unguarded(&self) -> Result<(), FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
         }
 
-        let command = QspiWriteCommand {
-            instruction: Some((FlashCommands::DeviceReset as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 0,
-            data: None,
-            double_data_rate: false,
-        };
-
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
+        let command = commands::device_reset();
+
+        // The reset puts the configuration register back to its defaults
+        self.ecc_enabled.set(None);
+        self.buffer_mode.set(None);
+
+        if let Err(err) = self.qspi_write(command) {
+            Err(err)
         } else {
             Ok(())
         }
     }
 
-    pub fn get_jedec_id(&mut self) -> Result<[u8; 3], FlashCommandError> {
+    pub fn get_jedec_id(&mut self) -> Result<[u8; 3], FlashError> {
+        let _guard = self.begin_operation()?;
+
+        self.read_jedec_id()
+    }
+
+    pub(crate) fn read_jedec_id(&self) -> Result<[u8; 3], FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
@@ -105,38 +371,116 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
 
         let mut id = [0_u8; 3];
 
-        let command = QspiReadCommand {
-            instruction: Some((FlashCommands::JEDECId as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 8,
-            data_mode: QspiMode::SingleChannel,
-            receive_length: 3,
-            double_data_rate: false,
-        };
-
-        if let Err(err) = self.qspi.transfer(command, &mut id) {
-            Err(FlashCommandError::from_qspi_error(err))
+        let command = commands::jedec_id();
+
+        if let Err(err) = self.qspi_transfer(command, &mut id) {
+            Err(err)
         } else {
             Ok(id)
         }
     }
 
-    pub fn wait_while_busy(&self) {
+    /// Spins until the device finishes whatever it's busy with, returning any error encountered
+    /// while reading the status register rather than taking it for the device being ready.
+    pub fn wait_while_busy(&self) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.wait_while_busy_unguarded()
+    }
+
+    pub(crate) fn wait_while_busy_unguarded(&self) -> Result<(), FlashError> {
+        while self.check_busy()? {}
+
+        Ok(())
+    }
+
+    /// Brings a freshly created driver in line with a device that may have been in use before it,
+    /// e.g. after restarting the task that owned the previous driver without power cycling the
+    /// flash. Waits (for at most 20ms) for any operation left running to finish, then reads the
+    /// configuration register into the driver's caches and returns it, so the caller can see
+    /// what the previous owner left behind, like BUF=0.
+    ///
+    /// Nothing is written, so this is safe to call over a device someone else configured. To put
+    /// the device into a known configuration instead, use `reset_configuration` or
+    /// `device_reset`. `new_w25_n01_gv` itself never touches the bus and assumes nothing until
+    /// something is read.
+    pub fn resync<D: DelayUs<u32>>(
+        &mut self,
+        delay: &mut D,
+    ) -> Result<status::ConfigurationRegister, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let mut waited_us = 0;
+        while self.check_busy()? {
+            if waited_us >= RESYNC_TIMEOUT_US {
+                return Err(FlashError::Timeout);
+            }
+
+            delay.delay_us(BUSY_POLL_INTERVAL_US);
+            waited_us += BUSY_POLL_INTERVAL_US;
+        }
+
+        // Whatever the previous owner left in the status register isn't this driver's to report
+        self.pending_program.set(false);
+        self.ecc_status_pending.set(false);
+        self.state_unverified.set(false);
+
+        self.read_configuration_register_unguarded()
+    }
+
+    /// Like `wait_while_busy`, but sleeps between polls instead of hammering the QSPI bus
+    pub fn wait_while_busy_with_delay<D: DelayUs<u32>>(
+        &self,
+        delay: &mut D,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.wait_while_busy_with_delay_unguarded(delay)
+    }
+
+    pub(crate) fn wait_while_busy_with_delay_unguarded<D: DelayUs<u32>>(
+        &self,
+        delay: &mut D,
+    ) -> Result<(), FlashError> {
         loop {
-            match self.check_busy() {
-                Ok(busy) => {
-                    if !busy {
-                        break;
-                    }
-                }
-                Err(_err) => break,
+            if !self.check_busy()? {
+                return Ok(());
             }
+
+            delay.delay_us(BUSY_POLL_INTERVAL_US);
         }
     }
 
-    pub fn check_write_or_erase_failure(&self) -> Result<bool, FlashCommandError> {
-        match self.read_status_register() {
+    /// Like `wait_while_busy_with_delay`, but gives up after polling `max_iters` times and returns
+    /// `FlashError::Timeout` if the device is still busy, so an unresponsive device can't
+    /// hang the caller. Polls are 10us apart.
+    pub fn wait_while_busy_timeout<D: DelayUs<u32>>(
+        &self,
+        delay: &mut D,
+        max_iters: u32,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.wait_while_busy_timeout_unguarded(delay, max_iters)
+    }
+
+    pub(crate) fn wait_while_busy_timeout_unguarded<D: DelayUs<u32>>(
+        &self,
+        delay: &mut D,
+        max_iters: u32,
+    ) -> Result<(), FlashError> {
+        for _ in 0..max_iters {
+            if !self.check_busy()? {
+                return Ok(());
+            }
+
+            delay.delay_us(BUSY_POLL_INTERVAL_US);
+        }
+
+        Err(FlashError::Timeout)
+    }
+
+    pub fn check_write_or_erase_failure(&self) -> Result<bool, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        match self.read_status_register_unguarded() {
             Ok(status_register) => {
                 Ok(status_register.erase_failure || status_register.write_failure)
             }
@@ -147,10 +491,242 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
     /// An internal method every function that sends a command (minus a special few) uses to
     /// check if the flash device is busy. The flash device will silently reject commands while
     /// busy.
-    fn check_busy(&self) -> Result<bool, FlashCommandError> {
-        match self.read_status_register() {
-            Ok(status_register) => Ok(status_register.device_busy),
-            Err(err) => Err(err),
+    fn check_busy(&self) -> Result<bool, FlashError> {
+        self.is_busy_unguarded()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimCommand, SimFlash};
+    use std::rc::Rc;
+
+    fn is_status_read(command: &SimCommand) -> bool {
+        (command.opcode == 0x05 || command.opcode == 0x0F) && command.alternative_bytes == [0xC0]
+    }
+
+    /// Starts a page read that keeps the device busy for 5 polls, and unplugs it on the second
+    fn busy_then_unplugged() -> (SimFlash, W25N01GV<SimFlash, ReadMode>, Rc<Cell<u32>>) {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(5);
+        let flash = sim.driver();
+        flash.read_memory_to_data_buffer(0).unwrap();
+
+        let polls = Rc::new(Cell::new(0));
+        let (hook_sim, hook_polls) = (sim.clone(), polls.clone());
+        sim.set_hook(move |command| {
+            if is_status_read(command) {
+                hook_polls.set(hook_polls.get() + 1);
+                if hook_polls.get() == 2 {
+                    hook_sim.disconnect();
+                }
+            }
+        });
+
+        (sim, flash, polls)
+    }
+
+    type Wait = fn(&W25N01GV<SimFlash, ReadMode>) -> Result<(), FlashError>;
+
+    #[test]
+    fn a_bus_error_mid_poll_ends_every_wait_with_the_error() {
+        let waits: [Wait; 3] = [
+            |flash| flash.wait_while_busy(),
+            |flash| flash.wait_while_busy_with_delay(&mut NoDelay),
+            |flash| flash.wait_while_busy_timeout(&mut NoDelay, 100),
+        ];
+
+        for wait in waits.iter() {
+            let (sim, flash, polls) = busy_then_unplugged();
+
+            assert_eq!(wait(&flash), Err(FlashError::QSPIUnknown));
+            // The failed poll is the last, rather than being taken for a ready device
+            assert_eq!(polls.get(), 2);
+
+            sim.clear_hook();
+            sim.power_cycle();
+            assert_eq!(wait(&flash), Ok(()));
+        }
+    }
+
+    #[test]
+    fn a_zero_poll_bound_times_out_without_polling() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        sim.clear_log();
+
+        assert_eq!(
+            flash.wait_while_busy_timeout(&mut NoDelay, 0),
+            Err(FlashError::Timeout)
+        );
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn the_poll_bound_is_exact() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(5);
+        let flash = sim.driver();
+
+        flash.read_memory_to_data_buffer(0).unwrap();
+        sim.clear_log();
+        assert_eq!(
+            flash.wait_while_busy_timeout(&mut NoDelay, 5),
+            Err(FlashError::Timeout)
+        );
+        assert_eq!(
+            sim.commands()
+                .iter()
+                .filter(|command| is_status_read(command))
+                .count(),
+            5
+        );
+
+        flash.read_memory_to_data_buffer(0).unwrap();
+        sim.clear_log();
+        assert_eq!(flash.wait_while_busy_timeout(&mut NoDelay, 6), Ok(()));
+        assert_eq!(
+            sim.commands()
+                .iter()
+                .filter(|command| is_status_read(command))
+                .count(),
+            6
+        );
+    }
+
+    /// Leaves continuous read mode set and a page read running, as a driver dropped by a task
+    /// restart would
+    fn abandoned_mid_read(sim: &SimFlash) {
+        let previous = sim.driver();
+        let mut configuration_register = previous.read_configuration_register().unwrap();
+        configuration_register.buf = false;
+        previous
+            .write_configuration_register(configuration_register)
+            .unwrap();
+        previous.read_memory_to_data_buffer(64).unwrap();
+    }
+
+    #[test]
+    fn resync_waits_out_a_leftover_command_and_picks_up_the_registers() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(50);
+        abandoned_mid_read(&sim);
+
+        let mut flash = sim.driver();
+        // Without resync the new driver runs into the busy device
+        assert_eq!(
+            flash.read_columns(Column::Physical(0), &mut [0; 4], ReadMethod::FastRead),
+            Err(FlashError::DeviceBusy)
+        );
+
+        sim.clear_log();
+        let configuration_register = flash.resync(&mut NoDelay).unwrap();
+        assert!(!configuration_register.buf);
+        assert!(configuration_register.ecc_e);
+        // Only register reads, nothing written
+        assert!(sim
+            .commands()
+            .iter()
+            .all(|command| command.opcode == 0x05 || command.opcode == 0x0F));
+
+        // The driver now goes by what the device is actually set to
+        sim.clear_log();
+        assert_eq!(flash.ecc_enabled(), Ok(true));
+        assert_eq!(
+            flash.read_columns(Column::Physical(4), &mut [0; 4], ReadMethod::FastRead),
+            Err(FlashError::ColumnIgnoredInContinuousRead { column: 4 })
+        );
+        assert!(sim.commands().is_empty());
+
+        let mut buffer = [0; 4];
+        flash
+            .read_columns(Column::Physical(0), &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0xFF; 4]);
+    }
+
+    #[test]
+    fn resync_gives_up_on_a_device_that_stays_busy() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(u32::MAX);
+        abandoned_mid_read(&sim);
+
+        let mut flash = sim.driver();
+        sim.clear_log();
+        assert_eq!(flash.resync(&mut NoDelay).err(), Some(FlashError::Timeout));
+        assert_eq!(
+            sim.commands()
+                .iter()
+                .filter(|command| is_status_read(command))
+                .count(),
+            (RESYNC_TIMEOUT_US / BUSY_POLL_INTERVAL_US) as usize + 1
+        );
+    }
+
+    type EmptyCall = fn(&W25N01GV<SimFlash, WriteMode>) -> Result<(), FlashError>;
+
+    #[test]
+    fn empty_inputs_succeed_without_reaching_the_bus() {
+        let cases: [(&str, EmptyCall); 9] = [
+            ("read_columns physical", |flash| {
+                flash.read_columns(Column::Physical(0), &mut [], ReadMethod::FastRead)
+            }),
+            ("read_columns logical", |flash| {
+                flash.read_columns(Column::Logical(0), &mut [], ReadMethod::QuadFastRead)
+            }),
+            ("load_columns", |flash| {
+                flash.load_columns(
+                    Column::Physical(0),
+                    &[],
+                    WriteMethod::SingleLoad,
+                    LoadMode::ResetThenLoad,
+                )
+            }),
+            ("load_to_data_buffer reset", |flash| {
+                flash.load_to_data_buffer(&[], 0, WriteMethod::QuadLoad, LoadMode::ResetThenLoad)
+            }),
+            ("load_to_data_buffer preserve", |flash| {
+                flash.load_to_data_buffer(
+                    &[],
+                    0,
+                    WriteMethod::SingleLoad,
+                    LoadMode::PreserveAndLoad,
+                )
+            }),
+            ("load_wrapping", |flash| {
+                assert_eq!(flash.load_wrapping(&[], 2000, WriteMethod::SingleLoad)?, 0);
+                Ok(())
+            }),
+            ("load_wrapping_to_start", |flash| {
+                flash.load_wrapping_to_start(&[], 2000, WriteMethod::SingleLoad)
+            }),
+            ("dump", |flash| {
+                let stats = flash.dump(0, 0, ReadMethod::FastRead, &mut NoDelay, |_, _| {
+                    panic!("no pages to dump")
+                })?;
+                assert_eq!(stats.pages_dumped, 0);
+                Ok(())
+            }),
+            ("verify_against", |flash| {
+                let mismatch = flash.verify_against(0, &[], ReadMethod::FastRead, &mut NoDelay)?;
+                assert_eq!(mismatch, None);
+                Ok(())
+            }),
+        ];
+
+        for (name, call) in cases.iter() {
+            let sim = SimFlash::new();
+            let flash = sim.driver().into_write_mode().unwrap();
+            sim.clear_log();
+
+            assert_eq!(call(&flash), Ok(()), "{}", name);
+            assert!(
+                sim.commands().is_empty(),
+                "{} sent {:?}",
+                name,
+                sim.commands()
+            );
         }
     }
 }
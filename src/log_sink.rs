@@ -0,0 +1,882 @@
+//! A persistent log that keeps records across power loss, e.g. for crash logs.
+//!
+//! Records are staged in RAM by `push`, which never touches the device, and written out a page
+//! at a time by `pump` from the main loop. The log fills a range of blocks as a ring, erasing the
+//! oldest block when it wraps around onto it.
+//!
+//! Each page starts with a header (magic and a page sequence number) followed by records packed
+//! back to back. A record is framed as its message length, level, timestamp, message, then a
+//! CRC-32 of everything before it, so a record torn by power loss while its page was programming
+//! fails its CRC and ends the page when reading back. Records stop short of the last 32 bytes of
+//! each page, where the first page of each block holds its `BlockHeader`.
+//!
+//! By default the ring overwrites its oldest records. With `set_retention(true)` it keeps records
+//! until the application releases them instead, e.g. once they've been uploaded: `release_through`
+//! releases every page up to a sequence number, and `reclaim` erases the oldest blocks holding
+//! only released records. With retention on, `pump` never erases a block that still holds records,
+//! so when the next block does it returns `StorageError::StorageFull`, with `reclaimable` telling
+//! whether `reclaim` would free it. `check_room` reports the same without giving up the driver, so
+//! a full log is handled by checking for room, reclaiming if that helps, and checking once more.
+//! What's been released is only kept in RAM, so after a mount every record counts as unreleased.
+//!
+//! `push` takes `&mut self`, so to use the sink as a global logger wrap it in whatever mutex
+//! suits the application and call `push` from the logger's `log`. With the `log-sink` feature,
+//! `FlashLogger` does that for the `log` crate: it's a `log::Log` that formats each record into a
+//! sink behind a `critical_section::Mutex`, and takes the sink out of the mutex while it pumps or
+//! reads it back so the device is never touched inside a critical section. Records logged while
+//! the sink is out, or before one is attached, are counted as dropped.
+
+use core::convert::TryInto;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    block_header::{BlockHeader, StructureKind, BLOCK_HEADER_COLUMN},
+    digest::Crc32,
+    status::ECCStatus,
+    Column, FlashError, Geometry, QspiBus, ReadMethod, ReadMode, StorageError, WriteMethod,
+    BLOCK_COUNT, PAGES_PER_BLOCK, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+const LOG_PAGE_MAGIC: u32 = 0x4C4F_4721;
+const PAGE_HEADER_BYTES: usize = 8;
+/// Records stop short of where the first page of each block holds its block header
+const RECORD_AREA_END: usize = BLOCK_HEADER_COLUMN as usize;
+/// Length, level, timestamp, and CRC
+const FRAME_OVERHEAD_BYTES: usize = 10;
+/// A length byte of 0xFF is erased flash, which marks the end of a page's records
+pub const MAX_LOG_MESSAGE_BYTES: usize = 254;
+
+/// A record read back by `FlashLogSink::read_logs`
+#[derive(Debug, Clone, Copy)]
+pub struct LogRecord<'a> {
+    /// The sequence number of the page the record was written in, increasing with each page
+    pub page_sequence: u32,
+    pub timestamp: u32,
+    pub level: u8,
+    pub message: &'a [u8],
+}
+
+/// Whether a block of a ring holds records, and if it does whether they've all been released
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BlockRecords {
+    None,
+    Released,
+    Live,
+}
+
+/// Is true if `released` covers `value`, comparing the two as wrapping counters
+pub(crate) fn is_released(released: Option<u32>, value: u32) -> bool {
+    released.is_some_and(|released| released.wrapping_sub(value) as i32 >= 0)
+}
+
+pub struct FlashLogSink {
+    first_block: u16,
+    block_count: u16,
+    next_page: u16,
+    next_sequence: u32,
+    method: ReadMethod,
+    retain: bool,
+    /// The sequence number of the newest page released
+    released: Option<u32>,
+    /// Blocks from the next one the log will write into that `reclaim` has already erased
+    erased_ahead: u16,
+    staged: [u8; RECORD_AREA_END - PAGE_HEADER_BYTES],
+    staged_len: usize,
+    dropped: u32,
+    /// `push` has no driver at hand, so records are checksummed on the engine it had at mount
+    digest: Crc32,
+}
+
+fn page_header(page: &[u8]) -> Option<u32> {
+    let magic = u32::from_le_bytes(page[0..4].try_into().ok()?);
+    let sequence = u32::from_le_bytes(page[4..8].try_into().ok()?);
+
+    if magic == LOG_PAGE_MAGIC {
+        Some(sequence)
+    } else {
+        None
+    }
+}
+
+impl FlashLogSink {
+    /// Opens the log kept in `block_count` blocks starting at `first_block`, finding where the
+    /// last session left off. An empty region starts a new log at its first block.
+    pub fn mount<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        first_block: u16,
+        block_count: u16,
+        method: ReadMethod,
+    ) -> Result<FlashLogSink, StorageError> {
+        if block_count == 0 || first_block as usize + block_count as usize > BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        flash.check_block0(first_block, block_count)?;
+
+        let mut sink = FlashLogSink {
+            first_block,
+            block_count,
+            next_page: Geometry::W25N01GV.block_first_page(first_block),
+            next_sequence: 0,
+            method,
+            retain: false,
+            released: None,
+            erased_ahead: 0,
+            staged: [0xFF; RECORD_AREA_END - PAGE_HEADER_BYTES],
+            staged_len: 0,
+            dropped: 0,
+            digest: flash.crc32_digest(),
+        };
+
+        // The newest block is the one whose first page has the highest sequence number
+        let mut newest: Option<(u16, u32)> = None;
+        let mut header = [0_u8; PAGE_HEADER_BYTES];
+
+        for block in first_block..first_block + block_count {
+            flash.read_memory_to_data_buffer(Geometry::W25N01GV.block_first_page(block))?;
+            flash.wait_while_busy()?;
+            flash.read_columns(Column::Physical(0), &mut header, method)?;
+
+            if let Some(sequence) = page_header(&header) {
+                let is_newer = match newest {
+                    Some((_, newest_sequence)) => sequence.wrapping_sub(newest_sequence) as i32 > 0,
+                    None => true,
+                };
+
+                if is_newer {
+                    newest = Some((block, sequence));
+                }
+            }
+        }
+
+        if let Some((block, sequence)) = newest {
+            let written_pages = flash.find_write_frontier(block, method)?;
+
+            sink.next_sequence = sequence.wrapping_add(written_pages as u32);
+            sink.next_page = Geometry::W25N01GV.block_first_page(block) + written_pages;
+            if written_pages as usize == PAGES_PER_BLOCK {
+                sink.next_page = sink.block_first_page(sink.next_block(block));
+            }
+        }
+
+        Ok(sink)
+    }
+
+    /// Stages a record to be written by the next `pump`. Messages longer than
+    /// `MAX_LOG_MESSAGE_BYTES` are truncated. Returns false and drops the record if the staged
+    /// page is already full.
+    pub fn push(&mut self, timestamp: u32, level: u8, message: &[u8]) -> bool {
+        let message = &message[..message.len().min(MAX_LOG_MESSAGE_BYTES)];
+        let frame_len = message.len() + FRAME_OVERHEAD_BYTES;
+
+        if self.staged_len + frame_len > self.staged.len() {
+            self.dropped = self.dropped.wrapping_add(1);
+            return false;
+        }
+
+        let frame = &mut self.staged[self.staged_len..self.staged_len + frame_len];
+        frame[0] = message.len() as u8;
+        frame[1] = level;
+        frame[2..6].copy_from_slice(&timestamp.to_le_bytes());
+        frame[6..6 + message.len()].copy_from_slice(message);
+
+        let crc = self.digest.checksum(&frame[..6 + message.len()]);
+        frame[6 + message.len()..].copy_from_slice(&crc.to_le_bytes());
+
+        self.staged_len += frame_len;
+
+        true
+    }
+
+    /// Sets whether the log keeps records until they're released rather than overwriting the
+    /// oldest, see the module docs
+    pub fn set_retention(&mut self, retain: bool) {
+        self.retain = retain;
+    }
+
+    /// Releases the records of every page up to and including the one with `page_sequence`, so
+    /// `reclaim` can erase them
+    pub fn release_through(&mut self, page_sequence: u32) {
+        self.released = Some(page_sequence);
+    }
+
+    /// Releases every record written so far
+    pub fn release_written(&mut self) {
+        self.released = Some(self.next_sequence.wrapping_sub(1));
+    }
+
+    /// Returns `StorageError::StorageFull` if the next `pump` has records to write but, with
+    /// retention on, nowhere to write them without erasing records. `reclaimable` is true if
+    /// `reclaim` would make the room.
+    pub fn check_room<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+    ) -> Result<(), StorageError> {
+        if self.staged_len == 0 {
+            return Ok(());
+        }
+
+        self.check_next_page(flash)
+    }
+
+    /// Like `check_room`, for a page about to be staged
+    pub(crate) fn check_next_page<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+    ) -> Result<(), StorageError> {
+        let block = Geometry::W25N01GV.block_of_page(self.next_page);
+        if !self.retain || self.erased_ahead > 0 || self.next_page != self.block_first_page(block) {
+            return Ok(());
+        }
+
+        match self.block_records(flash, block)? {
+            BlockRecords::None => Ok(()),
+            records => Err(StorageError::StorageFull {
+                region: self.first_block,
+                needed: 1,
+                reclaimable: records == BlockRecords::Released,
+            }),
+        }
+    }
+
+    /// Erases the oldest blocks of the log as long as they hold only released records, and
+    /// returns how many it erased. `pump` writes into them later without erasing them again.
+    pub fn reclaim<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        delay: &mut D,
+    ) -> Result<u16, StorageError> {
+        let head_block = Geometry::W25N01GV.block_of_page(self.next_page);
+        let (mut block, candidates) = if self.next_page == self.block_first_page(head_block) {
+            (head_block, self.block_count)
+        } else {
+            (self.next_block(head_block), self.block_count - 1)
+        };
+
+        for _ in 0..self.erased_ahead {
+            block = self.next_block(block);
+        }
+
+        let mut reclaimed = 0;
+        for _ in self.erased_ahead..candidates {
+            if self.block_records(flash, block)? == BlockRecords::Live {
+                break;
+            }
+
+            {
+                let _guard = flash.begin_operation()?;
+                flash.erase_block_unguarded(block, delay)?;
+            }
+            self.erased_ahead += 1;
+            reclaimed += 1;
+            block = self.next_block(block);
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Whether a block behind the head of the log holds records, from its first page's header.
+    /// The block is full, so its last page's sequence number follows from the first's.
+    fn block_records<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        block: u16,
+    ) -> Result<BlockRecords, StorageError> {
+        let mut header = [0_u8; PAGE_HEADER_BYTES];
+        flash.read_memory_to_data_buffer(self.block_first_page(block))?;
+        flash.wait_while_busy()?;
+        flash.read_columns(Column::Physical(0), &mut header, self.method)?;
+
+        Ok(match page_header(&header) {
+            None => BlockRecords::None,
+            Some(sequence) => {
+                let last = sequence.wrapping_add(PAGES_PER_BLOCK as u32 - 1);
+                if is_released(self.released, last) {
+                    BlockRecords::Released
+                } else {
+                    BlockRecords::Live
+                }
+            }
+        })
+    }
+
+    /// Writes any staged records to the next page of the log, erasing the page's block first if
+    /// the page is the start of a block. Call this from the main loop, not from the logger. With
+    /// retention on, returns `StorageError::StorageFull` rather than erase a block holding
+    /// records, like `check_room`.
+    pub fn pump<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        if self.staged_len == 0 {
+            return Ok(flash);
+        }
+
+        self.check_next_page(&flash)?;
+
+        let block = Geometry::W25N01GV.block_of_page(self.next_page);
+        let flash = if self.next_page != self.block_first_page(block) {
+            flash
+        } else if self.erased_ahead > 0 {
+            self.erased_ahead -= 1;
+            flash
+        } else {
+            flash.erase_block(block, delay)?
+        };
+
+        let mut page = [0xFF_u8; PAGE_SIZE_BYTES];
+        page[0..4].copy_from_slice(&LOG_PAGE_MAGIC.to_le_bytes());
+        page[4..8].copy_from_slice(&self.next_sequence.to_le_bytes());
+        page[PAGE_HEADER_BYTES..PAGE_HEADER_BYTES + self.staged_len]
+            .copy_from_slice(&self.staged[..self.staged_len]);
+        if self.next_page == self.block_first_page(block) {
+            BlockHeader {
+                kind: StructureKind::Log,
+                region_id: 0,
+                sequence: self.next_sequence,
+            }
+            .write_into(&mut page, flash.crc32_digest())?;
+        }
+
+        let flash = flash.into_write_mode()?.write_page_split(
+            self.next_page,
+            &page,
+            &[],
+            write_method,
+            delay,
+        )?;
+
+        self.staged = [0xFF; RECORD_AREA_END - PAGE_HEADER_BYTES];
+        self.staged_len = 0;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        if self.next_page == self.block_first_page(block) + PAGES_PER_BLOCK as u16 - 1 {
+            self.next_page = self.block_first_page(self.next_block(block));
+        } else {
+            self.next_page += 1;
+        }
+
+        Ok(flash)
+    }
+
+    /// Reads every record in the log, oldest first. Records still staged in RAM aren't included.
+    /// Pages that can't be read back cleanly are skipped.
+    pub fn read_logs<BUS: QspiBus, MODE, F>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        method: ReadMethod,
+        mut f: F,
+    ) -> Result<(), StorageError>
+    where
+        F: FnMut(&LogRecord),
+    {
+        let head_block = Geometry::W25N01GV.block_of_page(self.next_page);
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        // At the start of a block, the head block is whole and holds the oldest records
+        let head_is_whole = self.next_page == self.block_first_page(head_block);
+        let mut block = if head_is_whole {
+            head_block
+        } else {
+            self.next_block(head_block)
+        };
+
+        for _ in 0..self.block_count {
+            let block_pages = Geometry::W25N01GV.block_pages(block);
+            let first_page = block_pages.start;
+            let end_page = if block == head_block && !head_is_whole {
+                self.next_page as u32
+            } else {
+                block_pages.end
+            };
+
+            for page_address in first_page..end_page {
+                let page_address = page_address as u16;
+                flash.read_memory_to_data_buffer(page_address)?;
+                flash.wait_while_busy()?;
+
+                match flash.read_status_register()?.ecc_status {
+                    ECCStatus::SinglePageError | ECCStatus::MultiPageError => continue,
+                    _ => {}
+                }
+
+                flash.read_data_buffer(&mut buffer, method)?;
+
+                let page_sequence = match page_header(&buffer) {
+                    Some(sequence) => sequence,
+                    None => break,
+                };
+
+                let mut offset = PAGE_HEADER_BYTES;
+                while offset + FRAME_OVERHEAD_BYTES <= RECORD_AREA_END {
+                    let message_len = buffer[offset] as usize;
+                    let frame_len = message_len + FRAME_OVERHEAD_BYTES;
+                    if message_len > MAX_LOG_MESSAGE_BYTES || offset + frame_len > RECORD_AREA_END {
+                        break;
+                    }
+
+                    let frame = &buffer[offset..offset + frame_len];
+                    let stored_crc = u32::from_le_bytes([
+                        frame[frame_len - 4],
+                        frame[frame_len - 3],
+                        frame[frame_len - 2],
+                        frame[frame_len - 1],
+                    ]);
+                    if stored_crc != flash.crc32_digest().checksum(&frame[..frame_len - 4]) {
+                        break;
+                    }
+
+                    f(&LogRecord {
+                        page_sequence,
+                        timestamp: u32::from_le_bytes([frame[2], frame[3], frame[4], frame[5]]),
+                        level: frame[1],
+                        message: &frame[6..6 + message_len],
+                    });
+
+                    offset += frame_len;
+                }
+            }
+
+            block = self.next_block(block);
+        }
+
+        Ok(())
+    }
+
+    /// The number of records dropped by `push` because the staged page was full
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    fn next_block(&self, block: u16) -> u16 {
+        if block + 1 == self.first_block + self.block_count {
+            self.first_block
+        } else {
+            block + 1
+        }
+    }
+
+    fn block_first_page(&self, block: u16) -> u16 {
+        Geometry::W25N01GV.block_first_page(block)
+    }
+}
+
+#[cfg(feature = "log-sink")]
+pub use logger::FlashLogger;
+
+#[cfg(feature = "log-sink")]
+mod logger {
+    use core::{
+        cell::{Cell, RefCell},
+        fmt::{self, Write},
+    };
+
+    use critical_section::Mutex;
+    use hal::blocking::delay::DelayUs;
+
+    use super::{FlashLogSink, LogRecord, MAX_LOG_MESSAGE_BYTES};
+    use crate::{QspiBus, ReadMethod, ReadMode, StorageError, WriteMethod, W25N01GV};
+
+    /// A `log::Log` over a `FlashLogSink`, see the module docs. Records are stored with the
+    /// `log::Level` as their level, 1 for errors through 5 for trace.
+    pub struct FlashLogger {
+        sink: Mutex<RefCell<Option<FlashLogSink>>>,
+        dropped: Mutex<Cell<u32>>,
+        time_source: fn() -> u32,
+        max_level: log::Level,
+    }
+
+    impl FlashLogger {
+        /// A logger without a sink yet, keeping records up to `max_level` and stamping them
+        /// with `time_source`. It's const so the logger can be a `static`.
+        pub const fn new(time_source: fn() -> u32, max_level: log::Level) -> FlashLogger {
+            FlashLogger {
+                sink: Mutex::new(RefCell::new(None)),
+                dropped: Mutex::new(Cell::new(0)),
+                time_source,
+                max_level,
+            }
+        }
+
+        /// Gives the logger the sink to stage records in, returning the one it had
+        pub fn attach(&self, sink: FlashLogSink) -> Option<FlashLogSink> {
+            critical_section::with(|cs| self.sink.borrow_ref_mut(cs).replace(sink))
+        }
+
+        /// Writes the staged records out with `FlashLogSink::pump`. Does nothing without a sink.
+        pub fn pump<BUS: QspiBus, D>(
+            &self,
+            flash: W25N01GV<BUS, ReadMode>,
+            write_method: WriteMethod,
+            delay: &mut D,
+        ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>
+        where
+            D: DelayUs<u32>,
+        {
+            let mut sink = match self.take_sink() {
+                Some(sink) => sink,
+                None => return Ok(flash),
+            };
+
+            let result = sink.pump(flash, write_method, delay);
+            self.put_sink(sink);
+
+            result
+        }
+
+        /// Reads the log back with `FlashLogSink::read_logs`. Reads nothing without a sink.
+        pub fn read_logs<BUS: QspiBus, MODE, F>(
+            &self,
+            flash: &W25N01GV<BUS, MODE>,
+            method: ReadMethod,
+            f: F,
+        ) -> Result<(), StorageError>
+        where
+            F: FnMut(&LogRecord),
+        {
+            let sink = match self.take_sink() {
+                Some(sink) => sink,
+                None => return Ok(()),
+            };
+
+            let result = sink.read_logs(flash, method, f);
+            self.put_sink(sink);
+
+            result
+        }
+
+        /// Records dropped by the sink because its staged page was full, plus those logged
+        /// while the logger had no sink to stage them in
+        pub fn dropped(&self) -> u32 {
+            critical_section::with(|cs| {
+                let sink_dropped = self
+                    .sink
+                    .borrow_ref(cs)
+                    .as_ref()
+                    .map(|sink| sink.dropped())
+                    .unwrap_or(0);
+
+                self.dropped.borrow(cs).get().wrapping_add(sink_dropped)
+            })
+        }
+
+        /// Takes the sink out of the mutex, so the device can be used without holding it
+        fn take_sink(&self) -> Option<FlashLogSink> {
+            critical_section::with(|cs| self.sink.borrow_ref_mut(cs).take())
+        }
+
+        fn put_sink(&self, sink: FlashLogSink) {
+            critical_section::with(|cs| *self.sink.borrow_ref_mut(cs) = Some(sink));
+        }
+    }
+
+    impl log::Log for FlashLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= self.max_level
+        }
+
+        fn log(&self, record: &log::Record) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+
+            // Formatting stops once the message is full, keeping its start
+            let mut message = Message {
+                bytes: [0; MAX_LOG_MESSAGE_BYTES],
+                len: 0,
+            };
+            let _ = write!(message, "{}", record.args());
+            let timestamp = (self.time_source)();
+
+            critical_section::with(|cs| match self.sink.borrow_ref_mut(cs).as_mut() {
+                Some(sink) => {
+                    sink.push(
+                        timestamp,
+                        record.level() as u8,
+                        &message.bytes[..message.len],
+                    );
+                }
+                None => {
+                    let dropped = self.dropped.borrow(cs);
+                    dropped.set(dropped.get().wrapping_add(1));
+                }
+            });
+        }
+
+        /// Does nothing, writing to the device is `pump`'s job
+        fn flush(&self) {}
+    }
+
+    struct Message {
+        bytes: [u8; MAX_LOG_MESSAGE_BYTES],
+        len: usize,
+    }
+
+    impl Write for Message {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let room = self.bytes.len() - self.len;
+            let taken = s.len().min(room);
+            self.bytes[self.len..self.len + taken].copy_from_slice(&s.as_bytes()[..taken]);
+            self.len += taken;
+
+            if taken < s.len() {
+                Err(fmt::Error)
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use std::{format, vec::Vec};
+
+    const FIRST_BLOCK: u16 = 6;
+    const BLOCK_COUNT: u16 = 2;
+
+    fn mount(flash: &W25N01GV<SimFlash, ReadMode>) -> FlashLogSink {
+        FlashLogSink::mount(flash, FIRST_BLOCK, BLOCK_COUNT, ReadMethod::FastRead).unwrap()
+    }
+
+    fn messages(sink: &FlashLogSink, flash: &W25N01GV<SimFlash, ReadMode>) -> Vec<Vec<u8>> {
+        let mut messages = Vec::new();
+        sink.read_logs(flash, ReadMethod::FastRead, |record| {
+            messages.push(record.message.to_vec())
+        })
+        .unwrap();
+
+        messages
+    }
+
+    fn message(index: usize) -> Vec<u8> {
+        format!("record {:03} {}", index, "x".repeat(40)).into_bytes()
+    }
+
+    #[test]
+    fn records_survive_a_reboot() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut sink = mount(&flash);
+
+        let mut written = Vec::new();
+        for page in 0..3 {
+            for index in page * 10..page * 10 + 10 {
+                assert!(sink.push(index as u32, 3, &message(index)));
+                written.push(message(index));
+            }
+            flash = sink
+                .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+                .unwrap();
+        }
+        // Staged but never pumped, so lost with the power
+        assert!(sink.push(99, 3, b"never written"));
+        drop(flash);
+
+        sim.power_cycle();
+        let mut flash = sim.driver();
+        let mut sink = mount(&flash);
+        assert_eq!(messages(&sink, &flash), written);
+
+        // The log carries on after what the last session wrote
+        assert!(sink.push(100, 1, b"after reboot"));
+        flash = sink
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+        written.push(b"after reboot".to_vec());
+
+        let mut sequences = Vec::new();
+        sink.read_logs(&flash, ReadMethod::FastRead, |record| {
+            sequences.push(record.page_sequence)
+        })
+        .unwrap();
+        assert_eq!(sequences.last(), Some(&3));
+        assert_eq!(messages(&mount(&flash), &flash), written);
+    }
+
+    #[test]
+    fn a_torn_final_record_ends_the_log() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut sink = mount(&flash);
+
+        assert!(sink.push(1, 2, b"first page"));
+        flash = sink
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+
+        // A page of records that runs well past the half a torn program leaves behind
+        let frame_len = message(0).len() + FRAME_OVERHEAD_BYTES;
+        let count = 25;
+        for index in 0..count {
+            assert!(sink.push(index as u32, 2, &message(index)));
+        }
+        sim.cut_power_after(0, true);
+        assert!(sink
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .is_err());
+
+        sim.power_cycle();
+        let mut flash = sim.driver();
+        let mut sink = mount(&flash);
+
+        let intact = (PAGE_SIZE_WITH_ECC_BYTES / 2 - PAGE_HEADER_BYTES) / frame_len;
+        assert!(intact > 0 && intact < count);
+        let mut expected = std::vec![b"first page".to_vec()];
+        expected.extend((0..intact).map(message));
+        assert_eq!(messages(&sink, &flash), expected);
+
+        // New records go after the torn page and read back whole
+        assert!(sink.push(200, 1, b"after the tear"));
+        flash = sink
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+        expected.push(b"after the tear".to_vec());
+        assert_eq!(messages(&sink, &flash), expected);
+    }
+
+    fn pump_one(
+        sink: &mut FlashLogSink,
+        flash: W25N01GV<SimFlash, ReadMode>,
+        index: usize,
+    ) -> W25N01GV<SimFlash, ReadMode> {
+        assert!(sink.push(index as u32, 1, &message(index)));
+        sink.check_room(&flash).unwrap();
+        sink.pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap()
+    }
+
+    #[test]
+    fn a_retained_log_fills_then_reclaims_released_blocks() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut sink = mount(&flash);
+        sink.set_retention(true);
+
+        let capacity = BLOCK_COUNT as usize * PAGES_PER_BLOCK;
+        for index in 0..capacity {
+            flash = pump_one(&mut sink, flash, index);
+        }
+
+        let full = |reclaimable| {
+            Err(StorageError::StorageFull {
+                region: FIRST_BLOCK,
+                needed: 1,
+                reclaimable,
+            })
+        };
+
+        // Nothing staged needs no room
+        assert_eq!(sink.check_room(&flash), Ok(()));
+        assert!(sink.push(capacity as u32, 1, &message(capacity)));
+        assert_eq!(sink.check_room(&flash), full(false));
+
+        // Half of the oldest block released isn't enough to erase it
+        sink.release_through(PAGES_PER_BLOCK as u32 / 2);
+        assert_eq!(sink.check_room(&flash), full(false));
+        assert_eq!(sink.reclaim(&flash, &mut NoDelay), Ok(0));
+
+        sink.release_through(PAGES_PER_BLOCK as u32 - 1);
+        assert_eq!(sink.check_room(&flash), full(true));
+        sim.clear_log();
+        assert_eq!(sink.reclaim(&flash, &mut NoDelay), Ok(1));
+        assert_eq!(sim.count(0xD8), 1);
+        assert_eq!(sink.check_room(&flash), Ok(()));
+
+        // The reclaimed block takes exactly one block of pages, without being erased again
+        sim.clear_log();
+        flash = sink
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+        for index in capacity + 1..capacity + PAGES_PER_BLOCK {
+            flash = pump_one(&mut sink, flash, index);
+        }
+        assert_eq!(sim.count(0xD8), 0);
+
+        assert!(sink.push(0, 1, b"one too many"));
+        assert_eq!(sink.check_room(&flash), full(false));
+        assert_eq!(
+            messages(&sink, &flash),
+            (PAGES_PER_BLOCK..capacity + PAGES_PER_BLOCK)
+                .map(message)
+                .collect::<Vec<_>>()
+        );
+
+        // Pumping into a full log gives up the driver rather than erase records
+        assert_eq!(
+            sink.pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+                .err(),
+            Some(StorageError::StorageFull {
+                region: FIRST_BLOCK,
+                needed: 1,
+                reclaimable: false,
+            })
+        );
+    }
+
+    #[test]
+    fn without_retention_the_log_overwrites_its_oldest_block() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut sink = mount(&flash);
+
+        let capacity = BLOCK_COUNT as usize * PAGES_PER_BLOCK;
+        for index in 0..capacity + 1 {
+            flash = pump_one(&mut sink, flash, index);
+        }
+
+        let kept = messages(&sink, &flash);
+        assert_eq!(kept.first(), Some(&message(PAGES_PER_BLOCK)));
+        assert_eq!(kept.last(), Some(&message(capacity)));
+    }
+
+    #[cfg(feature = "log-sink")]
+    #[test]
+    fn flash_logger_stages_records_until_pumped() {
+        use log::Log;
+
+        static LOGGER: FlashLogger = FlashLogger::new(|| 1234, log::Level::Info);
+
+        fn log(level: log::Level, args: core::fmt::Arguments) {
+            LOGGER.log(&log::Record::builder().level(level).args(args).build());
+        }
+
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+
+        // Nowhere to put it yet
+        log(log::Level::Error, format_args!("too early"));
+        assert_eq!(LOGGER.dropped(), 1);
+
+        assert!(LOGGER.attach(mount(&flash)).is_none());
+        log(log::Level::Warn, format_args!("block {} worn", 7));
+        log(log::Level::Debug, format_args!("filtered out"));
+        log(log::Level::Info, format_args!("{}", "y".repeat(300)));
+        assert_eq!(sim.count(0x10), 0);
+
+        flash = LOGGER
+            .pump(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+
+        let mut records = Vec::new();
+        LOGGER
+            .read_logs(&flash, ReadMethod::FastRead, |record| {
+                records.push((record.timestamp, record.level, record.message.to_vec()))
+            })
+            .unwrap();
+        assert_eq!(
+            records,
+            std::vec![
+                (1234, 2, b"block 7 worn".to_vec()),
+                (1234, 3, std::vec![b'y'; MAX_LOG_MESSAGE_BYTES]),
+            ]
+        );
+        assert_eq!(LOGGER.dropped(), 1);
+    }
+}
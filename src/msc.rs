@@ -0,0 +1,359 @@
+//! Serving the device as the 512 byte logical blocks of a USB mass storage device, e.g. so a
+//! host can pull logs off it as a drive. `UsbMscBackend` is what the SCSI layer of a USB stack
+//! calls for READ(10) and friends, and doesn't depend on any USB stack itself.
+//!
+//! Logical block `lba` is the `lba % 4`th quarter of the main area of page `lba / 4`, so the spare
+//! areas aren't reachable and the drive is `MSC_BLOCK_COUNT` blocks. Hosts read a directory or a
+//! FAT one logical block at a time and keep coming back to the same few, so the backend keeps the
+//! last `N` pages it read in RAM and evicts the least recently used one on a miss. A page is only
+//! read from the device when it isn't cached, and `stats` counts how often that was.
+//!
+//! The backend is read-only: writes fail with `FlashError::Protected`, which the SCSI layer
+//! should report as a write protected medium. NAND can't be rewritten in place a logical block at a
+//! time, so a writable drive needs a translation layer this crate doesn't have. The cached pages
+//! also aren't dropped when the device changes underneath, so call `invalidate` after writing
+//! through `flash`.
+
+use crate::{
+    status::ECCStatus, FlashError, QspiBus, ReadMethod, ReadMode, BLOCK_COUNT, PAGES_PER_BLOCK,
+    PAGE_SIZE_BYTES, W25N01GV,
+};
+
+pub const MSC_BLOCK_SIZE: usize = 512;
+
+pub const MSC_BLOCKS_PER_PAGE: usize = PAGE_SIZE_BYTES / MSC_BLOCK_SIZE;
+
+/// How many logical blocks the drive is
+pub const MSC_BLOCK_COUNT: u32 = (BLOCK_COUNT * PAGES_PER_BLOCK * MSC_BLOCKS_PER_PAGE) as u32;
+
+/// How well the page cache is doing
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MscStats {
+    /// Logical blocks served from a cached page
+    pub hits: u32,
+    /// Pages read from the device
+    pub page_reads: u32,
+}
+
+#[derive(Clone, Copy)]
+struct CachedPage {
+    page_address: Option<u16>,
+    last_used: u32,
+    data: [u8; PAGE_SIZE_BYTES],
+}
+
+impl CachedPage {
+    const EMPTY: CachedPage = CachedPage {
+        page_address: None,
+        last_used: 0,
+        data: [0xFF; PAGE_SIZE_BYTES],
+    };
+}
+
+/// The driver as the logical blocks of a read-only USB drive, caching `N` pages, see the module
+/// docs. `N` has to be at least 1.
+pub struct UsbMscBackend<BUS, const N: usize> {
+    flash: W25N01GV<BUS, ReadMode>,
+    method: ReadMethod,
+    pages: [CachedPage; N],
+    clock: u32,
+    stats: MscStats,
+}
+
+impl<BUS: QspiBus, const N: usize> UsbMscBackend<BUS, N> {
+    /// Wraps the driver, reading pages with `method`
+    pub fn new(flash: W25N01GV<BUS, ReadMode>, method: ReadMethod) -> UsbMscBackend<BUS, N> {
+        UsbMscBackend {
+            flash,
+            method,
+            pages: [CachedPage::EMPTY; N],
+            clock: 0,
+            stats: MscStats::default(),
+        }
+    }
+
+    pub fn flash(&self) -> &W25N01GV<BUS, ReadMode> {
+        &self.flash
+    }
+
+    pub fn into_inner(self) -> W25N01GV<BUS, ReadMode> {
+        self.flash
+    }
+
+    pub fn block_count(&self) -> u32 {
+        MSC_BLOCK_COUNT
+    }
+
+    pub fn stats(&self) -> MscStats {
+        self.stats
+    }
+
+    /// Drops every cached page, e.g. after writing to the device through `flash`
+    pub fn invalidate(&mut self) {
+        for page in self.pages.iter_mut() {
+            page.page_address = None;
+        }
+    }
+
+    /// Reads logical blocks from `lba` on into `blocks`, whose length has to be a whole number of
+    /// blocks. Returns `FlashError::ECC` if a page had more bit errors than ECC could
+    /// correct.
+    pub fn read_blocks(&mut self, lba: u32, blocks: &mut [u8]) -> Result<(), FlashError> {
+        if !blocks.len().is_multiple_of(MSC_BLOCK_SIZE) {
+            return Err(FlashError::NotAligned);
+        }
+
+        let count = (blocks.len() / MSC_BLOCK_SIZE) as u32;
+        if lba
+            .checked_add(count)
+            .is_none_or(|end| end > MSC_BLOCK_COUNT)
+        {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        for (block_lba, block) in (lba..).zip(blocks.chunks_exact_mut(MSC_BLOCK_SIZE)) {
+            let page_address = (block_lba as usize / MSC_BLOCKS_PER_PAGE) as u16;
+            let offset = (block_lba as usize % MSC_BLOCKS_PER_PAGE) * MSC_BLOCK_SIZE;
+
+            let page = self.cached_page(page_address)?;
+            block.copy_from_slice(&page[offset..offset + MSC_BLOCK_SIZE]);
+        }
+
+        Ok(())
+    }
+
+    /// Always fails with `FlashError::Protected`, the backend being read-only
+    pub fn write_blocks(&mut self, _lba: u32, _blocks: &[u8]) -> Result<(), FlashError> {
+        Err(FlashError::Protected)
+    }
+
+    /// The main area of a page, from the cache if it's there and from the device if it isn't
+    fn cached_page(&mut self, page_address: u16) -> Result<&[u8; PAGE_SIZE_BYTES], FlashError> {
+        self.clock = self.clock.wrapping_add(1);
+        let clock = self.clock;
+
+        let index = match self
+            .pages
+            .iter()
+            .position(|page| page.page_address == Some(page_address))
+        {
+            Some(index) => {
+                self.stats.hits = self.stats.hits.saturating_add(1);
+                index
+            }
+            None => {
+                let index = self.least_recently_used();
+                let page = &mut self.pages[index];
+                page.page_address = None;
+
+                self.flash.read_memory_to_data_buffer(page_address)?;
+                self.flash.wait_while_busy()?;
+
+                let status = self.flash.read_status_register()?.ecc_status;
+                if let ECCStatus::SinglePageError | ECCStatus::MultiPageError = status {
+                    return Err(FlashError::ECC {
+                        status,
+                        page_address,
+                    });
+                }
+
+                self.flash.read_page_data(&mut page.data, self.method)?;
+                page.page_address = Some(page_address);
+                self.stats.page_reads = self.stats.page_reads.saturating_add(1);
+
+                index
+            }
+        };
+
+        let page = &mut self.pages[index];
+        page.last_used = clock;
+
+        Ok(&page.data)
+    }
+
+    /// An empty slot if there is one, otherwise the one used longest ago
+    fn least_recently_used(&self) -> usize {
+        let clock = self.clock;
+
+        self.pages
+            .iter()
+            .position(|page| page.page_address.is_none())
+            .or_else(|| (0..N).max_by_key(|index| clock.wrapping_sub(self.pages[*index].last_used)))
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::SimFlash;
+
+    fn backend<const N: usize>(sim: &SimFlash) -> UsbMscBackend<SimFlash, N> {
+        UsbMscBackend::new(sim.driver(), ReadMethod::FastRead)
+    }
+
+    /// Fills every logical block of `page_address` with its own LBA, low byte first, plus the
+    /// offset into the block
+    fn fill(sim: &SimFlash, page_address: u16) {
+        let mut bytes = vec![0; PAGE_SIZE_BYTES];
+        for (index, block) in bytes.chunks_exact_mut(MSC_BLOCK_SIZE).enumerate() {
+            let lba = page_address as usize * MSC_BLOCKS_PER_PAGE + index;
+            for (offset, byte) in block.iter_mut().enumerate() {
+                *byte = (lba + offset) as u8;
+            }
+        }
+        sim.set_page(page_address, &bytes);
+    }
+
+    fn expected(lba: u32) -> Vec<u8> {
+        (0..MSC_BLOCK_SIZE)
+            .map(|offset| (lba as usize + offset) as u8)
+            .collect()
+    }
+
+    fn pages_read(sim: &SimFlash) -> Vec<u16> {
+        sim.commands()
+            .iter()
+            .filter(|command| command.opcode == 0x13)
+            .filter_map(|command| command.page_address())
+            .collect()
+    }
+
+    #[test]
+    fn a_run_of_blocks_reads_each_page_once() {
+        let sim = SimFlash::new();
+        (0..3).for_each(|page| fill(&sim, page));
+        let mut backend = backend::<2>(&sim);
+        sim.clear_log();
+
+        let mut blocks = vec![0; 10 * MSC_BLOCK_SIZE];
+        backend.read_blocks(1, &mut blocks).unwrap();
+
+        for (lba, block) in (1..).zip(blocks.chunks_exact(MSC_BLOCK_SIZE)) {
+            assert_eq!(block, &expected(lba)[..], "lba {}", lba);
+        }
+        assert_eq!(pages_read(&sim), [0, 1, 2]);
+        assert_eq!(
+            backend.stats(),
+            MscStats {
+                hits: 7,
+                page_reads: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn directory_listing_pattern_stays_cached() {
+        let sim = SimFlash::new();
+        [0, 1, 40].iter().for_each(|page| fill(&sim, *page));
+        let mut backend = backend::<3>(&sim);
+        sim.clear_log();
+
+        // Boot sector, FAT, root directory and a file's first cluster, over and over like a host
+        // listing a directory
+        let pattern = [0, 4, 5, 160, 0, 4, 161, 5, 0, 160, 161, 4];
+        let mut block = [0; MSC_BLOCK_SIZE];
+        for _ in 0..10 {
+            for lba in pattern.iter() {
+                backend.read_blocks(*lba, &mut block).unwrap();
+                assert_eq!(&block[..], &expected(*lba)[..], "lba {}", lba);
+            }
+        }
+
+        assert_eq!(pages_read(&sim), [0, 1, 40]);
+        assert_eq!(backend.stats().hits, 10 * pattern.len() as u32 - 3);
+    }
+
+    #[test]
+    fn the_least_recently_used_page_is_evicted() {
+        let sim = SimFlash::new();
+        (0..3).for_each(|page| fill(&sim, page));
+        let mut backend = backend::<2>(&sim);
+        sim.clear_log();
+
+        let mut block = [0; MSC_BLOCK_SIZE];
+        for lba in [0, 4, 0, 8, 1, 5].iter() {
+            backend.read_blocks(*lba, &mut block).unwrap();
+            assert_eq!(&block[..], &expected(*lba)[..], "lba {}", lba);
+        }
+
+        // Page 1 was used longest ago when page 2 came in, so it's the one read again
+        assert_eq!(pages_read(&sim), [0, 1, 2, 1]);
+    }
+
+    #[test]
+    fn invalidate_rereads_the_device() {
+        let sim = SimFlash::new();
+        fill(&sim, 0);
+        let mut backend = backend::<2>(&sim);
+
+        let mut block = [0; MSC_BLOCK_SIZE];
+        backend.read_blocks(0, &mut block).unwrap();
+
+        sim.set_page(0, &[0xA5; PAGE_SIZE_BYTES]);
+        backend.read_blocks(0, &mut block).unwrap();
+        assert_eq!(&block[..], &expected(0)[..]);
+
+        backend.invalidate();
+        backend.read_blocks(0, &mut block).unwrap();
+        assert_eq!(block, [0xA5; MSC_BLOCK_SIZE]);
+        assert_eq!(backend.stats().page_reads, 2);
+    }
+
+    #[test]
+    fn uncorrectable_pages_fail_and_are_not_cached() {
+        let sim = SimFlash::new();
+        sim.set_uncorrectable(3);
+        let mut backend = backend::<2>(&sim);
+        sim.clear_log();
+
+        let mut block = [0; MSC_BLOCK_SIZE];
+        for _ in 0..2 {
+            assert!(matches!(
+                backend.read_blocks(12, &mut block),
+                Err(FlashError::ECC {
+                    page_address: 3,
+                    ..
+                })
+            ));
+        }
+
+        assert_eq!(pages_read(&sim), [3, 3]);
+        assert_eq!(sim.count(0x0B), 0);
+    }
+
+    #[test]
+    fn bad_requests_are_refused_without_touching_the_device() {
+        let sim = SimFlash::new();
+        let mut backend = backend::<2>(&sim);
+        sim.clear_log();
+
+        let mut blocks = vec![0; 2 * MSC_BLOCK_SIZE];
+        assert!(matches!(
+            backend.read_blocks(0, &mut blocks[..MSC_BLOCK_SIZE + 1]),
+            Err(FlashError::NotAligned)
+        ));
+        assert!(matches!(
+            backend.read_blocks(MSC_BLOCK_COUNT - 1, &mut blocks),
+            Err(FlashError::OutOfBounds)
+        ));
+        assert!(matches!(
+            backend.read_blocks(u32::MAX, &mut blocks),
+            Err(FlashError::OutOfBounds)
+        ));
+        assert!(matches!(
+            backend.write_blocks(0, &blocks),
+            Err(FlashError::Protected)
+        ));
+        assert!(sim.commands().is_empty());
+
+        backend
+            .read_blocks(MSC_BLOCK_COUNT - 2, &mut blocks)
+            .unwrap();
+        assert_eq!(pages_read(&sim), [(MSC_BLOCK_COUNT / 4 - 1) as u16]);
+    }
+}
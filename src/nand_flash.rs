@@ -0,0 +1,357 @@
+//! Storage traits shaped for NAND, implemented directly on the driver, so filesystem layers and
+//! generic tooling can take any NAND device without going through a NOR flash shim.
+//!
+//! `embedded-storage` only has NOR flash traits, which `NorFlashAdapter` implements. These follow
+//! the same shape, with offsets into the main area of the pages in page order, but carry what NAND
+//! consumers need to know up front: `PAGE_SIZE` is the unit of programming and `BLOCK_SIZE` the
+//! unit of erasing, and blocks can go bad.
+//!
+//! On `W25N01GV` the alignments are:
+//!
+//! - `read`: none. Any offset and length within the device, split at 2048 byte page boundaries,
+//!   each part a Page Data Read and a transfer of the part's columns.
+//! - `write`: offset and length multiples of a 2048 byte page, each page a load and a Program
+//!   Execute. Anything else returns `FlashError::NotAligned`.
+//! - `erase`: both ends multiples of a 128KB block, each block a Block Erase. Anything else returns
+//!   `FlashError::NotAligned`.
+//!
+//! Past the end of the device is `FlashError::OutOfBounds`, and the block 0 policy applies to
+//! writes and erases. The spare area isn't reachable through the traits.
+//!
+//! The traits take `&mut self` and the driver stays in read mode throughout, setting the write
+//! enable latch before each program and erase, so a failed operation leaves it usable. Data moves
+//! with `ReadMethod::FastRead` and `WriteMethod::SingleLoad`, which every wiring supports. The
+//! driver has no delay of its own, so the waits for programs and erases poll the status register
+//! back to back. Like the driver's other methods, an operation started while the device is busy
+//! returns `FlashError::DeviceBusy` rather than waiting for it.
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    status::ECCStatus, Column, FlashError, Geometry, QspiBus, ReadMethod, ReadMode, WriteMethod,
+    BLOCK_COUNT, PAGES_PER_BLOCK, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+/// Reading from a NAND device, see the module docs
+pub trait ReadNandFlash {
+    type Error;
+
+    /// The size of a page, which writes are aligned to
+    const PAGE_SIZE: usize;
+    /// The size of an erase block, which erases are aligned to
+    const BLOCK_SIZE: usize;
+
+    /// Reads `bytes.len()` bytes from `offset`
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// The size of the device in bytes
+    fn capacity(&self) -> usize;
+
+    /// Whether the erase block containing `offset` is marked bad
+    fn is_bad(&mut self, offset: u32) -> Result<bool, Self::Error>;
+}
+
+/// Writing to and erasing a NAND device, see the module docs
+pub trait NandFlash: ReadNandFlash {
+    /// Erases the blocks from `from` up to `to`, both aligned to `BLOCK_SIZE`
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error>;
+
+    /// Programs `bytes` from `offset`, both aligned to `PAGE_SIZE`. The pages have to be erased
+    /// first, since programming can only clear bits.
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+const BLOCK_SIZE_BYTES: usize = PAGES_PER_BLOCK * PAGE_SIZE_BYTES;
+const CAPACITY_BYTES: usize = BLOCK_COUNT * BLOCK_SIZE_BYTES;
+
+/// Polls back to back, since the driver has no delay to sleep with
+struct Spin;
+
+impl DelayUs<u32> for Spin {
+    fn delay_us(&mut self, _us: u32) {}
+}
+
+fn check_range(offset: u32, len: usize) -> Result<(), FlashError> {
+    if offset as usize + len > CAPACITY_BYTES {
+        return Err(FlashError::OutOfBounds);
+    }
+
+    Ok(())
+}
+
+fn check_aligned(offset: u32, len: usize, alignment: usize) -> Result<(), FlashError> {
+    if !(offset as usize).is_multiple_of(alignment) || !len.is_multiple_of(alignment) {
+        return Err(FlashError::NotAligned);
+    }
+
+    check_range(offset, len)
+}
+
+impl<BUS: QspiBus> ReadNandFlash for W25N01GV<BUS, ReadMode> {
+    type Error = FlashError;
+
+    const PAGE_SIZE: usize = PAGE_SIZE_BYTES;
+    const BLOCK_SIZE: usize = BLOCK_SIZE_BYTES;
+
+    /// Returns `FlashError::ECC` if a page had more bit errors than ECC could correct. Column
+    /// reads need buffered read mode, so continuous read mode is turned off for the duration of
+    /// the read and restored afterwards.
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        check_range(offset, bytes.len())?;
+
+        let _guard = self.begin_operation()?;
+
+        self.with_buffered_read(|| {
+            let mut done = 0;
+            while done < bytes.len() {
+                let position = offset as usize + done;
+                let page_address = (position / PAGE_SIZE_BYTES) as u16;
+                let column = position % PAGE_SIZE_BYTES;
+                let len = (PAGE_SIZE_BYTES - column).min(bytes.len() - done);
+
+                self.read_memory_to_data_buffer_unguarded(page_address)?;
+                self.wait_while_busy_unguarded()?;
+
+                let status = self.read_status_register_unguarded()?.ecc_status;
+                if let ECCStatus::SinglePageError | ECCStatus::MultiPageError = status {
+                    return Err(FlashError::ECC {
+                        status,
+                        page_address,
+                    });
+                }
+
+                self.read_columns_unguarded(
+                    Column::Physical(column as u16),
+                    &mut bytes[done..done + len],
+                    ReadMethod::FastRead,
+                )?;
+                done += len;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        CAPACITY_BYTES
+    }
+
+    fn is_bad(&mut self, offset: u32) -> Result<bool, FlashError> {
+        check_range(offset, 1)?;
+
+        let block = (offset as usize / BLOCK_SIZE_BYTES) as u16;
+        self.is_bad_block(block, ReadMethod::FastRead)
+    }
+}
+
+impl<BUS: QspiBus> NandFlash for W25N01GV<BUS, ReadMode> {
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        if from > to {
+            return Err(FlashError::OutOfBounds);
+        }
+        check_aligned(from, (to - from) as usize, BLOCK_SIZE_BYTES)?;
+
+        let first_block = (from as usize / BLOCK_SIZE_BYTES) as u16;
+        let end_block = (to as usize / BLOCK_SIZE_BYTES) as u16;
+        self.check_block0(first_block, end_block - first_block)?;
+
+        let _guard = self.begin_operation()?;
+
+        for block in first_block..end_block {
+            self.erase_block_unguarded(block, &mut Spin)?;
+        }
+
+        Ok(())
+    }
+
+    /// Verifies each page at the driver's verification level like `commit`
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        check_aligned(offset, bytes.len(), PAGE_SIZE_BYTES)?;
+
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let first_page = (offset as usize / PAGE_SIZE_BYTES) as u16;
+        let last_page = first_page + (bytes.len() / PAGE_SIZE_BYTES) as u16 - 1;
+        let first_block = Geometry::W25N01GV.block_of_page(first_page);
+        let last_block = Geometry::W25N01GV.block_of_page(last_page);
+        self.check_block0(first_block, last_block - first_block + 1)?;
+
+        let _guard = self.begin_operation()?;
+        let write_method = WriteMethod::SingleLoad;
+
+        for (index, page) in bytes.chunks_exact(PAGE_SIZE_BYTES).enumerate() {
+            self.send_write_enable()?;
+            self.load_split(0, page, write_method.resetting())?;
+            self.verify_load(0, page)?;
+            self.commit_with_unguarded(
+                first_page + index as u16,
+                self.verification_level(),
+                &mut Spin,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::{sim::SimFlash, Block0Policy};
+
+    fn fill(sim: &SimFlash, page_address: u16) {
+        let bytes: Vec<u8> = (0..PAGE_SIZE_BYTES)
+            .map(|column| (page_address as usize + column) as u8)
+            .collect();
+        sim.set_page(page_address, &bytes);
+    }
+
+    fn page_addresses(sim: &SimFlash, opcode: u8) -> Vec<u16> {
+        sim.commands()
+            .iter()
+            .filter(|command| command.opcode == opcode)
+            .filter_map(|command| command.page_address())
+            .collect()
+    }
+
+    #[test]
+    fn reads_map_offsets_to_pages_and_columns() {
+        let sim = SimFlash::new();
+        for page_address in [0, 1, 63, 64] {
+            fill(&sim, page_address);
+        }
+        let mut flash = sim.driver();
+
+        let cases: [(u32, usize, &[u16]); 3] = [
+            (PAGE_SIZE_BYTES as u32 - 4, 8, &[0, 1]),
+            (BLOCK_SIZE_BYTES as u32 - 3, 6, &[63, 64]),
+            (BLOCK_SIZE_BYTES as u32 + 7, 5, &[64]),
+        ];
+
+        for (offset, len, pages) in cases.iter() {
+            sim.clear_log();
+
+            let mut bytes = [0_u8; 8];
+            ReadNandFlash::read(&mut flash, *offset, &mut bytes[..*len]).unwrap();
+
+            assert_eq!(page_addresses(&sim, 0x13), *pages);
+            for (index, byte) in bytes[..*len].iter().enumerate() {
+                let position = *offset as usize + index;
+                assert_eq!(
+                    *byte,
+                    (position / PAGE_SIZE_BYTES + position % PAGE_SIZE_BYTES) as u8
+                );
+            }
+        }
+
+        let mut bytes = [0_u8; 5];
+        let end = flash.capacity() as u32 - 4;
+        assert_eq!(
+            ReadNandFlash::read(&mut flash, end, &mut bytes),
+            Err(FlashError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn writes_program_whole_pages_and_refuse_anything_else() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let page = PAGE_SIZE_BYTES as u32;
+
+        let mut bytes = std::vec![0x11_u8; 2 * PAGE_SIZE_BYTES];
+        bytes[PAGE_SIZE_BYTES..].fill(0x22);
+        NandFlash::write(&mut flash, 63 * page, &bytes).unwrap();
+
+        assert_eq!(page_addresses(&sim, 0x10), [63, 64]);
+        assert_eq!(&sim.page(63)[..PAGE_SIZE_BYTES], &bytes[..PAGE_SIZE_BYTES]);
+        assert_eq!(&sim.page(64)[..PAGE_SIZE_BYTES], &bytes[PAGE_SIZE_BYTES..]);
+
+        sim.clear_log();
+        let misaligned: [(u32, usize); 3] = [(1, PAGE_SIZE_BYTES), (page, 16), (page + 16, 16)];
+        for (offset, len) in misaligned.iter() {
+            assert_eq!(
+                NandFlash::write(&mut flash, *offset, &bytes[..*len]),
+                Err(FlashError::NotAligned)
+            );
+        }
+        let last_page = flash.capacity() as u32 - page;
+        assert_eq!(
+            NandFlash::write(&mut flash, last_page, &bytes),
+            Err(FlashError::OutOfBounds)
+        );
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn erases_map_to_whole_blocks_and_refuse_anything_else() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let block = BLOCK_SIZE_BYTES as u32;
+
+        NandFlash::erase(&mut flash, 2 * block, 4 * block).unwrap();
+        assert_eq!(page_addresses(&sim, 0xD8), [128, 192]);
+
+        sim.clear_log();
+        assert_eq!(
+            NandFlash::erase(&mut flash, block + 2048, 2 * block),
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            NandFlash::erase(&mut flash, block, block + 2048),
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            NandFlash::erase(&mut flash, 2 * block, block),
+            Err(FlashError::OutOfBounds)
+        );
+        let capacity = flash.capacity() as u32;
+        assert_eq!(
+            NandFlash::erase(&mut flash, capacity, capacity + block),
+            Err(FlashError::OutOfBounds)
+        );
+
+        flash.set_block0_policy(Block0Policy::Reserved);
+        assert_eq!(
+            NandFlash::erase(&mut flash, 0, block),
+            Err(FlashError::Block0Reserved)
+        );
+        assert_eq!(
+            NandFlash::write(&mut flash, 0, &[0; PAGE_SIZE_BYTES]),
+            Err(FlashError::Block0Reserved)
+        );
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn a_failed_program_leaves_the_driver_usable() {
+        let sim = SimFlash::new();
+        sim.fail_program(5);
+        fill(&sim, 6);
+        let mut flash = sim.driver();
+        let page = PAGE_SIZE_BYTES as u32;
+
+        assert_eq!(
+            NandFlash::write(&mut flash, 5 * page, &[0; PAGE_SIZE_BYTES]),
+            Err(FlashError::ProgramFailed { page_address: 5 })
+        );
+
+        let mut bytes = [0_u8; 4];
+        ReadNandFlash::read(&mut flash, 6 * page, &mut bytes).unwrap();
+        assert_eq!(bytes, [6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn bad_blocks_are_reported_by_offset() {
+        let sim = SimFlash::new();
+        sim.mark_bad(3);
+        let mut flash = sim.driver();
+        let block = BLOCK_SIZE_BYTES as u32;
+
+        assert!(flash.is_bad(3 * block + 100).unwrap());
+        assert!(!flash.is_bad(4 * block).unwrap());
+    }
+}
@@ -0,0 +1,333 @@
+//! Tracking of the partial page program budget. The W25N01GV allows at most `MAX_PAGE_PROGRAMS`
+//! program operations on a page between erases (the datasheet's NOP). Going over it silently
+//! corrupts data already programmed into the page, so the driver refuses to do it unless told to.
+//!
+//! Every page of the `NOP_TRACKED_BLOCKS` most recently programmed blocks is counted. A block that
+//! falls out of the tracker while it still has programmed pages can't be counted any more, so all
+//! of its pages are treated as out of budget until the block is erased. Blocks the driver hasn't
+//! programmed since it was created are assumed to be erased.
+
+use crate::{FlashCommands, FlashError, Geometry, BLOCK_COUNT, W25N01GV};
+
+pub const MAX_PAGE_PROGRAMS: u8 = 4;
+pub const NOP_TRACKED_BLOCKS: usize = 8;
+
+/// The program counts of every page of a block, as three bit planes so a block takes 24 bytes.
+/// Bit `n` of `planes[bit]` is that bit of page `n`'s count.
+#[derive(Debug, Clone, Copy)]
+struct BlockPrograms {
+    block: u16,
+    planes: [u64; 3],
+}
+
+impl BlockPrograms {
+    fn programs(&self, page_index: usize) -> u8 {
+        self.planes
+            .iter()
+            .enumerate()
+            .map(|(bit, plane)| (((plane >> page_index) & 1) as u8) << bit)
+            .sum()
+    }
+
+    fn record_program(&mut self, page_index: usize) {
+        let programs = (self.programs(page_index) + 1).min(7);
+
+        for (bit, plane) in self.planes.iter_mut().enumerate() {
+            *plane &= !(1 << page_index);
+            *plane |= (((programs >> bit) & 1) as u64) << page_index;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct NopTracker {
+    /// Most recently programmed first
+    blocks: [Option<BlockPrograms>; NOP_TRACKED_BLOCKS],
+    /// Blocks that fell out of `blocks` since they were last erased, one bit per block
+    untracked: [u32; BLOCK_COUNT / 32],
+    override_next: bool,
+}
+
+impl NopTracker {
+    pub(crate) fn new() -> NopTracker {
+        NopTracker {
+            blocks: [None; NOP_TRACKED_BLOCKS],
+            untracked: [0; BLOCK_COUNT / 32],
+            override_next: false,
+        }
+    }
+
+    fn is_untracked(&self, block: u16) -> bool {
+        self.untracked[block as usize / 32] & (1 << (block % 32)) != 0
+    }
+
+    fn set_untracked(&mut self, block: u16, untracked: bool) {
+        let word = &mut self.untracked[block as usize / 32];
+        if untracked {
+            *word |= 1 << (block % 32);
+        } else {
+            *word &= !(1 << (block % 32));
+        }
+    }
+
+    fn programs(&self, page_address: u16) -> u8 {
+        let block = Geometry::W25N01GV.block_of_page(page_address);
+        if self.is_untracked(block) {
+            return MAX_PAGE_PROGRAMS;
+        }
+
+        self.blocks
+            .iter()
+            .flatten()
+            .find(|programs| programs.block == block)
+            .map(|programs| programs.programs(page_index(page_address)))
+            .unwrap_or(0)
+    }
+
+    fn record_program(&mut self, page_address: u16) {
+        self.override_next = false;
+
+        let block = Geometry::W25N01GV.block_of_page(page_address);
+        if self.is_untracked(block) {
+            return;
+        }
+
+        // Moves the block to the front, evicting the least recently programmed one if it's new
+        let end = self
+            .blocks
+            .iter()
+            .position(|programs| programs.map(|programs| programs.block) == Some(block))
+            .or_else(|| self.blocks.iter().position(Option::is_none))
+            .unwrap_or(NOP_TRACKED_BLOCKS - 1);
+
+        let programs = match self.blocks[end] {
+            Some(programs) if programs.block == block => programs,
+            evicted => {
+                if let Some(evicted) = evicted {
+                    self.set_untracked(evicted.block, true);
+                }
+
+                BlockPrograms {
+                    block,
+                    planes: [0; 3],
+                }
+            }
+        };
+
+        self.blocks[..=end].rotate_right(1);
+        let front = self.blocks[0].insert(programs);
+        front.record_program(page_index(page_address));
+    }
+
+    fn record_erase(&mut self, page_address: u16) {
+        let block = Geometry::W25N01GV.block_of_page(page_address);
+
+        self.set_untracked(block, false);
+        for entry in self.blocks.iter_mut() {
+            if entry.map(|programs| programs.block) == Some(block) {
+                *entry = None;
+            }
+        }
+    }
+}
+
+fn page_index(page_address: u16) -> usize {
+    page_address as usize % Geometry::W25N01GV.pages_per_block
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Lets the next Program Execute go ahead even if it goes over the partial page program
+    /// budget. Only for parts or use cases known to tolerate more programs per page.
+    pub fn override_nop_budget(&mut self) {
+        self.nop_tracker.borrow_mut().override_next = true;
+    }
+
+    /// How many times the page has been programmed since its block was last erased, as far as the
+    /// driver knows. Pages of a block that fell out of the tracker count as `MAX_PAGE_PROGRAMS`.
+    pub fn page_program_count(&self, page_address: u16) -> u8 {
+        self.nop_tracker.borrow().programs(page_address)
+    }
+
+    pub(crate) fn check_nop_budget(&self, page_address: u16) -> Result<(), FlashError> {
+        let tracker = self.nop_tracker.borrow();
+
+        if !tracker.override_next && tracker.programs(page_address) >= MAX_PAGE_PROGRAMS {
+            Err(FlashError::PartialProgramBudgetExceeded { page_address })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Updates the program counts for a write command that was sent to the device
+    pub(crate) fn record_nop_command(&self, opcode: u8, data: &[u8]) {
+        if data.len() != 2 {
+            return;
+        }
+
+        let page_address = u16::from_be_bytes([data[0], data[1]]);
+
+        if opcode == FlashCommands::ProgramExecute as u8 {
+            self.nop_tracker.borrow_mut().record_program(page_address);
+        } else if opcode == FlashCommands::Erase128KBBlock as u8 {
+            self.nop_tracker.borrow_mut().record_erase(page_address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use crate::{
+        FlashLogSink, ReadMethod, ReadMode, WriteMethod, PAGES_PER_BLOCK, PAGE_SIZE_BYTES,
+    };
+
+    fn program(
+        flash: W25N01GV<SimFlash, ReadMode>,
+        page_address: u16,
+        column: u16,
+        bytes: &[u8],
+    ) -> Result<W25N01GV<SimFlash, ReadMode>, FlashError> {
+        flash
+            .program_page(page_address, bytes, column, WriteMethod::QuadLoad)
+            .map(|(flash, _)| flash)
+    }
+
+    fn is_refused(result: Result<W25N01GV<SimFlash, ReadMode>, FlashError>, page: u16) -> bool {
+        matches!(
+            result,
+            Err(FlashError::PartialProgramBudgetExceeded { page_address }) if page_address == page
+        )
+    }
+
+    #[test]
+    fn a_fifth_partial_program_is_refused() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+
+        for quarter in 0..4_u16 {
+            flash = program(flash, 70, quarter * 512, &[quarter as u8; 512]).unwrap();
+        }
+        assert_eq!(flash.page_program_count(70), MAX_PAGE_PROGRAMS);
+        assert_eq!(flash.page_program_count(71), 0);
+
+        sim.clear_log();
+        assert!(is_refused(program(flash, 70, 0, &[0; 4]), 70));
+        assert_eq!(sim.count(0x10), 0);
+    }
+
+    #[test]
+    fn the_override_lets_one_program_through() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+
+        for _ in 0..MAX_PAGE_PROGRAMS {
+            flash = program(flash, 70, 0, &[0; 4]).unwrap();
+        }
+        flash.override_nop_budget();
+        flash = program(flash, 70, 0, &[0; 4]).unwrap();
+        assert_eq!(flash.page_program_count(70), MAX_PAGE_PROGRAMS + 1);
+
+        assert!(is_refused(program(flash, 70, 0, &[0; 4]), 70));
+    }
+
+    #[test]
+    fn spare_programs_share_the_page_budget() {
+        let sim = SimFlash::new();
+        let mut flash = sim
+            .driver()
+            .into_write_mode()
+            .unwrap()
+            .write_page_split(
+                70,
+                &[0x5A; PAGE_SIZE_BYTES],
+                &[0xFF, 0xFF, 1],
+                WriteMethod::QuadLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+        assert_eq!(flash.page_program_count(70), 1);
+
+        for index in 0..3_u16 {
+            flash = program(
+                flash,
+                70,
+                PAGE_SIZE_BYTES as u16 + 4 + index,
+                &[index as u8],
+            )
+            .unwrap();
+        }
+        assert_eq!(flash.page_program_count(70), MAX_PAGE_PROGRAMS);
+
+        assert!(is_refused(
+            program(flash, 70, PAGE_SIZE_BYTES as u16 + 20, &[0]),
+            70
+        ));
+    }
+
+    #[test]
+    fn erasing_the_block_resets_its_pages() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+
+        for _ in 0..MAX_PAGE_PROGRAMS {
+            flash = program(flash, 70, 0, &[0; 4]).unwrap();
+            flash = program(flash, 130, 0, &[0; 4]).unwrap();
+        }
+
+        let flash = flash.erase_block(1, &mut NoDelay).unwrap();
+        assert_eq!(flash.page_program_count(70), 0);
+        assert_eq!(flash.page_program_count(130), MAX_PAGE_PROGRAMS);
+
+        let flash = program(flash, 70, 0, &[0; 4]).unwrap();
+        assert_eq!(flash.page_program_count(70), 1);
+        assert!(is_refused(program(flash, 130, 0, &[0; 4]), 130));
+    }
+
+    #[test]
+    fn blocks_that_fall_out_of_the_tracker_are_refused_until_erased() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+
+        // One program in each of one more block than is tracked, so block 1 falls out
+        for block in 1..=NOP_TRACKED_BLOCKS as u16 + 1 {
+            flash = program(flash, block * PAGES_PER_BLOCK as u16, 0, &[0; 4]).unwrap();
+        }
+        assert_eq!(flash.page_program_count(64), MAX_PAGE_PROGRAMS);
+        assert_eq!(flash.page_program_count(65), MAX_PAGE_PROGRAMS);
+        assert_eq!(flash.page_program_count(128), 1);
+
+        let mut flash = flash.erase_block(1, &mut NoDelay).unwrap();
+        assert_eq!(flash.page_program_count(64), 0);
+        flash = program(flash, 64, 0, &[0; 4]).unwrap();
+
+        // Block 2 is now the least recently programmed, so it's the one that fell out
+        assert_eq!(flash.page_program_count(128), MAX_PAGE_PROGRAMS);
+        assert!(is_refused(program(flash, 129, 0, &[0; 4]), 129));
+    }
+
+    #[test]
+    fn a_wrapping_log_stays_within_budget() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut sink = FlashLogSink::mount(&flash, 6, 2, ReadMethod::FastRead).unwrap();
+
+        // Each log page gets its own flag in the spare area after it's written, using a second
+        // program of the page
+        for index in 0..2 * PAGES_PER_BLOCK as u32 + 1 {
+            let page_address = 6 * PAGES_PER_BLOCK as u16 + (index % 128) as u16;
+
+            assert!(sink.push(index, 3, b"entry"));
+            flash = sink
+                .pump(flash, WriteMethod::QuadLoad, &mut NoDelay)
+                .unwrap();
+            flash = program(flash, page_address, PAGE_SIZE_BYTES as u16 + 4, &[0]).unwrap();
+
+            assert_eq!(flash.page_program_count(page_address), 2, "entry {}", index);
+        }
+
+        // The first page was erased when the log wrapped, and only written once since
+        assert_eq!(flash.page_program_count(6 * PAGES_PER_BLOCK as u16), 2);
+        assert_eq!(flash.page_program_count(6 * PAGES_PER_BLOCK as u16 + 1), 0);
+    }
+}
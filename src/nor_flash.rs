@@ -0,0 +1,474 @@
+//! The `embedded-storage` NOR flash traits over the main area of the device, so generic storage
+//! consumers (key-value stores, filesystems, bootloaders) can use it. Enabled with the `nor-flash`
+//! feature.
+//!
+//! `NorFlashAdapter` maps offsets linearly onto the main areas of the pages in page order, 2048
+//! bytes each, so the spare area isn't reachable through it. Reads can start and end anywhere and
+//! are split at page boundaries. NAND can't be programmed a few bytes at a time like NOR, so
+//! writes are whole pages (`WRITE_SIZE` is a page) and erases are whole 128KB blocks. The block 0
+//! policy applies, so with block 0 reserved its bytes can be read but not written or erased.
+//!
+//! The adapter keeps the driver in read mode and sets the write enable latch itself before each
+//! program and erase, rather than going through `into_write_mode`, so a failed command leaves the
+//! driver usable for the next call instead of consuming it. Past that, programs and erases take
+//! the same steps as `commit` and `erase_block`: the verification level, latency budget and
+//! partial program budget all apply.
+//!
+//! Storage consumers treat any error as fatal, so the adapter never returns
+//! `FlashError::DeviceBusy`. Before each page or block it touches it waits, sleeping with
+//! its delay, for the device to finish whatever it was left doing, e.g. an operation started
+//! through `flash` or by a previous owner. It gives up with `FlashError::Timeout` after
+//! `set_busy_timeout` polls, 20ms by default. The waits for its own programs and erases are the
+//! same as `commit` and `erase_block`'s. What's left to surface are persistent conditions:
+//! timeouts, program and erase failures, uncorrectable pages and out of bounds accesses.
+
+use embedded_storage::nor_flash::{
+    self, ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    status::ECCStatus, Column, FlashError, Geometry, QspiBus, ReadMethod, ReadMode, WriteMethod,
+    BLOCK_COUNT, PAGES_PER_BLOCK, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+pub(crate) const BLOCK_SIZE_BYTES: usize = PAGES_PER_BLOCK * PAGE_SIZE_BYTES;
+
+/// How many 10us polls the adapter waits for the device to become ready before an operation by
+/// default
+pub const DEFAULT_BUSY_TIMEOUT_POLLS: u32 = 2_000;
+
+impl NorFlashError for FlashError {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FlashError::NotAligned => NorFlashErrorKind::NotAligned,
+            FlashError::OutOfBounds | FlashError::WouldWrapPageBuffer { .. } => {
+                NorFlashErrorKind::OutOfBounds
+            }
+            _ => NorFlashErrorKind::Other,
+        }
+    }
+}
+
+fn from_kind(kind: NorFlashErrorKind) -> FlashError {
+    match kind {
+        NorFlashErrorKind::NotAligned => FlashError::NotAligned,
+        _ => FlashError::OutOfBounds,
+    }
+}
+
+/// The driver as a NOR flash, see the module docs
+pub struct NorFlashAdapter<BUS, D> {
+    flash: W25N01GV<BUS, ReadMode>,
+    delay: D,
+    method: ReadMethod,
+    write_method: WriteMethod,
+    busy_timeout_polls: u32,
+}
+
+impl<BUS: QspiBus, D: DelayUs<u32>> NorFlashAdapter<BUS, D> {
+    /// Wraps the driver, sleeping with `delay` while the device is busy and moving data with
+    /// `method` and `write_method`
+    pub fn new(
+        flash: W25N01GV<BUS, ReadMode>,
+        delay: D,
+        method: ReadMethod,
+        write_method: WriteMethod,
+    ) -> NorFlashAdapter<BUS, D> {
+        NorFlashAdapter {
+            flash,
+            delay,
+            method,
+            write_method,
+            busy_timeout_polls: DEFAULT_BUSY_TIMEOUT_POLLS,
+        }
+    }
+
+    /// Sets how many times the adapter polls a busy device, 10us apart, before giving up with
+    /// `FlashError::Timeout`
+    pub fn set_busy_timeout(&mut self, max_polls: u32) {
+        self.busy_timeout_polls = max_polls;
+    }
+
+    pub fn flash(&self) -> &W25N01GV<BUS, ReadMode> {
+        &self.flash
+    }
+
+    pub fn into_inner(self) -> (W25N01GV<BUS, ReadMode>, D) {
+        (self.flash, self.delay)
+    }
+}
+
+/// Reads `buffer.len()` bytes of a page's main area from `column` on, returning
+/// `FlashError::ECC` if the page had more bit errors than ECC could correct
+fn read_page_part<BUS: QspiBus, D: DelayUs<u32>>(
+    flash: &W25N01GV<BUS, ReadMode>,
+    delay: &mut D,
+    page_address: u16,
+    column: u16,
+    buffer: &mut [u8],
+    method: ReadMethod,
+    busy_timeout_polls: u32,
+) -> Result<(), FlashError> {
+    flash.wait_while_busy_timeout_unguarded(delay, busy_timeout_polls)?;
+    flash.read_memory_to_data_buffer_unguarded(page_address)?;
+    flash.wait_while_busy_timeout_unguarded(delay, busy_timeout_polls)?;
+
+    let status = flash.read_status_register_unguarded()?.ecc_status;
+    if let ECCStatus::SinglePageError | ECCStatus::MultiPageError = status {
+        return Err(FlashError::ECC {
+            status,
+            page_address,
+        });
+    }
+
+    flash.read_columns_unguarded(Column::Physical(column), buffer, method)
+}
+
+/// Programs a whole page of `bytes`, which is at most a page long, from column 0, verifying it
+/// at the driver's verification level like `commit`
+fn program_page<BUS: QspiBus, D: DelayUs<u32>>(
+    flash: &W25N01GV<BUS, ReadMode>,
+    delay: &mut D,
+    page_address: u16,
+    bytes: &[u8],
+    write_method: WriteMethod,
+) -> Result<(), FlashError> {
+    flash.send_write_enable()?;
+    flash.load_split(0, bytes, write_method.resetting())?;
+    flash.verify_load(0, bytes)?;
+
+    flash.commit_with_unguarded(page_address, flash.verification_level(), delay)
+}
+
+impl<BUS, D> ErrorType for NorFlashAdapter<BUS, D> {
+    type Error = FlashError;
+}
+
+impl<BUS: QspiBus, D: DelayUs<u32>> ReadNorFlash for NorFlashAdapter<BUS, D> {
+    const READ_SIZE: usize = 1;
+
+    /// Like `sweep_spare`, this needs buffered read mode, so continuous read mode is turned off
+    /// for the duration of the read and restored afterwards.
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        nor_flash::check_read(self, offset, bytes.len()).map_err(from_kind)?;
+
+        let NorFlashAdapter {
+            flash,
+            delay,
+            method,
+            busy_timeout_polls,
+            ..
+        } = self;
+
+        let _guard = flash.begin_operation()?;
+
+        // Switching to buffered read mode is a command too
+        flash.wait_while_busy_timeout_unguarded(delay, *busy_timeout_polls)?;
+
+        flash.with_buffered_read(|| {
+            let mut done = 0;
+            while done < bytes.len() {
+                let position = offset as usize + done;
+                let page_address = (position / PAGE_SIZE_BYTES) as u16;
+                let column = position % PAGE_SIZE_BYTES;
+                let len = (PAGE_SIZE_BYTES - column).min(bytes.len() - done);
+
+                read_page_part(
+                    flash,
+                    delay,
+                    page_address,
+                    column as u16,
+                    &mut bytes[done..done + len],
+                    *method,
+                    *busy_timeout_polls,
+                )?;
+                done += len;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn capacity(&self) -> usize {
+        BLOCK_COUNT * BLOCK_SIZE_BYTES
+    }
+}
+
+impl<BUS: QspiBus, D: DelayUs<u32>> NorFlash for NorFlashAdapter<BUS, D> {
+    const WRITE_SIZE: usize = PAGE_SIZE_BYTES;
+    const ERASE_SIZE: usize = BLOCK_SIZE_BYTES;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        nor_flash::check_erase(self, from, to).map_err(from_kind)?;
+
+        let first_block = (from as usize / BLOCK_SIZE_BYTES) as u16;
+        let end_block = (to as usize / BLOCK_SIZE_BYTES) as u16;
+        self.flash
+            .check_block0(first_block, end_block - first_block)?;
+
+        let _guard = self.flash.begin_operation()?;
+
+        for block in first_block..end_block {
+            self.flash
+                .wait_while_busy_timeout_unguarded(&mut self.delay, self.busy_timeout_polls)?;
+            self.flash.erase_block_unguarded(block, &mut self.delay)?;
+        }
+
+        Ok(())
+    }
+
+    /// Each page has to be erased before it's written, since programming can only clear bits
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), FlashError> {
+        nor_flash::check_write(self, offset, bytes.len()).map_err(from_kind)?;
+
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let first_page = (offset as usize / PAGE_SIZE_BYTES) as u16;
+        self.flash
+            .check_block0(Geometry::W25N01GV.block_of_page(first_page), 1)?;
+
+        let _guard = self.flash.begin_operation()?;
+
+        for (index, page) in bytes.chunks_exact(PAGE_SIZE_BYTES).enumerate() {
+            self.flash
+                .wait_while_busy_timeout_unguarded(&mut self.delay, self.busy_timeout_polls)?;
+            program_page(
+                &self.flash,
+                &mut self.delay,
+                first_page + index as u16,
+                page,
+                self.write_method,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+
+    fn adapter(sim: &SimFlash) -> NorFlashAdapter<SimFlash, NoDelay> {
+        NorFlashAdapter::new(
+            sim.driver(),
+            NoDelay,
+            ReadMethod::FastRead,
+            WriteMethod::QuadLoad,
+        )
+    }
+
+    /// Fills the main area of `page_address` with its low byte plus the column
+    fn fill(sim: &SimFlash, page_address: u16) {
+        let bytes: Vec<u8> = (0..PAGE_SIZE_BYTES)
+            .map(|column| (page_address as usize + column) as u8)
+            .collect();
+        sim.set_page(page_address, &bytes);
+    }
+
+    fn pages_read(sim: &SimFlash) -> Vec<u16> {
+        sim.commands()
+            .iter()
+            .filter(|command| command.opcode == 0x13)
+            .filter_map(|command| command.page_address())
+            .collect()
+    }
+
+    #[test]
+    fn reads_split_at_page_and_block_edges() {
+        let sim = SimFlash::new();
+        for page_address in [0, 1, 63, 64] {
+            fill(&sim, page_address);
+        }
+        let mut flash = adapter(&sim);
+
+        let cases: [(u32, usize, &[u16]); 3] = [
+            (PAGE_SIZE_BYTES as u32 - 4, 8, &[0, 1]),
+            (BLOCK_SIZE_BYTES as u32 - 3, 6, &[63, 64]),
+            (BLOCK_SIZE_BYTES as u32, 5, &[64]),
+        ];
+
+        for (offset, len, pages) in cases.iter() {
+            sim.clear_log();
+
+            let mut bytes = [0_u8; 8];
+            flash.read(*offset, &mut bytes[..*len]).unwrap();
+
+            assert_eq!(pages_read(&sim), *pages);
+            for (index, byte) in bytes[..*len].iter().enumerate() {
+                let position = *offset as usize + index;
+                let page_address = position / PAGE_SIZE_BYTES;
+                let column = position % PAGE_SIZE_BYTES;
+                assert_eq!(*byte, (page_address + column) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn reads_stop_at_the_end_of_the_device() {
+        let sim = SimFlash::new();
+        fill(&sim, u16::MAX);
+        let mut flash = adapter(&sim);
+        let capacity = flash.capacity() as u32;
+
+        let mut bytes = [0_u8; 4];
+        flash.read(capacity - 4, &mut bytes).unwrap();
+        assert_eq!(pages_read(&sim), [u16::MAX]);
+        assert_eq!(bytes, [0xFB, 0xFC, 0xFD, 0xFE]);
+
+        let mut bytes = [0_u8; 5];
+        assert_eq!(
+            flash.read(capacity - 4, &mut bytes),
+            Err(FlashError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn writes_program_whole_pages_across_a_block_edge() {
+        let sim = SimFlash::new();
+        let mut flash = adapter(&sim);
+
+        let mut bytes = [0_u8; 2 * PAGE_SIZE_BYTES];
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = (index / PAGE_SIZE_BYTES) as u8 + 1;
+        }
+
+        flash.write(127 * PAGE_SIZE_BYTES as u32, &bytes).unwrap();
+
+        let programmed: Vec<u16> = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0x10)
+            .filter_map(|command| command.page_address())
+            .collect();
+        assert_eq!(programmed, [127, 128]);
+        assert!(sim.page(127)[..PAGE_SIZE_BYTES]
+            .iter()
+            .all(|byte| *byte == 1));
+        assert!(sim.page(128)[..PAGE_SIZE_BYTES]
+            .iter()
+            .all(|byte| *byte == 2));
+    }
+
+    #[test]
+    fn writes_and_erases_off_their_edges_are_refused() {
+        let sim = SimFlash::new();
+        let mut flash = adapter(&sim);
+        let page = [0_u8; PAGE_SIZE_BYTES];
+        let capacity = flash.capacity() as u32;
+
+        assert_eq!(flash.write(1, &page), Err(FlashError::NotAligned));
+        assert_eq!(flash.write(capacity, &page), Err(FlashError::OutOfBounds));
+        assert_eq!(
+            flash.erase(PAGE_SIZE_BYTES as u32, BLOCK_SIZE_BYTES as u32),
+            Err(FlashError::NotAligned)
+        );
+        assert_eq!(
+            flash.erase(0, capacity + BLOCK_SIZE_BYTES as u32),
+            Err(FlashError::OutOfBounds)
+        );
+
+        assert_eq!(sim.destructive_ops(), 0);
+    }
+
+    #[test]
+    fn erases_cover_exactly_the_blocks_in_range() {
+        let sim = SimFlash::new();
+        let mut flash = adapter(&sim);
+        let block = BLOCK_SIZE_BYTES as u32;
+
+        flash.erase(block, 3 * block).unwrap();
+        let last = flash.capacity() as u32;
+        flash.erase(last - block, last).unwrap();
+
+        let erased: Vec<u16> = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0xD8)
+            .filter_map(|command| command.page_address())
+            .collect();
+        assert_eq!(erased, [64, 128, Geometry::W25N01GV.block_first_page(1023)]);
+    }
+
+    #[test]
+    fn a_second_write_to_a_page_counts_against_its_partial_program_budget() {
+        let sim = SimFlash::new();
+        let mut flash = adapter(&sim);
+        let page = [0xF0_u8; PAGE_SIZE_BYTES];
+
+        let mut results = Vec::new();
+        for _ in 0..5 {
+            results.push(flash.write(200 * PAGE_SIZE_BYTES as u32, &page));
+        }
+
+        assert!(results[..4].iter().all(Result::is_ok));
+        assert!(matches!(
+            results[4],
+            Err(FlashError::PartialProgramBudgetExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn a_device_left_busy_is_waited_for_instead_of_failing() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(50);
+        fill(&sim, 3);
+        let mut flash = adapter(&sim);
+        let block = BLOCK_SIZE_BYTES as u32;
+
+        // Something started through the driver is still running, which the driver itself refuses
+        flash.flash().read_memory_to_data_buffer(7).unwrap();
+        assert_eq!(
+            flash.flash().read_memory_to_data_buffer(8),
+            Err(FlashError::DeviceBusy)
+        );
+
+        let mut bytes = [0_u8; 4];
+        flash.read(3 * PAGE_SIZE_BYTES as u32, &mut bytes).unwrap();
+        assert_eq!(bytes, [3, 4, 5, 6]);
+
+        flash.flash().read_memory_to_data_buffer(7).unwrap();
+        flash.erase(block, 2 * block).unwrap();
+
+        flash.flash().read_memory_to_data_buffer(7).unwrap();
+        let page = [0x3C_u8; PAGE_SIZE_BYTES];
+        flash.write(block, &page).unwrap();
+        assert_eq!(&sim.page(64)[..PAGE_SIZE_BYTES], &page[..]);
+    }
+
+    #[test]
+    fn a_device_that_stays_busy_times_out() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(u32::MAX);
+        let mut flash = adapter(&sim);
+        flash.set_busy_timeout(100);
+        let block = BLOCK_SIZE_BYTES as u32;
+
+        flash.flash().read_memory_to_data_buffer(7).unwrap();
+        sim.clear_log();
+
+        let mut bytes = [0_u8; 4];
+        let result = flash.read(0, &mut bytes);
+        assert_eq!(result, Err(FlashError::Timeout));
+        assert_eq!(result.unwrap_err().kind(), NorFlashErrorKind::Other);
+        assert_eq!(flash.erase(block, 2 * block), Err(FlashError::Timeout));
+        assert_eq!(
+            flash.write(block, &[0_u8; PAGE_SIZE_BYTES]),
+            Err(FlashError::Timeout)
+        );
+
+        // Each gave up after its polls without sending anything else
+        let commands = sim.commands();
+        assert!(commands
+            .iter()
+            .all(|command| command.opcode == 0x05 || command.opcode == 0x0F));
+        assert_eq!(commands.len(), 3 * 100);
+        assert_eq!(sim.destructive_ops(), 0);
+    }
+}
@@ -0,0 +1,162 @@
+//! Driving the device from the OCTOSPI peripheral of the STM32L4+ parts (L4R5, L4P5, and the rest),
+//! which replaced QUADSPI there.
+//!
+//! OCTOSPI sends the same commands as QUADSPI, but sets each phase's line count from five IO modes
+//! and takes the address and alternate byte sizes with every command. `OctospiPeripheral` is that
+//! command model and nothing more, a regular command in indirect mode, so it's a few register
+//! writes to implement over whichever HAL or PAC drives the peripheral. `OctospiBus` wraps an
+//! implementation and implements `QspiBus`, mapping each phase of the driver's commands across
+//! unchanged: every `ReadMethod` and `WriteMethod` keeps its line counts, and the JEDEC ID, status
+//! register, and BBM commands keep their 8 dummy cycles and their alternate byte addressing.
+
+use core::cell::RefCell;
+
+use crate::bus::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand};
+
+/// How many lines a phase of an OCTOSPI command uses
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OctospiLines {
+    Single,
+    Dual,
+    Quad,
+    Octal,
+}
+
+impl OctospiLines {
+    /// The value of the phase's IMODE, ADMODE, ABMODE, or DMODE field in OCTOSPI_CCR. A phase
+    /// that isn't sent is 0.
+    pub const fn mode_bits(self) -> u8 {
+        match self {
+            OctospiLines::Single => 0b001,
+            OctospiLines::Dual => 0b010,
+            OctospiLines::Quad => 0b011,
+            OctospiLines::Octal => 0b100,
+        }
+    }
+}
+
+impl From<QspiMode> for OctospiLines {
+    fn from(mode: QspiMode) -> OctospiLines {
+        match mode {
+            QspiMode::SingleChannel => OctospiLines::Single,
+            QspiMode::DualChannel => OctospiLines::Dual,
+            QspiMode::QuadChannel => OctospiLines::Quad,
+        }
+    }
+}
+
+/// A regular command as OCTOSPI takes it. A phase that's None isn't sent.
+#[derive(Debug, Clone, Copy)]
+pub struct OctospiCommand<'a> {
+    /// An 8 bit instruction (ISIZE 0)
+    pub instruction: Option<(u8, OctospiLines)>,
+    /// A 16 bit address (ADSIZE 1)
+    pub address: Option<(u16, OctospiLines)>,
+    /// 1 to 4 alternate bytes, ABSIZE being the length less one
+    pub alternate_bytes: Option<(&'a [u8], OctospiLines)>,
+    /// 0 to 31, the DCYC field of OCTOSPI_TCR
+    pub dummy_cycles: u8,
+    pub data: Option<OctospiLines>,
+    /// Double transfer rate on every phase
+    pub double_transfer_rate: bool,
+}
+
+/// An OCTOSPI peripheral set up in regular command mode, sending commands in indirect mode
+pub trait OctospiPeripheral {
+    /// Sends `command` followed by `data` in the data phase, if the command has one
+    fn indirect_write(&mut self, command: OctospiCommand, data: &[u8]) -> Result<(), QspiError>;
+
+    /// Sends `command` and fills `buffer` from the data phase
+    fn indirect_read(
+        &mut self,
+        command: OctospiCommand,
+        buffer: &mut [u8],
+    ) -> Result<(), QspiError>;
+}
+
+/// Drives the device over an `OctospiPeripheral`, see the module docs
+pub struct OctospiBus<P> {
+    octospi: RefCell<P>,
+}
+
+impl<P: OctospiPeripheral> OctospiBus<P> {
+    pub fn new(octospi: P) -> OctospiBus<P> {
+        OctospiBus {
+            octospi: RefCell::new(octospi),
+        }
+    }
+
+    pub fn free(self) -> P {
+        self.octospi.into_inner()
+    }
+}
+
+/// Everything but the data phase's lines, checked against what OCTOSPI can send
+fn octospi_command<'a>(
+    instruction: Option<(u8, QspiMode)>,
+    address: Option<(u32, QspiMode)>,
+    alternative_bytes: Option<(&'a [u8], QspiMode)>,
+    dummy_cycles: u8,
+    data: Option<QspiMode>,
+    double_data_rate: bool,
+) -> Result<OctospiCommand<'a>, QspiError> {
+    let address = match address {
+        Some((address, mode)) if address <= u16::MAX as u32 => Some((address as u16, mode.into())),
+        Some(_) => return Err(QspiError::Address),
+        None => None,
+    };
+
+    if let Some((bytes, _)) = alternative_bytes {
+        if bytes.is_empty() || bytes.len() > 4 {
+            return Err(QspiError::Unsupported);
+        }
+    }
+
+    if dummy_cycles > 31 {
+        return Err(QspiError::Unsupported);
+    }
+
+    Ok(OctospiCommand {
+        instruction: instruction.map(|(instruction, mode)| (instruction, mode.into())),
+        address,
+        alternate_bytes: alternative_bytes.map(|(bytes, mode)| (bytes, mode.into())),
+        dummy_cycles,
+        data: data.map(OctospiLines::from),
+        double_transfer_rate: double_data_rate,
+    })
+}
+
+impl<P: OctospiPeripheral> QspiBus for OctospiBus<P> {
+    fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+        let octospi_command = octospi_command(
+            command.instruction,
+            command.address,
+            command.alternative_bytes,
+            command.dummy_cycles,
+            command.data.map(|(_, mode)| mode),
+            command.double_data_rate,
+        )?;
+        let data = command.data.map_or(&[][..], |(data, _)| data);
+
+        let mut octospi = self.octospi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+        octospi.indirect_write(octospi_command, data)
+    }
+
+    fn read_command(&self, command: QspiReadCommand, buffer: &mut [u8]) -> Result<(), QspiError> {
+        let octospi_command = octospi_command(
+            command.instruction,
+            command.address,
+            command.alternative_bytes,
+            command.dummy_cycles,
+            Some(command.data_mode),
+            command.double_data_rate,
+        )?;
+
+        let receive_length = command.receive_length as usize;
+        let buffer = buffer.get_mut(..receive_length).ok_or(QspiError::Unknown)?;
+
+        let mut octospi = self.octospi.try_borrow_mut().map_err(|_| QspiError::Busy)?;
+        octospi.indirect_read(octospi_command, buffer)
+    }
+}
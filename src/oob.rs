@@ -0,0 +1,266 @@
+//! Where the bad block marker and user bytes live in the 64 byte spare area, so the driver can
+//! share devices with other software that already decided, e.g. a Linux fixture that provisioned
+//! them.
+//!
+//! The spare area is four 16 byte sections. With on-chip ECC enabled the device keeps its ECC codes
+//! in the last 8 bytes of each section, whatever the layout says. A layout picks which of the
+//! remaining bytes hold the bad block marker and which are left to the user:
+//!
+//! | Layout           | Marker     | User bytes, each section | User bytes with ECC off |
+//! |------------------|------------|--------------------------|-------------------------|
+//! | `WinbondDefault` | byte 0     | 0..8                     | all 64                  |
+//! | `LinuxMtd`       | bytes 0..2 | 2..8                     | 2..16 of each section   |
+//!
+//! `WinbondDefault` is how the driver has always treated the spare area and is the default.
+//! `LinuxMtd` matches the kernel's SPI NAND driver for this part (`w25m02gv_ooblayout` in
+//! `drivers/mtd/nand/spi/winbond.c`), whose `nand_ecclayout` puts the ECC regions at
+//! `16 * section + 8` for 8 bytes and the free regions at `16 * section + 2` for 6 bytes, and whose
+//! bad block check reads the first two spare bytes. The kernel leaves bytes 0..2 of every section
+//! out of its free regions, so they're reserved here too. `Custom` takes masks for anything else.
+//!
+//! The layout decides what `Column::Logical` spare columns map to, which spare bytes
+//! `load_columns` and `write_page_split` accept, and which bytes `is_bad_block`, `scan`, and
+//! `export_provisioning_state` check for a marker. The driver's own structures keep their metadata
+//! in the main area, so they never place it where a layout reserves bytes.
+
+use crate::{
+    column::is_ecc_reserved_spare_byte, FlashError, QspiBus, ReadMethod, PAGE_SIZE_BYTES,
+    SPARE_BYTES, W25N01GV,
+};
+
+/// The spare bytes the device keeps its ECC codes in while ECC is enabled, one bit per byte
+pub const ECC_SPARE_MASK: u64 = 0xFF00_FF00_FF00_FF00;
+
+/// A spare area layout given as masks of one bit per spare byte, bit 0 being column 2048
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct OobMap {
+    /// The bytes that are anything other than 0xFF on a bad block
+    pub marker: u64,
+    /// The bytes left to the user. Marker bytes are never user bytes, nor are ECC bytes while ECC
+    /// is enabled.
+    pub free: u64,
+}
+
+/// See the module docs
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OobLayout {
+    WinbondDefault,
+    LinuxMtd,
+    Custom(OobMap),
+}
+
+impl OobLayout {
+    /// The bad block marker bytes, one bit per spare byte
+    pub const fn marker_mask(&self) -> u64 {
+        match self {
+            OobLayout::WinbondDefault => 0x0000_0000_0000_0001,
+            OobLayout::LinuxMtd => 0x0000_0000_0000_0003,
+            OobLayout::Custom(map) => map.marker,
+        }
+    }
+
+    /// The bytes left to the user, one bit per spare byte
+    pub const fn user_mask(&self, ecc_enabled: bool) -> u64 {
+        match (self, ecc_enabled) {
+            (OobLayout::WinbondDefault, true) => !ECC_SPARE_MASK,
+            (OobLayout::WinbondDefault, false) => u64::MAX,
+            (OobLayout::LinuxMtd, true) => 0x00FC_00FC_00FC_00FC,
+            (OobLayout::LinuxMtd, false) => 0xFFFC_FFFC_FFFC_FFFC,
+            (OobLayout::Custom(map), true) => map.free & !map.marker & !ECC_SPARE_MASK,
+            (OobLayout::Custom(map), false) => map.free & !map.marker,
+        }
+    }
+
+    /// Is true if any marker byte of the block's first page spare area isn't 0xFF
+    pub fn is_marked_bad(&self, spare: &[u8; SPARE_BYTES]) -> bool {
+        let marker = self.marker_mask();
+
+        spare
+            .iter()
+            .enumerate()
+            .any(|(index, byte)| marker & (1 << index) != 0 && *byte != 0xFF)
+    }
+
+    /// Returns an error if the user can't write the spare byte at `spare_index`
+    pub(crate) fn check_user_byte(
+        &self,
+        spare_index: usize,
+        ecc_enabled: bool,
+    ) -> Result<(), FlashError> {
+        if self.user_mask(ecc_enabled) & (1 << spare_index) != 0 {
+            Ok(())
+        } else if ecc_enabled && is_ecc_reserved_spare_byte(spare_index) {
+            Err(FlashError::WriteToECCReservedColumn)
+        } else {
+            Err(FlashError::WriteToLayoutReservedColumn {
+                column: (PAGE_SIZE_BYTES + spare_index) as u16,
+            })
+        }
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    pub fn oob_layout(&self) -> OobLayout {
+        self.oob_layout
+    }
+
+    /// Sets the spare area layout the driver works to, see the module docs. Nothing on the device
+    /// changes, the layout only decides how the driver reads and writes the spare area from now on.
+    pub fn set_oob_layout(&mut self, layout: OobLayout) {
+        self.oob_layout = layout;
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Checks the bad block marker of the page in the data buffer, under the driver's layout
+    pub(crate) fn read_bad_block_marker(&self, method: ReadMethod) -> Result<bool, FlashError> {
+        let marker_len = 64 - self.oob_layout.marker_mask().leading_zeros() as usize;
+
+        let mut spare = [0xFF_u8; SPARE_BYTES];
+        self.read_physical_columns(PAGE_SIZE_BYTES as u16, &mut spare[..marker_len], method)?;
+
+        Ok(self.oob_layout.is_marked_bad(&spare))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{column::SPARE_SECTION_BYTES, sim::SimFlash, Column, LoadMode, WriteMethod};
+    use std::vec::Vec;
+
+    /// One bit for each of `len` bytes from `start` of every spare section
+    fn every_section(start: usize, len: usize) -> u64 {
+        (0..SPARE_BYTES / SPARE_SECTION_BYTES).fold(0, |mask, section| {
+            let first = section * SPARE_SECTION_BYTES + start;
+            mask | (((1_u64 << len) - 1) << first)
+        })
+    }
+
+    #[test]
+    fn built_in_layouts_match_their_descriptions() {
+        assert_eq!(ECC_SPARE_MASK, every_section(8, 8));
+
+        assert_eq!(
+            OobLayout::WinbondDefault.user_mask(true),
+            every_section(0, 8)
+        );
+        assert_eq!(OobLayout::WinbondDefault.user_mask(false), u64::MAX);
+
+        // The kernel's free regions, 6 bytes at 16 * section + 2
+        assert_eq!(OobLayout::LinuxMtd.user_mask(true), every_section(2, 6));
+        assert_eq!(OobLayout::LinuxMtd.user_mask(false), every_section(2, 14));
+
+        // The default layout has always left its marker byte to the user, the kernel's doesn't
+        assert_eq!(
+            OobLayout::WinbondDefault.user_mask(true) & OobLayout::WinbondDefault.marker_mask(),
+            1
+        );
+        assert_eq!(
+            OobLayout::LinuxMtd.user_mask(false) & OobLayout::LinuxMtd.marker_mask(),
+            0
+        );
+    }
+
+    #[test]
+    fn custom_layouts_never_hand_out_marker_or_ecc_bytes() {
+        let layout = OobLayout::Custom(OobMap {
+            marker: 0b1000,
+            free: 0xFFFF,
+        });
+
+        assert_eq!(layout.user_mask(false), 0xFFF7);
+        assert_eq!(layout.user_mask(true), 0x00F7);
+        assert_eq!(layout.marker_mask(), 0b1000);
+    }
+
+    #[test]
+    fn markers_are_only_read_from_the_layout_bytes() {
+        let mut spare = [0xFF; SPARE_BYTES];
+        spare[1] = 0x00;
+
+        assert!(!OobLayout::WinbondDefault.is_marked_bad(&spare));
+        assert!(OobLayout::LinuxMtd.is_marked_bad(&spare));
+
+        spare[1] = 0xFF;
+        spare[0] = 0x7F;
+        assert!(OobLayout::WinbondDefault.is_marked_bad(&spare));
+        assert!(OobLayout::LinuxMtd.is_marked_bad(&spare));
+    }
+
+    #[test]
+    fn reserved_user_bytes_are_refused_with_the_reason() {
+        let layout = OobLayout::LinuxMtd;
+
+        assert_eq!(layout.check_user_byte(2, true), Ok(()));
+        assert_eq!(
+            layout.check_user_byte(8, true),
+            Err(FlashError::WriteToECCReservedColumn)
+        );
+        assert_eq!(
+            layout.check_user_byte(17, true),
+            Err(FlashError::WriteToLayoutReservedColumn { column: 2065 })
+        );
+        assert_eq!(layout.check_user_byte(8, false), Ok(()));
+        assert_eq!(
+            layout.check_user_byte(0, false),
+            Err(FlashError::WriteToLayoutReservedColumn { column: 2048 })
+        );
+    }
+
+    #[test]
+    fn is_bad_block_checks_the_layout_marker() {
+        let sim = SimFlash::new();
+        let mut spare_marked = [0xFF; PAGE_SIZE_BYTES + 2];
+        spare_marked[PAGE_SIZE_BYTES + 1] = 0x00;
+        sim.set_page(64, &spare_marked);
+        let mut flash = sim.driver();
+
+        assert_eq!(flash.is_bad_block(1, ReadMethod::FastRead), Ok(false));
+
+        flash.set_oob_layout(OobLayout::LinuxMtd);
+        assert_eq!(flash.oob_layout(), OobLayout::LinuxMtd);
+        assert_eq!(flash.is_bad_block(1, ReadMethod::FastRead), Ok(true));
+    }
+
+    #[test]
+    fn logical_spare_columns_follow_the_layout() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_oob_layout(OobLayout::LinuxMtd);
+        let flash = flash.into_write_mode().unwrap();
+
+        // The last free byte of section 0 and the first two of section 1
+        sim.clear_log();
+        flash
+            .load_columns(
+                Column::Logical(PAGE_SIZE_BYTES as u16 + 5),
+                &[0x11, 0x22, 0x33],
+                WriteMethod::SingleLoad,
+                LoadMode::PreserveAndLoad,
+            )
+            .unwrap();
+        let loads: Vec<(u32, Vec<u8>)> = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == WriteMethod::RandomSingleLoad as u8)
+            .map(|command| (command.address.unwrap().0, command.data.clone()))
+            .collect();
+        assert_eq!(
+            loads,
+            [(2055, [0x11].to_vec()), (2066, [0x22, 0x33].to_vec())]
+        );
+
+        assert_eq!(
+            flash.load_columns(
+                Column::Physical(PAGE_SIZE_BYTES as u16),
+                &[0x00],
+                WriteMethod::SingleLoad,
+                LoadMode::PreserveAndLoad,
+            ),
+            Err(FlashError::WriteToLayoutReservedColumn { column: 2048 })
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Reading and programming the one-time programmable pages, e.g. for a serial number or
+//! calibration data that must outlive every erase.
+//!
+//! Setting OTP-E in the configuration register points Page Data Read and Program Execute at the
+//! OTP area instead of the array. The area is 12 pages: page 0 holds the unique ID and page 1 the
+//! parameter page, both factory programmed, and pages 2 to 11 are the 10 user OTP pages that
+//! `otp_page` 0 to 9 address here. Each method sets OTP-E, does its work, and writes the
+//! configuration register back as it was, even if the work failed.
+//!
+//! `read_unique_id` and `read_parameter_page` read the two factory programmed pages the same way,
+//! for traceability along with `get_jedec_id` during manufacturing test.
+//!
+//! Programming and locking need write mode. `lock_otp` sets OTP-L, after which the OTP pages can
+//! only be read. OTP-L can't be cleared again, so lock only once every page holds what it should.
+
+use crate::{
+    commands,
+    status::{ConfigurationRegister, ECCStatus},
+    verification::VerificationLevel,
+    FlashError, QspiBus, ReadMethod, WriteMethod, WriteMode, PAGE_SIZE_BYTES,
+    PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+/// How many user OTP pages the device has
+pub const OTP_PAGE_COUNT: u8 = 10;
+
+/// The OTP area page address of the unique ID page
+const UNIQUE_ID_PAGE: u16 = 0;
+
+/// The OTP area page address of the parameter page
+const PARAMETER_PAGE: u16 = 1;
+
+/// The OTP area page address of the first user OTP page
+const FIRST_USER_OTP_PAGE: u16 = 2;
+
+#[derive(PartialEq)]
+enum OtpAccess {
+    Read,
+    Program,
+    /// Sets OTP-L along with OTP-E
+    Lock,
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads user OTP page `otp_page` into `buffer`, returning its ECC status
+    pub fn read_otp_page(
+        &self,
+        otp_page: u8,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        method: ReadMethod,
+    ) -> Result<ECCStatus, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let page_address = otp_page_address(otp_page)?;
+
+        self.with_otp_enabled(OtpAccess::Read, |flash| {
+            flash.read_page_unguarded(page_address, buffer, method)
+        })
+    }
+
+    /// Reads the unique ID: 16 bytes of ID followed by their complement, as the first 32 bytes of
+    /// the unique ID page. Returns `FlashError::ECC` if the page had more bit errors than
+    /// ECC could correct.
+    pub fn read_unique_id(&self) -> Result<[u8; 32], FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let mut page = [0_u8; PAGE_SIZE_BYTES];
+        self.read_factory_page(UNIQUE_ID_PAGE, &mut page)?;
+
+        let mut unique_id = [0_u8; 32];
+        unique_id.copy_from_slice(&page[..32]);
+
+        Ok(unique_id)
+    }
+
+    /// Reads the main area of the parameter page, which holds the device's parameters in the ONFI
+    /// parameter page format, repeated. Returns `FlashError::ECC` if the page had more bit
+    /// errors than ECC could correct.
+    pub fn read_parameter_page(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_BYTES],
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        self.read_factory_page(PARAMETER_PAGE, buffer)
+    }
+
+    /// Sets OTP-E (and OTP-L to lock), runs `f`, and restores OTP-E whatever `f` returned.
+    /// Returns `FlashError::RegisterLocked` without running `f` if `f` would program a
+    /// locked area.
+    fn with_otp_enabled<T>(
+        &self,
+        access: OtpAccess,
+        f: impl FnOnce(&Self) -> Result<T, FlashError>,
+    ) -> Result<T, FlashError> {
+        let configuration_register = self.read_configuration_register_unguarded()?;
+        if access == OtpAccess::Program && configuration_register.otp_l {
+            return Err(FlashError::RegisterLocked);
+        }
+
+        let otp_l = configuration_register.otp_l || access == OtpAccess::Lock;
+        self.write_configuration_register_unguarded(ConfigurationRegister {
+            otp_e: true,
+            otp_l,
+            ..configuration_register
+        })?;
+
+        let result = f(self);
+
+        self.write_configuration_register_unguarded(ConfigurationRegister {
+            otp_l,
+            ..configuration_register
+        })?;
+
+        result
+    }
+
+    fn read_factory_page(
+        &self,
+        page_address: u16,
+        buffer: &mut [u8; PAGE_SIZE_BYTES],
+    ) -> Result<(), FlashError> {
+        self.with_otp_enabled(OtpAccess::Read, |flash| {
+            flash.read_memory_to_data_buffer_unguarded(page_address)?;
+            flash.wait_while_busy_unguarded()?;
+
+            let status = flash.read_status_register_unguarded()?.ecc_status;
+            if let ECCStatus::SinglePageError | ECCStatus::MultiPageError = status {
+                return Err(FlashError::ECC {
+                    status,
+                    page_address,
+                });
+            }
+
+            flash.read_page_data_unguarded(buffer, ReadMethod::FastRead)
+        })
+    }
+
+    fn finish_otp_program(&self, page_address: u16) -> Result<(), FlashError> {
+        self.wait_while_busy_unguarded()?;
+
+        if self.verification_level != VerificationLevel::None
+            && self.read_status_register_unguarded()?.write_failure
+        {
+            return Err(FlashError::ProgramFailed { page_address });
+        }
+
+        Ok(())
+    }
+}
+
+impl<BUS: QspiBus> W25N01GV<BUS, WriteMode> {
+    /// Programs `data` into user OTP page `otp_page` from column 0, leaving the rest of the page
+    /// erased, and verifies it at the driver's verification level. Returns
+    /// `FlashError::RegisterLocked` if the OTP area is locked, and by default
+    /// `FlashError::ProgramFailed` if the device reports a failure.
+    pub fn program_otp_page(
+        &self,
+        otp_page: u8,
+        data: &[u8],
+        write_method: WriteMethod,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let page_address = otp_page_address(otp_page)?;
+        if data.len() > PAGE_SIZE_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.with_otp_enabled(OtpAccess::Program, |flash| {
+            flash.send_write_enable()?;
+            flash.qspi_write(commands::program_data_load(
+                write_method.resetting(),
+                0,
+                data,
+            ))?;
+            let expected = flash.capture_expected(flash.verification_level)?;
+            flash.send_program_execute(page_address)?;
+
+            flash.finish_otp_program(page_address)?;
+            flash.check_expected(page_address, &expected)
+        })
+    }
+
+    /// Permanently locks the OTP area against programming, see the module docs. Does nothing if
+    /// it's already locked.
+    pub fn lock_otp(&self) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if self.read_configuration_register_unguarded()?.otp_l {
+            return Ok(());
+        }
+
+        self.with_otp_enabled(OtpAccess::Lock, |flash| {
+            flash.send_write_enable()?;
+            flash.qspi_write(commands::program_execute(&[0, 0]))?;
+
+            flash.finish_otp_program(0)
+        })
+    }
+}
+
+fn otp_page_address(otp_page: u8) -> Result<u16, FlashError> {
+    if otp_page >= OTP_PAGE_COUNT {
+        return Err(FlashError::OutOfBounds);
+    }
+
+    Ok(FIRST_USER_OTP_PAGE + otp_page as u16)
+}
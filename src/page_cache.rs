@@ -0,0 +1,282 @@
+//! An optional single page cache for reads that keep going back to the same page, behind the
+//! `page-cache` feature since it adds a page sized buffer to the driver.
+//!
+//! `read_cached` serves reads of the cached page from RAM without touching the bus, and fills the
+//! cache with the whole page on a miss. Any command that could change what the page reads back as
+//! (a Program Execute or Block Erase anywhere, a status register write, a bad block swap, or a
+//! reset) drops the cache, so stale data is never served.
+
+use crate::{
+    status::ECCStatus, FlashCommands, FlashError, QspiBus, ReadMethod, PAGE_SIZE_WITH_ECC_BYTES,
+    W25N01GV,
+};
+
+/// How well the page cache is doing
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PageCacheStats {
+    pub hits: u32,
+    pub misses: u32,
+}
+
+pub(crate) struct PageCache {
+    page: [u8; PAGE_SIZE_WITH_ECC_BYTES],
+    page_address: u16,
+    valid: bool,
+    stats: PageCacheStats,
+}
+
+impl PageCache {
+    pub(crate) fn new() -> PageCache {
+        PageCache {
+            page: [0xFF; PAGE_SIZE_WITH_ECC_BYTES],
+            page_address: 0,
+            valid: false,
+            stats: PageCacheStats::default(),
+        }
+    }
+}
+
+fn invalidates_cache(opcode: u8) -> bool {
+    opcode == FlashCommands::ProgramExecute as u8
+        || opcode == FlashCommands::Erase128KBBlock as u8
+        || opcode == FlashCommands::WriteStatusRegister as u8
+        || opcode == FlashCommands::WriteStatusRegisterAlt as u8
+        || opcode == FlashCommands::SwapBlocks as u8
+        || opcode == FlashCommands::DeviceReset as u8
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Drops the cached page, e.g. after changing the device behind the driver's back
+    pub fn invalidate_cache(&mut self) {
+        self.page_cache.borrow_mut().valid = false;
+    }
+
+    pub fn page_cache_stats(&self) -> PageCacheStats {
+        self.page_cache.borrow().stats
+    }
+
+    /// Drops the cached page when a command that could change it is sent
+    pub(crate) fn record_page_cache_command(&self, opcode: u8) {
+        if invalidates_cache(opcode) {
+            self.page_cache.borrow_mut().valid = false;
+        }
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads `buffer.len()` bytes of the page starting at physical `column`, from the cache if the
+    /// page is cached. A miss reads the whole page into the data buffer and the cache. Pages with
+    /// uncorrectable ECC errors are returned as read but not cached.
+    pub fn read_cached(
+        &self,
+        page_address: u16,
+        column: u16,
+        buffer: &mut [u8],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let end = column as usize + buffer.len();
+        if end > PAGE_SIZE_WITH_ECC_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let mut cache = self.page_cache.borrow_mut();
+
+        if cache.valid && cache.page_address == page_address {
+            cache.stats.hits = cache.stats.hits.wrapping_add(1);
+            buffer.copy_from_slice(&cache.page[column as usize..end]);
+
+            return Ok(());
+        }
+
+        cache.stats.misses = cache.stats.misses.wrapping_add(1);
+        cache.valid = false;
+
+        self.read_memory_to_data_buffer_unguarded(page_address)?;
+        self.wait_while_busy_unguarded()?;
+        let ecc_status = self.read_status_register_unguarded()?.ecc_status;
+        self.read_data_buffer_unguarded(&mut cache.page, method)?;
+
+        buffer.copy_from_slice(&cache.page[column as usize..end]);
+        if ecc_status != ECCStatus::SinglePageError && ecc_status != ECCStatus::MultiPageError {
+            cache.page_address = page_address;
+            cache.valid = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{sim::SimFlash, WriteMethod};
+
+    const PAGE: u16 = 64;
+
+    #[test]
+    fn repeat_reads_of_the_cached_page_stay_off_the_bus() {
+        let sim = SimFlash::new();
+        sim.set_page(PAGE, &[0x11, 0x22, 0x33, 0x44]);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut buffer = [0u8; 2];
+        flash
+            .read_cached(PAGE, 1, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x22, 0x33]);
+        assert_eq!(sim.count(FlashCommands::PageDataRead as u8), 1);
+
+        sim.clear_log();
+        flash
+            .read_cached(PAGE, 2, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x33, 0x44]);
+        assert!(sim.commands().is_empty());
+
+        assert_eq!(
+            flash.page_cache_stats(),
+            PageCacheStats { hits: 1, misses: 1 }
+        );
+    }
+
+    #[test]
+    fn reading_another_page_replaces_the_cached_one() {
+        let sim = SimFlash::new();
+        sim.set_page(PAGE, &[0x11]);
+        sim.set_page(PAGE + 1, &[0x22]);
+        let flash = sim.driver();
+
+        let mut buffer = [0u8; 1];
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        flash
+            .read_cached(PAGE + 1, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x22]);
+
+        sim.clear_log();
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x11]);
+        assert_eq!(sim.count(FlashCommands::PageDataRead as u8), 1);
+        assert_eq!(
+            flash.page_cache_stats(),
+            PageCacheStats { hits: 0, misses: 3 }
+        );
+    }
+
+    #[test]
+    fn a_program_to_the_cached_page_is_never_served_stale() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+
+        let mut buffer = [0u8; 2];
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0xFF, 0xFF]);
+
+        let (flash, failed) = flash
+            .program_page(PAGE, &[0x5A, 0xA5], 0, WriteMethod::SingleLoad)
+            .unwrap();
+        assert!(!failed);
+
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x5A, 0xA5]);
+        assert_eq!(flash.page_cache_stats().hits, 0);
+    }
+
+    #[test]
+    fn an_erase_anywhere_drops_the_cache() {
+        let sim = SimFlash::new();
+        sim.set_page(PAGE, &[0x11]);
+        let flash = sim.driver();
+
+        let mut buffer = [0u8; 1];
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+
+        // A different block, but the cache is dropped conservatively
+        let flash = flash.erase_block(5, &mut crate::sim::NoDelay).unwrap();
+
+        sim.clear_log();
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x11]);
+        assert_eq!(sim.count(FlashCommands::PageDataRead as u8), 1);
+    }
+
+    #[test]
+    fn invalidate_cache_forces_a_reread() {
+        let sim = SimFlash::new();
+        sim.set_page(PAGE, &[0x11]);
+        let mut flash = sim.driver();
+
+        let mut buffer = [0u8; 1];
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+
+        // Changed behind the driver's back
+        sim.set_page(PAGE, &[0x22]);
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x11]);
+
+        flash.invalidate_cache();
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x22]);
+    }
+
+    #[test]
+    fn an_uncorrectable_page_is_returned_but_not_cached() {
+        let sim = SimFlash::new();
+        sim.set_page(PAGE, &[0x11]);
+        sim.set_uncorrectable(PAGE);
+        let flash = sim.driver();
+
+        let mut buffer = [0u8; 1];
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(buffer, [0x11]);
+
+        sim.clear_log();
+        flash
+            .read_cached(PAGE, 0, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(sim.count(FlashCommands::PageDataRead as u8), 1);
+    }
+
+    #[test]
+    fn a_read_past_the_page_is_refused() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut buffer = [0u8; 2];
+        assert_eq!(
+            flash.read_cached(
+                PAGE,
+                PAGE_SIZE_WITH_ECC_BYTES as u16 - 1,
+                &mut buffer,
+                ReadMethod::FastRead
+            ),
+            Err(FlashError::OutOfBounds)
+        );
+        assert!(sim.commands().is_empty());
+    }
+}
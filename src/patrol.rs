@@ -0,0 +1,261 @@
+use core::ops::{ControlFlow, Range};
+
+use crate::{
+    recovery::RecoveryPolicy,
+    scan::{EccScan, ScanDepth, ScanPage, ScanSkip, ScanVisitor},
+    status::ECCStatus,
+    FlashError, Geometry, QspiBus, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+pub const MAX_PATROL_FINDINGS: usize = 16;
+
+/// A page that reported anything other than a clean ECC status while being patrolled. Findings
+/// sort by page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PatrolFinding {
+    pub page_address: u16,
+    pub ecc_status: ECCStatus,
+}
+
+/// Periodically re-reads a range of blocks a few pages at a time so latent bit errors are noticed
+/// while ECC can still correct them. Each call to `step` reads the next `pages_per_step` pages,
+/// wrapping back to the start of the range once the end is reached.
+///
+/// Only the Page Data Read is issued for each page, the data buffer itself is never transferred
+/// over QSPI because the ECC status is all the patrol needs.
+pub struct Patrol {
+    first_page: u32,
+    end_page: u32,
+    pages_per_step: u16,
+    next_page: u32,
+    passes_completed: u32,
+    ecc: EccScan<MAX_PATROL_FINDINGS>,
+}
+
+impl Patrol {
+    /// Creates a patrol over `block_count` blocks starting at block `first_block`
+    pub fn new(first_block: u16, block_count: u16, pages_per_step: u16) -> Patrol {
+        let first_page = Geometry::W25N01GV.block_pages(first_block).start;
+
+        Patrol {
+            first_page,
+            end_page: first_page + block_count as u32 * Geometry::W25N01GV.pages_per_block as u32,
+            pages_per_step,
+            next_page: first_page,
+            passes_completed: 0,
+            ecc: EccScan::default(),
+        }
+    }
+
+    /// Reads the next batch of pages and records any ECC findings. Returns the number of pages
+    /// read.
+    pub fn step<BUS: QspiBus, MODE>(
+        &mut self,
+        flash: &W25N01GV<BUS, MODE>,
+    ) -> Result<u16, FlashError> {
+        let _guard = flash.begin_operation()?;
+
+        let batch = self.next_batch();
+        let stats = flash.scan_pages_unguarded(
+            batch,
+            ScanSkip::Nothing,
+            ScanDepth::Status,
+            &mut self.ecc,
+        )?;
+
+        Ok(self.advance(stats.pages_visited))
+    }
+
+    /// Like `step`, but a page with uncorrectable ECC errors is retried with
+    /// `read_page_with_recovery` before it's recorded as lost. Pages that are recovered are still
+    /// recorded, with the ECC status of the attempt that read them.
+    pub fn step_with_recovery<BUS: QspiBus, MODE>(
+        &mut self,
+        flash: &mut W25N01GV<BUS, MODE>,
+        policy: RecoveryPolicy,
+    ) -> Result<u16, FlashError> {
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+        let batch = self.next_batch();
+        let mut next_page = batch.start;
+
+        while next_page < batch.end {
+            let mut until_lost = UntilLost {
+                ecc: &mut self.ecc,
+                lost: None,
+            };
+            let stats = {
+                let _guard = flash.begin_operation()?;
+                flash.scan_pages_unguarded(
+                    next_page..batch.end,
+                    ScanSkip::Nothing,
+                    ScanDepth::Status,
+                    &mut until_lost,
+                )?
+            };
+            next_page += stats.pages_visited;
+
+            if let Some((page_address, ecc_status)) = until_lost.lost {
+                let ecc_status =
+                    match flash.read_page_with_recovery(page_address, &mut buffer, policy) {
+                        Ok(recovered) => {
+                            recovered.statuses[recovered.attempt].unwrap_or(ecc_status)
+                        }
+                        Err(FlashError::RecoveryFailed { .. }) => ecc_status,
+                        Err(err) => return Err(err),
+                    };
+
+                let _ = self.ecc.visit_page(&ScanPage {
+                    page_address,
+                    ecc_status,
+                    spare: None,
+                });
+            }
+        }
+
+        Ok(self.advance(next_page - batch.start))
+    }
+
+    /// The pages the next step reads, stopping at the end of the range
+    fn next_batch(&self) -> Range<u32> {
+        self.next_page..(self.next_page + self.pages_per_step as u32).min(self.end_page)
+    }
+
+    /// Moves past `pages` pages, wrapping back to the start of the range at its end
+    fn advance(&mut self, pages: u32) -> u16 {
+        self.next_page += pages;
+        if self.next_page == self.end_page && self.first_page != self.end_page {
+            self.next_page = self.first_page;
+            self.passes_completed = self.passes_completed.wrapping_add(1);
+        }
+
+        pages as u16
+    }
+
+    /// The page the next call to `step` will start reading from. Save this somewhere persistent
+    /// and pass it to `resume_from` to continue the patrol across reboots.
+    pub fn position(&self) -> u16 {
+        self.next_page as u16
+    }
+
+    /// Continues the patrol from a previously saved position. Positions outside of the patrolled
+    /// range restart the patrol from the beginning.
+    pub fn resume_from(&mut self, page_address: u16) {
+        let page_address = page_address as u32;
+
+        if page_address >= self.first_page && page_address < self.end_page {
+            self.next_page = page_address;
+        } else {
+            self.next_page = self.first_page;
+        }
+    }
+
+    /// The number of times the patrol has wrapped around the full range
+    pub fn passes_completed(&self) -> u32 {
+        self.passes_completed
+    }
+
+    /// The findings since the last `clear_findings`, in page order
+    pub fn findings(&self) -> impl Iterator<Item = &PatrolFinding> {
+        self.ecc.findings.iter()
+    }
+
+    /// Is true if more findings occurred than could be stored since the last `clear_findings`
+    pub fn findings_overflowed(&self) -> bool {
+        self.ecc.findings.overflowed()
+    }
+
+    pub fn clear_findings(&mut self) {
+        self.ecc.findings.clear();
+    }
+}
+
+/// Records findings like `EccScan` until a page is lost to uncorrectable errors, then ends the
+/// scan so `step_with_recovery` can retry that page
+struct UntilLost<'a> {
+    ecc: &'a mut EccScan<MAX_PATROL_FINDINGS>,
+    lost: Option<(u16, ECCStatus)>,
+}
+
+impl ScanVisitor for UntilLost<'_> {
+    fn visit_page(&mut self, page: &ScanPage) -> ControlFlow<()> {
+        match page.ecc_status {
+            ECCStatus::SinglePageError | ECCStatus::MultiPageError => {
+                self.lost = Some((page.page_address, page.ecc_status));
+                ControlFlow::Break(())
+            }
+            _ => self.ecc.visit_page(page),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+    use std::vec::Vec;
+
+    fn reads_of(sim: &SimFlash, page_address: u16) -> usize {
+        sim.commands()
+            .iter()
+            .filter(|command| {
+                command.opcode == 0x13 && command.page_address() == Some(page_address)
+            })
+            .count()
+    }
+
+    #[test]
+    fn steps_wrap_around_the_range() {
+        let sim = SimFlash::new();
+        sim.set_uncorrectable(70);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut patrol = Patrol::new(1, 1, 30);
+        let steps: Vec<u16> = (0..4).map(|_| patrol.step(&flash).unwrap()).collect();
+
+        assert_eq!(steps, [30, 30, 4, 30]);
+        assert_eq!(patrol.passes_completed(), 1);
+        assert_eq!(patrol.position(), 94);
+        assert_eq!(reads_of(&sim, 64), 2);
+        assert_eq!(reads_of(&sim, 100), 1);
+        assert_eq!(sim.count(0x0B), 0);
+
+        let findings: Vec<PatrolFinding> = patrol.findings().copied().collect();
+        assert_eq!(
+            findings,
+            [
+                PatrolFinding {
+                    page_address: 70,
+                    ecc_status: ECCStatus::SinglePageError,
+                },
+                PatrolFinding {
+                    page_address: 70,
+                    ecc_status: ECCStatus::SinglePageError,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn recovery_retries_only_the_lost_page() {
+        let sim = SimFlash::new();
+        sim.set_uncorrectable(70);
+        let mut flash = sim.driver();
+        sim.clear_log();
+
+        let mut patrol = Patrol::new(1, 1, 64);
+        let pages = patrol
+            .step_with_recovery(&mut flash, RecoveryPolicy::default())
+            .unwrap();
+
+        assert_eq!(pages, 64);
+        assert_eq!(patrol.passes_completed(), 1);
+        assert_eq!(patrol.findings().count(), 1);
+        assert!(reads_of(&sim, 70) > 1);
+        assert!((64..128)
+            .filter(|page| *page != 70)
+            .all(|page| reads_of(&sim, page) == 1));
+    }
+}
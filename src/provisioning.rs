@@ -0,0 +1,283 @@
+//! Copying one board's flash configuration onto another, e.g. on a manufacturing line.
+//!
+//! Only some of a chip's state can be carried over. The protection and configuration registers
+//! and the bad block look up table links are settings, so they're reapplied as is. Factory bad
+//! block markers are a property of each chip's silicon, so the bad block map is exported for
+//! reference (and for checking the links make sense on the new chip) but never written back.
+//!
+//! When only the look up table needs to move, e.g. onto a replacement chip on a repaired board
+//! whose software bad block table assumes the old remaps, `export_lut` and `apply_lut` carry just
+//! the links. A `LutImage` serializes to a fixed 84 byte record for repair tooling to transport.
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    digest::Crc32,
+    scan::Findings,
+    status::{ConfigurationRegister, ProtectionRegister},
+    FlashError, Geometry, QspiBus, ReadMethod, WriteMode, BLOCK_COUNT, MAX_BBM_LUT_ENTIRES,
+    W25N01GV,
+};
+
+/// One bit per block, set if the block's factory bad block marker was set
+#[derive(Debug, Clone, Copy)]
+pub struct BadBlockMap {
+    bits: [u8; BLOCK_COUNT / 8],
+}
+
+impl BadBlockMap {
+    pub fn is_bad(&self, block: u16) -> bool {
+        match self.bits.get(block as usize / 8) {
+            Some(byte) => byte & (1 << (block % 8)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn bad_block_count(&self) -> u16 {
+        self.bits.iter().map(|byte| byte.count_ones() as u16).sum()
+    }
+
+    fn mark_bad(&mut self, block: u16) {
+        self.bits[block as usize / 8] |= 1 << (block % 8);
+    }
+}
+
+/// Everything `export_provisioning_state` reads off a chip
+#[derive(Debug, Clone, Copy)]
+pub struct ProvisioningState {
+    pub protection_register: ProtectionRegister,
+    pub configuration_register: ConfigurationRegister,
+    /// The source chip's factory bad blocks, which aren't transferred by
+    /// `import_provisioning_state`
+    pub bad_blocks: BadBlockMap,
+    pub bbm_links: [Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES],
+}
+
+/// The bad block look up table links of a chip, see the module docs
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LutImage {
+    pub links: [Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES],
+}
+
+/// Four bytes per link and a CRC-32
+pub const LUT_IMAGE_BYTES: usize = MAX_BBM_LUT_ENTIRES * 4 + 4;
+
+impl LutImage {
+    /// Serializes the image as each link's logical then physical block, little endian, with
+    /// unused links as zeros, followed by a CRC-32 of the links computed in software
+    pub fn to_bytes(&self) -> [u8; LUT_IMAGE_BYTES] {
+        self.to_bytes_with(Crc32::new())
+    }
+
+    /// Serializes the image like `to_bytes`, with the CRC-32 computed by `digest`
+    pub fn to_bytes_with(&self, digest: Crc32) -> [u8; LUT_IMAGE_BYTES] {
+        let mut bytes = [0_u8; LUT_IMAGE_BYTES];
+
+        for (index, link) in self.links.iter().enumerate() {
+            if let Some((logical, physical)) = link {
+                bytes[index * 4..index * 4 + 2].copy_from_slice(&logical.to_le_bytes());
+                bytes[index * 4 + 2..index * 4 + 4].copy_from_slice(&physical.to_le_bytes());
+            }
+        }
+
+        let crc = digest.checksum(&bytes[..LUT_IMAGE_BYTES - 4]);
+        bytes[LUT_IMAGE_BYTES - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        bytes
+    }
+
+    /// Reads back an image written by `to_bytes`, or None if its CRC doesn't match
+    pub fn from_bytes(bytes: &[u8; LUT_IMAGE_BYTES]) -> Option<LutImage> {
+        LutImage::from_bytes_with(bytes, Crc32::new())
+    }
+
+    /// Reads back an image like `from_bytes`, checking its CRC-32 with `digest`
+    pub fn from_bytes_with(bytes: &[u8; LUT_IMAGE_BYTES], digest: Crc32) -> Option<LutImage> {
+        let crc = u32::from_le_bytes([
+            bytes[LUT_IMAGE_BYTES - 4],
+            bytes[LUT_IMAGE_BYTES - 3],
+            bytes[LUT_IMAGE_BYTES - 2],
+            bytes[LUT_IMAGE_BYTES - 1],
+        ]);
+        if crc != digest.checksum(&bytes[..LUT_IMAGE_BYTES - 4]) {
+            return None;
+        }
+
+        let mut links = [None; MAX_BBM_LUT_ENTIRES];
+        for (index, link) in links.iter_mut().enumerate() {
+            let logical = u16::from_le_bytes([bytes[index * 4], bytes[index * 4 + 1]]);
+            let physical = u16::from_le_bytes([bytes[index * 4 + 2], bytes[index * 4 + 3]]);
+
+            if logical != 0 || physical != 0 {
+                *link = Some((logical, physical));
+            }
+        }
+
+        Some(LutImage { links })
+    }
+}
+
+/// What `apply_lut` did with each link of the image
+#[derive(Debug, Default, Clone, Copy)]
+pub struct AppliedReport {
+    /// Links registered on the chip
+    pub applied: u16,
+    /// Links the chip already had
+    pub already_present: u16,
+    /// Links left out because their replacement block is bad on this chip
+    pub skipped_bad_target: Findings<(u16, u16), MAX_BBM_LUT_ENTIRES>,
+    /// Links registered despite conflicting with the chip's own, only with `force`
+    pub forced_conflicts: u16,
+    /// Links left out because the chip's look up table filled up
+    pub not_registered: u16,
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads the chip's bad block look up table links
+    pub fn export_lut(&self) -> Result<LutImage, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        Ok(LutImage {
+            links: self.read_bbm_lookup_table_unguarded()?,
+        })
+    }
+
+    /// Reads the registers, the factory bad block markers of every block, and the bad block look
+    /// up table links. Scanning the markers reads the first page of every block, so this leaves
+    /// the data buffer holding the last block's first page.
+    pub fn export_provisioning_state<D: DelayUs<u32>>(
+        &self,
+        method: ReadMethod,
+        delay: &mut D,
+    ) -> Result<ProvisioningState, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let protection_register = self.read_protection_register_unguarded()?;
+        let configuration_register = self.read_configuration_register_unguarded()?;
+
+        let mut bad_blocks = BadBlockMap {
+            bits: [0; BLOCK_COUNT / 8],
+        };
+        for block in 0..BLOCK_COUNT as u16 {
+            self.read_memory_to_data_buffer_unguarded(Geometry::W25N01GV.block_first_page(block))?;
+            self.wait_while_busy_with_delay_unguarded(delay)?;
+
+            if self.read_bad_block_marker(method)? {
+                bad_blocks.mark_bad(block);
+            }
+        }
+
+        Ok(ProvisioningState {
+            protection_register,
+            configuration_register,
+            bad_blocks,
+            bbm_links: self.read_bbm_lookup_table_unguarded()?,
+        })
+    }
+}
+
+impl<BUS: QspiBus> W25N01GV<BUS, WriteMode> {
+    /// Writes the exported registers and registers any of the exported look up table links this
+    /// chip doesn't already have, returning how many links were added. The bad block map isn't
+    /// applied, see the module docs.
+    ///
+    /// The protection register is written before the configuration register, since SR1-L in the
+    /// configuration register can lock the protection register. OTP-E is always cleared so the
+    /// chip isn't left in OTP access mode.
+    pub fn import_provisioning_state(
+        &self,
+        state: &ProvisioningState,
+    ) -> Result<usize, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        self.write_protection_register_unguarded(state.protection_register)?;
+        self.write_configuration_register_unguarded(ConfigurationRegister {
+            otp_e: false,
+            ..state.configuration_register
+        })?;
+
+        let existing_links = self.read_bbm_lookup_table_unguarded()?;
+        let mut new_links = [(0_u16, 0_u16); MAX_BBM_LUT_ENTIRES];
+        let mut new_link_count = 0;
+
+        for link in state.bbm_links.iter().flatten() {
+            if !existing_links.contains(&Some(*link)) {
+                new_links[new_link_count] = *link;
+                new_link_count += 1;
+            }
+        }
+
+        self.register_bad_block_links_unguarded(&new_links[..new_link_count])
+    }
+
+    /// Registers the links of `image` this chip doesn't already have. A link whose replacement
+    /// block has its factory bad block marker set here is left out and reported, since swapping
+    /// onto it would lose the data. Reading the markers leaves the data buffer holding the first
+    /// page of the last replacement block checked.
+    ///
+    /// A link conflicts if the chip already links either of its blocks differently. Without
+    /// `force`, any conflict returns `FlashError::LutConflict` before anything is
+    /// registered. With `force`, conflicting links are registered anyway and counted.
+    pub fn apply_lut(
+        &self,
+        image: &LutImage,
+        force: bool,
+        method: ReadMethod,
+    ) -> Result<AppliedReport, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let existing_links = self.read_bbm_lookup_table_unguarded()?;
+        let conflicts = |(logical, physical): (u16, u16)| {
+            existing_links
+                .iter()
+                .flatten()
+                .any(|(existing_logical, existing_physical)| {
+                    (*existing_logical == logical) != (*existing_physical == physical)
+                })
+        };
+
+        let mut report = AppliedReport::default();
+        let mut new_links = [(0_u16, 0_u16); MAX_BBM_LUT_ENTIRES];
+        let mut new_link_count = 0;
+
+        for link in image.links.iter().flatten() {
+            if existing_links.contains(&Some(*link)) {
+                report.already_present += 1;
+                continue;
+            }
+
+            if conflicts(*link) {
+                if !force {
+                    return Err(FlashError::LutConflict {
+                        logical_block: link.0,
+                    });
+                }
+
+                report.forced_conflicts += 1;
+            }
+
+            new_links[new_link_count] = *link;
+            new_link_count += 1;
+        }
+
+        let mut usable_links = [(0_u16, 0_u16); MAX_BBM_LUT_ENTIRES];
+        let mut usable_link_count = 0;
+
+        for link in &new_links[..new_link_count] {
+            if self.is_bad_block_unguarded(link.1, method)? {
+                report.skipped_bad_target.push(*link);
+            } else {
+                usable_links[usable_link_count] = *link;
+                usable_link_count += 1;
+            }
+        }
+
+        let registered =
+            self.register_bad_block_links_unguarded(&usable_links[..usable_link_count])?;
+        report.applied = registered as u16;
+        report.not_registered = (usable_link_count - registered) as u16;
+
+        Ok(report)
+    }
+}
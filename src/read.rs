@@ -1,9 +1,46 @@
-use stm32l4xx_hal::qspi::{QspiMode, QspiReadCommand, QspiWriteCommand};
+use core::{
+    convert::TryInto,
+    ops::{ControlFlow, Range},
+};
+
+use hal::blocking::delay::DelayUs;
 
 use crate::{
-    FlashCommandError, FlashCommands, MAX_BBM_LUT_ENTIRES, PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+    bus::QspiMode,
+    commands,
+    scan::{ScanDepth, ScanPage, ScanSkip, ScanVisitor},
+    scratch::Scratch,
+    soft_ecc::{self, SOFT_ECC_BYTES},
+    status::ECCStatus,
+    FlashError, Geometry, QspiBus, BLOCK_COUNT, MAX_BBM_LUT_ENTIRES, PAGES_PER_BLOCK,
+    PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, SPARE_BYTES, W25N01GV,
 };
 
+/// The block address bits of a look up table link's LBA
+const BBM_LBA_BLOCK_MASK: u16 = 0x3FFF;
+
+/// Decodes one 4 byte entry of the bad block look up table into its logical and physical block.
+/// Each address is sent MSB first, and the top two bits of the LBA are the link's enable and
+/// invalid flags rather than part of the block, so they're dropped. An entry that's all zeros is
+/// an unused slot.
+fn parse_bbm_lut_entry(bytes: [u8; 4]) -> Option<(u16, u16)> {
+    if bytes == [0; 4] {
+        return None;
+    }
+
+    let lba = u16::from_be_bytes([bytes[0], bytes[1]]) & BBM_LBA_BLOCK_MASK;
+    let pba = u16::from_be_bytes([bytes[2], bytes[3]]);
+
+    Some((lba, pba))
+}
+
+/// The most scratch `classify_page_in` takes
+pub const CLASSIFY_PAGE_SCRATCH_BYTES: usize = PAGE_SIZE_WITH_ECC_BYTES;
+/// The most scratch `dump_in` takes
+pub const DUMP_SCRATCH_BYTES: usize = PAGE_SIZE_WITH_ECC_BYTES;
+/// The most scratch `verify_against_in` takes
+pub const VERIFY_AGAINST_SCRATCH_BYTES: usize = PAGE_SIZE_WITH_ECC_BYTES;
+
 #[derive(Debug, Clone, Copy)]
 pub enum ReadMethod {
     FastRead = 0x0B,
@@ -14,7 +51,7 @@ pub enum ReadMethod {
 }
 
 impl ReadMethod {
-    fn dummy_cycles(&self) -> u8 {
+    pub(crate) fn dummy_cycles(&self) -> u8 {
         match self {
             ReadMethod::FastRead => 8,
             ReadMethod::DualFastRead => 8,
@@ -24,7 +61,7 @@ impl ReadMethod {
         }
     }
 
-    fn address_mode(&self) -> QspiMode {
+    pub(crate) fn address_mode(&self) -> QspiMode {
         match self {
             ReadMethod::FastRead => QspiMode::SingleChannel,
             ReadMethod::DualFastRead => QspiMode::SingleChannel,
@@ -34,7 +71,7 @@ impl ReadMethod {
         }
     }
 
-    fn data_mode(&self) -> QspiMode {
+    pub(crate) fn data_mode(&self) -> QspiMode {
         match self {
             ReadMethod::FastRead => QspiMode::SingleChannel,
             ReadMethod::DualFastRead => QspiMode::DualChannel,
@@ -45,12 +82,106 @@ impl ReadMethod {
     }
 }
 
-impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), MODE> {
-    pub fn read_memory_to_data_buffer(&self, page_address: u16) -> Result<(), FlashCommandError> {
+/// What a page was found to contain by `classify_page`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageClass {
+    /// The page hasn't been programmed since its block was last erased
+    Erased,
+    /// The page holds data that read back without uncorrectable ECC errors
+    Programmed,
+    /// The page holds data, but ECC couldn't correct it
+    Suspect,
+}
+
+/// How reads get data out of the device, set by the BUF bit of the configuration register.
+///
+/// The W25N01GV has a single 2,112 byte data buffer rather than two halves that reads alternate
+/// between. A Page Data Read always replaces the whole buffer with the page (main and spare area),
+/// and loads for a program write into that same buffer, so reading a page discards anything
+/// loaded but not yet programmed. There's no "active half" to track; the only thing that changes
+/// where read data comes from is this mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BufferMode {
+    /// BUF = 1. Reads start at the given column of the data buffer and stop at the end of it
+    Buffered,
+    /// BUF = 0. Reads ignore the column, start at the beginning of the data buffer, and carry on
+    /// into the following pages until the host stops clocking
+    Continuous,
+}
+
+/// Totals gathered while sweeping the spare areas of a range of pages
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SweepStats {
+    pub pages_visited: u16,
+    /// Pages whose read needed ECC correction
+    pub ecc_corrected: u16,
+    /// Pages whose read had more errors than ECC could correct
+    pub ecc_uncorrectable: u16,
+}
+
+/// `sweep_spare` as a visitor
+struct Sweep<F> {
+    f: F,
+    stats: SweepStats,
+}
+
+impl<F> ScanVisitor for Sweep<F>
+where
+    F: FnMut(u16, &[u8; SPARE_BYTES]) -> ControlFlow<()>,
+{
+    fn visit_page(&mut self, page: &ScanPage) -> ControlFlow<()> {
+        match page.ecc_status {
+            ECCStatus::Successful | ECCStatus::EccDisabled => {}
+            ECCStatus::CorrectedSuccessfully => self.stats.ecc_corrected += 1,
+            ECCStatus::SinglePageError | ECCStatus::MultiPageError => {
+                self.stats.ecc_uncorrectable += 1
+            }
+        }
+
+        self.stats.pages_visited += 1;
+
+        match page.spare {
+            Some(spare) => (self.f)(page.page_address, spare),
+            None => ControlFlow::Continue(()),
+        }
+    }
+}
+
+/// The data buffer split into a page's main area and spare area
+#[derive(Debug, Clone, Copy)]
+pub struct PageWithSpare {
+    pub data: [u8; PAGE_SIZE_BYTES],
+    pub spare: [u8; SPARE_BYTES],
+}
+
+/// Totals gathered while dumping a range of pages
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DumpStats {
+    pub pages_dumped: u32,
+    /// Bad blocks within the range whose pages were skipped
+    pub bad_blocks_skipped: u16,
+    /// Pages that were dumped even though ECC couldn't correct them
+    pub ecc_uncorrectable: u32,
+}
+
+fn is_blank(buffer: &[u8]) -> bool {
+    buffer.iter().all(|byte| *byte == 0xFF)
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    pub fn read_memory_to_data_buffer(&self, page_address: u16) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_memory_to_data_buffer_unguarded(page_address)
+    }
+
+    pub(crate) fn read_memory_to_data_buffer_unguarded(
+        &self,
+        page_address: u16,
+    ) -> Result<(), FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
@@ -58,21 +189,10 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
 
         let page_address = page_address.to_be_bytes();
 
-        let command = QspiWriteCommand {
-            instruction: Some((FlashCommands::PageDataRead as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 8,
-            data: Some((&page_address, QspiMode::SingleChannel)),
-            double_data_rate: false,
-        };
+        let command = commands::page_data_read(&page_address);
 
-        if let Err(err) = self.qspi.write(command) {
-            match err {
-                stm32l4xx_hal::qspi::QspiError::Busy => Err(FlashCommandError::QSPIBusy),
-                stm32l4xx_hal::qspi::QspiError::Address => Err(FlashCommandError::QSPIAddress),
-                stm32l4xx_hal::qspi::QspiError::Unknown => Err(FlashCommandError::QSPIUnknown),
-            }
+        if let Err(err) = self.qspi_write(command) {
+            Err(err)
         } else {
             Ok(())
         }
@@ -82,44 +202,130 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
         &self,
         buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
         method: ReadMethod,
-    ) -> Result<(), FlashCommandError> {
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_data_buffer_unguarded(buffer, method)
+    }
+
+    pub(crate) fn read_data_buffer_unguarded(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
         }
 
-        let command = QspiReadCommand {
-            instruction: Some((method as u8, QspiMode::SingleChannel)),
-            address: Some((0, method.address_mode())),
-            alternative_bytes: None,
-            dummy_cycles: method.dummy_cycles(),
-            data_mode: method.data_mode(),
-            receive_length: PAGE_SIZE_WITH_ECC_BYTES as u32,
-            double_data_rate: false,
-        };
+        self.transfer_split(0, buffer, method)
+    }
 
-        if let Err(err) = self.qspi.transfer(command, buffer) {
-            match err {
-                stm32l4xx_hal::qspi::QspiError::Busy => Err(FlashCommandError::QSPIBusy),
-                stm32l4xx_hal::qspi::QspiError::Address => Err(FlashCommandError::QSPIAddress),
-                stm32l4xx_hal::qspi::QspiError::Unknown => Err(FlashCommandError::QSPIUnknown),
-            }
-        } else {
-            Ok(())
+    /// Reads a page into the data buffer, waits for the read to finish, and reads the whole data
+    /// buffer into `buffer`. Returns the page's ECC status, and unlike `wait_while_busy`, any
+    /// error along the way.
+    pub fn read_page(
+        &self,
+        page_address: u16,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        method: ReadMethod,
+    ) -> Result<ECCStatus, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_page_unguarded(page_address, buffer, method)
+    }
+
+    pub(crate) fn read_page_unguarded(
+        &self,
+        page_address: u16,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        method: ReadMethod,
+    ) -> Result<ECCStatus, FlashError> {
+        self.read_memory_to_data_buffer_unguarded(page_address)?;
+        self.wait_while_busy_unguarded()?;
+
+        let ecc_status = self.read_status_register_unguarded()?.ecc_status;
+        self.read_data_buffer_unguarded(buffer, method)?;
+
+        Ok(ecc_status)
+    }
+
+    /// Reads only the main area (columns 0 to 2047) of the data buffer, leaving the spare area
+    /// off the bus
+    pub fn read_page_data(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_page_data_unguarded(buffer, method)
+    }
+
+    pub(crate) fn read_page_data_unguarded(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
         }
+
+        self.transfer_split(0, buffer, method)
+    }
+
+    /// Reads the whole data buffer like `read_data_buffer`, split into the main area and the spare
+    /// area
+    pub fn read_page_with_spare(&self, method: ReadMethod) -> Result<PageWithSpare, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        self.read_data_buffer_unguarded(&mut buffer, method)?;
+
+        let mut page = PageWithSpare {
+            data: [0_u8; PAGE_SIZE_BYTES],
+            spare: [0_u8; SPARE_BYTES],
+        };
+        page.data.copy_from_slice(&buffer[..PAGE_SIZE_BYTES]);
+        page.spare.copy_from_slice(&buffer[PAGE_SIZE_BYTES..]);
+
+        Ok(page)
+    }
+
+    /// Reads only the spare area (columns 2048 to 2111) of the data buffer. Only valid in buffered
+    /// read mode, in continuous read mode the column address is ignored.
+    pub fn read_spare_area(
+        &self,
+        buffer: &mut [u8; SPARE_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_spare_area_unguarded(buffer, method)
+    }
+
+    pub(crate) fn read_spare_area_unguarded(
+        &self,
+        buffer: &mut [u8; SPARE_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        self.read_physical_columns(PAGE_SIZE_BYTES as u16, buffer, method)
     }
 
     pub fn read_bbm_lookup_table(
         &self,
-    ) -> Result<[Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES], FlashCommandError> {
+    ) -> Result<[Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES], FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_bbm_lookup_table_unguarded()
+    }
+
+    pub(crate) fn read_bbm_lookup_table_unguarded(
+        &self,
+    ) -> Result<[Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES], FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
@@ -127,36 +333,726 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
 
         let mut buffer = [0_u8; MAX_BBM_LUT_ENTIRES * 4];
 
-        let command = QspiReadCommand {
-            instruction: Some((FlashCommands::ReadBBM as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 8,
-            data_mode: QspiMode::SingleChannel,
-            receive_length: buffer.len() as u32,
-            double_data_rate: false,
+        let command = commands::read_bbm_lookup_table();
+
+        if let Err(err) = self.qspi_transfer(command, &mut buffer) {
+            Err(err)
+        } else {
+            let mut links = [None; MAX_BBM_LUT_ENTIRES];
+
+            for (link, bytes) in links.iter_mut().zip(buffer.chunks_exact(4)) {
+                *link = parse_bbm_lut_entry([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            }
+
+            Ok(links)
+        }
+    }
+
+    /// Reads the address of the last page that had an ECC failure. Mostly useful after a
+    /// continuous read, where the ECC status only says a failure happened somewhere in the read.
+    pub fn read_last_ecc_failure_page_address(&self) -> Result<u16, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        match self.check_busy() {
+            Ok(busy) => {
+                if busy {
+                    return Err(FlashError::DeviceBusy);
+                }
+            }
+            Err(err) => return Err(err),
+        }
+
+        let mut buffer = [0_u8; 2];
+        let command = commands::last_ecc_failure_page_address();
+
+        if let Err(err) = self.qspi_transfer(command, &mut buffer) {
+            Err(err)
+        } else {
+            Ok(u16::from_be_bytes(buffer))
+        }
+    }
+
+    /// Works out whether a page is erased, programmed, or holds data ECC couldn't correct.
+    ///
+    /// An erased page is all 0xFF, which isn't necessarily a valid ECC codeword, so an ECC error
+    /// alone doesn't mean a page is corrupt. When the ECC-on read reports an uncorrectable error
+    /// the page is read again with ECC disabled, and it's only considered erased if that raw read
+    /// is completely blank. The configuration register is restored before returning. With ECC
+    /// disabled there's no ECC status to go on, so a blank page is erased and anything else is
+    /// programmed.
+    pub fn classify_page(
+        &self,
+        page_address: u16,
+        method: ReadMethod,
+    ) -> Result<PageClass, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.classify_page_unguarded(page_address, method)
+    }
+
+    pub(crate) fn classify_page_unguarded(
+        &self,
+        page_address: u16,
+        method: ReadMethod,
+    ) -> Result<PageClass, FlashError> {
+        let mut buffer = [0_u8; CLASSIFY_PAGE_SCRATCH_BYTES];
+        self.classify_page_in_unguarded(page_address, method, &mut Scratch::new(&mut buffer))
+    }
+
+    /// Like `classify_page`, but with its page buffer taken from `scratch`
+    pub fn classify_page_in(
+        &self,
+        page_address: u16,
+        method: ReadMethod,
+        scratch: &mut Scratch,
+    ) -> Result<PageClass, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.classify_page_in_unguarded(page_address, method, scratch)
+    }
+
+    pub(crate) fn classify_page_in_unguarded(
+        &self,
+        page_address: u16,
+        method: ReadMethod,
+        scratch: &mut Scratch,
+    ) -> Result<PageClass, FlashError> {
+        let mut scratch = scratch.reborrow();
+        let buffer = scratch.take_page()?;
+
+        self.read_memory_to_data_buffer_unguarded(page_address)?;
+        self.wait_while_busy_unguarded()?;
+
+        let ecc_status = self.read_status_register_unguarded()?.ecc_status;
+        self.read_data_buffer_unguarded(buffer, method)?;
+
+        match ecc_status {
+            ECCStatus::Successful | ECCStatus::CorrectedSuccessfully | ECCStatus::EccDisabled => {
+                if is_blank(buffer) {
+                    Ok(PageClass::Erased)
+                } else {
+                    Ok(PageClass::Programmed)
+                }
+            }
+            ECCStatus::SinglePageError | ECCStatus::MultiPageError => {
+                let configuration_register = self.read_configuration_register_unguarded()?;
+
+                let mut ecc_disabled = configuration_register;
+                ecc_disabled.ecc_e = false;
+                self.write_configuration_register_unguarded(ecc_disabled)?;
+
+                let raw_read = self
+                    .read_memory_to_data_buffer_unguarded(page_address)
+                    .and_then(|_| {
+                        self.wait_while_busy_unguarded()?;
+                        self.read_data_buffer_unguarded(buffer, method)
+                    });
+
+                self.write_configuration_register_unguarded(configuration_register)?;
+                raw_read?;
+
+                if is_blank(buffer) {
+                    Ok(PageClass::Erased)
+                } else {
+                    Ok(PageClass::Suspect)
+                }
+            }
+        }
+    }
+
+    /// Visits the spare area of every page in `pages`, transferring only the 64 spare bytes of each
+    /// page instead of the whole page. This is much quicker for scans that only care about
+    /// metadata. Returning `ControlFlow::Break` from the callback ends the sweep early.
+    ///
+    /// The sweep needs buffered read mode, so continuous read mode is turned off for the duration
+    /// of the sweep and restored afterwards.
+    pub fn sweep_spare<F>(
+        &self,
+        pages: Range<u16>,
+        method: ReadMethod,
+        f: F,
+    ) -> Result<SweepStats, FlashError>
+    where
+        F: FnMut(u16, &[u8; SPARE_BYTES]) -> ControlFlow<()>,
+    {
+        let _guard = self.begin_operation()?;
+
+        let mut sweep = Sweep {
+            f,
+            stats: SweepStats::default(),
         };
+        self.scan_pages_unguarded(
+            pages.start as u32..pages.end as u32,
+            ScanSkip::Nothing,
+            ScanDepth::Spare(method),
+            &mut sweep,
+        )?;
+
+        Ok(sweep.stats)
+    }
+
+    /// Runs `f` with buffered read mode on, turning continuous read mode off first if it's on and
+    /// restoring it afterwards
+    pub(crate) fn with_buffered_read<T, F>(&self, f: F) -> Result<T, FlashError>
+    where
+        F: FnOnce() -> Result<T, FlashError>,
+    {
+        let configuration_register = self.read_configuration_register_unguarded()?;
+        if !configuration_register.buf {
+            let mut buffered = configuration_register;
+            buffered.buf = true;
+            self.write_configuration_register_unguarded(buffered)?;
+        }
+
+        let result = f();
+
+        if !configuration_register.buf {
+            self.write_configuration_register_unguarded(configuration_register)?;
+        }
 
-        if let Err(err) = self.qspi.transfer(command, &mut buffer) {
-            match err {
-                stm32l4xx_hal::qspi::QspiError::Busy => Err(FlashCommandError::QSPIBusy),
-                stm32l4xx_hal::qspi::QspiError::Address => Err(FlashCommandError::QSPIAddress),
-                stm32l4xx_hal::qspi::QspiError::Unknown => Err(FlashCommandError::QSPIUnknown),
+        result
+    }
+
+    /// Reads a page into the data buffer and transfers only its spare area, returning the page's
+    /// ECC status
+    pub(crate) fn read_page_spare(
+        &self,
+        page_address: u16,
+        spare: &mut [u8; SPARE_BYTES],
+        method: ReadMethod,
+    ) -> Result<ECCStatus, FlashError> {
+        self.read_memory_to_data_buffer_unguarded(page_address)?;
+        self.wait_while_busy_unguarded()?;
+
+        let ecc_status = self.read_status_register_unguarded()?.ecc_status;
+        self.read_spare_area_unguarded(spare, method)?;
+
+        Ok(ecc_status)
+    }
+
+    /// Reads the whole data buffer and checks the main area against software ECC stored in the
+    /// spare area at `ecc_offset`, correcting the main area in place. Returns the number of bits
+    /// corrected. Intended for use with hardware ECC disabled, with the ECC calculated by
+    /// `soft_ecc::calculate` when the page was written.
+    pub fn read_buffer_with_software_ecc_check(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        ecc_offset: usize,
+        method: ReadMethod,
+    ) -> Result<u8, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if ecc_offset + SOFT_ECC_BYTES > SPARE_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.read_data_buffer_unguarded(buffer, method)?;
+
+        let (main, spare) = buffer.split_at_mut(PAGE_SIZE_BYTES);
+        let mut ecc = [0_u8; SOFT_ECC_BYTES];
+        ecc.copy_from_slice(&spare[ecc_offset..ecc_offset + SOFT_ECC_BYTES]);
+
+        match main.try_into() {
+            Ok(main) => soft_ecc::correct(main, &ecc),
+            Err(_) => Err(FlashError::OutOfBounds),
+        }
+    }
+
+    /// Finds how many pages of a block have been written. Since pages within a block must be
+    /// written sequentially, this binary searches for the first erased page instead of checking
+    /// every page. Returns `PAGES_PER_BLOCK` if the whole block has been written.
+    pub fn find_write_frontier(&self, block: u16, method: ReadMethod) -> Result<u16, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.find_write_frontier_unguarded(block, method)
+    }
+
+    pub(crate) fn find_write_frontier_unguarded(
+        &self,
+        block: u16,
+        method: ReadMethod,
+    ) -> Result<u16, FlashError> {
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let first_page = Geometry::W25N01GV.block_first_page(block);
+        let mut low = 0_u16;
+        let mut high = PAGES_PER_BLOCK as u16;
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            if self.classify_page_unguarded(first_page + middle, method)? == PageClass::Erased {
+                high = middle;
+            } else {
+                low = middle + 1;
             }
+        }
+
+        Ok(low)
+    }
+
+    /// Reads every written page of a block in order, skipping the erased tail of the block, and
+    /// passes each page's main data and ECC status to `f`. Useful for replaying a log that was
+    /// appended into the block.
+    pub fn read_written_pages<D, F>(
+        &self,
+        block: u16,
+        method: ReadMethod,
+        delay: &mut D,
+        mut f: F,
+    ) -> Result<(), FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(u16, &[u8; PAGE_SIZE_BYTES], ECCStatus),
+    {
+        let _guard = self.begin_operation()?;
+
+        let written_pages = self.find_write_frontier_unguarded(block, method)?;
+        let first_page = Geometry::W25N01GV.block_first_page(block);
+        let mut buffer = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+
+        for page_address in first_page..first_page + written_pages {
+            self.read_memory_to_data_buffer_unguarded(page_address)?;
+            self.wait_while_busy_with_delay_unguarded(delay)?;
+
+            let ecc_status = self.read_status_register_unguarded()?.ecc_status;
+            self.read_data_buffer_unguarded(&mut buffer, method)?;
+
+            match buffer[..PAGE_SIZE_BYTES].try_into() {
+                Ok(main) => f(page_address, main, ecc_status),
+                Err(_) => return Err(FlashError::OutOfBounds),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the BUF bit to find out how reads currently get data out of the data buffer
+    pub fn buffer_mode(&self) -> Result<BufferMode, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if self.read_configuration_register_unguarded()?.buf {
+            Ok(BufferMode::Buffered)
         } else {
-            let mut links = [None; MAX_BBM_LUT_ENTIRES];
+            Ok(BufferMode::Continuous)
+        }
+    }
+
+    /// Checks the factory bad block marker in the spare area of the block's first page, which is
+    /// anything other than 0xFF on a bad block. Which bytes make up the marker is up to the OOB
+    /// layout, the first spare byte by default. Leaves that page in the data buffer.
+    pub fn is_bad_block(&self, block: u16, method: ReadMethod) -> Result<bool, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.is_bad_block_unguarded(block, method)
+    }
+
+    pub(crate) fn is_bad_block_unguarded(
+        &self,
+        block: u16,
+        method: ReadMethod,
+    ) -> Result<bool, FlashError> {
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.read_memory_to_data_buffer_unguarded(Geometry::W25N01GV.block_first_page(block))?;
+        self.wait_while_busy_unguarded()?;
+
+        self.read_bad_block_marker(method)
+    }
+
+    /// Reads `page_count` pages starting at `start_page` and passes each page's main data to `f`
+    /// along with its page address, for streaming an image of the chip out to a host. Pages in
+    /// bad blocks are skipped without calling `f`, so the page addresses double as progress.
+    /// Pages with uncorrectable ECC errors are still passed on as read, since a recovery tool
+    /// wants whatever data is left, and are counted in the returned stats. A `page_count` of 0
+    /// reads nothing.
+    pub fn dump<D, F>(
+        &self,
+        start_page: u16,
+        page_count: u32,
+        method: ReadMethod,
+        delay: &mut D,
+        f: F,
+    ) -> Result<DumpStats, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(u16, &[u8; PAGE_SIZE_BYTES]),
+    {
+        let _guard = self.begin_operation()?;
+
+        let mut buffer = [0_u8; DUMP_SCRATCH_BYTES];
+        let mut scratch = Scratch::new(&mut buffer);
+        self.dump_in_unguarded(start_page, page_count, method, delay, &mut scratch, f)
+    }
+
+    /// Like `dump`, but with its page buffer taken from `scratch`
+    pub fn dump_in<D, F>(
+        &self,
+        start_page: u16,
+        page_count: u32,
+        method: ReadMethod,
+        delay: &mut D,
+        scratch: &mut Scratch,
+        f: F,
+    ) -> Result<DumpStats, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(u16, &[u8; PAGE_SIZE_BYTES]),
+    {
+        let _guard = self.begin_operation()?;
+        self.dump_in_unguarded(start_page, page_count, method, delay, scratch, f)
+    }
+
+    pub(crate) fn dump_in_unguarded<D, F>(
+        &self,
+        start_page: u16,
+        page_count: u32,
+        method: ReadMethod,
+        delay: &mut D,
+        scratch: &mut Scratch,
+        mut f: F,
+    ) -> Result<DumpStats, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(u16, &[u8; PAGE_SIZE_BYTES]),
+    {
+        if start_page as u32 + page_count > Geometry::W25N01GV.page_count() as u32 {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        // Each block's bad block marker costs a page read of its own, estimated as a whole one
+        let blocks = if page_count == 0 {
+            0
+        } else {
+            let last_page = start_page as u32 + page_count - 1;
+            last_page / PAGES_PER_BLOCK as u32 - start_page as u32 / PAGES_PER_BLOCK as u32 + 1
+        };
+        self.check_latency_budget(
+            self.page_read_worst_case_us(method)
+                .saturating_mul(page_count + blocks),
+        )?;
+
+        let mut stats = DumpStats::default();
+        let mut scratch = scratch.reborrow();
+        let buffer = scratch.take_page()?;
+        let mut page = start_page as u32;
+        let end_page = start_page as u32 + page_count;
+        let mut checked_block = None;
+
+        while page < end_page {
+            let page_address = page as u16;
+            let block = Geometry::W25N01GV.block_of_page(page_address);
 
-            for link_index in 0..MAX_BBM_LUT_ENTIRES {
-                let lba = buffer[link_index * 4] as u16 + (buffer[link_index * 4 + 1] as u16) << 8;
-                let pba =
-                    buffer[link_index * 4 + 2] as u16 + (buffer[link_index * 4 + 3] as u16) << 8;
+            if checked_block != Some(block) {
+                checked_block = Some(block);
 
-                if lba != 0 || pba != 0 {
-                    links[link_index] = Some((lba, pba));
+                if self.is_bad_block_unguarded(block, method)? {
+                    stats.bad_blocks_skipped += 1;
+                    page = Geometry::W25N01GV.block_pages(block).end;
+                    continue;
                 }
             }
 
-            Ok(links)
+            self.read_memory_to_data_buffer_unguarded(page_address)?;
+            self.wait_while_busy_with_delay_unguarded(delay)?;
+
+            match self.read_status_register_unguarded()?.ecc_status {
+                ECCStatus::SinglePageError | ECCStatus::MultiPageError => {
+                    stats.ecc_uncorrectable += 1
+                }
+                _ => {}
+            }
+            self.read_data_buffer_unguarded(buffer, method)?;
+
+            match buffer[..PAGE_SIZE_BYTES].try_into() {
+                Ok(main) => f(page_address, main),
+                Err(_) => return Err(FlashError::OutOfBounds),
+            }
+
+            stats.pages_dumped += 1;
+            page += 1;
+        }
+
+        Ok(stats)
+    }
+
+    /// Compares the main area of the pages starting at `start_page` against `golden`, a page at a
+    /// time, e.g. to check an image was programmed correctly. Returns the offset into `golden` of
+    /// the first byte that doesn't match, or None if the device holds exactly `golden`. An empty
+    /// `golden` matches without reading anything. See `verify_stream` for images too big to hold in
+    /// RAM.
+    pub fn verify_against<D: DelayUs<u32>>(
+        &self,
+        start_page: u16,
+        golden: &[u8],
+        method: ReadMethod,
+        delay: &mut D,
+    ) -> Result<Option<u64>, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let mut buffer = [0_u8; VERIFY_AGAINST_SCRATCH_BYTES];
+        let mut scratch = Scratch::new(&mut buffer);
+        self.verify_against_in_unguarded(start_page, golden, method, delay, &mut scratch)
+    }
+
+    /// Like `verify_against`, but with its page buffer taken from `scratch`
+    pub fn verify_against_in<D: DelayUs<u32>>(
+        &self,
+        start_page: u16,
+        golden: &[u8],
+        method: ReadMethod,
+        delay: &mut D,
+        scratch: &mut Scratch,
+    ) -> Result<Option<u64>, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.verify_against_in_unguarded(start_page, golden, method, delay, scratch)
+    }
+
+    pub(crate) fn verify_against_in_unguarded<D: DelayUs<u32>>(
+        &self,
+        start_page: u16,
+        golden: &[u8],
+        method: ReadMethod,
+        delay: &mut D,
+        scratch: &mut Scratch,
+    ) -> Result<Option<u64>, FlashError> {
+        let pages_available = Geometry::W25N01GV.page_count() - start_page as usize;
+        if golden.len() > pages_available * PAGE_SIZE_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let page_count = golden.len().div_ceil(PAGE_SIZE_BYTES) as u32;
+        self.check_latency_budget(
+            self.page_read_worst_case_us(method)
+                .saturating_mul(page_count),
+        )?;
+
+        let mut scratch = scratch.reborrow();
+        let buffer = scratch.take_page()?;
+
+        for (page_index, expected) in golden.chunks(PAGE_SIZE_BYTES).enumerate() {
+            self.read_memory_to_data_buffer_unguarded(start_page + page_index as u16)?;
+            self.wait_while_busy_with_delay_unguarded(delay)?;
+            self.read_data_buffer_unguarded(buffer, method)?;
+
+            if let Some(offset) = expected
+                .iter()
+                .zip(buffer.iter())
+                .position(|(expected, actual)| expected != actual)
+            {
+                return Ok(Some((page_index * PAGE_SIZE_BYTES + offset) as u64));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bus::{QspiError, QspiReadCommand, QspiWriteCommand},
+        new_w25_n01_gv,
+        sim::{NoDelay, SimFlash},
+    };
+    use std::vec::Vec;
+
+    /// The address and receive length of each read command sent
+    type SentReads = Vec<(Option<(u32, QspiMode)>, Option<u32>)>;
+
+    /// Answers the Last ECC Failure Page Address read with a fixed register pair, and every other
+    /// read with zeros, i.e. an idle device
+    struct EccFailureRegisters([u8; 2]);
+
+    impl QspiBus for EccFailureRegisters {
+        fn write_command(&self, _command: QspiWriteCommand) -> Result<(), QspiError> {
+            Ok(())
+        }
+
+        fn read_command(
+            &self,
+            command: QspiReadCommand,
+            buffer: &mut [u8],
+        ) -> Result<(), QspiError> {
+            buffer.iter_mut().for_each(|byte| *byte = 0);
+            if command.instruction.map(|(opcode, _)| opcode) == Some(0xA9) {
+                assert_eq!(command.receive_length, 2);
+                buffer[..2].copy_from_slice(&self.0);
+            }
+
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reading_page_data_leaves_the_spare_area_off_the_bus() {
+        let sim = SimFlash::new();
+        let mut page = [0xA5; PAGE_SIZE_WITH_ECC_BYTES];
+        page[PAGE_SIZE_BYTES..]
+            .iter_mut()
+            .for_each(|byte| *byte = 0x3C);
+        sim.set_page(9, &page);
+        let flash = sim.driver();
+        flash.read_memory_to_data_buffer(9).unwrap();
+        flash.wait_while_busy().unwrap();
+
+        sim.clear_log();
+        let mut data = [0_u8; PAGE_SIZE_BYTES];
+        flash
+            .read_page_data(&mut data, ReadMethod::FastRead)
+            .unwrap();
+
+        let reads: SentReads = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0x0B)
+            .map(|command| (command.address, command.receive_length))
+            .collect();
+        assert_eq!(reads, [(Some((0, QspiMode::SingleChannel)), Some(2048))]);
+        assert_eq!(data, [0xA5; PAGE_SIZE_BYTES]);
+
+        sim.clear_log();
+        let split = flash.read_page_with_spare(ReadMethod::FastRead).unwrap();
+        assert_eq!(sim.commands().last().unwrap().receive_length, Some(2112));
+        assert_eq!(split.data, [0xA5; PAGE_SIZE_BYTES]);
+        assert_eq!(split.spare, [0x3C; SPARE_BYTES]);
+    }
+
+    #[test]
+    fn the_last_ecc_failure_address_is_sent_msb_first() {
+        for (registers, page_address) in [
+            ([0x12, 0x34], 0x1234),
+            ([0x00, 0x40], 64),
+            ([0xFF, 0xFF], 65535),
+        ]
+        .iter()
+        {
+            let flash = new_w25_n01_gv(EccFailureRegisters(*registers));
+            assert_eq!(
+                flash.read_last_ecc_failure_page_address().unwrap(),
+                *page_address
+            );
+        }
+    }
+
+    #[test]
+    fn the_last_ecc_failure_address_follows_uncorrectable_reads() {
+        let sim = SimFlash::new();
+        sim.set_uncorrectable(4241);
+        let flash = sim.driver();
+
+        flash.read_memory_to_data_buffer(4241).unwrap();
+        flash.wait_while_busy().unwrap();
+        assert_eq!(flash.read_last_ecc_failure_page_address().unwrap(), 4241);
+    }
+
+    #[test]
+    fn lut_entries_decode_msb_first_without_their_flags() {
+        // Enabled link from block 0x123 to block 0x3FE
+        assert_eq!(
+            parse_bbm_lut_entry([0x81, 0x23, 0x03, 0xFE]),
+            Some((0x123, 0x3FE))
+        );
+        // Enabled but invalidated link, which still takes up its slot
+        assert_eq!(
+            parse_bbm_lut_entry([0xC0, 0x07, 0x03, 0xFF]),
+            Some((0x007, 0x3FF))
+        );
+        // A link for block 0 is still a link
+        assert_eq!(
+            parse_bbm_lut_entry([0x80, 0x00, 0x02, 0x00]),
+            Some((0, 0x200))
+        );
+        assert_eq!(parse_bbm_lut_entry([0; 4]), None);
+    }
+
+    #[test]
+    fn a_partly_used_lut_reads_back_in_slot_order() {
+        let sim = SimFlash::new();
+        sim.set_lut(&[(5, 1020), (0x3FF, 1021), (700, 1022)]);
+        let flash = sim.driver();
+
+        let links = flash.read_bbm_lookup_table().unwrap();
+
+        assert_eq!(
+            links[..4],
+            [
+                Some((5, 1020)),
+                Some((0x3FF, 1021)),
+                Some((700, 1022)),
+                None
+            ]
+        );
+        assert!(links[3..].iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn blocks_past_the_device_are_refused_before_any_page_math() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+
+        for block in [BLOCK_COUNT as u16, BLOCK_COUNT as u16 + 1, u16::MAX].iter() {
+            assert!(matches!(
+                flash.read_written_pages(*block, ReadMethod::FastRead, &mut NoDelay, |_, _, _| {
+                    panic!("no page should be read")
+                }),
+                Err(FlashError::OutOfBounds)
+            ));
+            assert!(matches!(
+                flash.find_write_frontier(*block, ReadMethod::FastRead),
+                Err(FlashError::OutOfBounds)
+            ));
         }
+
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn classify_page_tells_erased_from_programmed_pages() {
+        let sim = SimFlash::new();
+        sim.set_page(65, &[0x12, 0x34]);
+        let flash = sim.driver();
+
+        assert_eq!(
+            flash.classify_page(64, ReadMethod::FastRead),
+            Ok(PageClass::Erased)
+        );
+        assert_eq!(
+            flash.classify_page(65, ReadMethod::FastRead),
+            Ok(PageClass::Programmed)
+        );
+    }
+
+    #[test]
+    fn an_uncorrectable_page_is_read_raw_and_ecc_turned_back_on() {
+        let sim = SimFlash::new();
+        sim.set_page(65, &[0x12, 0x34]);
+        sim.set_uncorrectable(64);
+        sim.set_uncorrectable(65);
+        let flash = sim.driver();
+
+        // A blank page that isn't a valid codeword is still just erased
+        sim.clear_log();
+        assert_eq!(
+            flash.classify_page(64, ReadMethod::FastRead),
+            Ok(PageClass::Erased)
+        );
+        assert_eq!(sim.count(0x13), 2);
+        assert!(flash.read_configuration_register().unwrap().ecc_e);
+
+        assert_eq!(
+            flash.classify_page(65, ReadMethod::FastRead),
+            Ok(PageClass::Suspect)
+        );
+        assert!(flash.read_configuration_register().unwrap().ecc_e);
+
+        // ECC is back on for the next read, which reports the error again
+        flash.read_memory_to_data_buffer(65).unwrap();
+        flash.wait_while_busy().unwrap();
+        assert_eq!(
+            flash.read_status_register().unwrap().ecc_status,
+            ECCStatus::SinglePageError
+        );
     }
 }
@@ -0,0 +1,679 @@
+//! Read disturb counting that survives reboots, and a scrubber that refreshes blocks once they've
+//! been read often enough.
+//!
+//! Every read of a page slightly disturbs the other pages of its block, so a block read millions
+//! of times without being erased slowly collects bit errors. `ReadDisturbMonitor` counts reads of
+//! a set of monitored blocks since each was last erased, and keeps the counts in a counter block
+//! of its own so a device that reboots daily doesn't start from zero every morning.
+//!
+//! Counts are exact in RAM. Writing a page for every read would wear the counter block out long
+//! before the blocks it watches, so a block's count is only marked for persisting when its reads
+//! this session cross a power of two: its 1st, 2nd, 4th, 8th read and so on. `persist` writes
+//! every count to the next page of the counter block when any of them is marked, and erases the
+//! block each time it wraps around. That gives the trade-off:
+//!
+//! - Accuracy: after a reboot a count resumes from what was last persisted, which is never more
+//!   than the true count and loses fewer than half of the reads of any one session, provided
+//!   `persist` is called after reads that mark a count. A persisted count is always at least half
+//!   the true count, however many reboots there have been, so set scrub thresholds to half the
+//!   read disturb limit you're designing to.
+//! - Wear: a session writes at most `1 + log2(reads)` pages for each monitored block it reads,
+//!   however many reads there are, e.g. 21 pages for a block read a million times. The counter
+//!   block is erased once every 64 pages.
+//!
+//! `scrub` refreshes a block through a spare block: every programmed page is moved into the spare
+//! and back with the device's own page read and program, which rewrites each page's data with
+//! ECC's corrections applied, and the block's count is reset. The copy in the spare is recorded
+//! in the counter block before the block is erased, so a scrub cut short by a power cut is found
+//! by `mount` and finished with `finish_interrupted_scrub`.
+//!
+//! Counter page, little endian:
+//!
+//! | Bytes       | Field                                                    |
+//! |-------------|----------------------------------------------------------|
+//! | 0..4        | Magic, "RDCT"                                            |
+//! | 4..8        | Sequence number, increasing with each page               |
+//! | 8..10       | Number of counts                                         |
+//! | 10..12      | Block being scrubbed, 0xFFFF if none                     |
+//! | 12..14      | Spare holding its copy                                   |
+//! | 14..16      | Reserved, 0                                              |
+//! | 16..        | Counts, each a block (2 bytes) then its reads (4 bytes)  |
+//! | after those | CRC-32 of everything before it                           |
+
+use core::convert::TryInto;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    digest::StreamingDigest, write::LoadMode, Column, FlashError, Geometry, PageClass, QspiBus,
+    ReadMethod, ReadMode, StorageError, WriteMethod, PAGES_PER_BLOCK, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+const COUNTER_MAGIC: u32 = 0x5443_4452;
+const COUNTER_HEADER_BYTES: usize = 16;
+const COUNT_BYTES: usize = 6;
+const NO_SCRUB: u16 = 0xFFFF;
+
+/// The most blocks one counter page has room for
+pub const MAX_MONITORED_BLOCKS: usize = (PAGE_SIZE_BYTES - COUNTER_HEADER_BYTES - 4) / COUNT_BYTES;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct MonitoredBlock {
+    block: u16,
+    reads: u32,
+    session_reads: u32,
+}
+
+/// Persistent read counts for up to `N` blocks, see the module docs
+pub struct ReadDisturbMonitor<const N: usize> {
+    counter_block: u16,
+    next_page: u16,
+    next_sequence: u32,
+    method: ReadMethod,
+    blocks: [MonitoredBlock; N],
+    block_count: usize,
+    scrub: Option<(u16, u16)>,
+    marked: bool,
+    pages_written: u32,
+}
+
+impl<const N: usize> ReadDisturbMonitor<N> {
+    /// Loads the counts for `monitored` from the newest page of `counter_block`. Blocks without a
+    /// persisted count start from 0. Returns `StorageError::InvalidLayout` if there are more than
+    /// `N` or `MAX_MONITORED_BLOCKS` of them, or if the counter block is one of them.
+    pub fn mount<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        counter_block: u16,
+        monitored: &[u16],
+        method: ReadMethod,
+    ) -> Result<ReadDisturbMonitor<N>, StorageError> {
+        if monitored.len() > N.min(MAX_MONITORED_BLOCKS) || monitored.contains(&counter_block) {
+            return Err(StorageError::InvalidLayout);
+        }
+
+        let in_bounds = |block: u16| Geometry::W25N01GV.contains_block(block as u32);
+        if !in_bounds(counter_block) || !monitored.iter().all(|block| in_bounds(*block)) {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        flash.check_block0(counter_block, 1)?;
+
+        let mut monitor = ReadDisturbMonitor {
+            counter_block,
+            next_page: Geometry::W25N01GV.block_first_page(counter_block),
+            next_sequence: 0,
+            method,
+            blocks: [MonitoredBlock::default(); N],
+            block_count: monitored.len(),
+            scrub: None,
+            marked: false,
+            pages_written: 0,
+        };
+
+        for (slot, block) in monitor.blocks.iter_mut().zip(monitored) {
+            slot.block = *block;
+        }
+
+        let written_pages = flash.find_write_frontier(counter_block, method)?;
+        let first_page = Geometry::W25N01GV.block_first_page(counter_block);
+        monitor.next_page = first_page + written_pages;
+
+        // A page torn by a power cut fails its CRC, so the one before it is the newest
+        let mut page = [0_u8; PAGE_SIZE_BYTES];
+        for page_address in (first_page..first_page + written_pages).rev() {
+            flash.read_memory_to_data_buffer(page_address)?;
+            flash.wait_while_busy()?;
+            flash.read_columns(Column::Physical(0), &mut page, method)?;
+
+            if monitor.load_page(&page, flash) {
+                break;
+            }
+        }
+
+        Ok(monitor)
+    }
+
+    /// Adds `reads` page reads of `block` to its count. Reads of blocks that aren't monitored are
+    /// ignored.
+    pub fn record_reads(&mut self, block: u16, reads: u32) {
+        if let Some(monitored) = self.find_mut(block) {
+            let before = monitored.session_reads;
+            monitored.reads = monitored.reads.saturating_add(reads);
+            monitored.session_reads = monitored.session_reads.saturating_add(reads);
+
+            // The number of bits in the session count goes up as it crosses each power of two
+            if before.leading_zeros() != monitored.session_reads.leading_zeros() {
+                self.marked = true;
+            }
+        }
+    }
+
+    /// Records one page read, by page address
+    pub fn record_page_read(&mut self, page_address: u16) {
+        self.record_reads(Geometry::W25N01GV.block_of_page(page_address), 1);
+    }
+
+    /// Reads of `block` since it was last erased, or None if it isn't monitored
+    pub fn reads(&self, block: u16) -> Option<u32> {
+        self.monitored()
+            .iter()
+            .find(|m| m.block == block)
+            .map(|m| m.reads)
+    }
+
+    /// The monitored blocks read at least `threshold` times since they were last erased
+    pub fn due(&self, threshold: u32) -> impl Iterator<Item = u16> + '_ {
+        self.monitored()
+            .iter()
+            .filter(move |monitored| monitored.reads >= threshold)
+            .map(|monitored| monitored.block)
+    }
+
+    /// Whether a count has crossed a threshold since the last `persist`
+    pub fn needs_persist(&self) -> bool {
+        self.marked
+    }
+
+    /// Counter pages written since mounting, to check against the wear budget
+    pub fn pages_written(&self) -> u32 {
+        self.pages_written
+    }
+
+    /// Tells the monitor `block` was erased some other way, e.g. by its owner rewriting it,
+    /// restarting its count from 0
+    pub fn record_erase(&mut self, block: u16) {
+        if let Some(monitored) = self.find_mut(block) {
+            monitored.reads = 0;
+            monitored.session_reads = 0;
+            self.marked = true;
+        }
+    }
+
+    /// Writes the counts to the next page of the counter block if any of them has crossed a
+    /// threshold since the last call, erasing the counter block first when wrapping around onto
+    /// it. Returns whether a page was written.
+    pub fn persist<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<bool, StorageError> {
+        if !self.marked {
+            return Ok(false);
+        }
+
+        self.write_counter_page(flash, write_method, delay)?;
+        self.marked = false;
+
+        Ok(true)
+    }
+
+    /// Refreshes `block` through `spare_block`, whose contents are lost, and restarts its count
+    /// from 0. See the module docs.
+    pub fn scrub<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        block: u16,
+        spare_block: u16,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<(), StorageError> {
+        if spare_block == block
+            || spare_block == self.counter_block
+            || self.find_mut(spare_block).is_some()
+        {
+            return Err(StorageError::InvalidLayout);
+        }
+
+        if !Geometry::W25N01GV.contains_block(spare_block as u32) {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        flash.check_block0(spare_block, 1)?;
+
+        erase(flash, spare_block, delay)?;
+        move_block(flash, block, spare_block, self.method, delay)?;
+
+        self.scrub = Some((block, spare_block));
+        self.write_counter_page(flash, write_method, delay)?;
+
+        self.finish_interrupted_scrub(flash, write_method, delay)
+    }
+
+    /// The block and spare of a scrub a power cut interrupted after the block's pages were copied
+    /// into the spare, if `mount` found one
+    pub fn interrupted_scrub(&self) -> Option<(u16, u16)> {
+        self.scrub
+    }
+
+    /// Copies an interrupted scrub's pages back from its spare, and does nothing if there isn't one
+    pub fn finish_interrupted_scrub<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<(), StorageError> {
+        let (block, spare_block) = match self.scrub {
+            Some(scrub) => scrub,
+            None => return Ok(()),
+        };
+
+        erase(flash, block, delay)?;
+        move_block(flash, spare_block, block, self.method, delay)?;
+
+        self.scrub = None;
+        self.record_erase(block);
+        self.write_counter_page(flash, write_method, delay)?;
+        self.marked = false;
+
+        Ok(())
+    }
+
+    fn monitored(&self) -> &[MonitoredBlock] {
+        &self.blocks[..self.block_count]
+    }
+
+    fn find_mut(&mut self, block: u16) -> Option<&mut MonitoredBlock> {
+        self.blocks[..self.block_count]
+            .iter_mut()
+            .find(|monitored| monitored.block == block)
+    }
+
+    /// Takes the counts from a counter page, returning false if it isn't one
+    fn load_page<BUS, MODE>(&mut self, page: &[u8], flash: &W25N01GV<BUS, MODE>) -> bool {
+        let read_u16 = |offset: usize| u16::from_le_bytes([page[offset], page[offset + 1]]);
+        let read_u32 =
+            |offset: usize| u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap());
+
+        let count = read_u16(8) as usize;
+        if read_u32(0) != COUNTER_MAGIC || count > MAX_MONITORED_BLOCKS {
+            return false;
+        }
+
+        let crc_offset = COUNTER_HEADER_BYTES + count * COUNT_BYTES;
+        let mut digest = flash.crc32_digest();
+        digest.update(&page[..crc_offset]);
+        if digest.finalize() != read_u32(crc_offset) {
+            return false;
+        }
+
+        for index in 0..count {
+            let offset = COUNTER_HEADER_BYTES + index * COUNT_BYTES;
+            let block = read_u16(offset);
+            if let Some(monitored) = self.find_mut(block) {
+                monitored.reads = read_u32(offset + 2);
+            }
+        }
+
+        self.scrub = match read_u16(10) {
+            NO_SCRUB => None,
+            block => Some((block, read_u16(12))),
+        };
+        self.next_sequence = read_u32(4).wrapping_add(1);
+
+        true
+    }
+
+    fn write_counter_page<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<(), StorageError> {
+        let block_pages = Geometry::W25N01GV.block_pages(self.counter_block);
+        if self.next_page as u32 == block_pages.end {
+            self.next_page = block_pages.start as u16;
+        }
+
+        if self.next_page as u32 == block_pages.start {
+            erase(flash, self.counter_block, delay)?;
+        }
+
+        let mut page = [0xFF_u8; COUNTER_HEADER_BYTES + MAX_MONITORED_BLOCKS * COUNT_BYTES + 4];
+        let (scrub_block, scrub_spare) = self.scrub.unwrap_or((NO_SCRUB, NO_SCRUB));
+        page[0..4].copy_from_slice(&COUNTER_MAGIC.to_le_bytes());
+        page[4..8].copy_from_slice(&self.next_sequence.to_le_bytes());
+        page[8..10].copy_from_slice(&(self.block_count as u16).to_le_bytes());
+        page[10..12].copy_from_slice(&scrub_block.to_le_bytes());
+        page[12..14].copy_from_slice(&scrub_spare.to_le_bytes());
+        page[14..16].copy_from_slice(&[0, 0]);
+
+        for (index, monitored) in self.monitored().iter().enumerate() {
+            let offset = COUNTER_HEADER_BYTES + index * COUNT_BYTES;
+            page[offset..offset + 2].copy_from_slice(&monitored.block.to_le_bytes());
+            page[offset + 2..offset + 6].copy_from_slice(&monitored.reads.to_le_bytes());
+        }
+
+        let crc_offset = COUNTER_HEADER_BYTES + self.block_count * COUNT_BYTES;
+        let mut digest = flash.crc32_digest();
+        digest.update(&page[..crc_offset]);
+        page[crc_offset..crc_offset + 4].copy_from_slice(&digest.finalize().to_le_bytes());
+
+        {
+            let _guard = flash.begin_operation()?;
+            let level = flash.verification_level;
+
+            flash.send_write_enable()?;
+            flash.load_to_data_buffer_unguarded(
+                &page[..crc_offset + 4],
+                0,
+                write_method,
+                LoadMode::ResetThenLoad,
+            )?;
+            flash.commit_with_unguarded(self.next_page, level, delay)?;
+        }
+
+        self.next_page += 1;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.pages_written += 1;
+
+        Ok(())
+    }
+}
+
+fn erase<BUS: QspiBus, D: DelayUs<u32>>(
+    flash: &W25N01GV<BUS, ReadMode>,
+    block: u16,
+    delay: &mut D,
+) -> Result<(), FlashError> {
+    let _guard = flash.begin_operation()?;
+    flash.erase_block_unguarded(block, delay)
+}
+
+/// Moves every programmed page of `from` to the same page of the erased block `to` with a Page
+/// Data Read and a Program Execute, so the data never crosses the bus
+fn move_block<BUS: QspiBus, D: DelayUs<u32>>(
+    flash: &W25N01GV<BUS, ReadMode>,
+    from: u16,
+    to: u16,
+    method: ReadMethod,
+    delay: &mut D,
+) -> Result<(), FlashError> {
+    let _guard = flash.begin_operation()?;
+    let level = flash.verification_level;
+    let from_page = Geometry::W25N01GV.block_first_page(from);
+    let to_page = Geometry::W25N01GV.block_first_page(to);
+
+    for offset in 0..PAGES_PER_BLOCK as u16 {
+        if flash.classify_page_unguarded(from_page + offset, method)? == PageClass::Erased {
+            continue;
+        }
+
+        flash.read_memory_to_data_buffer_unguarded(from_page + offset)?;
+        flash.wait_while_busy_unguarded()?;
+        flash.send_write_enable()?;
+        flash.commit_with_unguarded(to_page + offset, level, delay)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use std::vec::Vec;
+
+    const COUNTER_BLOCK: u16 = 30;
+    const MONITORED: [u16; 3] = [20, 21, 22];
+    const SPARE_BLOCK: u16 = 40;
+
+    fn mount(flash: &W25N01GV<SimFlash, ReadMode>) -> ReadDisturbMonitor<4> {
+        ReadDisturbMonitor::mount(flash, COUNTER_BLOCK, &MONITORED, ReadMethod::FastRead).unwrap()
+    }
+
+    fn persist(monitor: &mut ReadDisturbMonitor<4>, flash: &W25N01GV<SimFlash, ReadMode>) {
+        monitor
+            .persist(flash, WriteMethod::SingleLoad, &mut NoDelay)
+            .unwrap();
+    }
+
+    /// A session's reads of a block, from a fixed pseudo-random sequence between none and a few
+    /// thousand
+    fn session_reads(seed: &mut u32) -> u32 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        let reads = *seed >> 20;
+        // About one session in ten doesn't read the block at all
+        if reads < 400 {
+            0
+        } else {
+            reads
+        }
+    }
+
+    fn budget(reads: u32) -> u32 {
+        if reads == 0 {
+            0
+        } else {
+            32 - reads.leading_zeros()
+        }
+    }
+
+    #[test]
+    fn counts_stay_within_the_documented_bound_across_many_reboots() {
+        let sim = SimFlash::new();
+        let mut seed = 1;
+        let mut true_reads = [0_u32; 3];
+
+        for _ in 0..300 {
+            let flash = sim.driver();
+            let mut monitor = mount(&flash);
+
+            let mut loaded = [0_u32; 3];
+            for (index, block) in MONITORED.iter().enumerate() {
+                loaded[index] = monitor.reads(*block).unwrap();
+                assert!(loaded[index] <= true_reads[index]);
+                assert!(loaded[index] as u64 * 2 >= true_reads[index] as u64);
+            }
+
+            // Interleave the blocks' reads in uneven batches, persisting whenever asked to
+            let planned: Vec<u32> = (0..3).map(|_| session_reads(&mut seed)).collect();
+            let mut remaining = planned.clone();
+            while remaining.iter().any(|reads| *reads > 0) {
+                for (index, block) in MONITORED.iter().enumerate() {
+                    let batch = remaining[index].min(1 + seed % 97);
+                    remaining[index] -= batch;
+                    true_reads[index] += batch;
+                    monitor.record_reads(*block, batch);
+                    persist(&mut monitor, &flash);
+                }
+                seed = seed.wrapping_mul(31).wrapping_add(7);
+            }
+
+            // In RAM the counts are exact
+            for (index, block) in MONITORED.iter().enumerate() {
+                assert_eq!(monitor.reads(*block), Some(loaded[index] + planned[index]));
+            }
+
+            let budget: u32 = planned.iter().map(|reads| budget(*reads)).sum();
+            assert!(monitor.pages_written() <= budget);
+
+            drop(flash);
+            sim.power_cycle();
+        }
+
+        // The counter block was only erased as it wrapped around
+        assert!(sim.commands().iter().all(|command| command.opcode != 0xD8
+            || command.page_address() == Some(Geometry::W25N01GV.block_first_page(COUNTER_BLOCK))));
+    }
+
+    #[test]
+    fn a_torn_counter_page_falls_back_to_the_one_before() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        let mut monitor = mount(&flash);
+
+        monitor.record_reads(20, 100);
+        persist(&mut monitor, &flash);
+
+        monitor.record_reads(20, 100);
+        persist(&mut monitor, &flash);
+        drop(flash);
+
+        // Left with a count the CRC no longer covers, as a program cut short could
+        let newest = Geometry::W25N01GV.block_first_page(COUNTER_BLOCK) + 1;
+        let mut page = sim.page(newest);
+        page[COUNTER_HEADER_BYTES + 2] ^= 0x01;
+        sim.set_page(newest, &page);
+
+        sim.power_cycle();
+        let flash = sim.driver();
+        let mut monitor = mount(&flash);
+        assert_eq!(monitor.reads(20), Some(100));
+
+        // Later pages go after the torn one
+        monitor.record_reads(21, 1);
+        persist(&mut monitor, &flash);
+        assert_eq!(mount(&flash).reads(21), Some(1));
+    }
+
+    fn fill_block(sim: &SimFlash, block: u16) -> Vec<Vec<u8>> {
+        let first_page = Geometry::W25N01GV.block_first_page(block);
+        (0..PAGES_PER_BLOCK as u16)
+            .map(|offset| {
+                // Every third page left erased
+                if offset % 3 != 2 {
+                    let data: Vec<u8> = (0..PAGE_SIZE_BYTES)
+                        .map(|index| (index as u8) ^ (offset as u8))
+                        .collect();
+                    sim.set_page(first_page + offset, &data);
+                }
+                sim.page(first_page + offset)[..PAGE_SIZE_BYTES].to_vec()
+            })
+            .collect()
+    }
+
+    fn block_contents(sim: &SimFlash, block: u16) -> Vec<Vec<u8>> {
+        let first_page = Geometry::W25N01GV.block_first_page(block);
+        (0..PAGES_PER_BLOCK as u16)
+            .map(|offset| sim.page(first_page + offset)[..PAGE_SIZE_BYTES].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn a_scrub_rewrites_the_block_and_restarts_its_count() {
+        let sim = SimFlash::new();
+        let contents = fill_block(&sim, 21);
+        let flash = sim.driver();
+        let mut monitor = mount(&flash);
+
+        monitor.record_reads(21, 5_000);
+        monitor.record_reads(22, 10);
+        assert_eq!(monitor.due(1_000).collect::<Vec<_>>(), [21]);
+
+        sim.clear_log();
+        monitor
+            .scrub(
+                &flash,
+                21,
+                SPARE_BLOCK,
+                WriteMethod::SingleLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+
+        assert_eq!(block_contents(&sim, 21), contents);
+        // Only the programmed pages were moved, there and back
+        let programmed = contents
+            .iter()
+            .filter(|page| page.iter().any(|b| *b != 0xFF));
+        assert_eq!(sim.count(0x10), 2 * programmed.count() + 2);
+        assert_eq!(monitor.due(1_000).count(), 0);
+        assert_eq!(monitor.interrupted_scrub(), None);
+        drop(flash);
+
+        sim.power_cycle();
+        let flash = sim.driver();
+        let monitor = mount(&flash);
+        assert_eq!(monitor.reads(21), Some(0));
+        assert_eq!(monitor.reads(22), Some(10));
+        assert_eq!(monitor.interrupted_scrub(), None);
+    }
+
+    #[test]
+    fn a_scrub_cut_short_anywhere_is_finished_after_a_reboot() {
+        let scrub_ops = {
+            let sim = SimFlash::new();
+            fill_block(&sim, 21);
+            let flash = sim.driver();
+            let mut monitor = mount(&flash);
+            monitor.record_reads(21, 5_000);
+            persist(&mut monitor, &flash);
+            let before = sim.destructive_ops();
+            monitor
+                .scrub(
+                    &flash,
+                    21,
+                    SPARE_BLOCK,
+                    WriteMethod::SingleLoad,
+                    &mut NoDelay,
+                )
+                .unwrap();
+            sim.destructive_ops() - before
+        };
+
+        for torn in [false, true] {
+            for cut in 0..scrub_ops {
+                let sim = SimFlash::new();
+                let contents = fill_block(&sim, 21);
+                let flash = sim.driver();
+                let mut monitor = mount(&flash);
+                monitor.record_reads(21, 5_000);
+                persist(&mut monitor, &flash);
+
+                sim.cut_power_after(cut, torn);
+                assert!(monitor
+                    .scrub(
+                        &flash,
+                        21,
+                        SPARE_BLOCK,
+                        WriteMethod::SingleLoad,
+                        &mut NoDelay
+                    )
+                    .is_err());
+                drop(flash);
+
+                sim.power_cycle();
+                let flash = sim.driver();
+                let mut monitor = mount(&flash);
+                let interrupted = monitor.interrupted_scrub();
+                assert!(interrupted.is_none() || interrupted == Some((21, SPARE_BLOCK)));
+
+                monitor
+                    .finish_interrupted_scrub(&flash, WriteMethod::SingleLoad, &mut NoDelay)
+                    .unwrap();
+
+                assert!(
+                    block_contents(&sim, 21) == contents,
+                    "cut {} torn {}",
+                    cut,
+                    torn
+                );
+                // A finished scrub restarts the count, one cut short before the copy in the spare
+                // was recorded leaves it
+                let reads = mount(&flash).reads(21);
+                if interrupted.is_some() {
+                    assert_eq!(reads, Some(0));
+                } else {
+                    assert!(reads == Some(0) || reads == Some(5_000));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mount_refuses_layouts_it_cant_keep() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        let method = ReadMethod::FastRead;
+
+        let too_many = ReadDisturbMonitor::<2>::mount(&flash, COUNTER_BLOCK, &MONITORED, method);
+        assert_eq!(too_many.err(), Some(StorageError::InvalidLayout));
+
+        let counts_itself = ReadDisturbMonitor::<4>::mount(&flash, 20, &MONITORED, method);
+        assert_eq!(counts_itself.err(), Some(StorageError::InvalidLayout));
+
+        let past_the_end = ReadDisturbMonitor::<4>::mount(&flash, COUNTER_BLOCK, &[1024], method);
+        assert_eq!(past_the_end.err(), Some(FlashError::OutOfBounds.into()));
+    }
+}
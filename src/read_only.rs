@@ -0,0 +1,342 @@
+//! Read only views of the driver, for components that must never be able to erase, program, or
+//! reconfigure the device. Only reads of memory and registers are reachable through them: there
+//! are no mode transitions, register writes, resets, or raw commands, and nothing that changes a
+//! register behind the scenes (like `classify_page`, which briefly disables ECC).
+//!
+//! There are two flavors:
+//! - `ReadOnlyW25N01GV` owns the driver. It's created with `into_read_only`, which also hands back
+//!   a `RestoreKey`, and only the holder of that key can turn it back into the full driver.
+//! - `ReadOnlyRef` borrows the driver immutably with `read_only`, so the owner gets it back as
+//!   soon as the borrow ends.
+
+use core::marker::PhantomData;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    geometry::MainAddress,
+    read::{BufferMode, DumpStats, PageWithSpare},
+    scratch::Scratch,
+    stats::Stats,
+    status::{ConfigurationRegister, ECCStatus, ProtectionRegister, StatusRegister},
+    Column, DeviceInfo, DeviceVariant, FlashError, QspiBus, ReadMethod, ReadMode, VerifyOpts,
+    VerifyOutcome, MAX_BBM_LUT_ENTIRES, PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, SPARE_BYTES,
+    W25N01GV,
+};
+
+/// Turns a `ReadOnlyW25N01GV` back into the full driver. It can't be created or copied outside
+/// of this crate, so only whoever called `into_read_only` has one.
+pub struct RestoreKey {
+    _private: PhantomData<()>,
+}
+
+/// Owns the driver while only allowing reads, see the module docs
+pub struct ReadOnlyW25N01GV<BUS> {
+    flash: W25N01GV<BUS, ReadMode>,
+}
+
+/// Borrows the driver while only allowing reads, see the module docs
+pub struct ReadOnlyRef<'a, BUS, MODE> {
+    flash: &'a W25N01GV<BUS, MODE>,
+}
+
+impl<BUS> W25N01GV<BUS, ReadMode> {
+    /// Wraps the driver so only reads can be done through it. Keep the returned key to get the
+    /// driver back with `ReadOnlyW25N01GV::restore`.
+    pub fn into_read_only(self) -> (ReadOnlyW25N01GV<BUS>, RestoreKey) {
+        (
+            ReadOnlyW25N01GV { flash: self },
+            RestoreKey {
+                _private: PhantomData,
+            },
+        )
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Borrows the driver as a read only view
+    pub fn read_only(&self) -> ReadOnlyRef<'_, BUS, MODE> {
+        ReadOnlyRef { flash: self }
+    }
+}
+
+impl<BUS> ReadOnlyW25N01GV<BUS> {
+    pub fn restore(self, _key: RestoreKey) -> W25N01GV<BUS, ReadMode> {
+        self.flash
+    }
+
+    /// The read APIs, borrowed from the wrapper
+    pub fn reader(&self) -> ReadOnlyRef<'_, BUS, ReadMode> {
+        ReadOnlyRef { flash: &self.flash }
+    }
+}
+
+impl<'a, BUS, MODE> ReadOnlyRef<'a, BUS, MODE> {
+    pub fn stats(&self) -> Stats {
+        self.flash.stats()
+    }
+}
+
+impl<'a, BUS: QspiBus, MODE> ReadOnlyRef<'a, BUS, MODE> {
+    pub fn device_info(&self) -> Result<DeviceInfo, FlashError> {
+        let jedec_id = self.flash.read_jedec_id()?;
+
+        Ok(DeviceInfo {
+            jedec_id,
+            variant: DeviceVariant::from_jedec_id(jedec_id),
+        })
+    }
+
+    pub fn wait_while_busy(&self) -> Result<(), FlashError> {
+        self.flash.wait_while_busy()
+    }
+
+    pub fn wait_while_busy_with_delay<D: DelayUs<u32>>(
+        &self,
+        delay: &mut D,
+    ) -> Result<(), FlashError> {
+        self.flash.wait_while_busy_with_delay(delay)
+    }
+
+    pub fn wait_while_busy_timeout<D: DelayUs<u32>>(
+        &self,
+        delay: &mut D,
+        max_iters: u32,
+    ) -> Result<(), FlashError> {
+        self.flash.wait_while_busy_timeout(delay, max_iters)
+    }
+
+    pub fn read_status_register(&self) -> Result<StatusRegister, FlashError> {
+        self.flash.read_status_register()
+    }
+
+    pub fn read_protection_register(&self) -> Result<ProtectionRegister, FlashError> {
+        self.flash.read_protection_register()
+    }
+
+    pub fn read_configuration_register(&self) -> Result<ConfigurationRegister, FlashError> {
+        self.flash.read_configuration_register()
+    }
+
+    pub fn ecc_enabled(&self) -> Result<bool, FlashError> {
+        self.flash.ecc_enabled()
+    }
+
+    pub fn buffer_mode(&self) -> Result<BufferMode, FlashError> {
+        self.flash.buffer_mode()
+    }
+
+    pub fn read_memory_to_data_buffer(&self, page_address: u16) -> Result<(), FlashError> {
+        self.flash.read_memory_to_data_buffer(page_address)
+    }
+
+    pub fn read_data_buffer(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        self.flash.read_data_buffer(buffer, method)
+    }
+
+    pub fn read_page(
+        &self,
+        page_address: u16,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        method: ReadMethod,
+    ) -> Result<ECCStatus, FlashError> {
+        self.flash.read_page(page_address, buffer, method)
+    }
+
+    pub fn read_page_data(
+        &self,
+        buffer: &mut [u8; PAGE_SIZE_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        self.flash.read_page_data(buffer, method)
+    }
+
+    pub fn read_page_with_spare(&self, method: ReadMethod) -> Result<PageWithSpare, FlashError> {
+        self.flash.read_page_with_spare(method)
+    }
+
+    pub fn read_spare_area(
+        &self,
+        buffer: &mut [u8; SPARE_BYTES],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        self.flash.read_spare_area(buffer, method)
+    }
+
+    pub fn read_columns(
+        &self,
+        column: Column,
+        buffer: &mut [u8],
+        method: ReadMethod,
+    ) -> Result<(), FlashError> {
+        self.flash.read_columns(column, buffer, method)
+    }
+
+    pub fn read_bbm_lookup_table(
+        &self,
+    ) -> Result<[Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES], FlashError> {
+        self.flash.read_bbm_lookup_table()
+    }
+
+    pub fn read_last_ecc_failure_page_address(&self) -> Result<u16, FlashError> {
+        self.flash.read_last_ecc_failure_page_address()
+    }
+
+    pub fn is_bad_block(&self, block: u16, method: ReadMethod) -> Result<bool, FlashError> {
+        self.flash.is_bad_block(block, method)
+    }
+
+    pub fn dump<D, F>(
+        &self,
+        start_page: u16,
+        page_count: u32,
+        method: ReadMethod,
+        delay: &mut D,
+        f: F,
+    ) -> Result<DumpStats, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(u16, &[u8; PAGE_SIZE_BYTES]),
+    {
+        self.flash.dump(start_page, page_count, method, delay, f)
+    }
+
+    pub fn dump_in<D, F>(
+        &self,
+        start_page: u16,
+        page_count: u32,
+        method: ReadMethod,
+        delay: &mut D,
+        scratch: &mut Scratch,
+        f: F,
+    ) -> Result<DumpStats, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(u16, &[u8; PAGE_SIZE_BYTES]),
+    {
+        self.flash
+            .dump_in(start_page, page_count, method, delay, scratch, f)
+    }
+
+    pub fn verify_against<D: DelayUs<u32>>(
+        &self,
+        start_page: u16,
+        golden: &[u8],
+        method: ReadMethod,
+        delay: &mut D,
+    ) -> Result<Option<u64>, FlashError> {
+        self.flash.verify_against(start_page, golden, method, delay)
+    }
+
+    pub fn verify_against_in<D: DelayUs<u32>>(
+        &self,
+        start_page: u16,
+        golden: &[u8],
+        method: ReadMethod,
+        delay: &mut D,
+        scratch: &mut Scratch,
+    ) -> Result<Option<u64>, FlashError> {
+        self.flash
+            .verify_against_in(start_page, golden, method, delay, scratch)
+    }
+
+    pub fn verify_stream<F: FnMut(&mut [u8]) -> usize>(
+        &self,
+        start: MainAddress,
+        expected: F,
+        opts: VerifyOpts,
+    ) -> Result<VerifyOutcome, FlashError> {
+        self.flash.verify_stream(start, expected, opts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use std::vec::Vec;
+
+    /// Opcodes that erase, program, load, reset, or write a register
+    const WRITING_OPCODES: [u8; 12] = [
+        0x06, 0x04, 0x10, 0xD8, 0xA1, 0x1F, 0x01, 0xFF, 0x02, 0x32, 0x84, 0x34,
+    ];
+
+    fn sent_writes(sim: &SimFlash) -> Vec<u8> {
+        sim.commands()
+            .iter()
+            .map(|command| command.opcode)
+            .filter(|opcode| WRITING_OPCODES.contains(opcode))
+            .collect()
+    }
+
+    #[test]
+    fn an_owned_view_only_reads_and_the_key_gives_the_driver_back() {
+        let sim = SimFlash::new();
+        sim.set_page(65, &[0x12; PAGE_SIZE_BYTES]);
+        sim.set_lut(&[(5, 1020)]);
+        let (view, key) = sim.driver().into_read_only();
+        sim.clear_log();
+
+        let reader = view.reader();
+        let mut page = [0_u8; PAGE_SIZE_WITH_ECC_BYTES];
+        reader
+            .read_page(65, &mut page, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(page[..PAGE_SIZE_BYTES], [0x12; PAGE_SIZE_BYTES]);
+
+        let mut dumped = Vec::new();
+        reader
+            .dump(
+                64,
+                2,
+                ReadMethod::FastRead,
+                &mut NoDelay,
+                |page_address, _| dumped.push(page_address),
+            )
+            .unwrap();
+        assert_eq!(dumped, [64, 65]);
+
+        assert_eq!(
+            reader.verify_against(65, &[0x12; 16], ReadMethod::FastRead, &mut NoDelay),
+            Ok(None)
+        );
+        assert_eq!(reader.read_bbm_lookup_table().unwrap()[0], Some((5, 1020)));
+        assert_eq!(reader.is_bad_block(1, ReadMethod::FastRead), Ok(false));
+        reader.read_status_register().unwrap();
+        reader.read_configuration_register().unwrap();
+        assert!(sent_writes(&sim).is_empty());
+
+        let flash = view.restore(key);
+        flash.erase_block(1, &mut NoDelay).unwrap();
+        assert_eq!(sim.page(65)[0], 0xFF);
+    }
+
+    #[test]
+    fn a_borrowed_view_ends_with_the_borrow() {
+        let sim = SimFlash::new();
+        sim.set_page(65, &[0x34; 4]);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        {
+            let reader = flash.read_only();
+            let mut spare = [0_u8; SPARE_BYTES];
+            reader.read_memory_to_data_buffer(65).unwrap();
+            reader.wait_while_busy().unwrap();
+            reader
+                .read_spare_area(&mut spare, ReadMethod::FastRead)
+                .unwrap();
+            assert_eq!(spare, [0xFF; SPARE_BYTES]);
+            assert_eq!(reader.stats(), flash.stats());
+        }
+        assert!(sent_writes(&sim).is_empty());
+
+        // The owner carries on with the driver
+        flash.erase_block(1, &mut NoDelay).unwrap();
+        assert_eq!(sim.page(65)[0], 0xFF);
+    }
+}
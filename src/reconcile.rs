@@ -0,0 +1,364 @@
+//! Bringing a `BlockAllocator`'s idea of which blocks are bad back in line with the device, for
+//! mounting after the chip's bad block handling was changed behind the allocator's back, e.g. by
+//! RMA reprogramming or a firmware downgrade.
+//!
+//! Three sources are compared: the links in the device's bad block look up table (LUT), the
+//! factory bad block markers, and the allocator's reserved blocks. Where they disagree, this is
+//! the order of precedence:
+//!
+//! 1. A LUT link wins for both of its blocks. The replacement block is reserved, since using it
+//!    directly would alias the block it stands in for. The linked block's marker isn't checked,
+//!    because reads of it are redirected to the replacement and so can't see its marker.
+//! 2. A bad block marker on a block outside the LUT reserves the block.
+//! 3. Anything the allocator reserves beyond that is kept, since it can't tell a deliberately
+//!    reserved block from a bad one.
+//!
+//! With `ReconcilePolicy::remap_marker_bad`, marker bad blocks are also linked to a free block in
+//! the LUT while it has room, which hands the bad block's address back to the allocator.
+
+use core::ops::ControlFlow;
+
+use crate::{
+    scan::{Findings, ScanDepth, ScanSkip, ScanVisitor},
+    BlockAllocator, FlashError, FlashEventKind, Geometry, QspiBus, ReadMethod, WriteMode,
+    BLOCK_COUNT, MAX_BBM_LUT_ENTIRES, W25N01GV,
+};
+
+pub const MAX_RECONCILE_CHANGES: usize = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ReconcilePolicy {
+    /// Links marker bad blocks missing from the LUT to free replacement blocks
+    pub remap_marker_bad: bool,
+}
+
+/// Changes sort by kind, then by block
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReconcileChange {
+    /// A LUT replacement block the allocator didn't reserve was reserved
+    ReservedReplacement {
+        logical_block: u16,
+        physical_block: u16,
+    },
+    /// A block with its bad block marker set that the allocator didn't reserve was reserved
+    ReservedMarkerBad { block: u16 },
+    /// A marker bad block was linked to a replacement in the LUT and made allocatable again
+    Remapped {
+        logical_block: u16,
+        physical_block: u16,
+    },
+    /// A marker bad block couldn't be remapped because the LUT or the allocator ran out of room
+    RemapSkipped { block: u16 },
+}
+
+/// Everything `reconcile_bad_blocks` found and did
+#[derive(Debug, Clone, Copy)]
+pub struct ReconcileReport {
+    /// How many blocks the sources disagreed about
+    pub discrepancies: u16,
+    pub changes: Findings<ReconcileChange, MAX_RECONCILE_CHANGES>,
+}
+
+impl BlockAllocator {
+    /// Compares the allocator against the LUT and the bad block markers and updates it, and the
+    /// LUT if `policy` says so, following the precedence in the module docs. Reads the marker of
+    /// every block in one `scan` at `ScanDepth::Blocks`. Save the allocator afterwards to keep the
+    /// result.
+    pub fn reconcile_bad_blocks<BUS: QspiBus>(
+        &mut self,
+        flash: &W25N01GV<BUS, WriteMode>,
+        policy: ReconcilePolicy,
+        method: ReadMethod,
+    ) -> Result<ReconcileReport, FlashError> {
+        let _guard = flash.begin_operation()?;
+
+        let mut report = ReconcileReport {
+            discrepancies: 0,
+            changes: Findings::new(),
+        };
+
+        let links = flash.read_bbm_lookup_table_unguarded()?;
+
+        for (logical_block, physical_block) in links.iter().flatten() {
+            if !self.is_reserved(*physical_block) {
+                self.mark_bad(*physical_block);
+                report.discrepancies += 1;
+                report.changes.push(ReconcileChange::ReservedReplacement {
+                    logical_block: *logical_block,
+                    physical_block: *physical_block,
+                });
+            }
+        }
+
+        let mut reconcile = Reconcile {
+            allocator: self,
+            flash,
+            policy,
+            free_links: links.iter().filter(|link| link.is_none()).count(),
+            links,
+            report,
+            error: None,
+        };
+        flash.scan_pages_unguarded(
+            Geometry::W25N01GV.block_pages(0).start
+                ..Geometry::W25N01GV.block_pages(BLOCK_COUNT as u16).start,
+            ScanSkip::Nothing,
+            ScanDepth::Blocks(method),
+            &mut reconcile,
+        )?;
+
+        match reconcile.error {
+            Some(err) => Err(err),
+            None => Ok(reconcile.report),
+        }
+    }
+}
+
+/// The marker half of `reconcile_bad_blocks` as a visitor, handling each block as it's announced
+struct Reconcile<'a, BUS> {
+    allocator: &'a mut BlockAllocator,
+    flash: &'a W25N01GV<BUS, WriteMode>,
+    policy: ReconcilePolicy,
+    links: [Option<(u16, u16)>; MAX_BBM_LUT_ENTIRES],
+    free_links: usize,
+    report: ReconcileReport,
+    error: Option<FlashError>,
+}
+
+impl<BUS: QspiBus> Reconcile<'_, BUS> {
+    fn in_lut(&self, block: u16) -> bool {
+        self.links
+            .iter()
+            .flatten()
+            .any(|(logical, physical)| *logical == block || *physical == block)
+    }
+
+    fn marked_bad(&mut self, block: u16) -> Result<(), FlashError> {
+        if !self.allocator.is_reserved(block) {
+            self.allocator.mark_bad(block);
+            self.flash
+                .log_event(FlashEventKind::BlockMarkedBad { block });
+            self.report.discrepancies += 1;
+            self.report
+                .changes
+                .push(ReconcileChange::ReservedMarkerBad { block });
+        }
+
+        if !self.policy.remap_marker_bad {
+            return Ok(());
+        }
+
+        let replacement = (0..BLOCK_COUNT as u16).find(|candidate| {
+            self.allocator.is_free(*candidate)
+                && !self.in_lut(*candidate)
+                && !(*candidate == 0 && self.flash.block0_reserved())
+        });
+
+        match replacement {
+            Some(physical_block) if self.free_links > 0 => {
+                if self
+                    .flash
+                    .register_bad_block_links_unguarded(&[(block, physical_block)])?
+                    == 0
+                {
+                    self.free_links = 0;
+                    self.report
+                        .changes
+                        .push(ReconcileChange::RemapSkipped { block });
+                    return Ok(());
+                }
+
+                self.free_links -= 1;
+                self.allocator.mark_bad(physical_block);
+                self.allocator.unreserve(block);
+                self.report.changes.push(ReconcileChange::Remapped {
+                    logical_block: block,
+                    physical_block,
+                });
+            }
+            _ => self
+                .report
+                .changes
+                .push(ReconcileChange::RemapSkipped { block }),
+        }
+
+        Ok(())
+    }
+}
+
+impl<BUS: QspiBus> ScanVisitor for Reconcile<'_, BUS> {
+    fn visit_block(&mut self, block: u16, marked_bad: bool) -> ControlFlow<()> {
+        // A linked block's reads go to its replacement, so its own marker can't be seen
+        if !marked_bad || self.in_lut(block) {
+            return ControlFlow::Continue(());
+        }
+
+        match self.marked_bad(block) {
+            Ok(()) => ControlFlow::Continue(()),
+            Err(err) => {
+                self.error = Some(err);
+                ControlFlow::Break(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+    use std::vec::Vec;
+
+    const SLOTS: [u16; 2] = [0, 1];
+
+    fn reconcile(
+        sim: &SimFlash,
+        allocator: &mut BlockAllocator,
+        policy: ReconcilePolicy,
+    ) -> ReconcileReport {
+        let flash = sim.driver().into_write_mode().unwrap();
+        allocator
+            .reconcile_bad_blocks(&flash, policy, ReadMethod::FastRead)
+            .unwrap()
+    }
+
+    fn changes(report: &ReconcileReport) -> Vec<ReconcileChange> {
+        report.changes.iter().copied().collect()
+    }
+
+    #[test]
+    fn agreeing_sources_change_nothing() {
+        let sim = SimFlash::new();
+        sim.mark_bad(30);
+        sim.set_lut(&[(10, 900)]);
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_bad(30);
+        allocator.mark_bad(900);
+
+        let report = reconcile(&sim, &mut allocator, ReconcilePolicy::default());
+
+        assert_eq!(report.discrepancies, 0);
+        assert!(report.changes.is_empty());
+    }
+
+    #[test]
+    fn a_lut_replacement_block_is_reserved() {
+        let sim = SimFlash::new();
+        sim.set_lut(&[(10, 900)]);
+        let mut allocator = BlockAllocator::new(SLOTS);
+
+        let report = reconcile(&sim, &mut allocator, ReconcilePolicy::default());
+
+        assert_eq!(report.discrepancies, 1);
+        assert_eq!(
+            changes(&report),
+            [ReconcileChange::ReservedReplacement {
+                logical_block: 10,
+                physical_block: 900,
+            }]
+        );
+        assert!(allocator.is_reserved(900));
+        // The linked block reads through to its replacement, so it's still usable
+        assert!(allocator.is_free(10));
+    }
+
+    #[test]
+    fn a_marker_bad_block_outside_the_lut_is_reserved() {
+        let sim = SimFlash::new();
+        sim.mark_bad(30);
+        let mut allocator = BlockAllocator::new(SLOTS);
+
+        let report = reconcile(&sim, &mut allocator, ReconcilePolicy::default());
+
+        assert_eq!(report.discrepancies, 1);
+        assert_eq!(
+            changes(&report),
+            [ReconcileChange::ReservedMarkerBad { block: 30 }]
+        );
+        assert!(allocator.is_reserved(30));
+    }
+
+    #[test]
+    fn a_marker_on_a_lut_block_defers_to_the_lut() {
+        let sim = SimFlash::new();
+        sim.mark_bad(900);
+        sim.set_lut(&[(10, 900)]);
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_bad(900);
+
+        let report = reconcile(&sim, &mut allocator, ReconcilePolicy::default());
+
+        assert_eq!(report.discrepancies, 0);
+        assert!(report.changes.is_empty());
+        assert!(allocator.is_free(10));
+    }
+
+    #[test]
+    fn blocks_only_the_allocator_reserves_stay_reserved() {
+        let sim = SimFlash::new();
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_reserved(40..44);
+
+        let report = reconcile(&sim, &mut allocator, ReconcilePolicy::default());
+
+        assert_eq!(report.discrepancies, 0);
+        assert!((40..44).all(|block| allocator.is_reserved(block)));
+    }
+
+    #[test]
+    fn remapping_links_a_marker_bad_block_to_a_free_block() {
+        let sim = SimFlash::new();
+        sim.mark_bad(30);
+        let mut allocator = BlockAllocator::new(SLOTS);
+        let policy = ReconcilePolicy {
+            remap_marker_bad: true,
+        };
+
+        let report = reconcile(&sim, &mut allocator, policy);
+
+        assert_eq!(report.discrepancies, 1);
+        assert_eq!(
+            changes(&report),
+            [
+                ReconcileChange::ReservedMarkerBad { block: 30 },
+                ReconcileChange::Remapped {
+                    logical_block: 30,
+                    physical_block: 2,
+                },
+            ]
+        );
+        assert!(allocator.is_free(30));
+        assert!(allocator.is_reserved(2));
+
+        let links = sim.driver().read_bbm_lookup_table().unwrap();
+        assert_eq!(links.iter().flatten().collect::<Vec<_>>(), [&(30, 2)]);
+    }
+
+    #[test]
+    fn remapping_is_skipped_once_the_lut_is_full() {
+        let sim = SimFlash::new();
+        let links: Vec<(u16, u16)> = (0..MAX_BBM_LUT_ENTIRES as u16)
+            .map(|link| (100 + link, 900 + link))
+            .collect();
+        sim.set_lut(&links);
+        sim.mark_bad(30);
+        let mut allocator = BlockAllocator::new(SLOTS);
+        allocator.mark_reserved(900..900 + MAX_BBM_LUT_ENTIRES as u16);
+        let policy = ReconcilePolicy {
+            remap_marker_bad: true,
+        };
+
+        let report = reconcile(&sim, &mut allocator, policy);
+
+        assert_eq!(
+            changes(&report),
+            [
+                ReconcileChange::ReservedMarkerBad { block: 30 },
+                ReconcileChange::RemapSkipped { block: 30 },
+            ]
+        );
+        assert!(allocator.is_reserved(30));
+    }
+}
@@ -0,0 +1,130 @@
+//! Read retries for pages that come back with uncorrectable ECC errors. Marginal cells sometimes
+//! read correctly on a second try, or after resetting the device, so a page isn't given up on
+//! until every attempt in a `RecoveryPolicy` has failed.
+
+use crate::{
+    status::ECCStatus, FlashError, FlashEventKind, QspiBus, ReadMethod, PAGE_SIZE_WITH_ECC_BYTES,
+    W25N01GV,
+};
+
+pub const MAX_RECOVERY_ATTEMPTS: usize = 4;
+
+/// The ECC status each attempt ended with, in order. Attempts that weren't made are None.
+pub type RecoveryStatuses = [Option<ECCStatus>; MAX_RECOVERY_ATTEMPTS];
+
+#[derive(Debug, Clone, Copy)]
+pub enum RecoveryAttempt {
+    /// Reads the page again with the given method
+    Read(ReadMethod),
+    /// Resets the device, restores its protection and configuration registers, then reads the
+    /// page with the given method
+    ResetThenRead(ReadMethod),
+}
+
+/// The sequence of attempts made by `read_page_with_recovery`, at most `MAX_RECOVERY_ATTEMPTS`
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveryPolicy {
+    attempts: [Option<RecoveryAttempt>; MAX_RECOVERY_ATTEMPTS],
+}
+
+impl RecoveryPolicy {
+    /// Creates a policy from a list of attempts. Attempts past `MAX_RECOVERY_ATTEMPTS` are ignored.
+    pub fn new(attempts: &[RecoveryAttempt]) -> RecoveryPolicy {
+        let mut policy = RecoveryPolicy {
+            attempts: [None; MAX_RECOVERY_ATTEMPTS],
+        };
+
+        for (slot, attempt) in policy.attempts.iter_mut().zip(attempts.iter()) {
+            *slot = Some(*attempt);
+        }
+
+        policy
+    }
+}
+
+impl Default for RecoveryPolicy {
+    /// A quad read, then a single line read, then a single line read after resetting the device
+    fn default() -> RecoveryPolicy {
+        RecoveryPolicy::new(&[
+            RecoveryAttempt::Read(ReadMethod::FastReadQuadIO),
+            RecoveryAttempt::Read(ReadMethod::FastRead),
+            RecoveryAttempt::ResetThenRead(ReadMethod::FastRead),
+        ])
+    }
+}
+
+/// The outcome of a successful `read_page_with_recovery`
+#[derive(Debug, Clone, Copy)]
+pub struct RecoveredRead {
+    /// The index of the attempt that read the page
+    pub attempt: usize,
+    pub statuses: RecoveryStatuses,
+}
+
+fn is_uncorrectable(ecc_status: ECCStatus) -> bool {
+    ecc_status == ECCStatus::SinglePageError || ecc_status == ECCStatus::MultiPageError
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads a page into `buffer`, working through the policy's attempts until one reads without
+    /// uncorrectable ECC errors. If every attempt fails, `FlashError::RecoveryFailed`
+    /// carries each attempt's ECC status and `buffer` holds the data from the last attempt.
+    ///
+    /// Resetting the device puts its registers back to their power on defaults, so they're saved
+    /// beforehand and written back after every reset.
+    pub fn read_page_with_recovery(
+        &mut self,
+        page_address: u16,
+        buffer: &mut [u8; PAGE_SIZE_WITH_ECC_BYTES],
+        policy: RecoveryPolicy,
+    ) -> Result<RecoveredRead, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        self.check_latency_budget(self.recovery_worst_case_us(&policy.attempts))?;
+
+        let mut statuses: RecoveryStatuses = [None; MAX_RECOVERY_ATTEMPTS];
+
+        for (index, attempt) in policy.attempts.iter().enumerate() {
+            let method = match attempt {
+                Some(RecoveryAttempt::Read(method)) => *method,
+                Some(RecoveryAttempt::ResetThenRead(method)) => {
+                    self.reset_keeping_registers()?;
+                    *method
+                }
+                None => break,
+            };
+
+            self.read_memory_to_data_buffer_unguarded(page_address)?;
+            self.wait_while_busy_unguarded()?;
+
+            let ecc_status = self.read_status_register_unguarded()?.ecc_status;
+            self.read_data_buffer_unguarded(buffer, method)?;
+            statuses[index] = Some(ecc_status);
+
+            if !is_uncorrectable(ecc_status) {
+                return Ok(RecoveredRead {
+                    attempt: index,
+                    statuses,
+                });
+            }
+        }
+
+        self.log_event(FlashEventKind::UncorrectableEcc { page_address });
+
+        Err(FlashError::RecoveryFailed {
+            page_address,
+            statuses,
+        })
+    }
+
+    fn reset_keeping_registers(&self) -> Result<(), FlashError> {
+        let protection_register = self.read_protection_register_unguarded()?;
+        let configuration_register = self.read_configuration_register_unguarded()?;
+
+        self.reset_device_unguarded()?;
+        self.wait_while_busy_unguarded()?;
+
+        self.write_protection_register_unguarded(protection_register)?;
+        self.write_configuration_register_unguarded(configuration_register)
+    }
+}
@@ -0,0 +1,214 @@
+//! Catching the driver being used again while it's already in the middle of something, behind the
+//! `reentrancy-guard` feature.
+//!
+//! Code that reaches the driver from an interrupt through an unsoundly shared static can start an
+//! operation while another is still running, interleaving the two and corrupting whatever was
+//! being read or written, e.g. a page read landing between loading the data buffer and executing
+//! the program. The guard is a flag set for the length of every public method that touches the
+//! bus and checked on entry to one, so the second user fails immediately instead. It costs a load
+//! and two stores per call.
+//!
+//! The flag covers the whole method, not just the bus transactions, so an interrupt between two
+//! commands of a longer sequence is caught too. Callbacks passed to a method run while it holds
+//! the flag, so they can't use the driver either. Without the feature the flag doesn't exist and
+//! nothing is checked.
+
+#[cfg(not(feature = "reentrancy-guard"))]
+use core::marker::PhantomData;
+
+#[cfg(feature = "reentrancy-guard")]
+use core::cell::Cell;
+
+use crate::{FlashError, W25N01GV};
+
+/// What happens when the driver is re-entered
+#[cfg(feature = "reentrancy-guard")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReentrancyAction {
+    /// The re-entering call returns `FlashError::ReentrantCall`, the default
+    Error,
+    /// The re-entering call panics
+    Panic,
+}
+
+/// Held by a public method for as long as it runs, clearing the flag when dropped
+pub(crate) struct OperationGuard<'a> {
+    #[cfg(feature = "reentrancy-guard")]
+    in_operation: &'a Cell<bool>,
+    #[cfg(not(feature = "reentrancy-guard"))]
+    _marker: PhantomData<&'a ()>,
+}
+
+impl Drop for OperationGuard<'_> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        #[cfg(feature = "reentrancy-guard")]
+        self.in_operation.set(false);
+    }
+}
+
+#[cfg(feature = "reentrancy-guard")]
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    pub fn set_reentrancy_action(&mut self, action: ReentrancyAction) {
+        self.reentrancy_action = action;
+    }
+
+    pub fn reentrancy_action(&self) -> ReentrancyAction {
+        self.reentrancy_action
+    }
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Marks the driver as in use until the returned guard is dropped, failing if it already is.
+    /// Every public method that touches the bus takes one first, and calls the unguarded
+    /// versions of other public methods from then on.
+    #[cfg(feature = "reentrancy-guard")]
+    pub(crate) fn begin_operation(&self) -> Result<OperationGuard<'_>, FlashError> {
+        if self.in_operation.replace(true) {
+            match self.reentrancy_action {
+                ReentrancyAction::Error => return Err(FlashError::ReentrantCall),
+                ReentrancyAction::Panic => panic!("W25N01GV driver re-entered during an operation"),
+            }
+        }
+
+        Ok(OperationGuard {
+            in_operation: &self.in_operation,
+        })
+    }
+
+    #[cfg(not(feature = "reentrancy-guard"))]
+    #[inline(always)]
+    pub(crate) fn begin_operation(&self) -> Result<OperationGuard<'_>, FlashError> {
+        Ok(OperationGuard {
+            _marker: PhantomData,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "reentrancy-guard"))]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::vec::Vec;
+
+    use hal::blocking::delay::DelayUs;
+
+    use super::*;
+    use crate::sim::SimFlash;
+    use crate::{Column, ReadMethod, ReadMode, PAGE_SIZE_WITH_ECC_BYTES};
+
+    type Driver = W25N01GV<SimFlash, ReadMode>;
+
+    /// Re-enters the driver the first time the device receives `opcode`, keeping what the nested
+    /// call returned
+    fn reenter_on(sim: &SimFlash, flash: &Rc<Driver>, opcode: u8) -> Rc<RefCell<Vec<bool>>> {
+        let nested_failed = Rc::new(RefCell::new(Vec::new()));
+
+        let weak = Rc::downgrade(flash);
+        let results = nested_failed.clone();
+        sim.set_hook(move |command| {
+            if command.opcode != opcode || !results.borrow().is_empty() {
+                return;
+            }
+
+            if let Some(flash) = weak.upgrade() {
+                let mut buffer = [0; PAGE_SIZE_WITH_ECC_BYTES];
+                let result = flash.read_page(3, &mut buffer, ReadMethod::FastRead);
+                results
+                    .borrow_mut()
+                    .push(result == Err(FlashError::ReentrantCall));
+            }
+        });
+
+        nested_failed
+    }
+
+    #[test]
+    fn reentering_from_inside_a_bus_transaction_fails() {
+        let sim = SimFlash::new();
+        let flash = Rc::new(sim.driver());
+        let nested_failed = reenter_on(&sim, &flash, 0x13);
+
+        let mut buffer = [0; PAGE_SIZE_WITH_ECC_BYTES];
+        flash
+            .read_page(1, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+
+        assert_eq!(*nested_failed.borrow(), [true]);
+    }
+
+    #[test]
+    fn reentering_between_the_commands_of_a_method_fails() {
+        struct ReenteringDelay {
+            flash: Rc<Driver>,
+            nested: Vec<Result<bool, FlashError>>,
+        }
+
+        impl DelayUs<u32> for ReenteringDelay {
+            fn delay_us(&mut self, _us: u32) {
+                self.nested.push(self.flash.is_busy());
+            }
+        }
+
+        let sim = SimFlash::new();
+        let flash = Rc::new(sim.driver());
+        let mut delay = ReenteringDelay {
+            flash: flash.clone(),
+            nested: Vec::new(),
+        };
+
+        // The delay only runs while the device is busy, between the Page Data Read and the
+        // transfer of the page
+        let mut pages = 0;
+        flash
+            .dump(0, 2, ReadMethod::FastRead, &mut delay, |_, _| pages += 1)
+            .unwrap();
+
+        assert_eq!(pages, 2);
+        assert!(!delay.nested.is_empty());
+        assert!(delay
+            .nested
+            .iter()
+            .all(|nested| *nested == Err(FlashError::ReentrantCall)));
+    }
+
+    #[test]
+    fn the_driver_is_usable_again_once_the_method_returns() {
+        let sim = SimFlash::new();
+        let flash = Rc::new(sim.driver());
+        let nested_failed = reenter_on(&sim, &flash, 0x13);
+
+        let mut buffer = [0; PAGE_SIZE_WITH_ECC_BYTES];
+        flash
+            .read_page(1, &mut buffer, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(*nested_failed.borrow(), [true]);
+
+        sim.clear_hook();
+        assert_eq!(flash.is_busy(), Ok(false));
+
+        // Including after an error
+        let result = flash.read_columns(Column::Physical(2100), &mut [0; 64], ReadMethod::FastRead);
+        assert!(result.is_err());
+        assert_eq!(flash.is_busy(), Ok(false));
+    }
+
+    #[test]
+    #[should_panic(expected = "re-entered")]
+    fn the_panic_action_panics() {
+        let sim = SimFlash::new();
+        let mut driver = sim.driver();
+        driver.set_reentrancy_action(ReentrancyAction::Panic);
+
+        let flash = Rc::new(driver);
+        let weak = Rc::downgrade(&flash);
+        sim.set_hook(move |_| {
+            if let Some(flash) = weak.upgrade() {
+                let _ = flash.is_busy();
+            }
+        });
+
+        let _ = flash.is_busy();
+    }
+}
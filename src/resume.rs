@@ -0,0 +1,167 @@
+//! Carrying the driver's cached state across a low power mode, for wakeup paths that can't afford
+//! to read registers back before their first operation.
+//!
+//! Before going to sleep, `save_state` copies what the driver has cached about the device along
+//! with its settings. After waking, `resume_fast` puts them back into a freshly created driver
+//! without touching the bus. The device may have been reset or reconfigured while asleep, so the
+//! restored state is only trusted until `verify_state` is called, or a register read refreshes
+//! the cache naturally.
+
+use crate::{Block0Policy, EccMode, FlashError, OobLayout, QspiBus, W25N01GV};
+
+/// What `save_state` keeps of a driver
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SavedDriverState {
+    ecc_enabled: Option<bool>,
+    verified_addressing: bool,
+    block0_policy: Block0Policy,
+    ecc_mode: EccMode,
+    oob_layout: OobLayout,
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Copies the driver's cached register state and settings, without touching the bus
+    pub fn save_state(&self) -> SavedDriverState {
+        SavedDriverState {
+            ecc_enabled: self.ecc_enabled.get(),
+            verified_addressing: self.verified_addressing,
+            block0_policy: self.block0_policy,
+            ecc_mode: self.ecc_mode,
+            oob_layout: self.oob_layout,
+        }
+    }
+
+    /// Restores state from `save_state` without touching the bus. The restored caches are used as
+    /// is until `verify_state` checks them against the device.
+    pub fn resume_fast(&mut self, saved: SavedDriverState) {
+        self.ecc_enabled.set(saved.ecc_enabled);
+        self.verified_addressing = saved.verified_addressing;
+        self.block0_policy = saved.block0_policy;
+        self.ecc_mode = saved.ecc_mode;
+        self.oob_layout = saved.oob_layout;
+        self.state_unverified.set(true);
+    }
+
+    /// Is true if state restored by `resume_fast` hasn't been checked against the device yet
+    pub fn state_unverified(&self) -> bool {
+        self.state_unverified.get()
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads the registers behind the cached state and compares them against it. On a mismatch
+    /// the caches are cleared, so they're read again on next use, and
+    /// `FlashError::StaleStateDetected` is returned.
+    pub fn verify_state(&mut self) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let cached_ecc_enabled = self.ecc_enabled.get();
+        let configuration_register = self.read_configuration_register_unguarded()?;
+
+        self.state_unverified.set(false);
+
+        // A reset while asleep turns ECC back on behind a pinned off mode
+        if self.ecc_mode == EccMode::Disabled && configuration_register.ecc_e {
+            return Err(FlashError::StaleStateDetected);
+        }
+
+        if let Some(ecc_enabled) = cached_ecc_enabled {
+            if ecc_enabled != configuration_register.ecc_e {
+                self.ecc_enabled.set(None);
+                return Err(FlashError::StaleStateDetected);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+
+    #[test]
+    fn a_resumed_driver_uses_its_saved_state_without_touching_the_bus() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        assert!(flash.ecc_enabled().unwrap());
+        flash.set_verified_addressing(true);
+        flash.set_block0_policy(Block0Policy::Reserved);
+        let saved = flash.save_state();
+        drop(flash);
+
+        let mut flash = sim.driver();
+        sim.clear_log();
+        flash.resume_fast(saved);
+
+        assert!(flash.state_unverified());
+        assert!(flash.verified_addressing());
+        assert_eq!(flash.block0_policy(), Block0Policy::Reserved);
+        assert_eq!(flash.save_state(), saved);
+        // Answered from the restored cache
+        assert_eq!(flash.ecc_enabled(), Ok(true));
+        assert!(sim.commands().is_empty());
+
+        assert_eq!(flash.verify_state(), Ok(()));
+        assert!(!flash.state_unverified());
+    }
+
+    #[test]
+    fn a_register_changed_while_asleep_is_caught_and_the_cache_dropped() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        assert!(flash.ecc_enabled().unwrap());
+        let saved = flash.save_state();
+        drop(flash);
+
+        // Something else turns ECC off while the driver sleeps
+        let other = sim.driver();
+        let mut configuration_register = other.read_configuration_register().unwrap();
+        configuration_register.ecc_e = false;
+        other
+            .write_configuration_register(configuration_register)
+            .unwrap();
+        drop(other);
+
+        let mut flash = sim.driver();
+        flash.resume_fast(saved);
+        assert_eq!(flash.verify_state(), Err(FlashError::StaleStateDetected));
+        assert!(!flash.state_unverified());
+
+        // The next read goes to the device
+        sim.clear_log();
+        assert_eq!(flash.ecc_enabled(), Ok(false));
+        assert!(!sim.commands().is_empty());
+        assert_eq!(flash.verify_state(), Ok(()));
+    }
+
+    #[test]
+    fn a_reset_while_asleep_is_caught_behind_a_pinned_off_ecc_mode() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_ecc_mode(EccMode::Disabled).unwrap();
+        let saved = flash.save_state();
+        drop(flash);
+
+        // Power-on defaults have ECC enabled
+        sim.power_cycle();
+
+        let mut flash = sim.driver();
+        flash.resume_fast(saved);
+        assert_eq!(flash.ecc_mode(), EccMode::Disabled);
+        assert_eq!(flash.verify_state(), Err(FlashError::StaleStateDetected));
+    }
+
+    #[test]
+    fn a_natural_register_read_verifies_the_state() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let saved = flash.save_state();
+        flash.resume_fast(saved);
+        assert!(flash.state_unverified());
+
+        flash.read_configuration_register().unwrap();
+        assert!(!flash.state_unverified());
+    }
+}
@@ -0,0 +1,510 @@
+//! One pass over the device for any number of checks at once.
+//!
+//! `scan` walks a range of blocks in order, block by block and page by page within each block,
+//! reading as little of each page as the `ScanDepth` asks for. Each block is announced to the
+//! visitor along with its factory bad block marker before its pages, and the `ScanSkip` policy
+//! decides whether the pages of marked blocks are visited at all. Visitors combine as a tuple, so
+//! `(&mut bad_blocks, &mut ecc)` gathers bad blocks and ECC findings in the same pass instead of
+//! reading the device twice.
+//!
+//! The driver's own whole device passes run on the same walk: `sweep_spare` and `Patrol` visit
+//! pages, and `IncrementalEraser` and `BlockAllocator::reconcile_bad_blocks` visit blocks.
+//!
+//! Everything the built-in checks report is kept in a `Findings`, a fixed capacity collection that
+//! stays sorted, so a report lists the same entries in the same order no matter which scan or
+//! order of calls produced it.
+
+use core::ops::{ControlFlow, Range};
+
+use crate::{
+    patrol::PatrolFinding, status::ECCStatus, FlashError, Geometry, QspiBus, ReadMethod,
+    BLOCK_COUNT, SPARE_BYTES, W25N01GV,
+};
+
+/// Up to `N` entries kept in ascending order. Once full, an entry that sorts before the last one
+/// pushes the last one out, so the collection always holds the `N` lowest entries it was given,
+/// and `overflowed` tells that some were lost.
+#[derive(Debug, Clone, Copy)]
+pub struct Findings<T, const N: usize> {
+    items: [Option<T>; N],
+    len: usize,
+    overflowed: bool,
+}
+
+impl<T: Copy + Ord, const N: usize> Findings<T, N> {
+    pub const fn new() -> Findings<T, N> {
+        Findings {
+            items: [None; N],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Inserts `item` after any entries equal to it
+    pub fn push(&mut self, item: T) {
+        let position = self.items[..self.len]
+            .iter()
+            .position(|existing| existing.is_some_and(|existing| existing > item))
+            .unwrap_or(self.len);
+
+        if self.len == N {
+            self.overflowed = true;
+            if position == N {
+                return;
+            }
+        } else {
+            self.len += 1;
+        }
+
+        self.items[position..self.len].rotate_right(1);
+        self.items[position] = Some(item);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items[..self.len].iter().flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Is true if more entries were pushed than fit since the last `clear`
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    pub fn clear(&mut self) {
+        *self = Findings::new();
+    }
+}
+
+impl<T: Copy + Ord, const N: usize> Default for Findings<T, N> {
+    fn default() -> Findings<T, N> {
+        Findings::new()
+    }
+}
+
+/// Which blocks `scan` leaves out of the page visits. Blocks are announced to the visitor either
+/// way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ScanSkip {
+    /// Visits the pages of every block
+    Nothing,
+    /// Skips the pages of blocks with the factory bad block marker set
+    MarkedBad,
+}
+
+/// How much of each page `scan` reads
+#[derive(Debug, Clone, Copy)]
+pub enum ScanDepth {
+    /// Reads just each block's bad block marker and visits no pages
+    Blocks(ReadMethod),
+    /// Reads each page's ECC status and nothing else, like `Patrol`. Nothing is transferred out
+    /// of the data buffer, so blocks aren't announced and `ScanSkip` has no effect.
+    Status,
+    /// Reads each page's ECC status and spare area, like `sweep_spare`
+    Spare(ReadMethod),
+}
+
+/// A page as `scan` read it
+#[derive(Debug, Clone, Copy)]
+pub struct ScanPage<'a> {
+    pub page_address: u16,
+    pub ecc_status: ECCStatus,
+    /// The page's spare area, None at `ScanDepth::Status`
+    pub spare: Option<&'a [u8; SPARE_BYTES]>,
+}
+
+/// Totals gathered while scanning
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ScanStats {
+    pub blocks_visited: u16,
+    /// Blocks whose pages were left out by the `ScanSkip` policy
+    pub blocks_skipped: u16,
+    pub pages_visited: u32,
+}
+
+/// A check run by `scan`, see the module docs
+pub trait ScanVisitor {
+    /// Called before each block's pages with whether its bad block marker is set. Returning
+    /// `ControlFlow::Break` ends the scan.
+    fn visit_block(&mut self, block: u16, marked_bad: bool) -> ControlFlow<()> {
+        let _ = (block, marked_bad);
+        ControlFlow::Continue(())
+    }
+
+    /// Called for each page visited. Returning `ControlFlow::Break` ends the scan.
+    fn visit_page(&mut self, page: &ScanPage) -> ControlFlow<()> {
+        let _ = page;
+        ControlFlow::Continue(())
+    }
+}
+
+impl<V: ScanVisitor> ScanVisitor for &mut V {
+    fn visit_block(&mut self, block: u16, marked_bad: bool) -> ControlFlow<()> {
+        (**self).visit_block(block, marked_bad)
+    }
+
+    fn visit_page(&mut self, page: &ScanPage) -> ControlFlow<()> {
+        (**self).visit_page(page)
+    }
+}
+
+/// Both visitors see everything, and the scan ends as soon as either asks it to
+impl<A: ScanVisitor, B: ScanVisitor> ScanVisitor for (A, B) {
+    fn visit_block(&mut self, block: u16, marked_bad: bool) -> ControlFlow<()> {
+        let first = self.0.visit_block(block, marked_bad);
+        let second = self.1.visit_block(block, marked_bad);
+
+        either_breaks(first, second)
+    }
+
+    fn visit_page(&mut self, page: &ScanPage) -> ControlFlow<()> {
+        let first = self.0.visit_page(page);
+        let second = self.1.visit_page(page);
+
+        either_breaks(first, second)
+    }
+}
+
+fn either_breaks(first: ControlFlow<()>, second: ControlFlow<()>) -> ControlFlow<()> {
+    if first.is_break() || second.is_break() {
+        ControlFlow::Break(())
+    } else {
+        ControlFlow::Continue(())
+    }
+}
+
+/// Gathers the blocks with their bad block marker set
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BadBlockScan<const N: usize> {
+    pub bad_blocks: Findings<u16, N>,
+}
+
+impl<const N: usize> ScanVisitor for BadBlockScan<N> {
+    fn visit_block(&mut self, block: u16, marked_bad: bool) -> ControlFlow<()> {
+        if marked_bad {
+            self.bad_blocks.push(block);
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+/// Gathers the pages that read back with anything other than a clean ECC status, the same
+/// findings a `Patrol` records
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EccScan<const N: usize> {
+    pub findings: Findings<PatrolFinding, N>,
+}
+
+impl<const N: usize> ScanVisitor for EccScan<N> {
+    fn visit_page(&mut self, page: &ScanPage) -> ControlFlow<()> {
+        if page.ecc_status != ECCStatus::Successful && page.ecc_status != ECCStatus::EccDisabled {
+            self.findings.push(PatrolFinding {
+                page_address: page.page_address,
+                ecc_status: page.ecc_status,
+            });
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Runs `visitor` over `blocks`, see the module docs. Reading the spare area or the marker
+    /// needs buffered read mode, so at those depths continuous read mode is turned off for the
+    /// duration of the scan and restored afterwards.
+    pub fn scan<V: ScanVisitor>(
+        &self,
+        blocks: Range<u16>,
+        skip: ScanSkip,
+        depth: ScanDepth,
+        visitor: &mut V,
+    ) -> Result<ScanStats, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if blocks.end as usize > BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let pages = Geometry::W25N01GV.block_pages(blocks.start).start
+            ..Geometry::W25N01GV.block_pages(blocks.end).start;
+
+        self.scan_pages_unguarded(pages, skip, depth, visitor)
+    }
+
+    /// The walk behind `scan`, over any range of pages. A block is only announced when the range
+    /// reaches its first page, since that's where its marker is.
+    pub(crate) fn scan_pages_unguarded<V: ScanVisitor>(
+        &self,
+        pages: Range<u32>,
+        skip: ScanSkip,
+        depth: ScanDepth,
+        visitor: &mut V,
+    ) -> Result<ScanStats, FlashError> {
+        let mut stats = ScanStats::default();
+
+        let method = match depth {
+            ScanDepth::Blocks(method) | ScanDepth::Spare(method) => method,
+            ScanDepth::Status => {
+                for page_address in pages {
+                    let page = ScanPage {
+                        page_address: page_address as u16,
+                        ecc_status: self.read_page_status(page_address as u16)?,
+                        spare: None,
+                    };
+
+                    stats.pages_visited += 1;
+                    if visitor.visit_page(&page).is_break() {
+                        break;
+                    }
+                }
+
+                return Ok(stats);
+            }
+        };
+
+        let mut spare = [0_u8; SPARE_BYTES];
+
+        self.with_buffered_read(|| {
+            let mut page_address = pages.start;
+
+            while page_address < pages.end {
+                let block_pages = Geometry::W25N01GV
+                    .block_pages(Geometry::W25N01GV.block_of_page(page_address as u16));
+
+                if let ScanDepth::Blocks(_) = depth {
+                    if page_address == block_pages.start {
+                        let block = Geometry::W25N01GV.block_of_page(page_address as u16);
+                        self.read_memory_to_data_buffer_unguarded(page_address as u16)?;
+                        self.wait_while_busy_unguarded()?;
+                        let marked_bad = self.read_bad_block_marker(method)?;
+
+                        stats.blocks_visited += 1;
+                        if visitor.visit_block(block, marked_bad).is_break() {
+                            break;
+                        }
+                    }
+
+                    page_address = block_pages.end;
+                    continue;
+                }
+
+                // The marker is in the first page, so reading it doubles as the first page visit
+                let ecc_status = self.read_page_spare(page_address as u16, &mut spare, method)?;
+
+                if page_address == block_pages.start {
+                    let block = Geometry::W25N01GV.block_of_page(page_address as u16);
+                    let marked_bad = self.oob_layout.is_marked_bad(&spare);
+
+                    stats.blocks_visited += 1;
+                    if visitor.visit_block(block, marked_bad).is_break() {
+                        break;
+                    }
+
+                    if marked_bad && skip == ScanSkip::MarkedBad {
+                        stats.blocks_skipped += 1;
+                        page_address = block_pages.end;
+                        continue;
+                    }
+                }
+
+                let page = ScanPage {
+                    page_address: page_address as u16,
+                    ecc_status,
+                    spare: Some(&spare),
+                };
+                stats.pages_visited += 1;
+                if visitor.visit_page(&page).is_break() {
+                    break;
+                }
+
+                page_address += 1;
+            }
+
+            Ok(stats)
+        })
+    }
+
+    /// Reads a page into the data buffer and returns its ECC status, transferring nothing else
+    pub(crate) fn read_page_status(&self, page_address: u16) -> Result<ECCStatus, FlashError> {
+        self.read_memory_to_data_buffer_unguarded(page_address)?;
+        self.wait_while_busy_unguarded()?;
+
+        Ok(self.read_status_register_unguarded()?.ecc_status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+    use std::{collections::BTreeMap, vec::Vec};
+
+    fn page_reads(sim: &SimFlash) -> BTreeMap<u16, usize> {
+        let mut reads = BTreeMap::new();
+        for command in sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0x13)
+        {
+            *reads.entry(command.page_address().unwrap()).or_insert(0) += 1;
+        }
+
+        reads
+    }
+
+    #[test]
+    fn a_composed_scan_reads_each_page_once() {
+        let sim = SimFlash::new();
+        sim.mark_bad(2);
+        sim.set_uncorrectable(70);
+        sim.set_uncorrectable(200);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut bad_blocks = BadBlockScan::<4>::default();
+        let mut ecc = EccScan::<4>::default();
+        let stats = flash
+            .scan(
+                0..4,
+                ScanSkip::Nothing,
+                ScanDepth::Spare(ReadMethod::FastRead),
+                &mut (&mut bad_blocks, &mut ecc),
+            )
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            ScanStats {
+                blocks_visited: 4,
+                blocks_skipped: 0,
+                pages_visited: 256,
+            }
+        );
+        let reads = page_reads(&sim);
+        assert_eq!(
+            reads.keys().copied().collect::<Vec<_>>(),
+            (0..256).collect::<Vec<_>>()
+        );
+        assert!(reads.values().all(|count| *count == 1));
+
+        assert_eq!(
+            bad_blocks.bad_blocks.iter().copied().collect::<Vec<_>>(),
+            [2]
+        );
+        let lost: Vec<u16> = ecc
+            .findings
+            .iter()
+            .map(|finding| finding.page_address)
+            .collect();
+        assert_eq!(lost, [70, 200]);
+    }
+
+    #[test]
+    fn marked_bad_blocks_are_announced_but_not_visited() {
+        let sim = SimFlash::new();
+        sim.mark_bad(1);
+        sim.set_uncorrectable(100);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut bad_blocks = BadBlockScan::<4>::default();
+        let mut ecc = EccScan::<4>::default();
+        let stats = flash
+            .scan(
+                0..3,
+                ScanSkip::MarkedBad,
+                ScanDepth::Spare(ReadMethod::FastRead),
+                &mut (&mut bad_blocks, &mut ecc),
+            )
+            .unwrap();
+
+        assert_eq!(stats.blocks_skipped, 1);
+        assert_eq!(stats.pages_visited, 128);
+        assert_eq!(
+            bad_blocks.bad_blocks.iter().copied().collect::<Vec<_>>(),
+            [1]
+        );
+        assert!(ecc.findings.is_empty());
+
+        // Only the marker of the skipped block was read
+        let reads = page_reads(&sim);
+        assert_eq!(reads.len(), 129);
+        assert_eq!(reads.get(&64), Some(&1));
+        assert_eq!(reads.get(&65), None);
+    }
+
+    #[test]
+    fn depths_read_only_what_they_need() {
+        let sim = SimFlash::new();
+        sim.mark_bad(1);
+        let flash = sim.driver();
+
+        sim.clear_log();
+        let mut bad_blocks = BadBlockScan::<4>::default();
+        let stats = flash
+            .scan(
+                0..4,
+                ScanSkip::Nothing,
+                ScanDepth::Blocks(ReadMethod::FastRead),
+                &mut bad_blocks,
+            )
+            .unwrap();
+        assert_eq!(stats.blocks_visited, 4);
+        assert_eq!(stats.pages_visited, 0);
+        assert_eq!(
+            page_reads(&sim).keys().copied().collect::<Vec<_>>(),
+            [0, 64, 128, 192]
+        );
+        assert_eq!(
+            bad_blocks.bad_blocks.iter().copied().collect::<Vec<_>>(),
+            [1]
+        );
+
+        sim.clear_log();
+        let mut bad_blocks = BadBlockScan::<4>::default();
+        let stats = flash
+            .scan(
+                0..2,
+                ScanSkip::MarkedBad,
+                ScanDepth::Status,
+                &mut bad_blocks,
+            )
+            .unwrap();
+        assert_eq!(stats.blocks_visited, 0);
+        assert_eq!(stats.pages_visited, 128);
+        assert!(bad_blocks.bad_blocks.is_empty());
+        assert_eq!(sim.count(0x0B), 0);
+    }
+
+    #[test]
+    fn sweep_spare_reads_a_partial_block_range() {
+        let sim = SimFlash::new();
+        let mut page = [0xFF; crate::PAGE_SIZE_WITH_ECC_BYTES];
+        page[crate::PAGE_SIZE_BYTES + 4] = 0x5A;
+        sim.set_page(66, &page);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut seen = Vec::new();
+        let stats = flash
+            .sweep_spare(60..70, ReadMethod::FastRead, |page_address, spare| {
+                seen.push((page_address, spare[4]));
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+
+        assert_eq!(stats.pages_visited, 10);
+        assert_eq!(seen.len(), 10);
+        assert_eq!(seen[6], (66, 0x5A));
+        assert!(page_reads(&sim).values().all(|count| *count == 1));
+    }
+}
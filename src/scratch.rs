@@ -0,0 +1,57 @@
+//! Scratch space lent to the helpers that need a temporary page buffer, so a firmware using several
+//! of them can share one arena instead of paying for a buffer per helper.
+//!
+//! The helpers that take a `Scratch` each have a `*_SCRATCH_BYTES` const saying the most they'll
+//! use, so the arena can be sized for the largest of them. A helper short on space returns
+//! `FlashError::InsufficientScratch` rather than doing less. The space a helper takes is
+//! handed back when it returns, so the same arena can be passed to one helper after another.
+
+use core::convert::TryInto;
+
+use crate::{FlashError, PAGE_SIZE_WITH_ECC_BYTES};
+
+pub struct Scratch<'a> {
+    buffer: &'a mut [u8],
+}
+
+impl<'a> Scratch<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Scratch<'a> {
+        Scratch { buffer }
+    }
+
+    /// Bytes not taken yet
+    pub fn available(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Lends out the untaken space, everything taken from the returned arena coming back once
+    /// it's dropped
+    pub fn reborrow(&mut self) -> Scratch<'_> {
+        Scratch {
+            buffer: &mut self.buffer[..],
+        }
+    }
+
+    /// Takes `len` bytes off the front of the arena
+    pub fn take(&mut self, len: usize) -> Result<&'a mut [u8], FlashError> {
+        if len > self.buffer.len() {
+            return Err(FlashError::InsufficientScratch {
+                needed: len,
+                available: self.buffer.len(),
+            });
+        }
+
+        let buffer = core::mem::take(&mut self.buffer);
+        let (taken, rest) = buffer.split_at_mut(len);
+        self.buffer = rest;
+
+        Ok(taken)
+    }
+
+    /// Takes a buffer the size of the data buffer
+    pub fn take_page(&mut self) -> Result<&'a mut [u8; PAGE_SIZE_WITH_ECC_BYTES], FlashError> {
+        self.take(PAGE_SIZE_WITH_ECC_BYTES)?
+            .try_into()
+            .map_err(|_| FlashError::OutOfBounds)
+    }
+}
@@ -0,0 +1,650 @@
+//! A simulated W25N01GV behind the `QspiBus` trait, for the crate's tests.
+//!
+//! `SimFlash` decodes the commands the driver sends the way the device does, keeping the array,
+//! the data buffer, the OTP area, the status registers, and the bad block look up table in
+//! memory. Every command is logged so tests can assert on exactly what went out on the bus, and
+//! tests can inject ECC errors, program and erase failures, power cuts (optionally tearing the
+//! interrupted program or erase), and a hook called after each command.
+//!
+//! It's a handle, so a clone kept by the test sees everything a driver does through another, and
+//! survives the driver being dropped to simulate a reboot with `power_cycle`.
+
+// Not every test needs every knob
+#![allow(dead_code)]
+
+use std::{
+    boxed::Box,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    vec,
+    vec::Vec,
+};
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    bus::{QspiBus, QspiError, QspiMode, QspiReadCommand, QspiWriteCommand},
+    new_w25_n01_gv, ReadMode, MAX_BBM_LUT_ENTIRES, PAGES_PER_BLOCK, PAGE_SIZE_BYTES,
+    PAGE_SIZE_WITH_ECC_BYTES, W25N01GV,
+};
+
+pub(crate) const JEDEC_ID: [u8; 3] = [0xEF, 0xAA, 0x21];
+
+const PROTECTION_DEFAULT: u8 = 0x7C;
+const CONFIGURATION_DEFAULT: u8 = 0x18;
+
+const OTP_L: u8 = 0x80;
+const OTP_E: u8 = 0x40;
+const ECC_E: u8 = 0x10;
+const BUF: u8 = 0x08;
+
+const LUT_FULL: u8 = 0x40;
+const ECC1: u8 = 0x20;
+const ECC0: u8 = 0x10;
+const P_FAIL: u8 = 0x08;
+const E_FAIL: u8 = 0x04;
+const WEL: u8 = 0x02;
+const BUSY: u8 = 0x01;
+
+/// The enable bit of a look up table link's LBA
+pub(crate) const LUT_ENABLE: u16 = 0x8000;
+
+/// A command as the simulated device received it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SimCommand {
+    pub opcode: u8,
+    pub instruction_mode: QspiMode,
+    pub address: Option<(u32, QspiMode)>,
+    pub alternative_bytes: Vec<u8>,
+    pub dummy_cycles: u8,
+    /// What was sent, for commands that send data
+    pub data: Vec<u8>,
+    pub data_mode: QspiMode,
+    /// How much was asked for, for commands that read data back
+    pub receive_length: Option<u32>,
+}
+
+impl SimCommand {
+    /// The page address a page addressed command carries
+    pub fn page_address(&self) -> Option<u16> {
+        if self.data.len() == 2 {
+            Some(u16::from_be_bytes([self.data[0], self.data[1]]))
+        } else {
+            None
+        }
+    }
+}
+
+type Page = Box<[u8; PAGE_SIZE_WITH_ECC_BYTES]>;
+
+fn erased_page() -> Page {
+    Box::new([0xFF; PAGE_SIZE_WITH_ECC_BYTES])
+}
+
+struct SimState {
+    array: BTreeMap<u16, Page>,
+    otp: BTreeMap<u16, Page>,
+    otp_locked: bool,
+    buffer: Page,
+    buffer_page: u16,
+    protection: u8,
+    configuration: u8,
+    status: u8,
+    lut: Vec<(u16, u16)>,
+    last_ecc_failure: u16,
+    busy_polls: u32,
+    busy_polls_per_op: u32,
+    uncorrectable: BTreeSet<u16>,
+    program_failures: BTreeSet<u16>,
+    otp_program_failures: BTreeSet<u16>,
+    erase_failures: BTreeSet<u16>,
+    powered: bool,
+    cut_after: Option<(usize, bool)>,
+    destructive_ops: usize,
+    log: Vec<SimCommand>,
+}
+
+impl SimState {
+    fn new() -> SimState {
+        let mut otp = BTreeMap::new();
+        let mut unique_id = erased_page();
+        for (index, byte) in unique_id[..16].iter_mut().enumerate() {
+            *byte = 0xA0 + index as u8;
+        }
+        for index in 16..32 {
+            unique_id[index] = !unique_id[index - 16];
+        }
+        otp.insert(0, unique_id);
+
+        let mut parameter_page = erased_page();
+        parameter_page[..4].copy_from_slice(b"ONFI");
+        otp.insert(1, parameter_page);
+
+        SimState {
+            array: BTreeMap::new(),
+            otp,
+            otp_locked: false,
+            buffer: erased_page(),
+            buffer_page: 0,
+            protection: PROTECTION_DEFAULT,
+            configuration: CONFIGURATION_DEFAULT,
+            status: 0,
+            lut: Vec::new(),
+            last_ecc_failure: 0,
+            busy_polls: 0,
+            busy_polls_per_op: 1,
+            uncorrectable: BTreeSet::new(),
+            program_failures: BTreeSet::new(),
+            otp_program_failures: BTreeSet::new(),
+            erase_failures: BTreeSet::new(),
+            powered: true,
+            cut_after: None,
+            destructive_ops: 0,
+            log: Vec::new(),
+        }
+    }
+
+    /// Follows the look up table from a logical page to the physical one
+    fn physical_page(&self, page_address: u16) -> u16 {
+        let block = page_address / PAGES_PER_BLOCK as u16;
+        let offset = page_address % PAGES_PER_BLOCK as u16;
+
+        match self.lut.iter().find(|(logical, _)| *logical == block) {
+            Some((_, physical)) => physical * PAGES_PER_BLOCK as u16 + offset,
+            None => page_address,
+        }
+    }
+
+    fn otp_enabled(&self) -> bool {
+        self.configuration & OTP_E != 0
+    }
+
+    fn start_busy(&mut self) {
+        self.busy_polls = self.busy_polls_per_op;
+    }
+
+    /// Counts a program or erase towards a scheduled power cut, returning whether it's the one
+    /// the power goes out during and if so whether it tears
+    fn power_cut_now(&mut self) -> Option<bool> {
+        match self.cut_after {
+            Some((0, torn)) => {
+                self.cut_after = None;
+                self.powered = false;
+                Some(torn)
+            }
+            Some((remaining, torn)) => {
+                self.cut_after = Some((remaining - 1, torn));
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn write(&mut self, command: &SimCommand) -> Result<(), QspiError> {
+        let data = &command.data;
+
+        match command.opcode {
+            0xFF => {
+                self.configuration = (self.configuration & OTP_L) | CONFIGURATION_DEFAULT;
+                self.protection = PROTECTION_DEFAULT;
+                self.status &= LUT_FULL;
+                self.buffer = erased_page();
+                self.start_busy();
+            }
+            0x06 => self.status |= WEL,
+            0x04 => self.status &= !WEL,
+            0x01 | 0x1F => match data[0] {
+                0xA0 => self.protection = data[1],
+                0xB0 => {
+                    let locked = if self.otp_locked { OTP_L } else { 0 };
+                    self.configuration = data[1] | locked;
+                }
+                _ => {}
+            },
+            0x02 | 0x32 | 0x84 | 0x34 => {
+                if command.opcode == 0x02 || command.opcode == 0x32 {
+                    self.buffer = erased_page();
+                }
+
+                let column = command.address.map(|(column, _)| column).unwrap_or(0) as usize;
+                for (offset, byte) in data.iter().enumerate() {
+                    if let Some(slot) = self.buffer.get_mut(column + offset) {
+                        *slot = *byte;
+                    }
+                }
+            }
+            0x10 => self.program_execute(u16::from_be_bytes([data[0], data[1]]))?,
+            0xD8 => self.block_erase(u16::from_be_bytes([data[0], data[1]]))?,
+            0x13 => self.page_data_read(u16::from_be_bytes([data[0], data[1]])),
+            0xA1 if self.status & WEL != 0 => {
+                if self.lut.len() < MAX_BBM_LUT_ENTIRES {
+                    let logical = u16::from_be_bytes([data[0], data[1]]);
+                    let physical = u16::from_be_bytes([data[2], data[3]]);
+                    self.lut.push((logical, physical));
+                }
+                if self.lut.len() == MAX_BBM_LUT_ENTIRES {
+                    self.status |= LUT_FULL;
+                }
+                self.status &= !WEL;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn program_execute(&mut self, page_address: u16) -> Result<(), QspiError> {
+        if self.status & WEL == 0 {
+            return Ok(());
+        }
+        self.status &= !(WEL | P_FAIL);
+        self.start_busy();
+
+        if self.otp_enabled() {
+            if self.configuration & OTP_L != 0 {
+                self.otp_locked = true;
+            } else if self.otp_locked || self.otp_program_failures.contains(&page_address) {
+                self.status |= P_FAIL;
+            } else {
+                let page = self.otp.entry(page_address).or_insert_with(erased_page);
+                for (cell, byte) in page.iter_mut().zip(self.buffer.iter()) {
+                    *cell &= *byte;
+                }
+            }
+
+            return Ok(());
+        }
+
+        let torn = self.power_cut_now();
+        self.destructive_ops += 1;
+
+        let physical = self.physical_page(page_address);
+        if self.program_failures.contains(&physical) {
+            self.status |= P_FAIL;
+            return Ok(());
+        }
+
+        let len = match torn {
+            Some(true) => PAGE_SIZE_WITH_ECC_BYTES / 2,
+            Some(false) => 0,
+            None => PAGE_SIZE_WITH_ECC_BYTES,
+        };
+        let page = self.array.entry(physical).or_insert_with(erased_page);
+        for (cell, byte) in page.iter_mut().zip(self.buffer.iter()).take(len) {
+            *cell &= *byte;
+        }
+
+        match torn {
+            Some(_) => Err(QspiError::Unknown),
+            None => Ok(()),
+        }
+    }
+
+    fn block_erase(&mut self, page_address: u16) -> Result<(), QspiError> {
+        if self.status & WEL == 0 {
+            return Ok(());
+        }
+        self.status &= !(WEL | E_FAIL);
+        self.start_busy();
+
+        let torn = self.power_cut_now();
+        self.destructive_ops += 1;
+
+        let first_page =
+            self.physical_page(page_address) / PAGES_PER_BLOCK as u16 * PAGES_PER_BLOCK as u16;
+        if self
+            .erase_failures
+            .contains(&(first_page / PAGES_PER_BLOCK as u16))
+        {
+            self.status |= E_FAIL;
+            return Ok(());
+        }
+
+        let pages = match torn {
+            Some(true) => PAGES_PER_BLOCK / 2,
+            Some(false) => 0,
+            None => PAGES_PER_BLOCK,
+        };
+        for page in first_page as u32..first_page as u32 + pages as u32 {
+            self.array.remove(&(page as u16));
+        }
+
+        match torn {
+            Some(_) => Err(QspiError::Unknown),
+            None => Ok(()),
+        }
+    }
+
+    fn page_data_read(&mut self, page_address: u16) {
+        self.status &= !(ECC0 | ECC1);
+        self.start_busy();
+
+        if self.otp_enabled() {
+            self.buffer = self
+                .otp
+                .get(&page_address)
+                .cloned()
+                .unwrap_or_else(erased_page);
+            return;
+        }
+
+        let physical = self.physical_page(page_address);
+        self.buffer = self
+            .array
+            .get(&physical)
+            .cloned()
+            .unwrap_or_else(erased_page);
+        self.buffer_page = page_address;
+
+        if self.configuration & ECC_E != 0 && self.uncorrectable.contains(&physical) {
+            self.status |= ECC1;
+            self.last_ecc_failure = page_address;
+        }
+    }
+
+    fn read(&mut self, command: &SimCommand, buffer: &mut [u8]) {
+        let len = command.receive_length.unwrap_or(0) as usize;
+        let buffer = &mut buffer[..len];
+
+        match command.opcode {
+            0x9F => buffer.copy_from_slice(&JEDEC_ID[..len]),
+            0x05 | 0x0F => {
+                buffer[0] = match command.alternative_bytes[0] {
+                    0xA0 => self.protection,
+                    0xB0 => self.configuration,
+                    0xC0 => {
+                        let busy = if self.busy_polls > 0 {
+                            self.busy_polls -= 1;
+                            BUSY
+                        } else {
+                            0
+                        };
+                        self.status | busy
+                    }
+                    _ => 0,
+                }
+            }
+            0xA5 => {
+                buffer.iter_mut().for_each(|byte| *byte = 0);
+                for ((logical, physical), bytes) in self.lut.iter().zip(buffer.chunks_exact_mut(4))
+                {
+                    bytes[..2].copy_from_slice(&(logical | LUT_ENABLE).to_be_bytes());
+                    bytes[2..].copy_from_slice(&physical.to_be_bytes());
+                }
+            }
+            0xA9 => buffer.copy_from_slice(&self.last_ecc_failure.to_be_bytes()),
+            0x03 | 0x0B | 0x3B | 0x6B | 0xBB | 0xEB => {
+                if self.configuration & BUF != 0 {
+                    let column = command.address.map(|(column, _)| column).unwrap_or(0) as usize;
+                    for (offset, byte) in buffer.iter_mut().enumerate() {
+                        *byte = self.buffer.get(column + offset).copied().unwrap_or(0xFF);
+                    }
+                } else {
+                    // Continuous reads carry on through the main areas of the following pages
+                    for (offset, byte) in buffer.iter_mut().enumerate() {
+                        let page = offset / PAGE_SIZE_BYTES;
+                        let column = offset % PAGE_SIZE_BYTES;
+                        *byte = if page == 0 {
+                            self.buffer[column]
+                        } else {
+                            let page_address = self.buffer_page.wrapping_add(page as u16);
+                            self.array
+                                .get(&self.physical_page(page_address))
+                                .map(|page| page[column])
+                                .unwrap_or(0xFF)
+                        };
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+type Hook = Box<dyn FnMut(&SimCommand)>;
+
+#[derive(Clone)]
+pub(crate) struct SimFlash {
+    state: Rc<RefCell<SimState>>,
+    hook: Rc<RefCell<Option<Hook>>>,
+}
+
+impl SimFlash {
+    pub fn new() -> SimFlash {
+        SimFlash {
+            state: Rc::new(RefCell::new(SimState::new())),
+            hook: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    /// A read mode driver over a new handle to this device
+    pub fn driver(&self) -> W25N01GV<SimFlash, ReadMode> {
+        new_w25_n01_gv(self.clone())
+    }
+
+    /// Every command received so far, oldest first
+    pub fn commands(&self) -> Vec<SimCommand> {
+        self.state.borrow().log.clone()
+    }
+
+    /// How many commands with `opcode` were received
+    pub fn count(&self, opcode: u8) -> usize {
+        self.state
+            .borrow()
+            .log
+            .iter()
+            .filter(|command| command.opcode == opcode)
+            .count()
+    }
+
+    pub fn clear_log(&self) {
+        self.state.borrow_mut().log.clear();
+    }
+
+    /// Calls `hook` after every command the device receives, from inside the bus call
+    pub fn set_hook(&self, hook: impl FnMut(&SimCommand) + 'static) {
+        *self.hook.borrow_mut() = Some(Box::new(hook));
+    }
+
+    pub fn clear_hook(&self) {
+        *self.hook.borrow_mut() = None;
+    }
+
+    /// A copy of a page as stored in the array, main and spare area
+    pub fn page(&self, page_address: u16) -> Vec<u8> {
+        let state = self.state.borrow();
+        match state.array.get(&page_address) {
+            Some(page) => page.to_vec(),
+            None => vec![0xFF; PAGE_SIZE_WITH_ECC_BYTES],
+        }
+    }
+
+    /// Stores `bytes` from column 0 of a page as if it had been programmed
+    pub fn set_page(&self, page_address: u16, bytes: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        let page = state.array.entry(page_address).or_insert_with(erased_page);
+        page[..bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// Marks a block bad the way the factory does, with a zero first spare byte
+    pub fn mark_bad(&self, block: u16) {
+        let mut page = [0xFF; PAGE_SIZE_WITH_ECC_BYTES];
+        page[PAGE_SIZE_BYTES] = 0x00;
+        self.set_page(block * PAGES_PER_BLOCK as u16, &page);
+    }
+
+    /// Makes reads of a page report an uncorrectable ECC error while ECC is enabled
+    pub fn set_uncorrectable(&self, page_address: u16) {
+        self.state.borrow_mut().uncorrectable.insert(page_address);
+    }
+
+    /// Makes programs of a page fail and leave it untouched
+    pub fn fail_program(&self, page_address: u16) {
+        self.state
+            .borrow_mut()
+            .program_failures
+            .insert(page_address);
+    }
+
+    /// Makes programs of an OTP area page fail and leave it untouched
+    pub fn fail_otp_program(&self, page_address: u16) {
+        self.state
+            .borrow_mut()
+            .otp_program_failures
+            .insert(page_address);
+    }
+
+    /// Stores `bytes` from column 0 of an OTP area page as if it had been programmed
+    pub fn set_otp_page(&self, page_address: u16, bytes: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        let page = state.otp.entry(page_address).or_insert_with(erased_page);
+        page[..bytes.len()].copy_from_slice(bytes);
+    }
+
+    /// How many status register reads each reset, page read, program and erase reports busy for,
+    /// 1 by default
+    pub fn set_busy_polls(&self, polls: u32) {
+        self.state.borrow_mut().busy_polls_per_op = polls;
+    }
+
+    /// Makes erases of a block fail and leave it untouched
+    pub fn fail_erase(&self, block: u16) {
+        self.state.borrow_mut().erase_failures.insert(block);
+    }
+
+    /// Replaces the look up table, setting the status register's LUT-F bit if it's full
+    pub fn set_lut(&self, links: &[(u16, u16)]) {
+        let mut state = self.state.borrow_mut();
+        state.lut = links.to_vec();
+        if links.len() >= MAX_BBM_LUT_ENTIRES {
+            state.status |= LUT_FULL;
+        } else {
+            state.status &= !LUT_FULL;
+        }
+    }
+
+    /// Programs and erases done to the array so far, including any a power cut interrupted
+    pub fn destructive_ops(&self) -> usize {
+        self.state.borrow().destructive_ops
+    }
+
+    /// Lets `ops` more programs or erases of the array through, then cuts the power during the
+    /// next one. A torn cut leaves half the page programmed, or half the block erased, instead of
+    /// none of it. Every command from then on fails until `power_cycle`.
+    pub fn cut_power_after(&self, ops: usize, torn: bool) {
+        self.state.borrow_mut().cut_after = Some((ops, torn));
+    }
+
+    /// Makes every command fail at the bus from now on, as if the device had come unplugged,
+    /// until the next `power_cycle`
+    pub fn disconnect(&self) {
+        self.state.borrow_mut().powered = false;
+    }
+
+    pub fn powered(&self) -> bool {
+        self.state.borrow().powered
+    }
+
+    /// Turns the device off and on again, resetting everything but the array, the OTP area, and
+    /// the look up table
+    pub fn power_cycle(&self) {
+        let mut state = self.state.borrow_mut();
+        state.powered = true;
+        state.cut_after = None;
+        state.buffer = erased_page();
+        state.protection = PROTECTION_DEFAULT;
+        state.configuration = CONFIGURATION_DEFAULT | if state.otp_locked { OTP_L } else { 0 };
+        state.status &= LUT_FULL;
+        state.busy_polls = 0;
+    }
+
+    fn received(&self, command: SimCommand) {
+        self.state.borrow_mut().log.push(command.clone());
+
+        let hook = self.hook.borrow_mut().take();
+        if let Some(mut hook) = hook {
+            hook(&command);
+
+            let mut slot = self.hook.borrow_mut();
+            if slot.is_none() {
+                *slot = Some(hook);
+            }
+        }
+    }
+}
+
+impl QspiBus for SimFlash {
+    fn write_command(&self, command: QspiWriteCommand) -> Result<(), QspiError> {
+        let command = SimCommand {
+            opcode: command.instruction.map(|(opcode, _)| opcode).unwrap_or(0),
+            instruction_mode: command
+                .instruction
+                .map(|(_, mode)| mode)
+                .unwrap_or(QspiMode::SingleChannel),
+            address: command.address,
+            alternative_bytes: command
+                .alternative_bytes
+                .map(|(bytes, _)| bytes.to_vec())
+                .unwrap_or_default(),
+            dummy_cycles: command.dummy_cycles,
+            data: command
+                .data
+                .map(|(data, _)| data.to_vec())
+                .unwrap_or_default(),
+            data_mode: command
+                .data
+                .map(|(_, mode)| mode)
+                .unwrap_or(QspiMode::SingleChannel),
+            receive_length: None,
+        };
+
+        let result = {
+            let mut state = self.state.borrow_mut();
+            if !state.powered {
+                return Err(QspiError::Unknown);
+            }
+            state.write(&command)
+        };
+
+        self.received(command);
+        result
+    }
+
+    fn read_command(&self, command: QspiReadCommand, buffer: &mut [u8]) -> Result<(), QspiError> {
+        let command = SimCommand {
+            opcode: command.instruction.map(|(opcode, _)| opcode).unwrap_or(0),
+            instruction_mode: command
+                .instruction
+                .map(|(_, mode)| mode)
+                .unwrap_or(QspiMode::SingleChannel),
+            address: command.address,
+            alternative_bytes: command
+                .alternative_bytes
+                .map(|(bytes, _)| bytes.to_vec())
+                .unwrap_or_default(),
+            dummy_cycles: command.dummy_cycles,
+            data: Vec::new(),
+            data_mode: command.data_mode,
+            receive_length: Some(command.receive_length),
+        };
+
+        {
+            let mut state = self.state.borrow_mut();
+            if !state.powered {
+                return Err(QspiError::Unknown);
+            }
+            state.read(&command, buffer);
+        }
+
+        self.received(command);
+        Ok(())
+    }
+}
+
+/// A delay that returns straight away, the simulated device is never busy for long
+pub(crate) struct NoDelay;
+
+impl DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _us: u32) {}
+}
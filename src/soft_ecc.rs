@@ -0,0 +1,104 @@
+//! A software Hamming code for use when the device's hardware ECC is disabled. Each 256 byte
+//! chunk of a page gets 3 bytes of ECC, which can correct a single bit error and detect double
+//! bit errors within the chunk.
+//!
+//! The code for a chunk is 11 pairs of parity bits, one pair per bit of the 11 bit index of each
+//! data bit within the chunk. The first parity of a pair covers data bits whose index has that
+//! bit set, the second covers the rest. A single flipped data bit flips exactly one parity of
+//! every pair, and the flipped "set" parities spell out its index.
+//!
+//! The code is stored inverted so that an erased page (all 0xFF, ECC included) checks as valid.
+
+use crate::{FlashError, PAGE_SIZE_BYTES};
+
+pub const SOFT_ECC_CHUNK_BYTES: usize = 256;
+pub const SOFT_ECC_BYTES_PER_CHUNK: usize = 3;
+pub const SOFT_ECC_BYTES: usize = PAGE_SIZE_BYTES / SOFT_ECC_CHUNK_BYTES * SOFT_ECC_BYTES_PER_CHUNK;
+
+const INDEX_BITS: u32 = 11;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const PARITIES_MASK: u32 = (1 << (INDEX_BITS * 2)) - 1;
+
+/// Returns the packed parities of a chunk, the "set" parities in the low 11 bits and the "clear"
+/// parities in the 11 bits above them
+fn chunk_parities(chunk: &[u8]) -> u32 {
+    let mut index_xor = 0_u32;
+    let mut total_parity = 0_u32;
+
+    for (byte_index, byte) in chunk.iter().enumerate() {
+        let mut bit_index_xor = 0_u32;
+        for bit in 0..8 {
+            if byte & (1 << bit) != 0 {
+                bit_index_xor ^= bit;
+            }
+        }
+
+        if byte.count_ones() % 2 == 1 {
+            index_xor ^= (byte_index as u32) << 3;
+            total_parity ^= 1;
+        }
+        index_xor ^= bit_index_xor;
+    }
+
+    // Each "clear" parity is the total parity minus the matching "set" parity
+    let clear_parities = if total_parity == 1 {
+        !index_xor & INDEX_MASK
+    } else {
+        index_xor
+    };
+
+    index_xor | (clear_parities << INDEX_BITS)
+}
+
+/// Calculates the software ECC for a page of data, to be stored in the page's spare area
+pub fn calculate(data: &[u8; PAGE_SIZE_BYTES]) -> [u8; SOFT_ECC_BYTES] {
+    let mut ecc = [0_u8; SOFT_ECC_BYTES];
+
+    for (chunk, code) in data
+        .chunks(SOFT_ECC_CHUNK_BYTES)
+        .zip(ecc.chunks_mut(SOFT_ECC_BYTES_PER_CHUNK))
+    {
+        let parities = (!chunk_parities(chunk)).to_le_bytes();
+        code.copy_from_slice(&parities[0..SOFT_ECC_BYTES_PER_CHUNK]);
+    }
+
+    ecc
+}
+
+/// Checks a page of data against its stored software ECC, correcting single bit errors in place.
+/// Returns the number of bits corrected, or `FlashError::UncorrectableSoftwareECC` if any
+/// chunk has more errors than can be corrected.
+pub fn correct(
+    data: &mut [u8; PAGE_SIZE_BYTES],
+    ecc: &[u8; SOFT_ECC_BYTES],
+) -> Result<u8, FlashError> {
+    let mut corrected_bits = 0;
+
+    for (chunk, code) in data
+        .chunks_mut(SOFT_ECC_CHUNK_BYTES)
+        .zip(ecc.chunks(SOFT_ECC_BYTES_PER_CHUNK))
+    {
+        let stored = !u32::from_le_bytes([code[0], code[1], code[2], 0]) & PARITIES_MASK;
+        let syndrome = stored ^ chunk_parities(chunk);
+
+        if syndrome == 0 {
+            continue;
+        }
+
+        let set_syndrome = syndrome & INDEX_MASK;
+        let clear_syndrome = (syndrome >> INDEX_BITS) & INDEX_MASK;
+
+        if set_syndrome ^ clear_syndrome == INDEX_MASK {
+            // A single data bit flipped, the set syndrome is its index
+            chunk[(set_syndrome >> 3) as usize] ^= 1 << (set_syndrome & 0x7);
+            corrected_bits += 1;
+        } else if syndrome.count_ones() == 1 {
+            // A bit of the stored ECC flipped, the data itself is fine
+            corrected_bits += 1;
+        } else {
+            return Err(FlashError::UncorrectableSoftwareECC);
+        }
+    }
+
+    Ok(corrected_bits)
+}
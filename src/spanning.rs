@@ -0,0 +1,836 @@
+//! Records larger than a page, written so that a power cut part way through can never leave a
+//! partial record that reads back as valid.
+//!
+//! A record is split into parts of up to `PART_DATA_BYTES`, each written to its own page with a
+//! header giving the record ID, the part's index, the total number of parts, and a CRC-32 of the
+//! header and the part's data. Once every part is written, a commit page holding just a header is
+//! written after them. Reading back only yields a record once its commit page has been seen
+//! following every one of its parts in order, so a record cut short anywhere before its commit
+//! page is skipped.
+//!
+//! Like `FlashLogSink`, the records fill a range of blocks as a ring, erasing the oldest block when
+//! wrapping around onto it. Records span block boundaries freely and bad blocks are stepped over.
+//! So are pages torn by a power cut, with the next record written after them.
+//!
+//! With `set_retention(true)` the ring keeps records until they're released instead of erasing
+//! the oldest block, as `FlashLogSink` does: `release_through` releases every record up to an ID,
+//! `reclaim` erases the oldest blocks holding only released records, and `write` returns
+//! `StorageError::StorageFull` rather than erase a block holding records, with `reclaimable`
+//! telling whether `reclaim` would make the room. `check_room` reports the same for a payload
+//! length without giving up the driver. Releases are only kept in RAM.
+//!
+//! Page header, little endian:
+//!
+//! | Bytes  | Field                                         |
+//! |--------|-----------------------------------------------|
+//! | 0..4   | Magic, "SPAN" for a part or "SPCM" for commit |
+//! | 4..8   | Record ID                                     |
+//! | 8..10  | Part index                                    |
+//! | 10..12 | Total parts                                   |
+//! | 12..14 | Part data length                              |
+//! | 14..16 | Reserved, 0                                   |
+//! | 16..20 | CRC-32 of bytes 0..16 followed by the data    |
+
+use core::convert::TryInto;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    block_header::{BlockHeader, StructureKind, BLOCK_HEADER_COLUMN},
+    digest::{Crc32, StreamingDigest},
+    log_sink::{is_released, BlockRecords},
+    Column, FlashError, Geometry, PageClass, QspiBus, ReadMethod, ReadMode, StorageError,
+    WriteMethod, PAGES_PER_BLOCK, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+const PART_MAGIC: u32 = 0x4E41_5053;
+const COMMIT_MAGIC: u32 = 0x4D43_5053;
+const PAGE_HEADER_BYTES: usize = 20;
+const CRC_OFFSET: usize = 16;
+
+/// The most record data that fits in one page, which stops short of the block header
+pub const PART_DATA_BYTES: usize = BLOCK_HEADER_COLUMN as usize - PAGE_HEADER_BYTES;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PageHeader {
+    commit: bool,
+    record_id: u32,
+    part_index: u16,
+    total_parts: u16,
+    part_len: u16,
+}
+
+impl PageHeader {
+    fn write_to(&self, bytes: &mut [u8], data: &[u8], mut digest: Crc32) {
+        let magic = if self.commit {
+            COMMIT_MAGIC
+        } else {
+            PART_MAGIC
+        };
+
+        bytes[0..4].copy_from_slice(&magic.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.record_id.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.part_index.to_le_bytes());
+        bytes[10..12].copy_from_slice(&self.total_parts.to_le_bytes());
+        bytes[12..14].copy_from_slice(&self.part_len.to_le_bytes());
+        bytes[14..16].copy_from_slice(&[0, 0]);
+
+        digest.update(&bytes[..CRC_OFFSET]);
+        digest.update(data);
+        let crc = digest.finalize();
+        bytes[CRC_OFFSET..PAGE_HEADER_BYTES].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Parses the header without checking its CRC, which needs the part's data
+    fn parse(bytes: &[u8; PAGE_HEADER_BYTES]) -> Option<PageHeader> {
+        let read_u16 = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+
+        let commit = match u32::from_le_bytes(bytes[0..4].try_into().ok()?) {
+            PART_MAGIC => false,
+            COMMIT_MAGIC => true,
+            _ => return None,
+        };
+
+        let header = PageHeader {
+            commit,
+            record_id: u32::from_le_bytes(bytes[4..8].try_into().ok()?),
+            part_index: read_u16(8),
+            total_parts: read_u16(10),
+            part_len: read_u16(12),
+        };
+
+        if header.part_len as usize > PART_DATA_BYTES || (commit && header.part_len != 0) {
+            return None;
+        }
+
+        Some(header)
+    }
+
+    fn crc_matches(bytes: &[u8; PAGE_HEADER_BYTES], data: &[u8], mut digest: Crc32) -> bool {
+        let stored_crc = u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]);
+
+        digest.update(&bytes[..CRC_OFFSET]);
+        digest.update(data);
+
+        stored_crc == digest.finalize()
+    }
+}
+
+/// A record being put back together while reading
+struct Span {
+    record_id: u32,
+    next_part: u16,
+    total_parts: u16,
+    len: usize,
+}
+
+pub struct SpanningRecordWriter {
+    first_block: u16,
+    block_count: u16,
+    next_page: u16,
+    next_record_id: u32,
+    method: ReadMethod,
+    retain: bool,
+    /// The ID of the newest record released
+    released: Option<u32>,
+    /// Good blocks from the next one records will be written into that `reclaim` has erased
+    erased_ahead: u16,
+}
+
+impl SpanningRecordWriter {
+    /// Opens the records kept in `block_count` blocks starting at `first_block`, finding where the
+    /// last session left off. At least two blocks are needed so a record never has to overwrite
+    /// itself.
+    pub fn mount<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        first_block: u16,
+        block_count: u16,
+        method: ReadMethod,
+    ) -> Result<SpanningRecordWriter, StorageError> {
+        if block_count < 2 || !Geometry::W25N01GV.contains_blocks(first_block, block_count) {
+            return Err(FlashError::OutOfBounds.into());
+        }
+
+        flash.check_block0(first_block, block_count)?;
+
+        let mut writer = SpanningRecordWriter {
+            first_block,
+            block_count,
+            next_page: Geometry::W25N01GV.block_first_page(first_block),
+            next_record_id: 0,
+            method,
+            retain: false,
+            released: None,
+            erased_ahead: 0,
+        };
+
+        // The newest block is the one whose first page is furthest along, by record ID and then by
+        // part index since one record can start several blocks
+        let mut newest: Option<(u16, PageHeader)> = None;
+        for block in first_block..first_block + block_count {
+            if flash.is_bad_block(block, method)? {
+                continue;
+            }
+
+            if let Some(header) =
+                writer.read_header(flash, Geometry::W25N01GV.block_first_page(block))?
+            {
+                let is_newer = match newest {
+                    Some((_, newest)) => {
+                        let id_delta = header.record_id.wrapping_sub(newest.record_id) as i32;
+                        id_delta > 0 || (id_delta == 0 && header.part_index > newest.part_index)
+                    }
+                    None => true,
+                };
+
+                if is_newer {
+                    newest = Some((block, header));
+                }
+            }
+        }
+
+        if let Some((block, first_header)) = newest {
+            let written_pages = flash.find_write_frontier(block, method)?;
+            let first_page = Geometry::W25N01GV.block_first_page(block);
+
+            // The newest ID is on the last page with a readable header. A power cut can leave the
+            // last page torn past reading, so work back from it, down to the first page if need be.
+            let mut newest_id = first_header.record_id;
+            for page_address in (first_page + 1..first_page + written_pages).rev() {
+                if let Some(header) = writer.read_header(flash, page_address)? {
+                    newest_id = header.record_id;
+                    break;
+                }
+            }
+            writer.next_record_id = newest_id.wrapping_add(1);
+
+            writer.next_page = if written_pages as usize == PAGES_PER_BLOCK {
+                Geometry::W25N01GV.block_first_page(writer.next_block(block))
+            } else {
+                first_page + written_pages
+            };
+        }
+
+        Ok(writer)
+    }
+
+    /// Sets whether records are kept until they're released rather than overwritten, see the
+    /// module docs
+    pub fn set_retention(&mut self, retain: bool) {
+        self.retain = retain;
+    }
+
+    /// Releases every record up to and including `record_id`, so `reclaim` can erase them
+    pub fn release_through(&mut self, record_id: u32) {
+        self.released = Some(record_id);
+    }
+
+    /// Returns `StorageError::StorageFull` if `write` couldn't fit a `payload_len` byte record,
+    /// either because it's too large for the region or because, with retention on, the pages it
+    /// needs still hold records. `reclaimable` is true if `reclaim` would make the room.
+    pub fn check_room<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        payload_len: usize,
+    ) -> Result<(), StorageError> {
+        let total_parts = payload_len.div_ceil(PART_DATA_BYTES);
+        let needed = total_parts as u32 + 1;
+        let full = |reclaimable| {
+            Err(StorageError::StorageFull {
+                region: self.first_block,
+                needed,
+                reclaimable,
+            })
+        };
+
+        if total_parts >= (self.block_count as usize - 1) * PAGES_PER_BLOCK {
+            // Older records are overwritten as needed, so this is the region's size, not its fill
+            return full(false);
+        }
+
+        if !self.retain {
+            return Ok(());
+        }
+
+        let head_block = Geometry::W25N01GV.block_of_page(self.next_page);
+        let head_pages = Geometry::W25N01GV.block_pages(head_block);
+        let (mut free, mut block, candidates) = if head_pages.start == self.next_page as u32 {
+            (0, head_block, self.block_count)
+        } else {
+            let left = head_pages.end - self.next_page as u32;
+            (left, self.next_block(head_block), self.block_count - 1)
+        };
+
+        let mut reclaimable = 0;
+        let mut erased_ahead = self.erased_ahead;
+        for _ in 0..candidates {
+            if free >= needed {
+                break;
+            }
+
+            if !flash.is_bad_block(block, self.method)? {
+                if erased_ahead > 0 {
+                    erased_ahead -= 1;
+                    free += PAGES_PER_BLOCK as u32;
+                } else {
+                    // `write` erases blocks that never held records on its way into them
+                    match self.block_records(flash, block)? {
+                        BlockRecords::None if reclaimable == 0 => free += PAGES_PER_BLOCK as u32,
+                        BlockRecords::Live => break,
+                        _ => reclaimable += PAGES_PER_BLOCK as u32,
+                    }
+                }
+            }
+
+            block = self.next_block(block);
+        }
+
+        if free >= needed {
+            Ok(())
+        } else {
+            full(free + reclaimable >= needed)
+        }
+    }
+
+    /// Erases the oldest good blocks of the region as long as they hold only released records,
+    /// and returns how many it erased. `write` fills them later without erasing them again.
+    pub fn reclaim<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: &W25N01GV<BUS, ReadMode>,
+        delay: &mut D,
+    ) -> Result<u16, StorageError> {
+        let head_block = Geometry::W25N01GV.block_of_page(self.next_page);
+        let (mut block, candidates) = if Geometry::W25N01GV.is_block_aligned(self.next_page as u32)
+        {
+            (head_block, self.block_count)
+        } else {
+            (self.next_block(head_block), self.block_count - 1)
+        };
+
+        let mut skipped = 0;
+        let mut reclaimed = 0;
+        for _ in 0..candidates {
+            if flash.is_bad_block(block, self.method)? {
+                block = self.next_block(block);
+                continue;
+            }
+
+            if skipped < self.erased_ahead {
+                skipped += 1;
+                block = self.next_block(block);
+                continue;
+            }
+
+            if self.block_records(flash, block)? == BlockRecords::Live {
+                break;
+            }
+
+            {
+                let _guard = flash.begin_operation()?;
+                flash.erase_block_unguarded(block, delay)?;
+            }
+            self.erased_ahead += 1;
+            reclaimed += 1;
+            block = self.next_block(block);
+        }
+
+        Ok(reclaimed)
+    }
+
+    /// Writes `payload` as the next record and returns its ID. The record only becomes visible to
+    /// `read_records` once this returns successfully. A record too large for the region, which
+    /// has to keep one block free, or with retention on one that would overwrite records, returns
+    /// `StorageError::StorageFull`, like `check_room`.
+    pub fn write<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        payload: &[u8],
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<(W25N01GV<BUS, ReadMode>, u32), StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        self.check_room(&flash, payload.len())?;
+
+        let total_parts = payload.len().div_ceil(PART_DATA_BYTES);
+
+        let record_id = self.next_record_id;
+        self.next_record_id = self.next_record_id.wrapping_add(1);
+
+        let mut flash = flash;
+        for (part_index, part) in payload.chunks(PART_DATA_BYTES).enumerate() {
+            let header = PageHeader {
+                commit: false,
+                record_id,
+                part_index: part_index as u16,
+                total_parts: total_parts as u16,
+                part_len: part.len() as u16,
+            };
+
+            flash = self.write_page(flash, &header, part, write_method, delay)?;
+        }
+
+        let commit = PageHeader {
+            commit: true,
+            record_id,
+            part_index: total_parts as u16,
+            total_parts: total_parts as u16,
+            part_len: 0,
+        };
+        flash = self.write_page(flash, &commit, &[], write_method, delay)?;
+
+        Ok((flash, record_id))
+    }
+
+    /// Reads every committed record, oldest first, passing each one's ID and data to `f`. `buffer`
+    /// has to be big enough for the largest record, records that don't fit are skipped. Parts of
+    /// records that were never committed are skipped by their headers alone, without reading
+    /// their data.
+    pub fn read_records<BUS: QspiBus, MODE, F>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        buffer: &mut [u8],
+        mut f: F,
+    ) -> Result<(), StorageError>
+    where
+        F: FnMut(u32, &[u8]),
+    {
+        let head_block = Geometry::W25N01GV.block_of_page(self.next_page);
+        // At the start of a block, the head block is whole and holds the oldest records
+        let head_is_whole = Geometry::W25N01GV.is_block_aligned(self.next_page as u32);
+        let mut block = if head_is_whole {
+            head_block
+        } else {
+            self.next_block(head_block)
+        };
+        let mut span: Option<Span> = None;
+
+        for _ in 0..self.block_count {
+            if !flash.is_bad_block(block, self.method)? {
+                let block_pages = Geometry::W25N01GV.block_pages(block);
+                let end_page = if block == head_block && !head_is_whole {
+                    self.next_page as u32
+                } else {
+                    block_pages.end
+                };
+
+                for page_address in block_pages.start..end_page {
+                    let mut header_bytes = [0_u8; PAGE_HEADER_BYTES];
+                    let header = match self.read_header_bytes(
+                        flash,
+                        page_address as u16,
+                        &mut header_bytes,
+                    )? {
+                        Some(header) => header,
+                        // The rest of the block was never written
+                        None if flash.classify_page(page_address as u16, self.method)?
+                            == PageClass::Erased =>
+                        {
+                            break
+                        }
+                        // A page torn by a power cut, which later records are written after
+                        None => {
+                            span = None;
+                            continue;
+                        }
+                    };
+
+                    span = self.continue_span(span, &header, buffer.len());
+
+                    if let Some(current) = span.as_mut() {
+                        if header.commit {
+                            if PageHeader::crc_matches(&header_bytes, &[], flash.crc32_digest()) {
+                                f(current.record_id, &buffer[..current.len]);
+                            }
+                            span = None;
+                            continue;
+                        }
+
+                        let data = &mut buffer[current.len..current.len + header.part_len as usize];
+                        flash.read_columns(
+                            Column::Physical(PAGE_HEADER_BYTES as u16),
+                            data,
+                            self.method,
+                        )?;
+
+                        if PageHeader::crc_matches(&header_bytes, data, flash.crc32_digest()) {
+                            current.len += header.part_len as usize;
+                            current.next_part += 1;
+                        } else {
+                            span = None;
+                        }
+                    }
+                }
+            }
+
+            block = self.next_block(block);
+        }
+
+        Ok(())
+    }
+
+    /// Decides whether a page carries on the record being read, starts a new one, or neither
+    fn continue_span(
+        &self,
+        span: Option<Span>,
+        header: &PageHeader,
+        capacity: usize,
+    ) -> Option<Span> {
+        let fits = |len: usize| len + header.part_len as usize <= capacity;
+
+        match span {
+            Some(span)
+                if span.record_id == header.record_id
+                    && span.next_part == header.part_index
+                    && span.total_parts == header.total_parts
+                    && (header.commit == (header.part_index == header.total_parts))
+                    && fits(span.len) =>
+            {
+                Some(span)
+            }
+            _ if header.part_index == 0
+                && (header.commit == (header.total_parts == 0))
+                && fits(0) =>
+            {
+                Some(Span {
+                    record_id: header.record_id,
+                    next_part: 0,
+                    total_parts: header.total_parts,
+                    len: 0,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether a block behind the write head holds records, and if it does whether the newest of
+    /// them, on its last page with a readable header, has been released
+    fn block_records<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        block: u16,
+    ) -> Result<BlockRecords, StorageError> {
+        let block_pages = Geometry::W25N01GV.block_pages(block);
+        let first_page = block_pages.start as u16;
+        if self.read_header(flash, first_page)?.is_none()
+            && flash.classify_page(first_page, self.method)? == PageClass::Erased
+        {
+            return Ok(BlockRecords::None);
+        }
+
+        for page_address in block_pages.rev() {
+            if let Some(header) = self.read_header(flash, page_address as u16)? {
+                return Ok(if is_released(self.released, header.record_id) {
+                    BlockRecords::Released
+                } else {
+                    BlockRecords::Live
+                });
+            }
+        }
+
+        // Written, but with nothing left that reads as a record
+        Ok(BlockRecords::Released)
+    }
+
+    /// Reads a page's header, returning None if the page isn't part of a record
+    fn read_header<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        page_address: u16,
+    ) -> Result<Option<PageHeader>, StorageError> {
+        let mut header_bytes = [0_u8; PAGE_HEADER_BYTES];
+
+        self.read_header_bytes(flash, page_address, &mut header_bytes)
+    }
+
+    fn read_header_bytes<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        page_address: u16,
+        header_bytes: &mut [u8; PAGE_HEADER_BYTES],
+    ) -> Result<Option<PageHeader>, StorageError> {
+        flash.read_memory_to_data_buffer(page_address)?;
+        flash.wait_while_busy()?;
+        flash.read_columns(Column::Physical(0), header_bytes, self.method)?;
+
+        Ok(PageHeader::parse(header_bytes))
+    }
+
+    /// Writes one page of a record at the next free page, moving on to the next good block (and
+    /// erasing it) when the current one is full
+    fn write_page<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        header: &PageHeader,
+        data: &[u8],
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        let mut flash = flash;
+        let mut good_block_found = false;
+
+        for _ in 0..self.block_count {
+            let block = Geometry::W25N01GV.block_of_page(self.next_page);
+            if !Geometry::W25N01GV.is_block_aligned(self.next_page as u32) {
+                good_block_found = true;
+                break;
+            }
+
+            if flash.is_bad_block(block, self.method)? {
+                self.next_page = Geometry::W25N01GV.block_first_page(self.next_block(block));
+                continue;
+            }
+
+            if self.erased_ahead > 0 {
+                self.erased_ahead -= 1;
+            } else {
+                flash = flash.erase_block(block, delay)?;
+            }
+            good_block_found = true;
+            break;
+        }
+
+        if !good_block_found {
+            return Err(StorageError::InsufficientGoodBlocks {
+                first_block: self.first_block,
+                good_blocks: 0,
+            });
+        }
+
+        let block = Geometry::W25N01GV.block_of_page(self.next_page);
+        let mut page = [0xFF_u8; PAGE_SIZE_BYTES];
+        header.write_to(&mut page[..PAGE_HEADER_BYTES], data, flash.crc32_digest());
+        page[PAGE_HEADER_BYTES..PAGE_HEADER_BYTES + data.len()].copy_from_slice(data);
+
+        if Geometry::W25N01GV.is_block_aligned(self.next_page as u32) {
+            BlockHeader {
+                kind: StructureKind::SpanningRecords,
+                region_id: 0,
+                sequence: header.record_id,
+            }
+            .write_into(&mut page, flash.crc32_digest())?;
+        }
+
+        let flash = flash.into_write_mode()?.write_page_split(
+            self.next_page,
+            &page,
+            &[],
+            write_method,
+            delay,
+        )?;
+
+        let block_pages = Geometry::W25N01GV.block_pages(block);
+        self.next_page = if self.next_page as u32 + 1 == block_pages.end {
+            Geometry::W25N01GV.block_first_page(self.next_block(block))
+        } else {
+            self.next_page + 1
+        };
+
+        Ok(flash)
+    }
+
+    fn next_block(&self, block: u16) -> u16 {
+        if block + 1 == self.first_block + self.block_count {
+            self.first_block
+        } else {
+            block + 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+
+    const FIRST_BLOCK: u16 = 10;
+
+    fn payload(seed: u8, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|index| (index as u8).wrapping_mul(7) ^ seed)
+            .collect()
+    }
+
+    fn mount(sim: &SimFlash) -> SpanningRecordWriter {
+        SpanningRecordWriter::mount(&sim.driver(), FIRST_BLOCK, 3, ReadMethod::FastRead).unwrap()
+    }
+
+    fn write(sim: &SimFlash, writer: &mut SpanningRecordWriter, payload: &[u8]) -> Option<u32> {
+        writer
+            .write(sim.driver(), payload, WriteMethod::QuadLoad, &mut NoDelay)
+            .ok()
+            .map(|(_, record_id)| record_id)
+    }
+
+    fn records(sim: &SimFlash) -> Vec<(u32, Vec<u8>)> {
+        let flash = sim.driver();
+        let mut buffer = vec![0; 64 * PART_DATA_BYTES];
+        let mut records = Vec::new();
+
+        mount(sim)
+            .read_records(&flash, &mut buffer, |record_id, data| {
+                records.push((record_id, data.to_vec()))
+            })
+            .unwrap();
+
+        records
+    }
+
+    #[test]
+    fn a_power_cut_at_any_page_of_a_three_page_record_hides_the_whole_record() {
+        // 60 parts and a commit page leave the three page record's parts on the last three pages
+        // of the first block and its commit page at the start of the next, after erasing it
+        let filler = payload(1, 60 * PART_DATA_BYTES);
+        let record = payload(2, 3 * PART_DATA_BYTES - 100);
+        let later = payload(3, 100);
+
+        let sim = SimFlash::new();
+        let mut writer = mount(&sim);
+        write(&sim, &mut writer, &filler).unwrap();
+        let before = sim.destructive_ops();
+        write(&sim, &mut writer, &record).unwrap();
+        let record_ops = sim.destructive_ops() - before;
+        assert_eq!(record_ops, 5);
+        assert!(records(&sim) == [(0, filler.clone()), (1, record.clone())]);
+
+        for torn in [false, true] {
+            for cut in 0..record_ops {
+                let sim = SimFlash::new();
+                let mut writer = mount(&sim);
+                write(&sim, &mut writer, &filler).unwrap();
+
+                sim.cut_power_after(cut, torn);
+                assert_eq!(write(&sim, &mut writer, &record), None);
+                sim.power_cycle();
+
+                // A commit page torn after its header made it still commits the record, since
+                // every part before it checks out. Anything earlier leaves no trace of it.
+                let mut expected = vec![(0, filler.clone())];
+                if torn && cut == record_ops - 1 {
+                    expected.push((1, record.clone()));
+                }
+                assert!(records(&sim) == expected, "cut {} torn {}", cut, torn);
+
+                // Writing carries on after whatever the cut left behind, without reusing an ID
+                let mut writer = mount(&sim);
+                let later_id = write(&sim, &mut writer, &later).unwrap();
+                assert!(later_id as usize >= expected.len());
+
+                expected.push((later_id, later.clone()));
+                assert!(records(&sim) == expected, "cut {} torn {}", cut, torn);
+            }
+        }
+    }
+
+    #[test]
+    fn a_last_page_torn_past_its_header_doesnt_restart_the_ids() {
+        let sim = SimFlash::new();
+        let mut writer = mount(&sim);
+        let first = payload(1, 10);
+        let second = payload(2, 10);
+        write(&sim, &mut writer, &first).unwrap();
+        write(&sim, &mut writer, &second).unwrap();
+
+        // Only the end of the next page got programmed before the power went out
+        let mut torn = [0xFF_u8; PAGE_SIZE_BYTES];
+        torn[PAGE_SIZE_BYTES - 64..].fill(0);
+        sim.set_page(Geometry::W25N01GV.block_first_page(FIRST_BLOCK) + 4, &torn);
+
+        let mut writer = mount(&sim);
+        let third = payload(3, 10);
+        assert_eq!(write(&sim, &mut writer, &third), Some(2));
+
+        assert_eq!(records(&sim), [(0, first), (1, second), (2, third)]);
+    }
+
+    #[test]
+    fn a_retained_region_fills_then_reclaims_released_blocks() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let mut writer =
+            SpanningRecordWriter::mount(&flash, FIRST_BLOCK, 2, ReadMethod::FastRead).unwrap();
+        writer.set_retention(true);
+
+        // A part and a commit page each, so 32 records to a block
+        let small = |id: u32| payload(id as u8, 10);
+        let full = |needed, reclaimable| {
+            Err(StorageError::StorageFull {
+                region: FIRST_BLOCK,
+                needed,
+                reclaimable,
+            })
+        };
+
+        for id in 0..64 {
+            writer.check_room(&flash, 10).unwrap();
+            let (next, written_id) = writer
+                .write(flash, &small(id), WriteMethod::QuadLoad, &mut NoDelay)
+                .unwrap();
+            assert_eq!(written_id, id);
+            flash = next;
+        }
+        assert_eq!(writer.check_room(&flash, 10), full(2, false));
+
+        // The oldest block still holds records 1 to 31
+        writer.release_through(0);
+        assert_eq!(writer.check_room(&flash, 10), full(2, false));
+        assert_eq!(writer.reclaim(&flash, &mut NoDelay), Ok(0));
+
+        writer.release_through(31);
+        assert_eq!(writer.check_room(&flash, 10), full(2, true));
+        assert_eq!(
+            writer.check_room(&flash, 3 * PART_DATA_BYTES),
+            full(4, true)
+        );
+        // No amount of reclaiming fits a record the size of the region
+        assert_eq!(
+            writer.check_room(&flash, 64 * PART_DATA_BYTES),
+            full(65, false)
+        );
+
+        sim.clear_log();
+        assert_eq!(writer.reclaim(&flash, &mut NoDelay), Ok(1));
+        assert_eq!(sim.count(0xD8), 1);
+
+        // Exactly one block's worth fits in the reclaimed block, which isn't erased again
+        sim.clear_log();
+        for id in 64..96 {
+            writer.check_room(&flash, 10).unwrap();
+            flash = writer
+                .write(flash, &small(id), WriteMethod::QuadLoad, &mut NoDelay)
+                .unwrap()
+                .0;
+        }
+        assert_eq!(sim.count(0xD8), 0);
+        assert_eq!(writer.check_room(&flash, 10), full(2, false));
+
+        let mut buffer = vec![0; PART_DATA_BYTES];
+        let mut read = Vec::new();
+        SpanningRecordWriter::mount(&flash, FIRST_BLOCK, 2, ReadMethod::FastRead)
+            .unwrap()
+            .read_records(&flash, &mut buffer, |id, data| {
+                read.push((id, data.to_vec()))
+            })
+            .unwrap();
+        assert!(read == (32..96).map(|id| (id, small(id))).collect::<Vec<_>>());
+
+        // Writing into a full region gives up the driver rather than erase records
+        assert_eq!(
+            writer
+                .write(flash, &small(96), WriteMethod::QuadLoad, &mut NoDelay)
+                .err(),
+            Some(StorageError::StorageFull {
+                region: FIRST_BLOCK,
+                needed: 2,
+                reclaimable: false,
+            })
+        );
+    }
+}
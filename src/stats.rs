@@ -0,0 +1,74 @@
+use crate::{status::ECCStatus, status::StatusRegister, FlashCommands, ReadMethod, W25N01GV};
+
+/// Cumulative counts of the operations sent to the device, useful for logging flash activity and
+/// estimating wear. Commands blocked by a dry run aren't counted.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stats {
+    /// Page Data Read commands (memory to data buffer)
+    pub reads: u32,
+    /// Program Execute commands (data buffer to memory)
+    pub writes: u32,
+    /// Block erases
+    pub erases: u32,
+    /// Bytes transferred out of the data buffer
+    pub bytes_read: u64,
+    /// Page reads that needed ECC correction
+    pub ecc_corrections: u32,
+}
+
+fn is_data_buffer_read(opcode: u8) -> bool {
+    opcode == FlashCommands::ReadData as u8
+        || opcode == ReadMethod::FastRead as u8
+        || opcode == ReadMethod::DualFastRead as u8
+        || opcode == ReadMethod::QuadFastRead as u8
+        || opcode == ReadMethod::FastReadDualIO as u8
+        || opcode == ReadMethod::FastReadQuadIO as u8
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    pub fn stats(&self) -> Stats {
+        self.stats.get()
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats.set(Stats::default());
+    }
+
+    /// Counts a write command that was sent to the device
+    pub(crate) fn record_write_command(&self, opcode: u8) {
+        let mut stats = self.stats.get();
+
+        if opcode == FlashCommands::PageDataRead as u8 {
+            stats.reads = stats.reads.wrapping_add(1);
+            // The ECC status of this read is counted by the first status read once it completes
+            self.ecc_status_pending.set(true);
+        } else if opcode == FlashCommands::ProgramExecute as u8 {
+            stats.writes = stats.writes.wrapping_add(1);
+        } else if opcode == FlashCommands::Erase128KBBlock as u8 {
+            stats.erases = stats.erases.wrapping_add(1);
+        }
+
+        self.stats.set(stats);
+    }
+
+    /// Counts a read command that was sent to the device
+    pub(crate) fn record_read_command(&self, opcode: u8, len: u32) {
+        if is_data_buffer_read(opcode) {
+            let mut stats = self.stats.get();
+            stats.bytes_read = stats.bytes_read.wrapping_add(len as u64);
+            self.stats.set(stats);
+        }
+    }
+
+    pub(crate) fn record_status_register(&self, status_register: &StatusRegister) {
+        if self.ecc_status_pending.get() && !status_register.device_busy {
+            self.ecc_status_pending.set(false);
+
+            if status_register.ecc_status == ECCStatus::CorrectedSuccessfully {
+                let mut stats = self.stats.get();
+                stats.ecc_corrections = stats.ecc_corrections.wrapping_add(1);
+                self.stats.set(stats);
+            }
+        }
+    }
+}
@@ -1,13 +1,13 @@
-use stm32l4xx_hal::qspi::{QspiMode, QspiReadCommand, QspiWriteCommand};
+use crate::{commands, EccMode, FlashError, QspiBus, W25N01GV};
 
-use crate::{FlashCommandError, FlashCommands, W25N01GV};
-
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ECCStatus {
     Successful,            // Data output is successful with no ECC correction
     CorrectedSuccessfully, // Data output is successful but had ECC correction for one or more pages
     SinglePageError,       // Data output had more errors than was fixable by ECC in a single page
     MultiPageError,        // Data output had more errors than was fixable by ECC in many pages
+    EccDisabled,           // ECC is disabled, so nothing is known about the data's integrity
 }
 
 impl ECCStatus {
@@ -28,7 +28,7 @@ impl ECCStatus {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ProtectionRegister {
     pub srp0: bool,
     pub bp3: bool,
@@ -40,7 +40,7 @@ pub struct ProtectionRegister {
     pub srp1: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct ConfigurationRegister {
     pub otp_l: bool,
     pub otp_e: bool,
@@ -65,9 +65,64 @@ pub struct StatusRegister {
     pub device_busy: bool,
 }
 
+/// How a single status register bit changed between two reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitChange {
+    Unchanged,
+    Set,     // The bit went from 0 to 1
+    Cleared, // The bit went from 1 to 0
+}
+
+impl BitChange {
+    fn between(before: bool, after: bool) -> BitChange {
+        match (before, after) {
+            (false, true) => BitChange::Set,
+            (true, false) => BitChange::Cleared,
+            _ => BitChange::Unchanged,
+        }
+    }
+}
+
+/// The difference between two status register snapshots. Useful for narrowing down exactly
+/// which step of an operation sequence latched a failure bit.
+#[derive(Debug)]
+pub struct StatusDelta {
+    pub bbm_lut_full: BitChange,
+    /// The (before, after) ECC status if it changed, otherwise None
+    pub ecc_status: Option<(ECCStatus, ECCStatus)>,
+    pub write_failure: BitChange,
+    pub erase_failure: BitChange,
+    pub write_enable_latch: BitChange,
+    pub device_busy: BitChange,
+}
+
+impl StatusDelta {
+    /// Returns true if any bit differs between the two snapshots
+    pub fn any_changed(&self) -> bool {
+        self.bbm_lut_full != BitChange::Unchanged
+            || self.ecc_status.is_some()
+            || self.write_failure != BitChange::Unchanged
+            || self.erase_failure != BitChange::Unchanged
+            || self.write_enable_latch != BitChange::Unchanged
+            || self.device_busy != BitChange::Unchanged
+    }
+}
+
 impl ProtectionRegister {
     const SAR_ADDRESS: u8 = 0xA0;
 
+    /// The protection register's power-on default, with the whole array protected
+    pub const DEFAULT: ProtectionRegister = ProtectionRegister {
+        srp0: false,
+        bp3: true,
+        bp2: true,
+        bp1: true,
+        bp0: true,
+        tb: true,
+        wpe: false,
+        srp1: false,
+    };
+
     const SRP0_BIT: u8 = 0x80;
     const BP3_BIT: u8 = 0x40;
     const BP2_BIT: u8 = 0x20;
@@ -77,7 +132,7 @@ impl ProtectionRegister {
     const WPE_BIT: u8 = 0x02;
     const SRP1_BIT: u8 = 0x01;
 
-    fn to_u8(&self) -> u8 {
+    fn to_u8(self) -> u8 {
         return if self.srp0 {
             ProtectionRegister::SRP0_BIT
         } else {
@@ -117,13 +172,23 @@ impl ProtectionRegister {
 impl ConfigurationRegister {
     const SAR_ADDRESS: u8 = 0xB0;
 
+    /// The configuration register's power-on default (for the -IG parts), with ECC enabled and
+    /// buffered read mode
+    pub const DEFAULT: ConfigurationRegister = ConfigurationRegister {
+        otp_l: false,
+        otp_e: false,
+        sr1_l: false,
+        ecc_e: true,
+        buf: true,
+    };
+
     const OTP_L_BIT: u8 = 0x80;
     const OTP_E_BIT: u8 = 0x40;
     const SR1_L_BIT: u8 = 0x20;
     const ECC_E_BIT: u8 = 0x10;
     const BUF_BIT: u8 = 0x08;
 
-    fn to_u8(&self) -> u8 {
+    fn to_u8(self) -> u8 {
         return if self.otp_l {
             ConfigurationRegister::OTP_L_BIT
         } else {
@@ -149,7 +214,7 @@ impl ConfigurationRegister {
 }
 
 impl StatusRegister {
-    const SAR_ADDRESS: u8 = 0xC0;
+    pub(crate) const SAR_ADDRESS: u8 = 0xC0;
 
     const BBMLUT_FULL_BIT: u8 = 0x40;
     const ECC1_STATUS_BIT: u8 = 0x20;
@@ -158,17 +223,59 @@ impl StatusRegister {
     const ERASE_FAILURE_BIT: u8 = 0x04;
     const WRITE_ENABLE_LATCH_BIT: u8 = 0x02;
     const BUSY_BIT: u8 = 0x01;
+    const RESERVED_BITS: u8 = 0x80;
+
+    pub(crate) fn from_u8(reg_value: u8) -> StatusRegister {
+        StatusRegister {
+            bbm_lut_full: reg_value & StatusRegister::BBMLUT_FULL_BIT != 0,
+            ecc_status: ECCStatus::from_bits(
+                reg_value & StatusRegister::ECC0_STATUS_BIT != 0,
+                reg_value & StatusRegister::ECC1_STATUS_BIT != 0,
+            ),
+            write_failure: reg_value & StatusRegister::PROGRAM_FAILURE_BIT != 0,
+            erase_failure: reg_value & StatusRegister::ERASE_FAILURE_BIT != 0,
+            write_enable_latch: reg_value & StatusRegister::WRITE_ENABLE_LATCH_BIT != 0,
+            device_busy: reg_value & StatusRegister::BUSY_BIT != 0,
+        }
+    }
+
+    /// Computes which bits changed going from `before` to this snapshot
+    pub fn delta_from(&self, before: &StatusRegister) -> StatusDelta {
+        StatusDelta {
+            bbm_lut_full: BitChange::between(before.bbm_lut_full, self.bbm_lut_full),
+            ecc_status: if before.ecc_status != self.ecc_status {
+                Some((before.ecc_status, self.ecc_status))
+            } else {
+                None
+            },
+            write_failure: BitChange::between(before.write_failure, self.write_failure),
+            erase_failure: BitChange::between(before.erase_failure, self.erase_failure),
+            write_enable_latch: BitChange::between(
+                before.write_enable_latch,
+                self.write_enable_latch,
+            ),
+            device_busy: BitChange::between(before.device_busy, self.device_busy),
+        }
+    }
 }
 
-impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), MODE> {
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
     pub fn write_protection_register(
         &self,
         protection_register: ProtectionRegister,
-    ) -> Result<(), FlashCommandError> {
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.write_protection_register_unguarded(protection_register)
+    }
+
+    pub(crate) fn write_protection_register_unguarded(
+        &self,
+        protection_register: ProtectionRegister,
+    ) -> Result<(), FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
@@ -176,47 +283,24 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
 
         let bytes = [ProtectionRegister::SAR_ADDRESS, protection_register.to_u8()];
 
-        let command = QspiWriteCommand {
-            instruction: Some((
-                FlashCommands::WriteStatusRegister as u8,
-                QspiMode::SingleChannel,
-            )),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 0,
-            data: Some((&bytes, QspiMode::SingleChannel)),
-            double_data_rate: false,
-        };
+        let command = commands::write_status_register(&bytes);
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
+        if let Err(err) = self.qspi_write(command) {
+            Err(err)
         } else {
             Ok(())
         }
     }
 
-    pub fn read_protection_register(&self) -> Result<ProtectionRegister, FlashCommandError> {
-        let mut reg_value = [0_u8; 1];
-        let addr = [ProtectionRegister::SAR_ADDRESS];
-
-        let command = QspiReadCommand {
-            instruction: Some((
-                FlashCommands::ReadStatusRegister as u8,
-                QspiMode::SingleChannel,
-            )),
-            address: None,
-            alternative_bytes: Some((&addr, QspiMode::SingleChannel)),
-            dummy_cycles: 0,
-            data_mode: QspiMode::SingleChannel,
-            receive_length: 1,
-            double_data_rate: false,
-        };
-
-        if let Err(err) = self.qspi.transfer(command, &mut reg_value) {
-            return Err(FlashCommandError::from_qspi_error(err));
-        }
+    pub fn read_protection_register(&self) -> Result<ProtectionRegister, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_protection_register_unguarded()
+    }
 
-        let reg_value = reg_value[0];
+    pub(crate) fn read_protection_register_unguarded(
+        &self,
+    ) -> Result<ProtectionRegister, FlashError> {
+        let reg_value = self.read_register_byte(ProtectionRegister::SAR_ADDRESS)?;
 
         let protection_register = ProtectionRegister {
             srp0: reg_value & ProtectionRegister::SRP0_BIT != 0,
@@ -235,62 +319,58 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
     pub fn write_configuration_register(
         &self,
         configuration_register: ConfigurationRegister,
-    ) -> Result<(), FlashCommandError> {
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.write_configuration_register_unguarded(configuration_register)
+    }
+
+    pub(crate) fn write_configuration_register_unguarded(
+        &self,
+        configuration_register: ConfigurationRegister,
+    ) -> Result<(), FlashError> {
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
         }
 
+        // ECC pinned off stays off whatever the caller asked for
+        let mut configuration_register = configuration_register;
+        if self.ecc_mode == EccMode::Disabled {
+            configuration_register.ecc_e = false;
+        }
+
         let bytes = [
             ConfigurationRegister::SAR_ADDRESS,
             configuration_register.to_u8(),
         ];
 
-        let command = QspiWriteCommand {
-            instruction: Some((
-                FlashCommands::WriteStatusRegister as u8,
-                QspiMode::SingleChannel,
-            )),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 0,
-            data: Some((&bytes, QspiMode::SingleChannel)),
-            double_data_rate: false,
-        };
+        let command = commands::write_status_register(&bytes);
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
+        // Whatever ECC and read mode state was cached may no longer hold, the next use reads it
+        // back
+        self.ecc_enabled.set(None);
+        self.buffer_mode.set(None);
+
+        if let Err(err) = self.qspi_write(command) {
+            Err(err)
         } else {
             Ok(())
         }
     }
 
-    pub fn read_configuration_register(&self) -> Result<ConfigurationRegister, FlashCommandError> {
-        let mut reg_value = [0_u8; 1];
-        let addr = [ConfigurationRegister::SAR_ADDRESS];
-
-        let command = QspiReadCommand {
-            instruction: Some((
-                FlashCommands::ReadStatusRegister as u8,
-                QspiMode::SingleChannel,
-            )),
-            address: None,
-            alternative_bytes: Some((&addr, QspiMode::SingleChannel)),
-            dummy_cycles: 0,
-            data_mode: QspiMode::SingleChannel,
-            receive_length: 1,
-            double_data_rate: false,
-        };
-
-        if let Err(err) = self.qspi.transfer(command, &mut reg_value) {
-            return Err(FlashCommandError::from_qspi_error(err));
-        }
+    pub fn read_configuration_register(&self) -> Result<ConfigurationRegister, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_configuration_register_unguarded()
+    }
 
-        let reg_value = reg_value[0];
+    pub(crate) fn read_configuration_register_unguarded(
+        &self,
+    ) -> Result<ConfigurationRegister, FlashError> {
+        let reg_value = self.read_register_byte(ConfigurationRegister::SAR_ADDRESS)?;
 
         let configuration_register = ConfigurationRegister {
             otp_l: reg_value & ConfigurationRegister::OTP_L_BIT != 0,
@@ -300,44 +380,129 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
             buf: reg_value & ConfigurationRegister::BUF_BIT != 0,
         };
 
+        self.ecc_enabled.set(Some(configuration_register.ecc_e));
+        self.buffer_mode.set(Some(configuration_register.buf));
+        self.state_unverified.set(false);
+
         Ok(configuration_register)
     }
 
-    pub fn read_status_register(&self) -> Result<StatusRegister, FlashCommandError> {
+    pub fn read_status_register(&self) -> Result<StatusRegister, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.read_status_register_unguarded()
+    }
+
+    pub(crate) fn read_status_register_unguarded(&self) -> Result<StatusRegister, FlashError> {
+        let reg_value = self.read_register_byte(StatusRegister::SAR_ADDRESS)?;
+        let status_register = self.decode_status_register(reg_value);
+
+        self.record_status_register(&status_register);
+        #[cfg(feature = "latency-histograms")]
+        self.record_busy_status(status_register.device_busy);
+
+        Ok(status_register)
+    }
+
+    /// Decodes a status register value, reporting `ECCStatus::EccDisabled` when ECC is known to be
+    /// off since the ECC bits don't mean anything then
+    fn decode_status_register(&self, reg_value: u8) -> StatusRegister {
+        let mut status_register = StatusRegister::from_u8(reg_value);
+        if self.ecc_known_disabled() {
+            status_register.ecc_status = ECCStatus::EccDisabled;
+        }
+
+        status_register
+    }
+
+    /// Reads just the BUSY bit of the status register. This is the polling primitive every wait
+    /// loop and busy check uses, since it skips decoding the rest of the register.
+    ///
+    /// Any command sent while data is being clocked out in continuous read mode ends the read, so
+    /// only poll before starting a read or after its transfer has finished. Every read in the
+    /// driver is a single QSPI transfer, so the driver itself never polls part way through one.
+    pub fn is_busy(&self) -> Result<bool, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.is_busy_unguarded()
+    }
+
+    pub(crate) fn is_busy_unguarded(&self) -> Result<bool, FlashError> {
+        let reg_value = self.read_register_byte(StatusRegister::SAR_ADDRESS)?;
+
+        // The first status read after a page read is where its ECC status gets counted
+        if self.ecc_status_pending.get() {
+            self.record_status_register(&self.decode_status_register(reg_value));
+        }
+
+        let busy = reg_value & StatusRegister::BUSY_BIT != 0;
+        #[cfg(feature = "latency-histograms")]
+        self.record_busy_status(busy);
+
+        Ok(busy)
+    }
+
+    /// Reads the status register and reports which bits changed relative to an earlier snapshot
+    pub fn status_delta(&self, before: &StatusRegister) -> Result<StatusDelta, FlashError> {
+        let _guard = self.begin_operation()?;
+
+        match self.read_status_register_unguarded() {
+            Ok(status_register) => Ok(status_register.delta_from(before)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// A cheap liveness probe. Reads the status registers and checks the values could plausibly
+    /// have come from a W25N01GV, returning `FlashError::NoDeviceDetected` if not.
+    ///
+    /// A bus with nothing driving it usually reads back as all 1s or all 0s. All 1s is caught by
+    /// the reserved bit of the status register, which always reads as 0 on a real chip. The
+    /// protection register is allowed to read as all 1s since a fully protected chip is valid.
+    /// All 0s is only treated as missing if the protection, configuration, and status registers
+    /// all read 0, which a real chip only does when it's unprotected with ECC disabled and in
+    /// continuous read mode. Use `get_jedec_id` if that configuration needs to be supported.
+    pub fn ping(&self) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        let status = self.read_register_byte(StatusRegister::SAR_ADDRESS)?;
+        if status & StatusRegister::RESERVED_BITS != 0 {
+            return Err(FlashError::NoDeviceDetected);
+        }
+
+        let protection = self.read_register_byte(ProtectionRegister::SAR_ADDRESS)?;
+        let configuration = self.read_register_byte(ConfigurationRegister::SAR_ADDRESS)?;
+        if status == 0 && protection == 0 && configuration == 0 {
+            return Err(FlashError::NoDeviceDetected);
+        }
+
+        Ok(())
+    }
+
+    /// Reads a single status register byte. Like the other status register reads this doesn't
+    /// check if the device is busy, since it's what the busy check itself uses.
+    fn read_register_byte(&self, sar_address: u8) -> Result<u8, FlashError> {
         let mut reg_value = [0_u8; 1];
-        let addr = [StatusRegister::SAR_ADDRESS];
-
-        let command = QspiReadCommand {
-            instruction: Some((
-                FlashCommands::ReadStatusRegister as u8,
-                QspiMode::SingleChannel,
-            )),
-            address: None,
-            alternative_bytes: Some((&addr, QspiMode::SingleChannel)),
-            dummy_cycles: 0,
-            data_mode: QspiMode::SingleChannel,
-            receive_length: 1,
-            double_data_rate: false,
-        };
+        let addr = [sar_address];
+
+        let command = commands::read_status_register(&addr);
 
-        if let Err(err) = self.qspi.transfer(command, &mut reg_value) {
-            return Err(FlashCommandError::from_qspi_error(err));
+        if let Err(err) = self.qspi_transfer(command, &mut reg_value) {
+            Err(err)
+        } else {
+            Ok(reg_value[0])
         }
+    }
 
-        let reg_value = reg_value[0];
+    /// Writes the protection and configuration registers back to their power-on defaults. The
+    /// OTP lock and protection register lock bits are permanent, so if either is set nothing is
+    /// written and `FlashError::RegisterLocked` is returned.
+    pub fn reset_configuration(&self) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
 
-        let status_register = StatusRegister {
-            bbm_lut_full: reg_value & StatusRegister::BBMLUT_FULL_BIT != 0,
-            ecc_status: ECCStatus::from_bits(
-                reg_value & StatusRegister::ECC0_STATUS_BIT != 0,
-                reg_value & StatusRegister::ECC1_STATUS_BIT != 0,
-            ),
-            write_failure: reg_value & StatusRegister::PROGRAM_FAILURE_BIT != 0,
-            erase_failure: reg_value & StatusRegister::ERASE_FAILURE_BIT != 0,
-            write_enable_latch: reg_value & StatusRegister::WRITE_ENABLE_LATCH_BIT != 0,
-            device_busy: reg_value & StatusRegister::BUSY_BIT != 0,
-        };
+        let configuration_register = self.read_configuration_register_unguarded()?;
+        if configuration_register.otp_l || configuration_register.sr1_l {
+            return Err(FlashError::RegisterLocked);
+        }
 
-        Ok(status_register)
+        self.write_protection_register_unguarded(ProtectionRegister::DEFAULT)?;
+        self.write_configuration_register_unguarded(ConfigurationRegister::DEFAULT)
     }
 }
@@ -0,0 +1,610 @@
+//! Updating two regions so that either both updates survive a power cut or neither does, e.g. a
+//! key-value entry and the log head pointer that refers to it.
+//!
+//! Each region takes part by implementing `TwoPhaseParticipant`. The application hands each
+//! participant its update, then `TwoPhase::run` goes through the sequence:
+//!
+//! 1. Both participants `stage` their update somewhere it doesn't replace the current data yet,
+//!    marked pending under the transaction number.
+//! 2. A commit record holding the transaction number is programmed into the coordinator's own
+//!    block. This single page program is the point the transaction takes effect.
+//! 3. Both participants `commit`, flipping their pending update to valid.
+//!
+//! After a power cut, `TwoPhase::recover` asks each participant for a pending transaction with
+//! `recover_pending` and completes it if its commit record made it to the device, or has the
+//! participant `rollback` otherwise. Call it after `mount` and before the next `run`.
+//!
+//! Commit records are one page each, a magic number, the transaction number, and a CRC-32 at
+//! column 0, so a record torn by power loss fails its CRC and counts as never written. When the
+//! block fills up it's erased and reused from its first page, which is only safe because every
+//! transaction before the current one has already been completed or rolled back.
+//!
+//! The erase also takes the newest transaction number with it until the next record is written.
+//! A power cut in between leaves the block empty and `mount` starting again from 0, but the
+//! participants have already staged the transaction by then, so `recover` finds it pending and
+//! carries on numbering after it. That's the other reason to always `recover` before `run`.
+
+use core::convert::TryInto;
+
+use hal::blocking::delay::DelayUs;
+
+use crate::{
+    block_header::{BlockHeader, StructureKind},
+    digest::Crc32,
+    Column, FlashError, Geometry, QspiBus, ReadMethod, ReadMode, StorageError, WriteMethod,
+    BLOCK_COUNT, PAGES_PER_BLOCK, PAGE_SIZE_BYTES, W25N01GV,
+};
+
+const COMMIT_RECORD_MAGIC: u32 = 0x5450_4331;
+/// Magic, transaction, and CRC
+const COMMIT_RECORD_BYTES: usize = 12;
+
+/// A region that can take part in a `TwoPhase` transaction, see the module docs. The update
+/// itself is handed to the participant beforehand through its own API.
+pub trait TwoPhaseParticipant {
+    /// Writes the update somewhere it doesn't replace the current data yet, marked pending under
+    /// `transaction`
+    fn stage<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        transaction: u32,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>;
+
+    /// Makes the update staged under `transaction` the valid one. Must be safe to repeat, since a
+    /// power cut can interrupt it.
+    fn commit<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        transaction: u32,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>;
+
+    /// Discards the update staged under `transaction`, leaving the data as it was. Must be safe to
+    /// repeat, since a power cut can interrupt it.
+    fn rollback<BUS: QspiBus, D: DelayUs<u32>>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        transaction: u32,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>;
+
+    /// The transaction of an update that was staged but neither committed nor rolled back, if any
+    fn recover_pending<BUS: QspiBus, MODE>(
+        &mut self,
+        flash: &W25N01GV<BUS, MODE>,
+    ) -> Result<Option<u32>, StorageError>;
+}
+
+/// What `TwoPhase::recover` did with each participant's pending transaction
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TwoPhaseRecovery {
+    /// Nothing was pending
+    None,
+    /// The commit record was found, so the transaction was completed
+    Completed(u32),
+    /// There was no commit record, so the transaction was rolled back
+    RolledBack(u32),
+}
+
+/// Coordinates transactions over two participants, keeping its commit records in one block
+pub struct TwoPhase {
+    block: u16,
+    /// How many pages of the block hold records, torn ones included
+    written_pages: u16,
+    next_transaction: u32,
+}
+
+fn parse_record(bytes: &[u8], digest: Crc32) -> Option<u32> {
+    let magic = u32::from_le_bytes(bytes[0..4].try_into().ok()?);
+    let transaction = u32::from_le_bytes(bytes[4..8].try_into().ok()?);
+    let crc = u32::from_le_bytes(bytes[8..12].try_into().ok()?);
+
+    if magic == COMMIT_RECORD_MAGIC && crc == digest.checksum(&bytes[..8]) {
+        Some(transaction)
+    } else {
+        None
+    }
+}
+
+impl TwoPhase {
+    /// Opens the coordinator whose commit records are kept in `block`, carrying on numbering
+    /// transactions from the newest record
+    pub fn mount<BUS: QspiBus, MODE>(
+        flash: &W25N01GV<BUS, MODE>,
+        block: u16,
+        method: ReadMethod,
+    ) -> Result<TwoPhase, StorageError> {
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds.into());
+        }
+        flash.check_block0(block, 1)?;
+
+        let mut two_phase = TwoPhase {
+            block,
+            written_pages: flash.find_write_frontier(block, method)?,
+            next_transaction: 0,
+        };
+
+        let mut newest = None;
+        two_phase.for_each_record(flash, method, |transaction| {
+            let is_newer = match newest {
+                Some(newest) => transaction.wrapping_sub(newest) as i32 > 0,
+                None => true,
+            };
+
+            if is_newer {
+                newest = Some(transaction);
+            }
+
+            false
+        })?;
+
+        if let Some(newest) = newest {
+            two_phase.next_transaction = newest.wrapping_add(1);
+        }
+
+        Ok(two_phase)
+    }
+
+    /// Completes or rolls back whatever each participant has pending, see the module docs. Later
+    /// transactions are numbered after any that were pending.
+    pub fn recover<BUS: QspiBus, A, B, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        first: &mut A,
+        second: &mut B,
+        method: ReadMethod,
+        delay: &mut D,
+    ) -> Result<(W25N01GV<BUS, ReadMode>, [TwoPhaseRecovery; 2]), StorageError>
+    where
+        A: TwoPhaseParticipant,
+        B: TwoPhaseParticipant,
+        D: DelayUs<u32>,
+    {
+        let first_pending = first.recover_pending(&flash)?;
+        let second_pending = second.recover_pending(&flash)?;
+        for transaction in first_pending.iter().chain(second_pending.iter()) {
+            if transaction.wrapping_sub(self.next_transaction) as i32 >= 0 {
+                self.next_transaction = transaction.wrapping_add(1);
+            }
+        }
+
+        let (flash, first_action) = match first_pending {
+            Some(transaction) if self.is_committed(&flash, transaction, method)? => (
+                first.commit(flash, transaction, delay)?,
+                TwoPhaseRecovery::Completed(transaction),
+            ),
+            Some(transaction) => (
+                first.rollback(flash, transaction, delay)?,
+                TwoPhaseRecovery::RolledBack(transaction),
+            ),
+            None => (flash, TwoPhaseRecovery::None),
+        };
+
+        let (flash, second_action) = match second_pending {
+            Some(transaction) if self.is_committed(&flash, transaction, method)? => (
+                second.commit(flash, transaction, delay)?,
+                TwoPhaseRecovery::Completed(transaction),
+            ),
+            Some(transaction) => (
+                second.rollback(flash, transaction, delay)?,
+                TwoPhaseRecovery::RolledBack(transaction),
+            ),
+            None => (flash, TwoPhaseRecovery::None),
+        };
+
+        Ok((flash, [first_action, second_action]))
+    }
+
+    /// Applies the updates handed to `first` and `second` as one transaction, returning its
+    /// number. If this returns an error before the commit record was written, `recover` rolls the
+    /// transaction back, and after it, `recover` completes it.
+    pub fn run<BUS: QspiBus, A, B, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        first: &mut A,
+        second: &mut B,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<(W25N01GV<BUS, ReadMode>, u32), StorageError>
+    where
+        A: TwoPhaseParticipant,
+        B: TwoPhaseParticipant,
+        D: DelayUs<u32>,
+    {
+        let transaction = self.next_transaction;
+
+        let flash = first.stage(flash, transaction, delay)?;
+        let flash = second.stage(flash, transaction, delay)?;
+
+        let flash = self.write_record(flash, transaction, write_method, delay)?;
+        self.next_transaction = transaction.wrapping_add(1);
+
+        let flash = first.commit(flash, transaction, delay)?;
+        let flash = second.commit(flash, transaction, delay)?;
+
+        Ok((flash, transaction))
+    }
+
+    /// The number the next transaction will be given
+    pub fn next_transaction(&self) -> u32 {
+        self.next_transaction
+    }
+
+    fn write_record<BUS: QspiBus, D>(
+        &mut self,
+        flash: W25N01GV<BUS, ReadMode>,
+        transaction: u32,
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, StorageError>
+    where
+        D: DelayUs<u32>,
+    {
+        let flash = if self.written_pages as usize == PAGES_PER_BLOCK {
+            let flash = flash.erase_block(self.block, delay)?;
+            self.written_pages = 0;
+            flash
+        } else {
+            flash
+        };
+
+        let mut page = [0xFF_u8; PAGE_SIZE_BYTES];
+        page[0..4].copy_from_slice(&COMMIT_RECORD_MAGIC.to_le_bytes());
+        page[4..8].copy_from_slice(&transaction.to_le_bytes());
+        let crc = flash.crc32_digest().checksum(&page[..8]);
+        page[8..COMMIT_RECORD_BYTES].copy_from_slice(&crc.to_le_bytes());
+        if self.written_pages == 0 {
+            BlockHeader {
+                kind: StructureKind::CommitRecords,
+                region_id: 0,
+                sequence: transaction,
+            }
+            .write_into(&mut page, flash.crc32_digest())?;
+        }
+
+        let page_address = Geometry::W25N01GV.block_first_page(self.block) + self.written_pages;
+        // Counted before programming, so a failed program isn't programmed over
+        self.written_pages += 1;
+
+        let flash = flash.into_write_mode()?.write_page_split(
+            page_address,
+            &page,
+            &[],
+            write_method,
+            delay,
+        )?;
+
+        Ok(flash)
+    }
+
+    fn is_committed<BUS: QspiBus, MODE>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        transaction: u32,
+        method: ReadMethod,
+    ) -> Result<bool, StorageError> {
+        let mut found = false;
+        self.for_each_record(flash, method, |record| {
+            found = record == transaction;
+            found
+        })?;
+
+        Ok(found)
+    }
+
+    /// Calls `f` with each intact record's transaction, stopping early once `f` returns true
+    fn for_each_record<BUS: QspiBus, MODE, F>(
+        &self,
+        flash: &W25N01GV<BUS, MODE>,
+        method: ReadMethod,
+        mut f: F,
+    ) -> Result<(), StorageError>
+    where
+        F: FnMut(u32) -> bool,
+    {
+        let first_page = Geometry::W25N01GV.block_first_page(self.block);
+        let mut record = [0_u8; COMMIT_RECORD_BYTES];
+
+        for page_address in first_page..first_page + self.written_pages {
+            flash.read_memory_to_data_buffer(page_address)?;
+            flash.wait_while_busy()?;
+            flash.read_columns(Column::Physical(0), &mut record, method)?;
+
+            if let Some(transaction) = parse_record(&record, flash.crc32_digest()) {
+                if f(transaction) {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+
+    const COMMIT_BLOCK: u16 = 20;
+
+    const STAGED: u8 = 1;
+    const COMMITTED: u8 = 2;
+    const ROLLED_BACK: u8 = 3;
+
+    /// A participant holding one value, keeping a journal of stage, commit and rollback pages in
+    /// its own block
+    struct Slot {
+        block: u16,
+        next_value: u32,
+    }
+
+    /// What a `Slot`'s journal says
+    struct SlotState {
+        value: u32,
+        pending: Option<(u32, u32)>,
+        next_page: u16,
+    }
+
+    impl Slot {
+        fn read<BUS: QspiBus, MODE>(
+            &self,
+            flash: &W25N01GV<BUS, MODE>,
+        ) -> Result<SlotState, StorageError> {
+            let mut state = SlotState {
+                value: 0,
+                pending: None,
+                next_page: Geometry::W25N01GV.block_first_page(self.block),
+            };
+
+            for page_address in Geometry::W25N01GV.block_pages(self.block) {
+                let mut entry = [0_u8; 9];
+                flash.read_memory_to_data_buffer(page_address as u16)?;
+                flash.wait_while_busy()?;
+                flash.read_columns(Column::Physical(0), &mut entry, ReadMethod::FastRead)?;
+                if entry == [0xFF; 9] {
+                    break;
+                }
+                state.next_page += 1;
+
+                let transaction = u32::from_le_bytes(entry[1..5].try_into().unwrap());
+                let value = u32::from_le_bytes(entry[5..9].try_into().unwrap());
+                match (entry[0], state.pending) {
+                    (STAGED, _) => state.pending = Some((transaction, value)),
+                    (COMMITTED, Some((pending, value))) if pending == transaction => {
+                        state.value = value;
+                        state.pending = None;
+                    }
+                    (ROLLED_BACK, Some((pending, _))) if pending == transaction => {
+                        state.pending = None
+                    }
+                    _ => {}
+                }
+            }
+
+            Ok(state)
+        }
+
+        fn append<BUS: QspiBus, D: DelayUs<u32>>(
+            &self,
+            flash: W25N01GV<BUS, ReadMode>,
+            kind: u8,
+            transaction: u32,
+            value: u32,
+            delay: &mut D,
+        ) -> Result<W25N01GV<BUS, ReadMode>, StorageError> {
+            let page_address = self.read(&flash)?.next_page;
+
+            let mut page = [0xFF_u8; PAGE_SIZE_BYTES];
+            page[0] = kind;
+            page[1..5].copy_from_slice(&transaction.to_le_bytes());
+            page[5..9].copy_from_slice(&value.to_le_bytes());
+
+            let flash = flash.into_write_mode()?.write_page_split(
+                page_address,
+                &page,
+                &[],
+                WriteMethod::QuadLoad,
+                delay,
+            )?;
+
+            Ok(flash)
+        }
+    }
+
+    impl TwoPhaseParticipant for Slot {
+        fn stage<BUS: QspiBus, D: DelayUs<u32>>(
+            &mut self,
+            flash: W25N01GV<BUS, ReadMode>,
+            transaction: u32,
+            delay: &mut D,
+        ) -> Result<W25N01GV<BUS, ReadMode>, StorageError> {
+            self.append(flash, STAGED, transaction, self.next_value, delay)
+        }
+
+        fn commit<BUS: QspiBus, D: DelayUs<u32>>(
+            &mut self,
+            flash: W25N01GV<BUS, ReadMode>,
+            transaction: u32,
+            delay: &mut D,
+        ) -> Result<W25N01GV<BUS, ReadMode>, StorageError> {
+            self.append(flash, COMMITTED, transaction, 0, delay)
+        }
+
+        fn rollback<BUS: QspiBus, D: DelayUs<u32>>(
+            &mut self,
+            flash: W25N01GV<BUS, ReadMode>,
+            transaction: u32,
+            delay: &mut D,
+        ) -> Result<W25N01GV<BUS, ReadMode>, StorageError> {
+            self.append(flash, ROLLED_BACK, transaction, 0, delay)
+        }
+
+        fn recover_pending<BUS: QspiBus, MODE>(
+            &mut self,
+            flash: &W25N01GV<BUS, MODE>,
+        ) -> Result<Option<u32>, StorageError> {
+            Ok(self
+                .read(flash)?
+                .pending
+                .map(|(transaction, _)| transaction))
+        }
+    }
+
+    fn slots() -> (Slot, Slot) {
+        (
+            Slot {
+                block: 21,
+                next_value: 0,
+            },
+            Slot {
+                block: 22,
+                next_value: 0,
+            },
+        )
+    }
+
+    /// Runs a transaction setting both slots to `value`
+    fn run(
+        sim: &SimFlash,
+        two_phase: &mut TwoPhase,
+        (first, second): &mut (Slot, Slot),
+        value: u32,
+    ) -> Result<u32, StorageError> {
+        first.next_value = value;
+        second.next_value = value;
+
+        two_phase
+            .run(
+                sim.driver(),
+                first,
+                second,
+                WriteMethod::QuadLoad,
+                &mut NoDelay,
+            )
+            .map(|(_, transaction)| transaction)
+    }
+
+    /// Mounts and recovers as after a reboot, returning both slots' values and whether anything
+    /// was pending
+    fn reboot(sim: &SimFlash, slots: &mut (Slot, Slot)) -> (TwoPhase, [u32; 2], bool) {
+        let flash = sim.driver();
+        let mut two_phase = TwoPhase::mount(&flash, COMMIT_BLOCK, ReadMethod::FastRead).unwrap();
+        let (_, recovered) = two_phase
+            .recover(
+                flash,
+                &mut slots.0,
+                &mut slots.1,
+                ReadMethod::FastRead,
+                &mut NoDelay,
+            )
+            .unwrap();
+
+        let flash = sim.driver();
+        let values = [
+            slots.0.read(&flash).unwrap().value,
+            slots.1.read(&flash).unwrap().value,
+        ];
+
+        let pending = recovered != [TwoPhaseRecovery::None; 2];
+
+        (two_phase, values, pending)
+    }
+
+    /// Sets up a commit block holding `records` records, and both slots holding 1 from the last
+    fn prepare(records: u32) -> (SimFlash, TwoPhase, (Slot, Slot)) {
+        let sim = SimFlash::new();
+        let mut slots = slots();
+        let mut two_phase =
+            TwoPhase::mount(&sim.driver(), COMMIT_BLOCK, ReadMethod::FastRead).unwrap();
+
+        for transaction in 0..records - 1 {
+            two_phase
+                .write_record(
+                    sim.driver(),
+                    transaction,
+                    WriteMethod::QuadLoad,
+                    &mut NoDelay,
+                )
+                .unwrap();
+        }
+        two_phase.next_transaction = records - 1;
+        run(&sim, &mut two_phase, &mut slots, 1).unwrap();
+
+        (sim, two_phase, slots)
+    }
+
+    /// Cuts the power at every program and erase of a transaction setting both slots to 2,
+    /// checking that both slots end up with the same value and no transaction number comes back
+    fn cut_everywhere(records: u32) {
+        let (sim, mut two_phase, mut slots) = prepare(records);
+        let before = sim.destructive_ops();
+        run(&sim, &mut two_phase, &mut slots, 2).unwrap();
+        let ops = sim.destructive_ops() - before;
+
+        for torn in [false, true] {
+            for cut in 0..ops {
+                let (sim, mut two_phase, mut slots) = prepare(records);
+                sim.cut_power_after(cut, torn);
+                assert!(run(&sim, &mut two_phase, &mut slots, 2).is_err());
+                sim.power_cycle();
+
+                let (mut two_phase, values, pending) = reboot(&sim, &mut slots);
+                assert!(
+                    values == [1, 1] || values == [2, 2],
+                    "cut {} torn {}: {:?}",
+                    cut,
+                    torn,
+                    values
+                );
+
+                // The interrupted transaction was `records`. Once it has left a trace anywhere, the
+                // next one is numbered after it, otherwise its number is free to use again.
+                let expected = if pending || values == [2, 2] {
+                    records + 1
+                } else {
+                    records
+                };
+                let transaction = run(&sim, &mut two_phase, &mut slots, 3).unwrap();
+                assert_eq!(transaction, expected, "cut {} torn {}", cut, torn);
+                assert_eq!(reboot(&sim, &mut slots).1, [3, 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn a_power_cut_anywhere_in_a_transaction_keeps_both_updates_or_neither() {
+        cut_everywhere(1);
+    }
+
+    #[test]
+    fn a_power_cut_while_reusing_the_commit_block_keeps_the_numbering() {
+        // The interrupted transaction's record goes at the start of the erased commit block
+        cut_everywhere(PAGES_PER_BLOCK as u32);
+    }
+
+    #[test]
+    fn transactions_apply_to_both_participants() {
+        let (sim, mut two_phase, mut slots) = prepare(1);
+        assert_eq!(run(&sim, &mut two_phase, &mut slots, 7), Ok(1));
+
+        let (two_phase, values, pending) = reboot(&sim, &mut slots);
+        assert_eq!(values, [7, 7]);
+        assert!(!pending);
+        assert_eq!(two_phase.next_transaction(), 2);
+
+        let records: Vec<u16> = sim
+            .commands()
+            .iter()
+            .filter(|command| command.opcode == 0x10)
+            .filter_map(|command| command.page_address())
+            .filter(|page| Geometry::W25N01GV.block_of_page(*page) == COMMIT_BLOCK)
+            .collect();
+        assert_eq!(records.len(), 2);
+    }
+}
@@ -0,0 +1,365 @@
+//! How hard the driver checks that a page programmed correctly.
+//!
+//! The level is set once for the whole driver with `set_verification_level` and applies to every
+//! write path that waits for its program to finish: `commit` and the methods built on it
+//! (`write_page_split`, `write_block_header`, and the allocator, layouts, log sink, spanning
+//! records and two phase commits that write through them), `program_page`, `program_otp_page`,
+//! and `NorFlashAdapter`. `commit_with` overrides it for a single page.
+//!
+//! `write_data_buffer_to_memory` only sends the Program Execute and returns, so there's nothing
+//! for it to check, and the endurance test checks every page itself. The async driver has no
+//! verification level and always checks the program failure bit.
+//!
+//! The readback levels read the main area of the data buffer over QSPI before programming and
+//! read the page back afterwards, so they need the device in buffer read mode (BUF=1, the
+//! default). They compare the main area only, since the spare area's ECC bytes are filled in by
+//! the device while programming.
+
+use crate::{digest::StreamingDigest, FlashError, QspiBus, ReadMethod, PAGE_SIZE_BYTES, W25N01GV};
+
+/// How much of the main area is read over QSPI at a time when reading it back
+const CRC_CHUNK_BYTES: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum VerificationLevel {
+    /// Waits for the program to finish without checking anything
+    None,
+    /// Checks the status register's program failure bit, the default
+    CheckFailureBits,
+    /// Also reads the page back and compares it byte for byte against what was programmed. Needs
+    /// a page sized buffer on the stack.
+    ReadbackCompare,
+    /// Also reads the page back and compares a CRC-32 of it against one of what was programmed.
+    /// Costs the same bus time as `ReadbackCompare` without the page sized buffer.
+    ReadbackCrc,
+}
+
+/// What was captured from the data buffer before programming, to check the page against. Only ever
+/// lives on the stack for the length of a commit, so the size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Expected {
+    Nothing,
+    Bytes([u8; PAGE_SIZE_BYTES]),
+    Crc(u32),
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Sets the verification level every write path uses, see the module docs
+    pub fn set_verification_level(&mut self, level: VerificationLevel) {
+        self.verification_level = level;
+    }
+
+    pub fn verification_level(&self) -> VerificationLevel {
+        self.verification_level
+    }
+}
+
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    /// Reads whatever `level` needs from the data buffer before it's programmed
+    pub(crate) fn capture_expected(
+        &self,
+        level: VerificationLevel,
+    ) -> Result<Expected, FlashError> {
+        match level {
+            VerificationLevel::None | VerificationLevel::CheckFailureBits => Ok(Expected::Nothing),
+            VerificationLevel::ReadbackCompare => {
+                let mut bytes = [0_u8; PAGE_SIZE_BYTES];
+                self.read_physical_columns(0, &mut bytes, ReadMethod::FastRead)?;
+
+                Ok(Expected::Bytes(bytes))
+            }
+            VerificationLevel::ReadbackCrc => Ok(Expected::Crc(self.data_buffer_crc()?)),
+        }
+    }
+
+    /// Reads the programmed page back and checks it against what was captured
+    pub(crate) fn check_expected(
+        &self,
+        page_address: u16,
+        expected: &Expected,
+    ) -> Result<(), FlashError> {
+        let matches = match expected {
+            Expected::Nothing => return Ok(()),
+            Expected::Bytes(bytes) => {
+                self.read_memory_to_data_buffer_unguarded(page_address)?;
+                self.wait_while_busy_unguarded()?;
+
+                let mut chunk = [0_u8; CRC_CHUNK_BYTES];
+                let mut matches = true;
+                for (index, expected_chunk) in bytes.chunks(CRC_CHUNK_BYTES).enumerate() {
+                    let column = (index * CRC_CHUNK_BYTES) as u16;
+                    self.read_physical_columns(column, &mut chunk, ReadMethod::FastRead)?;
+                    matches &= chunk[..] == *expected_chunk;
+                }
+
+                matches
+            }
+            Expected::Crc(crc) => {
+                self.read_memory_to_data_buffer_unguarded(page_address)?;
+                self.wait_while_busy_unguarded()?;
+
+                self.data_buffer_crc()? == *crc
+            }
+        };
+
+        if !matches {
+            return Err(FlashError::VerifyFailed { page_address });
+        }
+
+        Ok(())
+    }
+
+    fn data_buffer_crc(&self) -> Result<u32, FlashError> {
+        let mut chunk = [0_u8; CRC_CHUNK_BYTES];
+        let mut digest = self.crc32_digest();
+
+        for column in (0..PAGE_SIZE_BYTES).step_by(CRC_CHUNK_BYTES) {
+            self.read_physical_columns(column as u16, &mut chunk, ReadMethod::FastRead)?;
+            digest.update(&chunk);
+        }
+
+        Ok(digest.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec;
+    use std::vec::Vec;
+
+    use super::*;
+    use crate::sim::{NoDelay, SimFlash};
+    use crate::{
+        BlockHeader, FlashLogSink, Geometry, LoadMode, ReadMode, StorageError, StructureKind,
+        WriteMethod,
+    };
+
+    type Driver = W25N01GV<SimFlash, ReadMode>;
+
+    const LEVELS: [VerificationLevel; 4] = [
+        VerificationLevel::None,
+        VerificationLevel::CheckFailureBits,
+        VerificationLevel::ReadbackCompare,
+        VerificationLevel::ReadbackCrc,
+    ];
+
+    /// A write path and the page it programs, in the OTP area if `otp`
+    struct Layer {
+        name: &'static str,
+        page_address: u16,
+        otp: bool,
+        write: fn(&SimFlash, Driver) -> Result<(), FlashError>,
+    }
+
+    /// The storage layers only fail here because the driver underneath did
+    fn driver_error(err: StorageError) -> FlashError {
+        err.flash_error().unwrap()
+    }
+
+    fn pattern() -> [u8; PAGE_SIZE_BYTES] {
+        let mut page = [0_u8; PAGE_SIZE_BYTES];
+        for (index, byte) in page.iter_mut().enumerate() {
+            *byte = (index % 251) as u8 | 0x01;
+        }
+
+        page
+    }
+
+    fn layers() -> Vec<Layer> {
+        #[cfg_attr(not(feature = "nor-flash"), allow(unused_mut))]
+        let mut layers = vec![
+            Layer {
+                name: "commit",
+                page_address: 130,
+                otp: false,
+                write: |_, flash| {
+                    let flash = flash.into_write_mode()?;
+                    flash.load_to_data_buffer(
+                        &pattern(),
+                        0,
+                        WriteMethod::QuadLoad,
+                        LoadMode::ResetThenLoad,
+                    )?;
+                    flash.commit(130, &mut NoDelay).map(drop)
+                },
+            },
+            Layer {
+                name: "write_page_split",
+                page_address: 131,
+                otp: false,
+                write: |_, flash| {
+                    flash
+                        .into_write_mode()?
+                        .write_page_split(131, &pattern(), &[], WriteMethod::QuadLoad, &mut NoDelay)
+                        .map(drop)
+                },
+            },
+            Layer {
+                name: "program_page",
+                page_address: 132,
+                otp: false,
+                write: |_, flash| {
+                    let (_, write_failure) =
+                        flash.program_page(132, &pattern(), 0, WriteMethod::QuadLoad)?;
+                    if write_failure {
+                        return Err(FlashError::ProgramFailed { page_address: 132 });
+                    }
+
+                    Ok(())
+                },
+            },
+            Layer {
+                name: "write_block_header",
+                page_address: Geometry::W25N01GV.block_first_page(3),
+                otp: false,
+                write: |_, flash| {
+                    let header = BlockHeader {
+                        kind: StructureKind::Log,
+                        region_id: 0,
+                        sequence: 7,
+                    };
+                    flash
+                        .into_write_mode()?
+                        .write_block_header(3, &header, WriteMethod::QuadLoad, &mut NoDelay)
+                        .map(drop)
+                        .map_err(driver_error)
+                },
+            },
+            Layer {
+                name: "log sink",
+                page_address: Geometry::W25N01GV.block_first_page(4),
+                otp: false,
+                write: |sim, flash| {
+                    let mut sink = FlashLogSink::mount(&flash, 4, 2, ReadMethod::FastRead)
+                        .map_err(driver_error)?;
+                    assert!(sink.push(1, 2, b"verified"));
+
+                    sim.clear_log();
+                    sink.pump(flash, WriteMethod::QuadLoad, &mut NoDelay)
+                        .map(drop)
+                        .map_err(driver_error)
+                },
+            },
+            Layer {
+                name: "program_otp_page",
+                page_address: 2,
+                otp: true,
+                write: |_, flash| {
+                    flash
+                        .into_write_mode()?
+                        .program_otp_page(0, &pattern(), WriteMethod::QuadLoad)
+                },
+            },
+        ];
+
+        #[cfg(feature = "nor-flash")]
+        layers.push(Layer {
+            name: "NorFlashAdapter",
+            page_address: 133,
+            otp: false,
+            write: |_, flash| {
+                use embedded_storage::nor_flash::NorFlash;
+
+                let mut adapter = crate::NorFlashAdapter::new(
+                    flash,
+                    NoDelay,
+                    ReadMethod::FastRead,
+                    WriteMethod::QuadLoad,
+                );
+                adapter.write(133 * PAGE_SIZE_BYTES as u32, &pattern())
+            },
+        });
+
+        layers
+    }
+
+    fn driver_at(sim: &SimFlash, level: VerificationLevel) -> Driver {
+        let mut flash = sim.driver();
+        flash.set_verification_level(level);
+
+        flash
+    }
+
+    fn is_readback(level: VerificationLevel) -> bool {
+        matches!(
+            level,
+            VerificationLevel::ReadbackCompare | VerificationLevel::ReadbackCrc
+        )
+    }
+
+    #[test]
+    fn every_layer_reads_the_page_back_only_at_the_readback_levels() {
+        for layer in layers() {
+            for level in LEVELS {
+                let sim = SimFlash::new();
+                let flash = driver_at(&sim, level);
+                sim.clear_log();
+
+                (layer.write)(&sim, flash).unwrap();
+
+                // Page Data Reads after the program, the only time the layer reads a page
+                let expected = if is_readback(level) { 1 } else { 0 };
+                assert_eq!(sim.count(0x13), expected, "{} at {:?}", layer.name, level);
+            }
+        }
+    }
+
+    #[test]
+    fn every_layer_checks_the_failure_bit_unless_the_level_is_none() {
+        for layer in layers() {
+            for level in LEVELS {
+                let sim = SimFlash::new();
+                if layer.otp {
+                    sim.fail_otp_program(layer.page_address);
+                } else {
+                    sim.fail_program(layer.page_address);
+                }
+
+                let result = (layer.write)(&sim, driver_at(&sim, level));
+
+                if level == VerificationLevel::None {
+                    assert_eq!(result, Ok(()), "{}", layer.name);
+                } else {
+                    let expected = FlashError::ProgramFailed {
+                        page_address: layer.page_address,
+                    };
+                    assert_eq!(result, Err(expected), "{} at {:?}", layer.name, level);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_layer_catches_a_bad_readback_at_the_readback_levels() {
+        for layer in layers() {
+            for level in LEVELS {
+                let sim = SimFlash::new();
+
+                // Clears every bit of the page as it's programmed, as if it were worn out
+                let device = sim.clone();
+                let (page_address, otp) = (layer.page_address, layer.otp);
+                sim.set_hook(move |command| {
+                    if command.opcode == 0x10 && command.page_address() == Some(page_address) {
+                        if otp {
+                            device.set_otp_page(page_address, &[0; PAGE_SIZE_BYTES]);
+                        } else {
+                            device.set_page(page_address, &[0; PAGE_SIZE_BYTES]);
+                        }
+                    }
+                });
+
+                let result = (layer.write)(&sim, driver_at(&sim, level));
+
+                if is_readback(level) {
+                    let expected = FlashError::VerifyFailed {
+                        page_address: layer.page_address,
+                    };
+                    assert_eq!(result, Err(expected), "{} at {:?}", layer.name, level);
+                } else {
+                    assert_eq!(result, Ok(()), "{} at {:?}", layer.name, level);
+                }
+            }
+        }
+    }
+}
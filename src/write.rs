@@ -1,8 +1,10 @@
-use core::marker::PhantomData;
+use hal::blocking::delay::DelayUs;
 
-use stm32l4xx_hal::qspi::{QspiMode, QspiWriteCommand};
-
-use crate::{FlashCommandError, FlashCommands, ReadMode, WriteMode, W25N01GV};
+use crate::{
+    bus::QspiMode, column::check_buffer_end, commands, verification::VerificationLevel,
+    FlashCommands, FlashError, FlashEventKind, Geometry, QspiBus, ReadMode, WriteMode, BLOCK_COUNT,
+    PAGE_SIZE_BYTES, PAGE_SIZE_WITH_ECC_BYTES, SPARE_BYTES, W25N01GV,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub enum WriteMethod {
@@ -13,15 +15,15 @@ pub enum WriteMethod {
 }
 
 impl WriteMethod {
-    fn dummy_cycles(&self) -> u8 {
+    pub(crate) fn dummy_cycles(&self) -> u8 {
         0
     }
 
-    fn address_mode(&self) -> QspiMode {
+    pub(crate) fn address_mode(&self) -> QspiMode {
         QspiMode::SingleChannel
     }
 
-    fn data_mode(&self) -> QspiMode {
+    pub(crate) fn data_mode(&self) -> QspiMode {
         match self {
             WriteMethod::SingleLoad => QspiMode::SingleChannel,
             WriteMethod::RandomSingleLoad => QspiMode::SingleChannel,
@@ -29,179 +31,583 @@ impl WriteMethod {
             WriteMethod::RandomQuadLoad => QspiMode::QuadChannel,
         }
     }
-}
 
-impl<CLK, NCS, IO0, IO1, IO2, IO3> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), ReadMode> {
-    pub fn into_write_mode(
-        self,
-    ) -> Result<W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), WriteMode>, FlashCommandError> {
-        match self.check_busy() {
-            Ok(busy) => {
-                if busy {
-                    return Err(FlashCommandError::DeviceBusy);
-                }
+    /// The variant of this method that resets the rest of the data buffer to 0xFF
+    pub(crate) fn resetting(&self) -> WriteMethod {
+        match self {
+            WriteMethod::SingleLoad | WriteMethod::RandomSingleLoad => WriteMethod::SingleLoad,
+            WriteMethod::QuadLoad | WriteMethod::RandomQuadLoad => WriteMethod::QuadLoad,
+        }
+    }
+
+    /// The variant of this method that leaves the rest of the data buffer untouched
+    pub(crate) fn random(&self) -> WriteMethod {
+        match self {
+            WriteMethod::SingleLoad | WriteMethod::RandomSingleLoad => {
+                WriteMethod::RandomSingleLoad
             }
-            Err(err) => return Err(err),
+            WriteMethod::QuadLoad | WriteMethod::RandomQuadLoad => WriteMethod::RandomQuadLoad,
         }
+    }
+}
 
-        let command = QspiWriteCommand {
-            instruction: Some((FlashCommands::EnableWrite as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 0,
-            data: None,
-            double_data_rate: false,
-        };
+/// Controls what happens to the parts of the data buffer a load doesn't cover
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadMode {
+    /// Fills the whole data buffer with 0xFF before loading, so any columns the load doesn't
+    /// cover program as erased
+    ResetThenLoad,
+    /// Keeps the previous contents of the data buffer, used for chaining several loads into one
+    /// page before programming it
+    PreserveAndLoad,
+}
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
-        } else {
-            Ok(W25N01GV {
-                _marker: PhantomData {},
-                qspi: self.qspi,
-            })
+fn is_program_data_load(opcode: u8) -> bool {
+    opcode == WriteMethod::SingleLoad as u8
+        || opcode == WriteMethod::RandomSingleLoad as u8
+        || opcode == WriteMethod::QuadLoad as u8
+        || opcode == WriteMethod::RandomQuadLoad as u8
+}
+
+impl<BUS, MODE> W25N01GV<BUS, MODE> {
+    /// Is true if data has been loaded into the data buffer but not programmed yet, i.e. dropping
+    /// the driver now would lose it
+    pub fn has_pending_program(&self) -> bool {
+        self.pending_program.get()
+    }
+
+    /// Tracks loads and programs for `has_pending_program`
+    pub(crate) fn record_pending_program(&self, opcode: u8) {
+        if is_program_data_load(opcode) {
+            self.pending_program.set(true);
+        } else if opcode == FlashCommands::ProgramExecute as u8 {
+            self.pending_program.set(false);
         }
     }
 }
 
-impl<CLK, NCS, IO0, IO1, IO2, IO3> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), WriteMode> {
-    pub fn into_read_mode(
-        self,
-    ) -> Result<W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), ReadMode>, FlashCommandError> {
+/// The steps behind the mode changing commands, for wrappers like `NorFlashAdapter` that keep
+/// the driver in one mode and can't give it up to a failed command
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
+    pub(crate) fn send_write_enable(&self) -> Result<(), FlashError> {
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
+        }
+
+        self.qspi_write(commands::write_enable())
+    }
+
+    pub(crate) fn send_block_erase(&self, page_address: u16) -> Result<(), FlashError> {
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
+        }
+
+        let bytes = page_address.to_be_bytes();
+
+        self.qspi_write(commands::block_erase(&bytes))?;
+        self.verify_submission()
+    }
+
+    pub(crate) fn send_program_execute(&self, page_address: u16) -> Result<(), FlashError> {
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
+        }
+
+        self.check_nop_budget(page_address)?;
+
+        let bytes = page_address.to_be_bytes();
+
+        self.qspi_write(commands::program_execute(&bytes))?;
+        self.verify_submission()
+    }
+
+    pub(crate) fn send_write_disable(&self) -> Result<(), FlashError> {
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
+        }
+
+        self.qspi_write(commands::write_disable())
+    }
+
+    pub(crate) fn load_to_data_buffer_unguarded(
+        &self,
+        bytes: &[u8],
+        starting_address: u16,
+        write_method: WriteMethod,
+        load_mode: LoadMode,
+    ) -> Result<(), FlashError> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        check_buffer_end(starting_address, bytes.len())?;
+
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
         }
 
-        let command = QspiWriteCommand {
-            instruction: Some((FlashCommands::DisableWrite as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 0,
-            data: None,
-            double_data_rate: false,
+        let write_method = match load_mode {
+            LoadMode::ResetThenLoad => write_method.resetting(),
+            LoadMode::PreserveAndLoad => write_method.random(),
         };
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
-        } else {
-            Ok(W25N01GV {
-                _marker: PhantomData {},
-                qspi: self.qspi,
-            })
+        self.load_split(starting_address, bytes, write_method)?;
+
+        self.verify_load(starting_address, bytes)
+    }
+
+    pub(crate) fn erase_block_unguarded<D: DelayUs<u32>>(
+        &self,
+        block: u16,
+        delay: &mut D,
+    ) -> Result<(), FlashError> {
+        if block as usize >= BLOCK_COUNT {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.check_latency_budget(self.erase_worst_case_us())?;
+
+        let page_address = Geometry::W25N01GV.block_first_page(block);
+
+        self.send_write_enable()?;
+        self.send_block_erase(page_address)?;
+        self.wait_while_busy_with_delay_unguarded(delay)?;
+
+        if self.read_status_register_unguarded()?.erase_failure {
+            return Err(FlashError::EraseFailed { page_address });
         }
+
+        Ok(())
     }
 
-    /// Erases a 128KB block within the block of the specified page. The W25N01GVxxIG/IT has 65,536
-    /// pages of 2048 bytes each. Memory is erasable in groups of 64 pages (one group being a block).
-    pub fn erase_128kb_block(
+    pub(crate) fn commit_with_unguarded<D: DelayUs<u32>>(
+        &self,
+        page_address: u16,
+        level: VerificationLevel,
+        delay: &mut D,
+    ) -> Result<(), FlashError> {
+        self.check_latency_budget(self.program_worst_case_us(level))?;
+
+        let expected = self.capture_expected(level)?;
+
+        self.send_program_execute(page_address)?;
+        self.wait_while_busy_with_delay_unguarded(delay)?;
+
+        if level != VerificationLevel::None && self.read_status_register_unguarded()?.write_failure
+        {
+            return Err(FlashError::ProgramFailed { page_address });
+        }
+
+        self.check_expected(page_address, &expected)
+    }
+}
+
+impl<BUS: QspiBus> W25N01GV<BUS, ReadMode> {
+    pub fn into_write_mode(self) -> Result<W25N01GV<BUS, WriteMode>, FlashError> {
+        let guard = self.begin_operation()?;
+
+        self.send_write_enable()?;
+
+        drop(guard);
+        Ok(self.into_mode())
+    }
+
+    /// Sets the write enable latch, loads `data` into the data buffer from `column` on (resetting
+    /// the rest of it), programs the buffer into the page, and waits for the program to finish.
+    /// Returns the driver back in read mode along with whether the device reported a program
+    /// failure.
+    ///
+    /// The page is verified at the driver's verification level like `commit`, except that a
+    /// reported program failure is returned rather than an error. At `VerificationLevel::None` the
+    /// status register isn't read, so it's always false.
+    pub fn program_page(
         self,
         page_address: u16,
-    ) -> Result<W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), ReadMode>, FlashCommandError> {
-        match self.check_busy() {
-            Ok(busy) => {
-                if busy {
-                    return Err(FlashCommandError::DeviceBusy);
-                }
-            }
-            Err(err) => return Err(err),
+        data: &[u8],
+        column: u16,
+        method: WriteMethod,
+    ) -> Result<(Self, bool), FlashError> {
+        let guard = self.begin_operation()?;
+
+        let level = self.verification_level;
+        self.check_latency_budget(self.program_page_worst_case_us(data.len() as u32, method))?;
+
+        self.send_write_enable()?;
+        self.load_to_data_buffer_unguarded(data, column, method, LoadMode::ResetThenLoad)?;
+        let expected = self.capture_expected(level)?;
+
+        self.send_program_execute(page_address)?;
+        self.wait_while_busy_unguarded()?;
+
+        let write_failure = level != VerificationLevel::None
+            && self.read_status_register_unguarded()?.write_failure;
+        if !write_failure {
+            self.check_expected(page_address, &expected)?;
         }
 
-        let bytes = page_address.to_be_bytes();
+        drop(guard);
+        Ok((self, write_failure))
+    }
 
-        let command = QspiWriteCommand {
-            instruction: Some((
-                FlashCommands::Erase128KBBlock as u8,
-                QspiMode::SingleChannel,
-            )),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 8,
-            data: Some((&bytes, QspiMode::SingleChannel)),
-            double_data_rate: false,
-        };
+    /// Erases a block (block index, not page address), waits for the erase to finish, and returns
+    /// `FlashError::EraseFailed` if the device reports a failure.
+    pub fn erase_block<D: DelayUs<u32>>(
+        self,
+        block: u16,
+        delay: &mut D,
+    ) -> Result<Self, FlashError> {
+        let guard = self.begin_operation()?;
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
-        } else {
-            Ok(W25N01GV {
-                _marker: PhantomData {},
-                qspi: self.qspi,
-            })
+        self.erase_block_unguarded(block, delay)?;
+
+        drop(guard);
+        Ok(self)
+    }
+
+    /// Erases every block holding a page from `start_page` to `end_page` (inclusive), one block at
+    /// a time, stopping at the first block that fails to erase. Block 0 is skipped while the
+    /// block 0 policy reserves it.
+    pub fn erase_range<D: DelayUs<u32>>(
+        self,
+        start_page: u16,
+        end_page: u16,
+        delay: &mut D,
+    ) -> Result<Self, FlashError> {
+        self.erase_range_chunked(start_page, end_page, delay, || {})
+    }
+
+    /// Like `erase_range`, but calls `between` after each block is erased. A full chip erase
+    /// takes several seconds, so this is where to pet a watchdog.
+    pub fn erase_range_chunked<D, F>(
+        self,
+        start_page: u16,
+        end_page: u16,
+        delay: &mut D,
+        mut between: F,
+    ) -> Result<Self, FlashError>
+    where
+        D: DelayUs<u32>,
+        F: FnMut(),
+    {
+        let guard = self.begin_operation()?;
+
+        if start_page > end_page {
+            return Err(FlashError::OutOfBounds);
         }
+
+        let first_block = Geometry::W25N01GV.block_of_page(start_page);
+        let last_block = Geometry::W25N01GV.block_of_page(end_page);
+
+        let block_count = (last_block - first_block) as u32 + 1;
+        self.check_latency_budget(self.erase_worst_case_us().saturating_mul(block_count))?;
+
+        for block in first_block..=last_block {
+            if block == 0 && self.block0_reserved() {
+                continue;
+            }
+
+            self.erase_block_unguarded(block, delay)?;
+            between();
+        }
+
+        drop(guard);
+        Ok(self)
     }
+}
+
+impl<BUS: QspiBus> W25N01GV<BUS, WriteMode> {
+    pub fn into_read_mode(self) -> Result<W25N01GV<BUS, ReadMode>, FlashError> {
+        let guard = self.begin_operation()?;
 
+        self.send_write_disable()?;
+
+        drop(guard);
+        Ok(self.into_mode())
+    }
+
+    /// Erases a 128KB block within the block of the specified page. The W25N01GVxxIG/IT has
+    /// 65,536 pages of 2048 bytes each. Memory is erasable in groups of 64 pages (one group being
+    /// a block).
+    pub fn erase_128kb_block(
+        self,
+        page_address: u16,
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError> {
+        let guard = self.begin_operation()?;
+
+        self.send_block_erase(page_address)?;
+
+        drop(guard);
+        Ok(self.into_mode())
+    }
+
+    /// Loads `bytes` into the data buffer starting at column `starting_address`. The load mode
+    /// decides whether the rest of the buffer is reset, so the write method only selects between
+    /// single and quad data lines (e.g. `QuadLoad` with `PreserveAndLoad` issues a random quad
+    /// load).
+    ///
+    /// Loads themselves don't count against a page's partial program budget, only the Program
+    /// Execute that follows them does.
+    ///
+    /// Empty `bytes` does nothing and sends nothing, even with `LoadMode::ResetThenLoad`, since the
+    /// QSPI peripheral rejects a zero length data phase.
     pub fn load_to_data_buffer(
         &self,
         bytes: &[u8],
         starting_address: u16,
         write_method: WriteMethod,
-    ) -> Result<(), FlashCommandError> {
-        match self.check_busy() {
-            Ok(busy) => {
-                if busy {
-                    return Err(FlashCommandError::DeviceBusy);
-                }
-            }
-            Err(err) => return Err(err),
+        load_mode: LoadMode,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.load_to_data_buffer_unguarded(bytes, starting_address, write_method, load_mode)
+    }
+
+    /// Loads as much of `bytes` as fits between `start_column` and the end of the data buffer,
+    /// returning how many bytes were loaded. The load never wraps back around to column 0, so
+    /// anything past the end of the buffer is left for the caller to load into another page. The
+    /// write method is used as is, i.e. the random methods keep the rest of the buffer. Empty
+    /// `bytes` loads nothing and returns 0.
+    pub fn load_wrapping(
+        &self,
+        bytes: &[u8],
+        start_column: u16,
+        write_method: WriteMethod,
+    ) -> Result<usize, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.load_wrapping_unguarded(bytes, start_column, write_method)
+    }
+
+    pub(crate) fn load_wrapping_unguarded(
+        &self,
+        bytes: &[u8],
+        start_column: u16,
+        write_method: WriteMethod,
+    ) -> Result<usize, FlashError> {
+        if start_column as usize >= PAGE_SIZE_WITH_ECC_BYTES {
+            return Err(FlashError::OutOfBounds);
         }
 
-        let command = QspiWriteCommand {
-            instruction: Some((write_method as u8, write_method.address_mode())),
-            address: Some((starting_address.to_be() as u32, QspiMode::SingleChannel)),
-            alternative_bytes: None,
-            dummy_cycles: write_method.dummy_cycles(),
-            data: Some((bytes, write_method.data_mode())),
-            double_data_rate: false,
-        };
+        let len = bytes
+            .len()
+            .min(PAGE_SIZE_WITH_ECC_BYTES - start_column as usize);
+        if len == 0 {
+            return Ok(0);
+        }
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
-        } else {
-            Ok(())
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
         }
+
+        self.load_split(start_column, &bytes[..len], write_method)?;
+        self.verify_load(start_column, &bytes[..len])?;
+
+        Ok(len)
     }
 
+    /// Loads `bytes` from `start_column` to the end of the data buffer and the rest from column 0
+    /// on, the wrap the datasheet describes for loads past column 2111. It's done as two loads
+    /// rather than left to the device, since not every revision wraps. The write method is used as
+    /// is for the first load and the second keeps the buffer. `bytes` can be at most a whole
+    /// buffer long, so the wrapped part never reaches `start_column`.
+    pub fn load_wrapping_to_start(
+        &self,
+        bytes: &[u8],
+        start_column: u16,
+        write_method: WriteMethod,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
+        if start_column as usize >= PAGE_SIZE_WITH_ECC_BYTES
+            || bytes.len() > PAGE_SIZE_WITH_ECC_BYTES
+        {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let loaded = self.load_wrapping_unguarded(bytes, start_column, write_method)?;
+        let rest = &bytes[loaded..];
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        self.load_split(0, rest, write_method.random())?;
+        self.verify_load(0, rest)
+    }
+
+    /// Programs the data buffer into the page with a Program Execute. Each page can only be
+    /// programmed `nop::MAX_PAGE_PROGRAMS` times between erases, going over that returns
+    /// `FlashError::PartialProgramBudgetExceeded` unless `override_nop_budget` was called.
+    ///
+    /// This is the bare command: it returns without waiting for the program to finish, so nothing
+    /// is checked whatever the verification level. Use `commit` to wait and verify.
     pub fn write_data_buffer_to_memory(
         self,
         page_address: u16,
-    ) -> Result<W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), ReadMode>, FlashCommandError> {
-        match self.check_busy() {
-            Ok(busy) => {
-                if busy {
-                    return Err(FlashCommandError::DeviceBusy);
-                }
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError> {
+        let guard = self.begin_operation()?;
+
+        self.send_program_execute(page_address)?;
+
+        drop(guard);
+        Ok(self.into_mode())
+    }
+
+    /// Programs the data buffer into the page, waits for the program to finish, and verifies it
+    /// at the driver's verification level. By default that returns
+    /// `FlashError::ProgramFailed` if the device reports a failure.
+    pub fn commit<D: DelayUs<u32>>(
+        self,
+        page_address: u16,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError> {
+        let level = self.verification_level;
+        self.commit_with(page_address, level, delay)
+    }
+
+    /// Like `commit`, but verifies at `level` instead of the driver's verification level
+    pub fn commit_with<D: DelayUs<u32>>(
+        self,
+        page_address: u16,
+        level: VerificationLevel,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError> {
+        let guard = self.begin_operation()?;
+
+        self.commit_with_unguarded(page_address, level, delay)?;
+
+        drop(guard);
+        Ok(self.into_mode())
+    }
+
+    /// Links `logical_block_address` to the replacement `physical_block_address` in the device's
+    /// bad block look up table with the Bad Block Management command, e.g. to relocate a block
+    /// after a program or erase failure. Returns `FlashError::LutFull` without sending
+    /// anything if the table has no room left.
+    ///
+    /// Sets the write enable latch first, since the device clears it after every link.
+    pub fn swap_bad_block(
+        &self,
+        logical_block_address: u16,
+        physical_block_address: u16,
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+        self.swap_bad_block_unguarded(logical_block_address, physical_block_address)
+    }
+
+    pub(crate) fn swap_bad_block_unguarded(
+        &self,
+        logical_block_address: u16,
+        physical_block_address: u16,
+    ) -> Result<(), FlashError> {
+        if self.block0_reserved() && physical_block_address == 0 {
+            return Err(FlashError::Block0Reserved);
+        }
+
+        if self.check_busy()? {
+            return Err(FlashError::DeviceBusy);
+        }
+
+        if self.read_status_register_unguarded()?.bbm_lut_full {
+            return Err(FlashError::LutFull);
+        }
+
+        self.qspi_write(commands::write_enable())?;
+
+        let lba = logical_block_address.to_be_bytes();
+        let pba = physical_block_address.to_be_bytes();
+        let addresses = [lba[0], lba[1], pba[0], pba[1]];
+
+        self.qspi_write(commands::swap_blocks(&addresses))?;
+        self.wait_while_busy_unguarded()?;
+
+        self.log_event(FlashEventKind::Relocation {
+            logical_block: logical_block_address,
+            physical_block: physical_block_address,
+        });
+
+        Ok(())
+    }
+
+    /// Adds each `(logical, physical)` link to the device's bad block look up table in turn with
+    /// `swap_bad_block`, e.g. to restore a saved set of links. Stops as soon as the table is full
+    /// and returns how many of the links were registered, so the links that didn't fit are exactly
+    /// `links[count..]`.
+    pub fn register_bad_block_links(&self, links: &[(u16, u16)]) -> Result<usize, FlashError> {
+        let _guard = self.begin_operation()?;
+        self.register_bad_block_links_unguarded(links)
+    }
+
+    pub(crate) fn register_bad_block_links_unguarded(
+        &self,
+        links: &[(u16, u16)],
+    ) -> Result<usize, FlashError> {
+        if self.block0_reserved() && links.iter().any(|(_, physical)| *physical == 0) {
+            return Err(FlashError::Block0Reserved);
+        }
+
+        for (registered, (logical, physical)) in links.iter().enumerate() {
+            match self.swap_bad_block_unguarded(*logical, *physical) {
+                Ok(()) => {}
+                Err(FlashError::LutFull) => return Ok(registered),
+                Err(err) => return Err(err),
             }
-            Err(err) => return Err(err),
         }
 
-        let bytes = page_address.to_be_bytes();
+        Ok(links.len())
+    }
 
-        let command = QspiWriteCommand {
-            instruction: Some((FlashCommands::ProgramExecute as u8, QspiMode::SingleChannel)),
-            address: None,
-            alternative_bytes: None,
-            dummy_cycles: 8,
-            data: Some((&bytes, QspiMode::SingleChannel)),
-            double_data_rate: false,
-        };
+    /// Programs the main area and the spare area of a page with a single Program Execute, so data
+    /// and its metadata either both make it to memory or neither does. The main data resets the
+    /// rest of the data buffer, so any spare bytes not covered by `spare` program as erased.
+    ///
+    /// With ECC enabled the ECC bytes of the spare area are reserved, so any byte of `spare` that
+    /// falls on one of them must be left as 0xFF. Waits for the program to finish and returns
+    /// `FlashError::ProgramFailed` if the device reports a failure. Uses one of the page's
+    /// partial program budget.
+    pub fn write_page_split<D: DelayUs<u32>>(
+        self,
+        page_address: u16,
+        main: &[u8; PAGE_SIZE_BYTES],
+        spare: &[u8],
+        write_method: WriteMethod,
+        delay: &mut D,
+    ) -> Result<W25N01GV<BUS, ReadMode>, FlashError> {
+        let guard = self.begin_operation()?;
+
+        if spare.len() > SPARE_BYTES {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        self.check_latency_budget(self.write_page_worst_case_us(spare.len() as u32, write_method))?;
+
+        let ecc_enabled = self.read_configuration_register_unguarded()?.ecc_e;
+        for (index, byte) in spare.iter().enumerate() {
+            if *byte != 0xFF {
+                self.oob_layout.check_user_byte(index, ecc_enabled)?;
+            }
+        }
 
-        if let Err(err) = self.qspi.write(command) {
-            Err(FlashCommandError::from_qspi_error(err))
-        } else {
-            Ok(W25N01GV {
-                _marker: PhantomData {},
-                qspi: self.qspi,
-            })
+        self.load_to_data_buffer_unguarded(main, 0, write_method, LoadMode::ResetThenLoad)?;
+        if !spare.is_empty() {
+            self.load_to_data_buffer_unguarded(
+                spare,
+                PAGE_SIZE_BYTES as u16,
+                write_method,
+                LoadMode::PreserveAndLoad,
+            )?;
         }
+
+        self.commit_with_unguarded(page_address, self.verification_level, delay)?;
+
+        drop(guard);
+        Ok(self.into_mode())
     }
 }
 
-impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3), MODE> {
+impl<BUS: QspiBus, MODE> W25N01GV<BUS, MODE> {
     pub fn set_write_protection(
         &self,
         tb: bool,
@@ -209,17 +615,19 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
         bp2: bool,
         bp1: bool,
         bp0: bool,
-    ) -> Result<(), FlashCommandError> {
+    ) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
         }
 
-        match self.read_protection_register() {
+        match self.read_protection_register_unguarded() {
             Ok(mut protection_register) => {
                 protection_register.tb = tb;
                 protection_register.bp3 = bp3;
@@ -227,29 +635,329 @@ impl<CLK, NCS, IO0, IO1, IO2, IO3, MODE> W25N01GV<(CLK, NCS, IO0, IO1, IO2, IO3)
                 protection_register.bp1 = bp1;
                 protection_register.bp0 = bp0;
 
-                self.write_protection_register(protection_register)
+                self.write_protection_register_unguarded(protection_register)
             }
             Err(err) => Err(err),
         }
     }
 
-    pub fn set_continuous_read_mode(&self, continuous_read: bool) -> Result<(), FlashCommandError> {
+    /// Switches between `BufferMode::Continuous` (true) and `BufferMode::Buffered` (false)
+    pub fn set_continuous_read_mode(&self, continuous_read: bool) -> Result<(), FlashError> {
+        let _guard = self.begin_operation()?;
+
         match self.check_busy() {
             Ok(busy) => {
                 if busy {
-                    return Err(FlashCommandError::DeviceBusy);
+                    return Err(FlashError::DeviceBusy);
                 }
             }
             Err(err) => return Err(err),
         }
 
-        match self.read_configuration_register() {
+        match self.read_configuration_register_unguarded() {
             Ok(mut configuration_register) => {
                 configuration_register.buf = !continuous_read;
 
-                self.write_configuration_register(configuration_register)
+                self.write_configuration_register_unguarded(configuration_register)
             }
             Err(err) => Err(err),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{NoDelay, SimFlash},
+        Block0Policy, EventLog, ReadMethod, MAX_BBM_LUT_ENTIRES,
+    };
+    use std::vec::Vec;
+
+    fn erased_pages(sim: &SimFlash) -> Vec<u16> {
+        sim.commands()
+            .iter()
+            .filter(|command| command.opcode == 0xD8)
+            .filter_map(|command| command.page_address())
+            .collect()
+    }
+
+    #[test]
+    fn program_page_refuses_a_program_over_the_latency_budget() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        flash.set_latency_budget_us(Some(crate::latency::PROGRAM_MAX_US - 1));
+        sim.clear_log();
+
+        let result = flash.program_page(5, &[0xA5; 16], 0, WriteMethod::SingleLoad);
+
+        assert!(matches!(result, Err(FlashError::WouldExceedBudget { .. })));
+        assert!(sim.commands().is_empty());
+    }
+
+    #[test]
+    fn program_page_runs_within_the_latency_budget() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver();
+        let worst_case = flash.program_page_worst_case_us(16, WriteMethod::SingleLoad);
+        flash.set_latency_budget_us(Some(worst_case));
+
+        let (_, write_failure) = flash
+            .program_page(5, &[0xA5; 16], 0, WriteMethod::SingleLoad)
+            .unwrap();
+
+        assert!(!write_failure);
+        assert_eq!(&sim.page(5)[..16], &[0xA5; 16]);
+        assert_eq!(sim.count(0x10), 1);
+    }
+
+    #[test]
+    fn a_chunked_erase_skips_a_reserved_block_0() {
+        let sim = SimFlash::new();
+        sim.set_page(0, &[0x42; 16]);
+        let mut flash = sim.driver();
+        flash.set_block0_policy(Block0Policy::Reserved);
+        sim.clear_log();
+
+        let mut chunks = 0;
+        let flash = flash
+            .erase_range_chunked(0, 3 * 64 - 1, &mut NoDelay, || chunks += 1)
+            .unwrap();
+
+        assert_eq!(erased_pages(&sim), [64, 128]);
+        assert_eq!(chunks, 2);
+        assert_eq!(&sim.page(0)[..16], &[0x42; 16]);
+
+        // The raw block API still reaches it
+        flash.erase_block(0, &mut NoDelay).unwrap();
+        assert_eq!(&sim.page(0)[..16], &[0xFF; 16]);
+    }
+
+    #[test]
+    fn a_range_erase_takes_in_block_0_under_the_normal_policy() {
+        let sim = SimFlash::new();
+        sim.set_page(0, &[0x42; 16]);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        flash.erase_range(10, 64, &mut NoDelay).unwrap();
+
+        assert_eq!(erased_pages(&sim), [0, 64]);
+        assert_eq!(&sim.page(0)[..16], &[0xFF; 16]);
+    }
+
+    #[test]
+    fn a_chunked_erase_stops_at_the_first_failed_block() {
+        let sim = SimFlash::new();
+        sim.fail_erase(2);
+        sim.set_page(3 * 64, &[0x42; 16]);
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let mut chunks = 0;
+        let result = flash.erase_range_chunked(64, 4 * 64 - 1, &mut NoDelay, || chunks += 1);
+
+        assert_eq!(
+            result.err(),
+            Some(FlashError::EraseFailed { page_address: 128 })
+        );
+        assert_eq!(erased_pages(&sim), [64, 128]);
+        assert_eq!(chunks, 1);
+        assert_eq!(&sim.page(3 * 64)[..16], &[0x42; 16]);
+    }
+
+    #[test]
+    fn a_reversed_range_erases_nothing() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        sim.clear_log();
+
+        let result = flash.erase_range(64, 63, &mut NoDelay);
+
+        assert_eq!(result.err(), Some(FlashError::OutOfBounds));
+        assert_eq!(sim.count(0xD8), 0);
+    }
+
+    #[test]
+    fn swap_bad_block_refuses_a_full_lut_without_sending_anything() {
+        let sim = SimFlash::new();
+        let links: Vec<_> = (0..MAX_BBM_LUT_ENTIRES as u16)
+            .map(|index| (100 + index, 1000 + index))
+            .collect();
+        sim.set_lut(&links);
+        let flash = sim.driver().into_write_mode().unwrap();
+        sim.clear_log();
+
+        assert_eq!(flash.swap_bad_block(5, 1020), Err(FlashError::LutFull));
+        assert_eq!(sim.count(0x06), 0);
+        assert_eq!(sim.count(0xA1), 0);
+    }
+
+    #[test]
+    fn swap_bad_block_only_uses_block_0_as_a_replacement_when_it_isnt_reserved() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver().into_write_mode().unwrap();
+        flash.set_block0_policy(Block0Policy::Reserved);
+        sim.clear_log();
+
+        assert_eq!(flash.swap_bad_block(5, 0), Err(FlashError::Block0Reserved));
+        assert!(sim.commands().is_empty());
+
+        flash.set_block0_policy(Block0Policy::Normal);
+        flash.swap_bad_block(5, 0).unwrap();
+        assert_eq!(flash.read_bbm_lookup_table().unwrap()[0], Some((5, 0)));
+    }
+
+    #[test]
+    fn swap_bad_block_refuses_a_busy_device_without_sending_anything() {
+        let sim = SimFlash::new();
+        sim.set_busy_polls(3);
+        let flash = sim.driver().into_write_mode().unwrap();
+
+        // Started, but not waited for
+        flash.read_memory_to_data_buffer(64).unwrap();
+        sim.clear_log();
+
+        assert_eq!(flash.swap_bad_block(5, 1020), Err(FlashError::DeviceBusy));
+        assert_eq!(sim.count(0x06), 0);
+        assert_eq!(sim.count(0xA1), 0);
+
+        flash.wait_while_busy().unwrap();
+        flash.swap_bad_block(5, 1020).unwrap();
+        assert_eq!(flash.read_bbm_lookup_table().unwrap()[0], Some((5, 1020)));
+    }
+
+    #[test]
+    fn swap_bad_block_sets_the_latch_then_sends_both_block_numbers() {
+        let sim = SimFlash::new();
+        let flash = sim.driver().into_write_mode().unwrap();
+        sim.clear_log();
+
+        flash.swap_bad_block(5, 1020).unwrap();
+
+        // Everything but the status register reads
+        let commands: Vec<_> = sim
+            .commands()
+            .into_iter()
+            .filter(|command| command.opcode != 0x05)
+            .collect();
+        let opcodes: Vec<_> = commands.iter().map(|command| command.opcode).collect();
+        assert_eq!(opcodes, [0x06, 0xA1]);
+        assert_eq!(commands[1].data, [0x00, 0x05, 0x03, 0xFC]);
+    }
+
+    #[test]
+    fn swap_bad_block_logs_the_relocation() {
+        let sim = SimFlash::new();
+        let flash = sim.driver();
+        let mut log = EventLog::mount(&flash, 6, 2, ReadMethod::FastRead).unwrap();
+
+        let flash = flash.into_write_mode().unwrap();
+        flash.swap_bad_block(5, 1020).unwrap();
+        let flash = log
+            .flush(
+                flash.into_read_mode().unwrap(),
+                WriteMethod::SingleLoad,
+                &mut NoDelay,
+            )
+            .unwrap();
+
+        let mut kinds = Vec::new();
+        log.events(&flash, ReadMethod::FastRead, |event| kinds.push(event.kind))
+            .unwrap();
+        assert_eq!(
+            kinds,
+            [FlashEventKind::Relocation {
+                logical_block: 5,
+                physical_block: 1020,
+            }]
+        );
+    }
+
+    #[test]
+    fn swapped_links_land_in_the_devices_lut() {
+        let sim = SimFlash::new();
+        sim.set_page(1020 * 64 + 3, &[0x5A; 16]);
+        let flash = sim.driver().into_write_mode().unwrap();
+
+        flash.swap_bad_block(5, 1020).unwrap();
+        flash.swap_bad_block(0x3FF, 1021).unwrap();
+
+        let lut = flash.read_bbm_lookup_table().unwrap();
+        assert_eq!(lut[..3], [Some((5, 1020)), Some((0x3FF, 1021)), None]);
+
+        // Reads of the logical block now come from its replacement
+        let mut data = [0_u8; crate::PAGE_SIZE_WITH_ECC_BYTES];
+        flash.read_memory_to_data_buffer(5 * 64 + 3).unwrap();
+        flash.wait_while_busy().unwrap();
+        flash
+            .read_data_buffer(&mut data, ReadMethod::FastRead)
+            .unwrap();
+        assert_eq!(data[..16], [0x5A; 16]);
+    }
+
+    #[test]
+    fn register_bad_block_links_adds_the_links_in_order() {
+        let sim = SimFlash::new();
+        let flash = sim.driver().into_write_mode().unwrap();
+        sim.clear_log();
+
+        let links = [(5, 1020), (9, 1021), (7, 1022)];
+        assert_eq!(flash.register_bad_block_links(&links), Ok(3));
+
+        let sent: Vec<_> = sim
+            .commands()
+            .into_iter()
+            .filter(|command| command.opcode == 0xA1)
+            .map(|command| command.data)
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                [0x00, 0x05, 0x03, 0xFC],
+                [0x00, 0x09, 0x03, 0xFD],
+                [0x00, 0x07, 0x03, 0xFE]
+            ]
+        );
+
+        let lut = flash.read_bbm_lookup_table().unwrap();
+        assert_eq!(
+            lut[..4],
+            [Some((5, 1020)), Some((9, 1021)), Some((7, 1022)), None]
+        );
+    }
+
+    #[test]
+    fn register_bad_block_links_stops_when_the_lut_fills() {
+        let sim = SimFlash::new();
+        let existing: Vec<_> = (0..MAX_BBM_LUT_ENTIRES as u16 - 2)
+            .map(|index| (100 + index, 1000 + index))
+            .collect();
+        sim.set_lut(&existing);
+        let flash = sim.driver().into_write_mode().unwrap();
+        sim.clear_log();
+
+        let links = [(5, 1020), (9, 1021), (7, 1022), (8, 1023)];
+        assert_eq!(flash.register_bad_block_links(&links), Ok(2));
+        assert_eq!(sim.count(0xA1), 2);
+
+        // The links that didn't fit are exactly the rest
+        let lut = flash.read_bbm_lookup_table().unwrap();
+        assert!(lut.contains(&Some((9, 1021))));
+        assert!(!lut.contains(&Some((7, 1022))));
+    }
+
+    #[test]
+    fn register_bad_block_links_checks_block_0_before_linking_any() {
+        let sim = SimFlash::new();
+        let mut flash = sim.driver().into_write_mode().unwrap();
+        flash.set_block0_policy(Block0Policy::Reserved);
+        sim.clear_log();
+
+        assert_eq!(
+            flash.register_bad_block_links(&[(5, 1020), (9, 0)]),
+            Err(FlashError::Block0Reserved)
+        );
+        assert!(sim.commands().is_empty());
+    }
+}